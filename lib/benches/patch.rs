@@ -0,0 +1,73 @@
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use everest_lib::{
+    backend::maildir::{Durability, Mdir},
+    build_patch,
+    cache::{Cache, FileCache, Snapshot},
+    iter_patch,
+    synthetic::{self, SyntheticMailbox},
+    Flag, Flags,
+};
+
+const SIZES: [usize; 3] = [10_000, 100_000, 1_000_000];
+
+fn patch(c: &mut Criterion) {
+    let mut group = c.benchmark_group("patch");
+    group.sample_size(10);
+    for size in SIZES {
+        let mailbox = SyntheticMailbox::generate(size, 42);
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("build", size), &mailbox, |b, m| {
+            b.iter(|| build_patch(&m.prev_imap, &m.next_imap, &m.prev_mdir, &m.next_mdir))
+        });
+        group.bench_with_input(BenchmarkId::new("iter", size), &mailbox, |b, m| {
+            b.iter(|| iter_patch(&m.prev_imap, &m.next_imap, &m.prev_mdir, &m.next_mdir).count())
+        });
+    }
+    group.finish();
+}
+
+fn cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("file-cache");
+    group.sample_size(10);
+    let dir = tempfile::tempdir().unwrap();
+    let cache = FileCache::new(dir.path());
+    for size in SIZES {
+        let snapshot = Snapshot::new(
+            synthetic::envelopes(size, 42),
+            synthetic::envelopes(size, 43),
+        );
+        let folder = size.to_string();
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("save", size), &snapshot, |b, s| {
+            b.iter(|| cache.save(&folder, s).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("load", size), |b| {
+            b.iter(|| black_box(cache.load(&folder).unwrap()))
+        });
+    }
+    group.finish();
+}
+
+fn maildir(c: &mut Criterion) {
+    let mut group = c.benchmark_group("maildir");
+    group.sample_size(10);
+    // messages are real files, so only the smallest mailbox is used
+    let size = SIZES[0];
+    let dir = tempfile::tempdir().unwrap();
+    let mdir = Mdir::new(dir.path()).with_durability(Durability::None);
+    mdir.create_dirs().unwrap();
+    let flags = Flags::from_iter([Flag::Seen]);
+    for _ in 0..size {
+        mdir.add_msg(b"Subject: synthetic\r\n\r\n", &flags).unwrap();
+    }
+    group.throughput(Throughput::Elements(size as u64));
+    group.bench_function(BenchmarkId::new("envelopes", size), |b| {
+        b.iter(|| mdir.envelopes().unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, patch, cache, maildir);
+criterion_main!(benches);
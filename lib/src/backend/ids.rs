@@ -0,0 +1,174 @@
+//! Ids replicas give to the messages they add, kept apart from the
+//! ids of the other side the sync knows them by.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use crate::{Envelopes, EverestError};
+
+/// Mapping between the ids the sync knows the messages of a folder
+/// by and the ids a replica gave them, kept in a file with one
+/// `<sync id>\t<replica id>` line per message.
+///
+/// Replicas like maildirs and IMAP servers cannot choose the ids of
+/// the messages they add: [`crate::sync::Replica`] implementations
+/// map the ids of the other side to their own with it. Messages never
+/// added by the sync are known by their own id and have no line.
+///
+/// Changes are kept in memory until [`ReplicaIds::flush`], so that
+/// the file is written once per folder sync rather than once per
+/// message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplicaIds {
+    path: PathBuf,
+    ids: HashMap<String, String>,
+    changed: bool,
+}
+
+impl ReplicaIds {
+    /// Loads the mapping of the given file, empty if missing.
+    pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, EverestError> {
+        let path = path.into();
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(EverestError::ReadReplicaIdsError(err, path)),
+        };
+        let ids = content.lines().filter_map(|line| line.split_once('\t'));
+        let ids = ids.map(|(id, local)| (id.to_owned(), local.to_owned()));
+        Ok(Self {
+            ids: ids.collect(),
+            path,
+            changed: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the id the replica gave to the message the sync knows
+    /// by the given id.
+    pub fn local<'a>(&'a self, id: &'a str) -> &'a str {
+        self.ids.get(id).map(String::as_str).unwrap_or(id)
+    }
+
+    /// Returns the reverse mapping, from the ids of the replica to
+    /// the ids of the sync.
+    pub fn reversed(&self) -> HashMap<&str, &str> {
+        let ids = self.ids.iter();
+        ids.map(|(id, local)| (local.as_str(), id.as_str()))
+            .collect()
+    }
+
+    /// Maps the given id of the sync to the given id of the replica.
+    pub fn insert(&mut self, id: &str, local: &str) {
+        if id != local {
+            self.ids.insert(id.to_owned(), local.to_owned());
+            self.changed = true;
+        }
+    }
+
+    /// Forgets the given id of the sync.
+    pub fn remove(&mut self, id: &str) {
+        self.changed |= self.ids.remove(id).is_some();
+    }
+
+    /// Renames the given envelopes of the replica to the ids of the
+    /// sync, forgetting the messages no longer found in the replica.
+    pub fn map_envelopes(&mut self, envelopes: Envelopes) -> Envelopes {
+        self.retain(&envelopes.keys().map(|id| &**id).collect());
+        let reversed = self.reversed();
+        let envelopes = envelopes.into_iter().map(|mut envelope| {
+            if let Some(id) = reversed.get(&*envelope.id) {
+                envelope.id = (*id).into();
+            }
+            envelope
+        });
+        envelopes.collect()
+    }
+
+    /// Forgets the messages no longer found in the replica, given by
+    /// their ids in the replica.
+    pub fn retain(&mut self, locals: &HashSet<&str>) {
+        let len = self.ids.len();
+        self.ids.retain(|_, local| locals.contains(local.as_str()));
+        self.changed |= self.ids.len() != len;
+    }
+
+    /// Saves the mapping when it changed since loaded or last saved.
+    ///
+    /// The mapping is written to a `.new` file, synced to disk then
+    /// renamed, so that a crash never leaves a truncated mapping
+    /// behind.
+    pub fn flush(&mut self) -> Result<(), EverestError> {
+        if !self.changed {
+            return Ok(());
+        }
+        let mut ids = self.ids.iter().collect::<Vec<_>>();
+        ids.sort_unstable();
+        let content = ids.iter().map(|(id, local)| format!("{}\t{}\n", id, local));
+        let content = content.collect::<String>();
+        let mut tmp_path = self.path.clone();
+        tmp_path.as_mut_os_string().push(".new");
+        fs::File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content.as_bytes())?;
+                file.sync_all()
+            })
+            .and_then(|()| fs::rename(&tmp_path, &self.path))
+            .map_err(|err| EverestError::WriteReplicaIdsError(err, self.path.clone()))?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Envelope;
+
+    use super::*;
+
+    #[test]
+    fn ids_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("ids");
+        let mut ids = ReplicaIds::load(&path).unwrap();
+        assert_eq!("42", ids.local("42"));
+
+        // ids equal on both sides are not kept
+        ids.insert("7", "7");
+        ids.flush().unwrap();
+        assert!(!path.exists());
+        ids.insert("42", "1663512456.M1P2Q3.host");
+        ids.insert("43", "1663512457.M1P2Q4.host");
+        assert_eq!("1663512456.M1P2Q3.host", ids.local("42"));
+        assert_eq!(Some(&"42"), ids.reversed().get("1663512456.M1P2Q3.host"));
+
+        // changes are only saved once flushed
+        assert!(!path.exists());
+        ids.flush().unwrap();
+
+        let mut ids = ReplicaIds::load(&path).unwrap();
+        assert_eq!("1663512457.M1P2Q4.host", ids.local("43"));
+        let envelopes = Envelopes::from_iter([
+            Envelope::new("1663512456.M1P2Q3.host"),
+            Envelope::new("1663512457.M1P2Q4.host"),
+            Envelope::new("1663512458.M1P2Q5.host"),
+        ]);
+        let envelopes = ids.map_envelopes(envelopes);
+        let mut sync_ids = envelopes.keys().map(|id| &**id).collect::<Vec<_>>();
+        sync_ids.sort_unstable();
+        assert_eq!(vec!["1663512458.M1P2Q5.host", "42", "43"], sync_ids);
+
+        ids.remove("42");
+        ids.retain(&HashSet::new());
+        ids.flush().unwrap();
+        assert_eq!(ReplicaIds::load(&path).unwrap(), ids);
+        assert_eq!("43", ids.local("43"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Envelopes of IMAP fetches, and health checks of IMAP sessions.
+
+use std::{
+    fmt::Write,
+    io::{self, Read},
+};
+
+#[cfg(feature = "metadata")]
+use crate::Metadata;
+use crate::{
+    health::{Check, Probe},
+    plan::Backend,
+    BackendError, Envelope, Envelopes, ErrorCode, ErrorKind, EverestError, Flag, Flags, Id,
+};
+
+/// Builds the error of an IMAP fetch missing its UID, identified by
+/// its sequence number.
+pub(crate) fn missing_uid(message: u32) -> EverestError {
+    let err = BackendError::new(ErrorKind::InvalidData, Backend::Imap, "find uid");
+    let err = err.with_code(ErrorCode::UidMissing);
+    err.with_id(message.to_string()).into()
+}
+
+/// Logs in with the given credentials, checking the authentication.
+/// Returns the session when it succeeds, to check the rest with
+/// [`check`].
+pub fn check_login<T: Read + io::Write>(
+    client: imap::Client<T>,
+    username: &str,
+    password: &str,
+) -> (Check, Option<imap::Session<T>>) {
+    match client.login(username, password) {
+        Ok(session) => (Check::new(Probe::Authentication, Ok(())), Some(session)),
+        Err((err, _)) => {
+            let err = imap_error("log in", err);
+            (Check::new::<()>(Probe::Authentication, Err(err)), None)
+        }
+    }
+}
+
+/// Checks that the server of the given session can still be reached
+/// and that the given folders exist, examining them read-only so that
+/// no flag like `\Recent` is changed. Folders are skipped when the
+/// server cannot be reached.
+pub fn check<T: Read + io::Write>(
+    session: &mut imap::Session<T>,
+    folders: &[String],
+) -> Vec<Check> {
+    let result = session
+        .noop()
+        .map_err(|err| imap_error("reach server", err));
+    let connection = Check::new(Probe::Connection, result);
+    let reachable = connection.is_ok();
+    let mut checks = vec![connection];
+    for folder in folders {
+        let probe = Probe::Folder(folder.clone());
+        checks.push(match reachable {
+            true => {
+                let result = session.examine(folder).map_err(|source| {
+                    let err = BackendError::new(ErrorKind::Imap, Backend::Imap, "examine");
+                    EverestError::from(err.with_folder(folder.as_str()).with_source(source))
+                });
+                Check::new(probe, result)
+            }
+            false => Check::skipped(probe),
+        });
+    }
+    checks
+}
+
+fn imap_error(operation: &'static str, err: imap::Error) -> EverestError {
+    BackendError::new(ErrorKind::Imap, Backend::Imap, operation)
+        .with_source(err)
+        .into()
+}
+
+/// Stream replaying the given responses, ignoring commands.
+#[cfg(test)]
+pub(crate) struct Script(pub(crate) io::Cursor<Vec<u8>>);
+
+#[cfg(test)]
+impl Read for Script {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+#[cfg(test)]
+impl io::Write for Script {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl TryFrom<imap::types::Fetches> for Envelopes {
+    type Error = EverestError;
+
+    fn try_from(fetches: imap::types::Fetches) -> Result<Self, Self::Error> {
+        Envelopes::try_from(fetches.iter().as_slice())
+    }
+}
+
+impl<'a, 'f> TryFrom<&'a [imap::types::Fetch<'f>]> for Envelopes {
+    type Error = EverestError;
+
+    fn try_from(fetches: &'a [imap::types::Fetch<'f>]) -> Result<Self, Self::Error> {
+        Envelopes::try_from_fetches(fetches)
+    }
+}
+
+impl Envelopes {
+    /// Builds envelopes from the given fetches, like the ones of
+    /// several chunks chained together.
+    pub fn try_from_fetches<'a, 'f: 'a, I>(fetches: I) -> Result<Self, EverestError>
+    where
+        I: IntoIterator<Item = &'a imap::types::Fetch<'f>>,
+    {
+        let mut envelopes = Envelopes::default();
+        envelopes.extend_from_fetches(fetches)?;
+        Ok(envelopes)
+    }
+
+    /// Adds the envelopes of the given fetches, like a chunk streamed
+    /// by the fetch pipeline. Envelopes of the fetches before a fetch
+    /// missing its UID are kept.
+    pub fn extend_from_fetches<'a, 'f: 'a, I>(&mut self, fetches: I) -> Result<(), EverestError>
+    where
+        I: IntoIterator<Item = &'a imap::types::Fetch<'f>>,
+    {
+        let fetches = fetches.into_iter();
+        self.reserve(fetches.size_hint().0);
+        // UIDs are formatted in the same buffer, ids being allocated
+        // only once, in their shared form
+        let mut buf = String::new();
+        for fetch in fetches {
+            let uid = fetch.uid.ok_or_else(|| missing_uid(fetch.message))?;
+            buf.clear();
+            // writing to a string never fails
+            let _ = write!(buf, "{}", uid);
+            let id = Id::from(buf.as_str());
+
+            let mut flags = Flags::default();
+            for flag in fetch.flags() {
+                match flag {
+                    imap::types::Flag::Seen => flags.insert(Flag::Seen),
+                    imap::types::Flag::Answered => flags.insert(Flag::Replied),
+                    imap::types::Flag::Flagged => flags.insert(Flag::Flagged),
+                    imap::types::Flag::Deleted => flags.insert(Flag::Trashed),
+                    imap::types::Flag::Draft => flags.insert(Flag::Draft),
+                    imap::types::Flag::Custom(keyword) if !keyword.starts_with('\\') => {
+                        flags.insert(Flag::Keyword(keyword.to_string()))
+                    }
+                    _ => false,
+                };
+            }
+            let envelope = Envelope {
+                id,
+                flags,
+                metadata: None,
+            };
+            #[cfg(feature = "metadata")]
+            let envelope = envelope.with_metadata(Metadata::from_fetch(fetch));
+            self.insert(envelope.id.clone(), envelope);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::mpsc};
+
+    use imap::types::Fetches;
+
+    use crate::{health::Probe, Envelope, Envelopes, ErrorCode, Flag};
+
+    use super::{check, check_login, Script};
+
+    fn fetches(raw: &str) -> Fetches {
+        let (mut tx, _rx) = mpsc::channel();
+        Fetches::parse(raw.as_bytes().to_vec(), &mut tx).unwrap()
+    }
+
+    #[test]
+    fn fetches_test() {
+        let chunk = fetches(
+            "* 1 FETCH (UID 4 FLAGS (\\Seen))\r\n\
+             * 2 FETCH (UID 7 FLAGS (\\Answered custom))\r\n",
+        );
+        let expected = Envelopes::from_iter([
+            Envelope::new("4").with_flag(Flag::Seen),
+            Envelope::new("7")
+                .with_flag(Flag::Replied)
+                .with_flag(Flag::Keyword("custom".into())),
+        ]);
+        assert_eq!(
+            expected,
+            Envelopes::try_from(chunk.iter().as_slice()).unwrap()
+        );
+        assert_eq!(expected, Envelopes::try_from(chunk).unwrap());
+
+        // chunks streamed one after the other
+        let chunks = [
+            fetches("* 1 FETCH (UID 4 FLAGS (\\Seen))\r\n"),
+            fetches("* 2 FETCH (UID 7 FLAGS (\\Answered custom))\r\n"),
+        ];
+        let mut envelopes = Envelopes::default();
+        for chunk in &chunks {
+            envelopes.extend_from_fetches(chunk.iter()).unwrap();
+        }
+        assert_eq!(expected, envelopes);
+        let chained = Envelopes::try_from_fetches(chunks.iter().flat_map(Fetches::iter));
+        assert_eq!(expected, chained.unwrap());
+
+        let err = envelopes
+            .extend_from_fetches(fetches("* 3 FETCH (FLAGS ())\r\n").iter())
+            .unwrap_err();
+        assert_eq!(ErrorCode::UidMissing, err.code());
+        assert_eq!(2, envelopes.len());
+    }
+
+    #[test]
+    fn check_test() {
+        let script = "a1 OK logged in\r\n\
+            a2 OK noop\r\n\
+            * 3 EXISTS\r\n\
+            a3 OK [READ-ONLY] examined\r\n\
+            a4 NO no such folder\r\n";
+        let client = imap::Client::new(Script(Cursor::new(script.into())));
+        let (login, session) = check_login(client, "alice", "secret");
+        assert!(login.is_ok());
+
+        let folders = ["INBOX".to_owned(), "Archive".to_owned()];
+        let checks = check(&mut session.unwrap(), &folders);
+        let checks = checks.iter().map(|c| (&c.probe, c.status.name()));
+        assert_eq!(
+            vec![
+                (&Probe::Connection, "ok"),
+                (&Probe::Folder("INBOX".into()), "ok"),
+                (&Probe::Folder("Archive".into()), "failed"),
+            ],
+            checks.collect::<Vec<_>>()
+        );
+
+        // folders are skipped once the server is gone
+        let client = imap::Client::new(Script(Cursor::new(b"a1 OK logged in\r\n".to_vec())));
+        let (_, session) = check_login(client, "alice", "secret");
+        let checks = check(&mut session.unwrap(), &folders);
+        let statuses = checks.iter().map(|c| c.status.name());
+        assert_eq!(
+            vec!["failed", "skipped", "skipped"],
+            statuses.collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,509 @@
+//! IMAP side of a sync, over a logged in session.
+
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::{Read, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+use crate::{
+    backend::{ids::ReplicaIds, imap::check},
+    download::{BodySource, Downloader},
+    error::is_throttling_imap,
+    fetch::{ChunkSizer, ENVELOPES_QUERY},
+    flag::format_flag,
+    health::Check,
+    plan::{format_uid_set, uid_ranges, Backend},
+    sync::Replica,
+    throttle::{Provider, Throttle},
+    BackendError, Envelopes, ErrorKind, EverestError, Flag, Flags,
+};
+
+/// Folders of an IMAP account, as the left side of a
+/// [`crate::sync::Sync`], messages being identified by their UID.
+///
+/// Messages added by the sync get a UID of the server: the ids of the
+/// other side they keep for the sync are mapped to them in a file per
+/// folder in the given directory, see [`ReplicaIds`]. The UID of an
+/// appended message is the highest one from the `UIDNEXT` of the
+/// folder before appending it, so that other clients appending at the
+/// same time may confuse it.
+///
+/// Commands go through a [`Throttle`], reporting throttling responses
+/// to it, so that long syncs stay below the rate limits of providers.
+/// Envelopes are fetched in chunks sized by a [`ChunkSizer`], and
+/// large bodies can be read in parts by a [`Downloader`].
+pub struct ImapReplica<T: Read + Write> {
+    session: imap::Session<T>,
+    ids_dir: PathBuf,
+    selected: Option<String>,
+    uid_validity: u32,
+    ids: HashMap<String, ReplicaIds>,
+    throttle: Throttle,
+    sizer: ChunkSizer,
+    downloads: Option<(PathBuf, u64)>,
+}
+
+impl<T: Read + Write> ImapReplica<T> {
+    /// Builds a replica over the given session, keeping the ids of
+    /// each folder in the given directory.
+    pub fn new<P: Into<PathBuf>>(session: imap::Session<T>, ids_dir: P) -> Self {
+        Self {
+            session,
+            ids_dir: ids_dir.into(),
+            selected: None,
+            uid_validity: 0,
+            ids: HashMap::new(),
+            throttle: Throttle::new(),
+            sizer: ChunkSizer::new(),
+            downloads: None,
+        }
+    }
+
+    /// Limits commands the way the provider of the server of the given
+    /// host expects, see [`Throttle::for_provider`]. Commands are only
+    /// held back by throttling responses by default.
+    pub fn with_host(mut self, host: &str) -> Self {
+        self.throttle = Throttle::for_provider(Provider::from_host(host));
+        self
+    }
+
+    /// Sizes the chunks envelopes are fetched in with the given sizer.
+    pub fn with_chunk_sizer(mut self, sizer: ChunkSizer) -> Self {
+        self.sizer = sizer;
+        self
+    }
+
+    /// Reads bodies larger than the given chunk size in parts with a
+    /// [`Downloader`] keeping them in the given directory, so that a
+    /// dropped connection resumes the read of a large message in the
+    /// next sync instead of starting over. Bodies are read in one
+    /// fetch by default.
+    pub fn with_downloads<P: Into<PathBuf>>(mut self, dir: P, chunk_size: u64) -> Self {
+        self.downloads = Some((dir.into(), chunk_size.max(1)));
+        self
+    }
+
+    /// Returns the session, to log out once the sync is done.
+    pub fn into_session(self) -> imap::Session<T> {
+        self.session
+    }
+
+    /// Returns the ids of the given folder, loading them the first
+    /// time. Folder names are escaped into file names, `/` becoming
+    /// `%2F`.
+    fn ids(&mut self, folder: &str) -> Result<&mut ReplicaIds, EverestError> {
+        match self.ids.entry(folder.to_owned()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let name = folder.replace('%', "%25").replace('/', "%2F");
+                let ids = ReplicaIds::load(self.ids_dir.join(name + ".ids"))?;
+                Ok(entry.insert(ids))
+            }
+        }
+    }
+
+    /// Runs the given command once the throttle lets it through, and
+    /// reports its response to the throttle.
+    fn command<R, F>(&mut self, f: F) -> imap::Result<R>
+    where
+        F: FnOnce(&mut imap::Session<T>) -> imap::Result<R>,
+    {
+        self.throttled(f, is_throttling_imap)
+    }
+
+    /// Runs the given command like [`ImapReplica::command`], telling
+    /// throttling errors apart with the given function.
+    fn throttled<R, E, F>(&mut self, f: F, is_throttling: fn(&E) -> bool) -> Result<R, E>
+    where
+        F: FnOnce(&mut imap::Session<T>) -> Result<R, E>,
+    {
+        self.throttle.wait(Backend::Imap);
+        let result = f(&mut self.session);
+        match &result {
+            Ok(_) => self.throttle.succeeded(Backend::Imap),
+            Err(err) if is_throttling(err) => {
+                self.throttle.throttled(Backend::Imap, Instant::now());
+            }
+            Err(_) => (),
+        }
+        result
+    }
+
+    /// Selects the given folder unless already selected, returning
+    /// the UID of the given message in it.
+    fn select(&mut self, folder: &str, id: &str) -> Result<String, EverestError> {
+        if self.selected.as_deref() != Some(folder) {
+            let mailbox = self.command(|session| session.select(folder));
+            let mailbox = mailbox.map_err(|err| imap_error("select", folder, err))?;
+            self.selected = Some(folder.to_owned());
+            self.uid_validity = mailbox.uid_validity.unwrap_or_default();
+        }
+        Ok(self.ids(folder)?.local(id).to_owned())
+    }
+
+    /// Stores the given flag change of the given message.
+    fn store(&mut self, folder: &str, id: &str, query: String) -> Result<(), EverestError> {
+        let uid = self.select(folder, id)?;
+        let result = self.command(|session| session.uid_store(&uid, query));
+        result.map_err(|err| imap_error("store", folder, err).with_id(id))?;
+        Ok(())
+    }
+
+    /// Fetches the envelopes of the selected folder in chunks, each
+    /// chunk being sized from the round-trip time and size of the
+    /// previous ones. UIDs are searched first, so that chunks span
+    /// the number of messages the sizer asks for whatever the gaps
+    /// between UIDs.
+    fn fetch_envelopes(&mut self, folder: &str) -> Result<Envelopes, EverestError> {
+        let uids = self.command(|session| session.uid_search("ALL"));
+        let uids = uids.map_err(|err| imap_error("search", folder, err))?;
+        let mut uids = uids.into_iter().collect::<Vec<_>>();
+        uids.sort_unstable();
+
+        let mut envelopes = Envelopes::default();
+        let mut uids = uids.as_slice();
+        while !uids.is_empty() {
+            let (chunk, rest) = self.sizer.split(uids);
+            let set = format_uid_set(&uid_ranges(chunk));
+            // the round trip is timed once the throttle let the
+            // command through
+            let fetches = self.command(|session| {
+                let start = Instant::now();
+                let fetches = session.uid_fetch(&set, ENVELOPES_QUERY)?;
+                Ok((fetches, start.elapsed()))
+            });
+            let (fetches, elapsed) = fetches.map_err(|err| imap_error("fetch", folder, err))?;
+            let bytes = fetches.iter().map(fetch_len).sum();
+            self.sizer.record(chunk.len(), bytes, elapsed);
+            envelopes.extend_from_fetches(fetches.iter())?;
+            uids = rest;
+        }
+        Ok(envelopes)
+    }
+
+    /// Reads the body of the given message of the selected folder in
+    /// parts when it is larger than the chunk size of the downloads,
+    /// returning `None` for smaller ones, read in one fetch. The
+    /// downloaded body is removed once read, the sync keeping it in
+    /// memory to add it to the other side.
+    fn download(&mut self, folder: &str, uid: &str) -> Result<Option<Vec<u8>>, EverestError> {
+        let (dir, chunk_size) = match &self.downloads {
+            Some((dir, chunk_size)) => (dir.clone(), *chunk_size),
+            None => return Ok(None),
+        };
+        let uid = match uid.parse() {
+            Ok(uid) => uid,
+            Err(_) => return Ok(None),
+        };
+        let uid_validity = self.uid_validity;
+        let mut source = ThrottledSource {
+            replica: self,
+            size: None,
+        };
+        if source.body_size(uid)? <= chunk_size {
+            return Ok(None);
+        }
+        let downloader = Downloader::new(dir, folder, uid_validity).with_chunk_size(chunk_size);
+        let path = downloader.download(&mut source, uid)?;
+        let body =
+            fs::read(&path).map_err(|err| EverestError::DownloadBodyError(err, path.clone()))?;
+        fs::remove_file(&path).map_err(|err| EverestError::DownloadBodyError(err, path))?;
+        Ok(Some(body))
+    }
+}
+
+/// Source of the bodies of the selected folder of a replica, its
+/// commands going through the throttle of the replica. The size of
+/// the last body is kept, so that it is fetched only once.
+struct ThrottledSource<'a, T: Read + Write> {
+    replica: &'a mut ImapReplica<T>,
+    size: Option<(u32, u64)>,
+}
+
+impl<T: Read + Write> BodySource for ThrottledSource<'_, T> {
+    fn body_size(&mut self, uid: u32) -> Result<u64, EverestError> {
+        match self.size {
+            Some((sized, size)) if sized == uid => Ok(size),
+            _ => {
+                let size = self
+                    .replica
+                    .throttled(|session| session.body_size(uid), is_throttling_body)?;
+                self.size = Some((uid, size));
+                Ok(size)
+            }
+        }
+    }
+
+    fn fetch_part(&mut self, uid: u32, offset: u64, len: u64) -> Result<Vec<u8>, EverestError> {
+        self.replica.throttled(
+            |session| session.fetch_part(uid, offset, len),
+            is_throttling_body,
+        )
+    }
+}
+
+fn is_throttling_body(err: &EverestError) -> bool {
+    match err {
+        EverestError::FetchImapBodyError(err, _) => is_throttling_imap(err),
+        _ => false,
+    }
+}
+
+impl<T: Read + Write> Replica for ImapReplica<T> {
+    fn envelopes(&mut self, folder: &str) -> Result<Envelopes, EverestError> {
+        let mailbox = self.command(|session| session.select(folder));
+        let mailbox = mailbox.map_err(|err| imap_error("select", folder, err))?;
+        self.selected = Some(folder.to_owned());
+        self.uid_validity = mailbox.uid_validity.unwrap_or_default();
+        let envelopes = match mailbox.exists {
+            0 => Envelopes::default(),
+            _ => self.fetch_envelopes(folder)?,
+        };
+        Ok(self.ids(folder)?.map_envelopes(envelopes))
+    }
+
+    fn read_msg(&mut self, folder: &str, id: &str) -> Result<Vec<u8>, EverestError> {
+        let uid = self.select(folder, id)?;
+        if let Some(body) = self.download(folder, &uid)? {
+            return Ok(body);
+        }
+        let fetches = self.command(|session| session.uid_fetch(&uid, "BODY.PEEK[]"));
+        let fetches = fetches.map_err(|err| imap_error("fetch", folder, err).with_id(id))?;
+        let fetch = fetches.iter().find(|fetch| fetch.uid == uid.parse().ok());
+        match fetch.and_then(|fetch| fetch.body()) {
+            Some(body) => Ok(body.to_vec()),
+            None => {
+                let err = BackendError::new(ErrorKind::NotFound, Backend::Imap, "find message");
+                Err(err.with_folder(folder).with_id(id).into())
+            }
+        }
+    }
+
+    fn add_msg(
+        &mut self,
+        folder: &str,
+        id: &str,
+        raw: &[u8],
+        flags: &Flags,
+    ) -> Result<(), EverestError> {
+        let next = self.command(|session| session.status(folder, "(UIDNEXT)"));
+        let next = next.map_err(|err| imap_error("status", folder, err))?;
+        let next = next.uid_next.unwrap_or(1);
+        let result = self.command(|session| {
+            let flags = flags.iter().map(|flag| format_flag(flag).into());
+            session.append(folder, raw).flags(flags).finish()
+        });
+        result.map_err(|err| imap_error("append", folder, err).with_id(id))?;
+
+        self.select(folder, id)?;
+        let uids = self.command(|session| session.uid_search(format!("UID {}:*", next)));
+        let uids = uids.map_err(|err| imap_error("search", folder, err).with_id(id))?;
+        match uids.into_iter().filter(|uid| *uid >= next).max() {
+            Some(uid) => {
+                self.ids(folder)?.insert(id, &uid.to_string());
+                Ok(())
+            }
+            None => {
+                let op = "find appended message";
+                let err = BackendError::new(ErrorKind::NotFound, Backend::Imap, op);
+                Err(err.with_folder(folder).with_id(id).into())
+            }
+        }
+    }
+
+    /// Flags the message as deleted then expunges it, with the
+    /// `UIDPLUS` extension so that other messages flagged as deleted
+    /// stay.
+    fn remove_msg(&mut self, folder: &str, id: &str) -> Result<(), EverestError> {
+        self.store(folder, id, "+FLAGS.SILENT (\\Deleted)".to_owned())?;
+        let uid = self.select(folder, id)?;
+        let result = self.command(|session| session.uid_expunge(&uid));
+        result.map_err(|err| imap_error("expunge", folder, err).with_id(id))?;
+        self.ids(folder)?.remove(id);
+        Ok(())
+    }
+
+    fn add_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.store(folder, id, format!("+FLAGS.SILENT ({})", format_flag(flag)))
+    }
+
+    fn remove_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.store(folder, id, format!("-FLAGS.SILENT ({})", format_flag(flag)))
+    }
+
+    /// Saves the ids of the messages appended to the given folder,
+    /// see [`ReplicaIds::flush`].
+    fn flush(&mut self, folder: &str) -> Result<(), EverestError> {
+        match self.ids.get_mut(folder) {
+            Some(ids) => ids.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the connection and the folders with [`check`],
+    /// examining them read-only.
+    fn check(&mut self, folders: &[String]) -> Vec<Check> {
+        self.selected = None;
+        check(&mut self.session, folders)
+    }
+}
+
+/// Estimates the size of the response of the given fetch from the
+/// items whose size varies, the session not telling the size of what
+/// it reads.
+fn fetch_len(fetch: &imap::types::Fetch) -> u64 {
+    let flags = fetch.flags().iter().map(|flag| flag.to_string().len() + 1);
+    let envelope = fetch.envelope().map_or(0, |envelope| {
+        let texts = [
+            &envelope.date,
+            &envelope.subject,
+            &envelope.in_reply_to,
+            &envelope.message_id,
+        ];
+        let texts = texts.iter().filter_map(|text| text.as_ref());
+        let addresses = [
+            &envelope.from,
+            &envelope.sender,
+            &envelope.reply_to,
+            &envelope.to,
+            &envelope.cc,
+            &envelope.bcc,
+        ];
+        let addresses = addresses.iter().filter_map(|addresses| addresses.as_ref());
+        // addresses take about 64 bytes, quoting and NILs included
+        texts.map(|text| text.len()).sum::<usize>() + 64 * addresses.map(Vec::len).sum::<usize>()
+    });
+    // `* <seq> FETCH (UID <uid> FLAGS (...))` and the items of fixed
+    // size take about 64 bytes
+    (64 + flags.sum::<usize>() + envelope) as u64
+}
+
+fn imap_error(operation: &'static str, folder: &str, source: imap::Error) -> BackendError {
+    let err = BackendError::new(ErrorKind::Imap, Backend::Imap, operation);
+    err.with_folder(folder).with_source(source)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, time::Duration};
+
+    use crate::backend::imap::Script;
+
+    use super::*;
+
+    #[test]
+    fn replica_test() {
+        let script = "a1 OK logged in\r\n\
+            * 2 EXISTS\r\n\
+            a2 OK [READ-WRITE] selected\r\n\
+            * SEARCH 7 4\r\n\
+            a3 OK searched\r\n\
+            * 1 FETCH (UID 4 FLAGS (\\Seen))\r\n\
+            a4 OK fetched\r\n\
+            * 2 FETCH (UID 7 FLAGS ())\r\n\
+            a5 OK fetched\r\n\
+            * STATUS INBOX (UIDNEXT 8)\r\n\
+            a6 OK status\r\n\
+            + ready\r\n\
+            a7 OK appended\r\n\
+            * SEARCH 8\r\n\
+            a8 OK searched\r\n\
+            * 3 EXISTS\r\n\
+            a9 OK [READ-WRITE] selected\r\n\
+            * SEARCH 4 7 8\r\n\
+            a10 OK searched\r\n\
+            * 1 FETCH (UID 4 FLAGS (\\Seen))\r\n\
+            a11 OK fetched\r\n\
+            * 2 FETCH (UID 7 FLAGS ())\r\n\
+            a12 OK fetched\r\n\
+            * 3 FETCH (UID 8 FLAGS (\\Seen))\r\n\
+            a13 OK fetched\r\n\
+            * 3 FETCH (UID 8 BODY[] {5}\r\nhello)\r\n\
+            a14 OK fetched\r\n\
+            a15 OK stored\r\n\
+            * 3 EXPUNGE\r\n\
+            a16 OK expunged\r\n";
+        let client = imap::Client::new(Script(Cursor::new(script.into())));
+        let session = client.login("alice", "secret").map_err(|(err, _)| err);
+        let dir = tempfile::tempdir().unwrap();
+        // envelopes are fetched one message at a time
+        let sizer = ChunkSizer::new().with_bounds(1, 1);
+        let mut replica = ImapReplica::new(session.unwrap(), dir.path()).with_chunk_sizer(sizer);
+
+        let envelopes = replica.envelopes("INBOX").unwrap();
+        assert_eq!(2, envelopes.len());
+
+        // added messages keep the id of the other side
+        let flags = Flags::from_iter([Flag::Seen]);
+        let id = "1663512456.M1P2Q3.host";
+        replica.add_msg("INBOX", id, b"hello", &flags).unwrap();
+        let envelopes = replica.envelopes("INBOX").unwrap();
+        assert_eq!(Some(&flags), envelopes.get(id).map(|e| &e.flags));
+        assert!(envelopes.contains_key("4") && !envelopes.contains_key("8"));
+        assert_eq!(b"hello".to_vec(), replica.read_msg("INBOX", id).unwrap());
+
+        replica.remove_msg("INBOX", id).unwrap();
+        replica.flush("INBOX").unwrap();
+        let ids = ReplicaIds::load(dir.path().join("INBOX.ids")).unwrap();
+        assert_eq!(id, ids.local(id));
+    }
+
+    #[test]
+    fn download_test() {
+        let script = "a1 OK logged in\r\n\
+            * 2 EXISTS\r\n\
+            * OK [UIDVALIDITY 3] uids valid\r\n\
+            a2 OK [READ-WRITE] selected\r\n\
+            * 1 FETCH (UID 4 RFC822.SIZE 10)\r\n\
+            a3 OK fetched\r\n\
+            * 1 FETCH (UID 4 BODY[]<4> {4}\r\n4567)\r\n\
+            a4 OK fetched\r\n\
+            * 1 FETCH (UID 4 BODY[]<8> {2}\r\n89)\r\n\
+            a5 OK fetched\r\n\
+            * 2 FETCH (UID 7 RFC822.SIZE 3)\r\n\
+            a6 OK fetched\r\n\
+            * 2 FETCH (UID 7 BODY[] {3}\r\nabc)\r\n\
+            a7 OK fetched\r\n";
+        let client = imap::Client::new(Script(Cursor::new(script.into())));
+        let session = client.login("alice", "secret").map_err(|(err, _)| err);
+        let dir = tempfile::tempdir().unwrap();
+        let downloads = dir.path().join("bodies");
+        let mut replica =
+            ImapReplica::new(session.unwrap(), dir.path()).with_downloads(&downloads, 4);
+
+        // large bodies resume the parts of a previous sync
+        let part = downloads.join("INBOX").join("3").join("4.part");
+        fs::create_dir_all(part.parent().unwrap()).unwrap();
+        fs::write(&part, "0123").unwrap();
+        assert_eq!(
+            b"0123456789".to_vec(),
+            replica.read_msg("INBOX", "4").unwrap()
+        );
+        assert!(!part.exists() && !part.with_extension("eml").exists());
+
+        // small ones are read in one fetch
+        assert_eq!(b"abc".to_vec(), replica.read_msg("INBOX", "7").unwrap());
+    }
+
+    #[test]
+    fn throttle_test() {
+        let script = "a1 OK logged in\r\n\
+            * 1 EXISTS\r\n\
+            a2 OK [READ-WRITE] selected\r\n\
+            a3 NO [THROTTLED] Request is throttled\r\n";
+        let client = imap::Client::new(Script(Cursor::new(script.into())));
+        let session = client.login("alice", "secret").map_err(|(err, _)| err);
+        let dir = tempfile::tempdir().unwrap();
+        let mut replica =
+            ImapReplica::new(session.unwrap(), dir.path()).with_host("imap.gmail.com");
+
+        // throttling responses hold the next commands back
+        let err = replica.add_flag("INBOX", "4", &Flag::Seen).unwrap_err();
+        assert!(err.is_transient());
+        let delay = replica.throttle.reserve(Backend::Imap, Instant::now());
+        assert!(delay > Duration::from_millis(500), "{:?}", delay);
+    }
+}
@@ -0,0 +1,271 @@
+//! Wire-level trace of IMAP sessions, to diagnose server
+//! incompatibilities.
+//!
+//! [`TraceStream`] wraps the stream an IMAP client is built on, after
+//! TLS, and traces every line it sends (`C: `) and receives (`S: `)
+//! to a [`TraceSink`]:
+//!
+//! ```text
+//! S: * OK IMAP4rev1 ready
+//! C: a1 LOGIN [redacted]
+//! S: a1 OK LOGIN completed
+//! C: a2 SELECT INBOX
+//! ```
+//!
+//! Credentials never reach the trace: the arguments of `LOGIN` and
+//! `AUTHENTICATE` commands are redacted, as well as everything the
+//! client sends until the server ends the command, like literals and
+//! answers to authentication challenges.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, LineWriter, Read, Write},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::EverestError;
+
+/// Replacement of redacted arguments and lines.
+const REDACTED: &str = "[redacted]";
+
+/// Length of the longest line traced as is, longer lines like the
+/// ones of message bodies being truncated.
+const MAX_LINE_LEN: usize = 1000;
+
+/// Destination of a wire trace.
+#[derive(Clone)]
+pub enum TraceSink {
+    /// Writes lines to a writer, like a file.
+    Writer(Arc<Mutex<dyn Write + Send>>),
+    /// Emits lines as `TRACE` events of the `everest_lib::imap::wire`
+    /// target, with the `tracing` feature.
+    #[cfg(feature = "tracing")]
+    Tracing,
+}
+
+impl TraceSink {
+    /// Builds a sink writing lines to the given writer.
+    pub fn writer<W: Write + Send + 'static>(writer: W) -> Self {
+        Self::Writer(Arc::new(Mutex::new(writer)))
+    }
+
+    /// Opens the trace file at the given path, appending lines to it
+    /// and creating it if needed.
+    pub fn file<P: AsRef<Path>>(path: P) -> Result<Self, EverestError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| EverestError::OpenImapTraceError(err, path.to_owned()))?;
+        Ok(Self::writer(LineWriter::<File>::new(file)))
+    }
+
+    fn trace(&self, line: &str) {
+        match self {
+            Self::Writer(writer) => {
+                // the trace is a diagnostic, failing to write it must
+                // not fail the session
+                let _ = writeln!(writer.lock().unwrap(), "{}", line);
+            }
+            #[cfg(feature = "tracing")]
+            Self::Tracing => tracing::trace!(target: "everest_lib::imap::wire", "{}", line),
+        }
+    }
+}
+
+/// Stream tracing the lines of the IMAP session going through it,
+/// see the [module](self) documentation.
+pub struct TraceStream<S> {
+    inner: S,
+    sink: TraceSink,
+    /// Bytes read or written since the end of the last line.
+    read: Vec<u8>,
+    written: Vec<u8>,
+    /// Tag of the command whose client lines are redacted, until the
+    /// server ends it.
+    redacting: Option<String>,
+}
+
+impl<S> TraceStream<S> {
+    pub fn new(inner: S, sink: TraceSink) -> Self {
+        Self {
+            inner,
+            sink,
+            read: Vec::new(),
+            written: Vec::new(),
+            redacting: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    fn trace_read(&mut self, bytes: &[u8]) {
+        for line in lines(&mut self.read, bytes) {
+            let line = String::from_utf8_lossy(&line).into_owned();
+            if let Some(tag) = &self.redacting {
+                if line.split(' ').next() == Some(tag.as_str()) {
+                    self.redacting = None;
+                }
+            }
+            self.sink.trace(&format!("S: {}", truncate(&line)));
+        }
+    }
+
+    fn trace_written(&mut self, bytes: &[u8]) {
+        for line in lines(&mut self.written, bytes) {
+            let line = String::from_utf8_lossy(&line).into_owned();
+            let line = match &self.redacting {
+                Some(_) => REDACTED.to_owned(),
+                None => match redact(&line) {
+                    Some((tag, line)) => {
+                        self.redacting = Some(tag);
+                        line
+                    }
+                    None => line,
+                },
+            };
+            self.sink.trace(&format!("C: {}", truncate(&line)));
+        }
+    }
+}
+
+impl<S: Read> Read for TraceStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.trace_read(&buf[..n]);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for TraceStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.trace_written(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Appends the given bytes to the given buffer, and returns the lines
+/// they end, without their line ending.
+fn lines(buf: &mut Vec<u8>, bytes: &[u8]) -> Vec<Vec<u8>> {
+    let mut lines = Vec::new();
+    for &byte in bytes {
+        if byte == b'\n' {
+            let mut line = std::mem::take(buf);
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            lines.push(line);
+        } else {
+            buf.push(byte);
+        }
+    }
+    lines
+}
+
+/// Redacts the arguments of the given command line if it carries
+/// credentials, and returns its tag with the redacted line.
+fn redact(line: &str) -> Option<(String, String)> {
+    let mut parts = line.splitn(3, ' ');
+    let tag = parts.next()?;
+    let command = parts.next()?;
+    if command.eq_ignore_ascii_case("LOGIN") {
+        return Some((tag.to_owned(), format!("{} {} {}", tag, command, REDACTED)));
+    }
+    if command.eq_ignore_ascii_case("AUTHENTICATE") {
+        // the mechanism tells nothing secret, the initial response
+        // does
+        let mechanism = parts.next().and_then(|args| args.split(' ').next());
+        let line = match mechanism {
+            Some(mechanism) => format!("{} {} {} {}", tag, command, mechanism, REDACTED),
+            None => format!("{} {}", tag, command),
+        };
+        return Some((tag.to_owned(), line));
+    }
+    None
+}
+
+fn truncate(line: &str) -> String {
+    match line.char_indices().nth(MAX_LINE_LEN) {
+        Some((end, _)) => format!("{}… [{} bytes]", &line[..end], line.len()),
+        None => line.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writer sharing its buffer with the test.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_test() {
+        let trace = Buffer::default();
+        let mut stream = TraceStream::new(io::sink(), TraceSink::writer(trace.clone()));
+        stream.trace_read(b"* OK ready\r\n");
+        // literals are sent as lines of their own
+        stream
+            .write_all(b"a1 LOGIN alice {6}\r\nsecret\r\n")
+            .unwrap();
+        stream.trace_read(b"a1 OK LOGIN completed\r\n");
+        stream
+            .write_all(b"a2 AUTHENTICATE PLAIN AGFsaWNlAHNlY3JldA==\r\n")
+            .unwrap();
+        stream.trace_read(b"+ \r\n");
+        stream.write_all(b"AGFsaWNlAHNlY3JldA==\r\n").unwrap();
+        stream.trace_read(b"a2 OK authenticated\r\n");
+        // split writes still trace whole lines
+        stream.write_all(b"a3 SELECT").unwrap();
+        stream.write_all(b" INBOX\r\n").unwrap();
+        stream.trace_read(&[b'x'; MAX_LINE_LEN + 1]);
+        stream.trace_read(b"\r\n");
+
+        let trace = String::from_utf8(trace.0.lock().unwrap().clone()).unwrap();
+        let mut lines = trace.lines().collect::<Vec<_>>();
+        let long = lines.pop().unwrap();
+        assert_eq!(
+            vec![
+                "S: * OK ready",
+                "C: a1 LOGIN [redacted]",
+                "C: [redacted]",
+                "S: a1 OK LOGIN completed",
+                "C: a2 AUTHENTICATE PLAIN [redacted]",
+                "S: + ",
+                "C: [redacted]",
+                "S: a2 OK authenticated",
+                "C: a3 SELECT INBOX",
+            ],
+            lines
+        );
+        assert!(long.ends_with(&format!("… [{} bytes]", MAX_LINE_LEN + 1)));
+        assert!(!trace.contains("secret") && !trace.contains("AGFsaWNl"));
+    }
+}
@@ -0,0 +1,146 @@
+use sha2::{Digest, Sha256};
+use std::{
+    fs,
+    io::{self, Read},
+    ops::Deref,
+    path::Path,
+};
+
+use crate::EverestError;
+
+use super::{dedup::ContentHash, Mdir};
+
+/// Size from which messages are mapped in memory rather than read,
+/// with the `mmap` feature: 1 MiB.
+pub const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
+/// Content of a maildir message. Large messages are mapped in memory
+/// with the `mmap` feature, so that uploading many of them keeps the
+/// memory flat; others are read.
+#[derive(Debug)]
+pub struct MsgContent(Content);
+
+#[derive(Debug)]
+enum Content {
+    Read(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl Deref for MsgContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Content::Read(content) => content,
+            #[cfg(feature = "mmap")]
+            Content::Mapped(content) => content,
+        }
+    }
+}
+
+impl AsRef<[u8]> for MsgContent {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl MsgContent {
+    /// Returns `true` if the content is mapped in memory.
+    pub fn is_mapped(&self) -> bool {
+        match self.0 {
+            Content::Read(_) => false,
+            #[cfg(feature = "mmap")]
+            Content::Mapped(_) => true,
+        }
+    }
+
+    fn read(path: &Path) -> io::Result<Self> {
+        let mut file = fs::File::open(path)?;
+        #[cfg(feature = "mmap")]
+        if let Some(content) = map(&file)? {
+            return Ok(Self(Content::Mapped(content)));
+        }
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        Ok(Self(Content::Read(content)))
+    }
+}
+
+/// Maps the given file in memory if large enough. Mapping failures
+/// (like file systems not supporting it) fall back to reading, by
+/// returning `None`.
+#[cfg(feature = "mmap")]
+fn map(file: &fs::File) -> io::Result<Option<memmap2::Mmap>> {
+    if file.metadata()?.len() < MMAP_THRESHOLD {
+        return Ok(None);
+    }
+    // SAFETY: maildir messages are never modified in place, deliveries
+    // and flag changes go through renames. A message truncated by
+    // another program while mapped would still fault, which is the
+    // price of the flat memory.
+    Ok(unsafe { memmap2::Mmap::map(file) }.ok())
+}
+
+/// Hashes the content of the given message without holding it in
+/// memory: mapped with the `mmap` feature, streamed otherwise.
+pub(super) fn hash_file(path: &Path) -> io::Result<ContentHash> {
+    let mut file = fs::File::open(path)?;
+    #[cfg(feature = "mmap")]
+    if let Some(content) = map(&file)? {
+        return Ok(Sha256::digest(&content[..]).into());
+    }
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+impl Mdir {
+    /// Reads the content of the message matching the given id, like
+    /// before uploading it.
+    pub fn read_msg(&self, id: &str) -> Result<MsgContent, EverestError> {
+        let path = self.find(id)?;
+        MsgContent::read(&path).map_err(|err| EverestError::ReadMaildirMsgError(err, path))
+    }
+
+    /// Hashes the content of the message matching the given id, the
+    /// same way [`crate::cache::content_hash`] does.
+    pub fn msg_hash(&self, id: &str) -> Result<String, EverestError> {
+        let path = self.find(id)?;
+        let hash = hash_file(&path).map_err(|err| EverestError::ReadMaildirMsgError(err, path))?;
+        Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Flags;
+
+    use super::*;
+
+    fn content_hash(raw: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(raw))
+    }
+
+    #[test]
+    fn read_msg_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+
+        let small = b"Subject: small\r\n\r\n".to_vec();
+        let id = mdir.add_msg(&small, &Flags::default()).unwrap();
+        let content = mdir.read_msg(&id).unwrap();
+        assert_eq!(small, &*content);
+        assert!(!content.is_mapped());
+        assert_eq!(content_hash(&small), mdir.msg_hash(&id).unwrap());
+
+        let mut large = b"Subject: large\r\n\r\n".to_vec();
+        large.resize(MMAP_THRESHOLD as usize + 1, b'a');
+        let id = mdir.add_msg(&large, &Flags::default()).unwrap();
+        let content = mdir.read_msg(&id).unwrap();
+        assert_eq!(large, &*content);
+        assert_eq!(cfg!(feature = "mmap"), content.is_mapped());
+        assert_eq!(content_hash(&large), mdir.msg_hash(&id).unwrap());
+    }
+}
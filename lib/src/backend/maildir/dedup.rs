@@ -0,0 +1,167 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use crate::EverestError;
+
+use super::{content::hash_file, Mdir};
+
+pub(super) type ContentHash = [u8; 32];
+
+/// Index of message contents shared between maildir folders. When a
+/// folder delivers a message whose content already exists in one of
+/// the folders sharing the store, the existing file is hardlinked
+/// instead of being written again. Cloning the store shares the
+/// underlying index.
+#[derive(Default, Debug, Clone)]
+pub struct DedupStore(Arc<Mutex<DedupIndex>>);
+
+#[derive(Default, Debug)]
+struct DedupIndex {
+    paths: HashMap<ContentHash, Vec<PathBuf>>,
+    hashes: HashMap<PathBuf, ContentHash>,
+}
+
+impl DedupStore {
+    /// Indexes the messages already present in the given maildir, so
+    /// that they can be hardlinked by future deliveries.
+    pub fn index(&self, mdir: &Mdir) -> Result<(), EverestError> {
+        for entry in mdir.entries()? {
+            let path = entry?.path;
+            let hash = hash_file(&path)
+                .map_err(|err| EverestError::ReadMaildirMsgError(err, path.clone()))?;
+            self.insert(hash, path);
+        }
+        Ok(())
+    }
+
+    /// Returns the existing messages having the given content.
+    pub(super) fn find(&self, hash: &ContentHash) -> Vec<PathBuf> {
+        let index = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        index.paths.get(hash).cloned().unwrap_or_default()
+    }
+
+    pub(super) fn insert(&self, hash: ContentHash, path: PathBuf) {
+        let mut index = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        index.paths.entry(hash).or_default().push(path.clone());
+        index.hashes.insert(path, hash);
+    }
+
+    /// Forgets the given message. Other links to the same content
+    /// stay indexed: removing one link does not remove the content.
+    pub(super) fn remove(&self, path: &Path) {
+        let mut index = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(hash) = index.hashes.remove(path) {
+            if let Some(paths) = index.paths.get_mut(&hash) {
+                paths.retain(|p| p != path);
+                if paths.is_empty() {
+                    index.paths.remove(&hash);
+                }
+            }
+        }
+    }
+
+    /// Follows the given message after a rename (flag changes).
+    pub(super) fn rename(&self, from: &Path, to: &Path) {
+        let mut index = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if let Some(hash) = index.hashes.remove(from) {
+            index.hashes.insert(to.to_owned(), hash);
+            if let Some(paths) = index.paths.get_mut(&hash) {
+                for path in paths.iter_mut().filter(|p| *p == from) {
+                    *path = to.to_owned();
+                }
+            }
+        }
+    }
+}
+
+impl Mdir {
+    /// Returns `true` if the content of the message matching the given
+    /// id is shared with other messages through hardlinks, in which
+    /// case removing the message does not remove its content.
+    pub fn is_shared(&self, id: &str) -> Result<bool, EverestError> {
+        let path = self.find(id)?;
+        let meta =
+            fs::metadata(&path).map_err(|err| EverestError::ReadMaildirMsgError(err, path))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            Ok(meta.nlink() > 1)
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = meta;
+            Ok(false)
+        }
+    }
+}
+
+pub(super) fn content_hash(raw: &[u8]) -> ContentHash {
+    Sha256::digest(raw).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Flag, Flags};
+
+    use super::*;
+
+    #[test]
+    fn dedup_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = DedupStore::default();
+        let inbox = Mdir::new(dir.path().join("inbox")).with_dedup(store.clone());
+        let archive = Mdir::new(dir.path().join("archive")).with_dedup(store.clone());
+        inbox.create_dirs().unwrap();
+        archive.create_dirs().unwrap();
+
+        let id1 = inbox
+            .add_msg(b"Subject: a\r\n\r\n", &Flags::default())
+            .unwrap();
+        assert!(!inbox.is_shared(&id1).unwrap());
+
+        // renamed messages stay linkable
+        inbox.add_flag(&id1, &Flag::Seen).unwrap();
+
+        let id2 = archive
+            .add_msg(b"Subject: a\r\n\r\n", &Flags::default())
+            .unwrap();
+        assert!(inbox.is_shared(&id1).unwrap());
+        assert!(archive.is_shared(&id2).unwrap());
+
+        // removing one link keeps the content
+        inbox.remove_msg(&id1).unwrap();
+        assert!(!archive.is_shared(&id2).unwrap());
+        let path = archive.find(&id2).unwrap();
+        assert_eq!(b"Subject: a\r\n\r\n".to_vec(), fs::read(path).unwrap());
+
+        let id3 = inbox
+            .add_msg(b"Subject: a\r\n\r\n", &Flags::default())
+            .unwrap();
+        assert!(inbox.is_shared(&id3).unwrap());
+    }
+
+    #[test]
+    fn index_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let inbox = Mdir::new(dir.path());
+        inbox.create_dirs().unwrap();
+        let id1 = inbox
+            .add_msg(b"Subject: a\r\n\r\n", &Flags::default())
+            .unwrap();
+
+        let store = DedupStore::default();
+        store.index(&inbox).unwrap();
+        let inbox = inbox.with_dedup(store);
+        let id2 = inbox
+            .add_msg(b"Subject: a\r\n\r\n", &Flags::default())
+            .unwrap();
+
+        assert!(inbox.is_shared(&id1).unwrap());
+        assert!(inbox.is_shared(&id2).unwrap());
+    }
+}
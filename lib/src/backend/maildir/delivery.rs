@@ -0,0 +1,268 @@
+use std::{
+    fs::{self, FileTimes},
+    io::{self, Write},
+    path::PathBuf,
+    time::SystemTime,
+};
+
+use crate::{EverestError, Flags};
+
+use super::{
+    dedup::{content_hash, DedupStore},
+    flags::encode_flags,
+    sync_dir, DovecotKeywords, Mdir,
+};
+
+/// How hard the maildir tries to make deliveries survive a crash.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Durability {
+    /// Never fsync, the OS flushes data whenever it wants.
+    None,
+    /// Fsync the message before moving it out of `tmp`, so a crash
+    /// never exposes a truncated message.
+    #[default]
+    File,
+    /// Also fsync the destination directory after the rename, so the
+    /// delivery itself survives a crash.
+    Full,
+}
+
+impl Mdir {
+    /// Delivers the given raw message. The message is written to
+    /// `tmp`, synced according to the durability level then renamed
+    /// into `new` (no flags) or `cur` (with flags). Returns the id
+    /// of the delivered message.
+    pub fn add_msg(&self, raw: &[u8], flags: &Flags) -> Result<String, EverestError> {
+        self.deliver(raw, flags, None)
+    }
+
+    /// Delivers the given raw message like [`Mdir::add_msg`],
+    /// preserving its original delivery date: the date is used as
+    /// the seconds prefix of the unique name and as the message file
+    /// mtime, which is what MUAs sort by arrival with. For messages
+    /// coming from IMAP, the date is the INTERNALDATE, which converts
+    /// with `SystemTime::from(fetch.internal_date())`.
+    pub fn add_msg_with_date(
+        &self,
+        raw: &[u8],
+        flags: &Flags,
+        date: SystemTime,
+    ) -> Result<String, EverestError> {
+        self.deliver(raw, flags, Some(date))
+    }
+
+    /// Returns the delivery date of the message matching the given id,
+    /// as kept by the message file mtime. This is the date to use as
+    /// INTERNALDATE when uploading the message to IMAP.
+    pub fn msg_date(&self, id: &str) -> Result<SystemTime, EverestError> {
+        let path = self.find(id)?;
+        fs::metadata(&path)
+            .and_then(|meta| meta.modified())
+            .map_err(|err| EverestError::ReadMaildirMsgError(err, path))
+    }
+
+    fn deliver(
+        &self,
+        raw: &[u8],
+        flags: &Flags,
+        date: Option<SystemTime>,
+    ) -> Result<String, EverestError> {
+        self.reserve_quota(raw.len() as u64)?;
+
+        let info = if flags.is_empty() {
+            None
+        } else {
+            let mut keywords = DovecotKeywords::load(&self.path)?;
+            let prev_keywords = keywords.clone();
+            let info = encode_flags(flags, "", &mut keywords);
+            if keywords != prev_keywords {
+                keywords.save(&self.path)?;
+            }
+            Some(info)
+        };
+
+        let uid = if self.mbsync_uids {
+            Some(self.next_uid()?)
+        } else {
+            None
+        };
+        let hash = self.dedup.as_ref().map(|_| content_hash(raw));
+        let linked = match (&self.dedup, &hash) {
+            (Some(dedup), Some(hash)) => self.link_tmp(dedup, hash, date, uid),
+            _ => None,
+        };
+        let (id, tmp_path) = match linked {
+            Some(linked) => linked,
+            None => self.write_tmp(raw, date, uid)?,
+        };
+
+        let (dir, filename) = match info {
+            None => (self.path.join("new"), id.clone()),
+            Some(info) => (self.path.join("cur"), self.cur_filename(&id, &info)),
+        };
+        let path = dir.join(filename);
+        fs::rename(&tmp_path, &path)
+            .map_err(|err| EverestError::DeliverMaildirMsgError(err, path.clone()))?;
+
+        if self.durability == Durability::Full {
+            sync_dir(&dir)
+                .map_err(|err| EverestError::DeliverMaildirMsgError(err, path.clone()))?;
+        }
+
+        if let (Some(dedup), Some(hash)) = (&self.dedup, hash) {
+            dedup.insert(hash, path);
+        }
+
+        self.record_quota(raw.len() as i64, 1)?;
+
+        Ok(id)
+    }
+
+    /// Hardlinks into `tmp` an existing message having the same
+    /// content. Returns `None` when no such message can be linked
+    /// (none exists, or it lives on another filesystem), in which case
+    /// the message needs to be written.
+    fn link_tmp(
+        &self,
+        dedup: &DedupStore,
+        hash: &[u8; 32],
+        date: Option<SystemTime>,
+        uid: Option<u32>,
+    ) -> Option<(String, PathBuf)> {
+        for existing_path in dedup.find(hash) {
+            let mut id = self.unique_name(date, uid);
+            let mut path = self.path.join("tmp").join(&id);
+            loop {
+                match fs::hard_link(&existing_path, &path) {
+                    Ok(()) => return Some((id, path)),
+                    Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                        id = self.unique_name(date, uid);
+                        path.set_file_name(&id);
+                    }
+                    Err(_) => break,
+                }
+            }
+        }
+        None
+    }
+
+    fn write_tmp(
+        &self,
+        raw: &[u8],
+        date: Option<SystemTime>,
+        uid: Option<u32>,
+    ) -> Result<(String, PathBuf), EverestError> {
+        let mut id = self.unique_name(date, uid);
+        let mut path = self.path.join("tmp").join(&id);
+
+        // the file must not exist yet, otherwise another process
+        // could be writing it
+        let mut file = loop {
+            match fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+            {
+                Ok(file) => break file,
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    id = self.unique_name(date, uid);
+                    path.set_file_name(&id);
+                }
+                Err(err) => return Err(EverestError::DeliverMaildirMsgError(err, path)),
+            }
+        };
+
+        self.permissions
+            .apply_to_file(&file)
+            .and_then(|()| file.write_all(raw))
+            .and_then(|()| match date {
+                Some(date) => {
+                    file.set_times(FileTimes::new().set_accessed(date).set_modified(date))
+                }
+                None => Ok(()),
+            })
+            .and_then(|()| match self.durability {
+                Durability::None => Ok(()),
+                Durability::File | Durability::Full => file.sync_all(),
+            })
+            .map_err(|err| {
+                let _ = fs::remove_file(&path);
+                EverestError::DeliverMaildirMsgError(err, path.clone())
+            })?;
+
+        Ok((id, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use crate::Flag;
+
+    use super::*;
+
+    fn mdir(durability: Durability) -> (tempfile::TempDir, Mdir) {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_durability(durability);
+        mdir.create_dirs().unwrap();
+        (dir, mdir)
+    }
+
+    #[test]
+    fn add_msg_to_new_test() {
+        let (_dir, mdir) = mdir(Durability::None);
+
+        let id = mdir
+            .add_msg(b"Subject: test\r\n\r\n", &Flags::default())
+            .unwrap();
+
+        let path = mdir.path().join("new").join(&id);
+        assert_eq!(b"Subject: test\r\n\r\n".to_vec(), fs::read(path).unwrap());
+        assert_eq!(0, fs::read_dir(mdir.path().join("tmp")).unwrap().count());
+    }
+
+    #[test]
+    fn add_msg_to_cur_test() {
+        let (_dir, mdir) = mdir(Durability::Full);
+        let flags = Flags::from_iter([Flag::Seen, Flag::Flagged, Flag::Keyword("Work".into())]);
+
+        let id = mdir.add_msg(b"Subject: test\r\n\r\n", &flags).unwrap();
+
+        assert!(mdir
+            .path()
+            .join("cur")
+            .join(format!("{}:2,FSa", id))
+            .is_file());
+        assert_eq!(
+            Some("Work"),
+            DovecotKeywords::load(mdir.path()).unwrap().keyword('a')
+        );
+    }
+
+    #[test]
+    fn add_msg_with_date_test() {
+        let (_dir, mdir) = mdir(Durability::File);
+        let date = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+
+        let id = mdir
+            .add_msg_with_date(b"", &Flags::default(), date)
+            .unwrap();
+
+        assert!(id.starts_with("1000000000."));
+        assert_eq!(date, mdir.msg_date(&id).unwrap());
+    }
+
+    #[test]
+    fn add_msg_without_dirs_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+
+        assert!(matches!(
+            mdir.add_msg(b"", &Flags::default()),
+            Err(EverestError::DeliverMaildirMsgError(..))
+        ));
+    }
+}
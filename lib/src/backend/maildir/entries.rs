@@ -0,0 +1,46 @@
+//! Envelopes of the entries listed with the `maildir` crate.
+
+#[cfg(feature = "metadata")]
+use crate::Metadata;
+use crate::{plan::Backend, BackendError, Envelope, Envelopes, ErrorCode, ErrorKind, EverestError};
+
+use super::{decode_flags, DovecotKeywords};
+
+#[cfg(feature = "maildir")]
+impl TryFrom<maildir::MailEntries> for Envelopes {
+    type Error = EverestError;
+
+    fn try_from(entries: maildir::MailEntries) -> Result<Self, Self::Error> {
+        Envelopes::try_from((entries, &DovecotKeywords::default()))
+    }
+}
+
+#[cfg(feature = "maildir")]
+impl TryFrom<(maildir::MailEntries, &DovecotKeywords)> for Envelopes {
+    type Error = EverestError;
+
+    fn try_from(
+        (entries, keywords): (maildir::MailEntries, &DovecotKeywords),
+    ) -> Result<Self, Self::Error> {
+        let mut envelopes = Envelopes::with_capacity(entries.size_hint().0);
+        for entry in entries {
+            let entry = entry.map_err(|err| {
+                BackendError::new(ErrorKind::Io, Backend::Maildir, "read entry")
+                    .with_code(ErrorCode::MaildirEntryReadFailed)
+                    .with_source(err)
+            })?;
+            let envelope = Envelope {
+                id: entry.id().into(),
+                flags: decode_flags(entry.flags(), keywords),
+                metadata: None,
+            };
+            #[cfg(feature = "metadata")]
+            let envelope = {
+                let mut entry = entry;
+                envelope.with_metadata(Metadata::from_mail_entry(&mut entry)?)
+            };
+            envelopes.insert(envelope.id.clone(), envelope);
+        }
+        Ok(envelopes)
+    }
+}
@@ -0,0 +1,136 @@
+use crate::{flag::decode_flag, Flag, Flags};
+
+use super::{mbsync_uid, DovecotKeywords, Mdir};
+
+/// Filename of a maildir message, parsed without allocating: its
+/// parts borrow the filename. Scanning a large `cur` directory only
+/// allocates for what is kept, like the envelope.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MdirFilename<'a> {
+    /// Unique name of the message, used as its id.
+    pub unique: &'a str,
+    /// Info flags, without the `2,` prefix. `None` for filenames
+    /// without info section, like messages of `new`.
+    pub info: Option<&'a str>,
+}
+
+impl<'a> MdirFilename<'a> {
+    /// Parses the given filename, using the given info separator.
+    /// `None` for files that are not messages, starting with a dot.
+    pub fn parse(filename: &'a str, separator: char) -> Option<Self> {
+        if filename.starts_with('.') {
+            return None;
+        }
+        let (unique, info) = match filename.split_once(separator) {
+            Some((unique, info)) => match info.strip_prefix("2,") {
+                Some(info) => (unique, Some(info)),
+                None => (filename, None),
+            },
+            None => (filename, None),
+        };
+        Some(Self { unique, info })
+    }
+
+    /// Returns the delivery time of the message in seconds since the
+    /// epoch, taken from the unique name.
+    pub fn time(&self) -> Option<u64> {
+        let (secs, _) = self.unique.split_once('.')?;
+        secs.parse().ok()
+    }
+
+    /// Returns the hostname of the unique name, without the isync
+    /// suffixes.
+    pub fn hostname(&self) -> Option<&'a str> {
+        let (_, rest) = self.unique.split_once('.')?;
+        let (_, hostname) = rest.split_once('.')?;
+        Some(hostname.split(',').next().unwrap_or(hostname))
+    }
+
+    /// Returns the isync UID of the unique name, if any.
+    pub fn uid(&self) -> Option<u32> {
+        mbsync_uid(self.unique)
+    }
+
+    /// Returns `true` if the info flags contain the given letter.
+    pub fn has_flag(&self, letter: char) -> bool {
+        self.info.is_some_and(|info| info.contains(letter))
+    }
+
+    /// Builds the flags of the info section. Custom flags are resolved
+    /// using the given Dovecot keywords.
+    pub fn flags(&self, keywords: &DovecotKeywords) -> Flags {
+        self.info
+            .map(|info| decode_flags(info, keywords))
+            .unwrap_or_default()
+    }
+}
+
+impl Mdir {
+    /// Parses the given filename of a message of this maildir.
+    pub fn parse_filename<'a>(&self, filename: &'a str) -> Option<MdirFilename<'a>> {
+        MdirFilename::parse(filename, self.info_separator)
+    }
+}
+
+/// Builds the flags matching the given maildir info flags. Custom
+/// flags are resolved using the given Dovecot keywords.
+pub(crate) fn decode_flags(info: &str, keywords: &DovecotKeywords) -> Flags {
+    let mut flags = Flags::default();
+    // info flags are ASCII letters
+    for b in info.bytes() {
+        let flag = match b {
+            b'a'..=b'z' => keywords
+                .keyword(b as char)
+                .map(|keyword| Flag::Keyword(keyword.to_owned())),
+            b => decode_flag(b),
+        };
+        if let Some(flag) = flag {
+            flags.insert(flag);
+        }
+    }
+    flags
+}
+
+/// Returns the letters of the given maildir info flags no flag stands
+/// for, like `P` (passed) or keywords missing from the given Dovecot
+/// keywords, to keep them when the info flags are rewritten.
+pub(crate) fn unknown_letters(info: &str, keywords: &DovecotKeywords) -> String {
+    let unknown = |b: &u8| match b {
+        b'a'..=b'z' => keywords.keyword(*b as char).is_none(),
+        b => b.is_ascii_alphabetic() && decode_flag(*b).is_none(),
+    };
+    info.bytes().filter(unknown).map(char::from).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let filename = MdirFilename::parse("1663512470.M1P2Q3.host.local,U=42:2,FS", ':').unwrap();
+        assert_eq!("1663512470.M1P2Q3.host.local,U=42", filename.unique);
+        assert_eq!(Some("FS"), filename.info);
+        assert_eq!(Some(1663512470), filename.time());
+        assert_eq!(Some("host.local"), filename.hostname());
+        assert_eq!(Some(42), filename.uid());
+        assert!(filename.has_flag('S'));
+        assert!(!filename.has_flag('R'));
+        assert_eq!(
+            Flags::from_iter([Flag::Flagged, Flag::Seen]),
+            filename.flags(&DovecotKeywords::default())
+        );
+        assert_eq!("Pa", unknown_letters("FPSa", &DovecotKeywords::default()));
+
+        let filename = MdirFilename::parse("1.M1P2.host", ':').unwrap();
+        assert_eq!(None, filename.info);
+        assert_eq!(None, filename.uid());
+        assert!(filename.flags(&DovecotKeywords::default()).is_empty());
+
+        // unknown info versions are part of the unique name
+        let filename = MdirFilename::parse("1.M1P2.host;1,S", ';').unwrap();
+        assert_eq!(("1.M1P2.host;1,S", None), (filename.unique, filename.info));
+        assert_eq!(None, MdirFilename::parse(".keep", ':'));
+        assert_eq!(None, MdirFilename::parse("garbage", ':').unwrap().time());
+    }
+}
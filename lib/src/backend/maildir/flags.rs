@@ -0,0 +1,320 @@
+use std::{collections::HashMap, fs, path::PathBuf};
+
+use crate::{flag::encode_flag, EverestError, Flag, Flags};
+
+use super::{
+    decode_flags, entry_error, filename::unknown_letters, sync_dir, DovecotKeywords, Durability,
+    Mdir,
+};
+
+/// Change of the flags of a message, applied by
+/// [`Mdir::update_flags_batch`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlagChange<'a> {
+    Add(&'a Flag),
+    Remove(&'a Flag),
+}
+
+impl Mdir {
+    pub fn add_flag(&self, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.update_flags(id, |flags| {
+            flags.insert(flag.to_owned());
+        })
+    }
+
+    pub fn remove_flag(&self, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.update_flags(id, |flags| {
+            flags.remove(flag);
+        })
+    }
+
+    pub fn set_flags(&self, id: &str, flags: &Flags) -> Result<(), EverestError> {
+        self.update_flags(id, |prev_flags| *prev_flags = flags.to_owned())
+    }
+
+    /// Applies the given flag changes in a single pass over `new` and
+    /// `cur`, rather than looking each message up, which pays off
+    /// when many messages of the folder change (like after marking a
+    /// whole folder as seen). Keywords are saved once, and with the
+    /// [`Durability::Full`] level the directories are synced once at
+    /// the end.
+    ///
+    /// A failing message does not stop the others: the errors of the
+    /// messages that failed are returned by id, including for messages
+    /// not found. Fails without changing any message when the maildir
+    /// cannot be read or its keywords saved, or once renamed when the
+    /// directories cannot be synced.
+    pub fn update_flags_batch<'a, I>(
+        &self,
+        changes: I,
+    ) -> Result<Vec<(&'a str, EverestError)>, EverestError>
+    where
+        I: IntoIterator<Item = (&'a str, FlagChange<'a>)>,
+    {
+        let mut changes_by_id = HashMap::<&str, Vec<FlagChange>>::new();
+        for (id, change) in changes {
+            changes_by_id.entry(id).or_default().push(change);
+        }
+        if changes_by_id.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let mut errors = vec![];
+        let mut keywords = DovecotKeywords::load(&self.path)?;
+        let prev_keywords = keywords.clone();
+        let cur = self.path.join("cur");
+
+        // renames are collected first, so that the keywords they need
+        // are saved before any filename refers to them
+        let mut renames = Vec::<(&str, PathBuf, PathBuf)>::new();
+        for dir in [self.path.join("new"), cur.clone()] {
+            let entries = fs::read_dir(&dir)
+                .map_err(|err| EverestError::ReadMaildirDirError(err, dir.clone()))?;
+            for entry in entries {
+                let entry = entry.map_err(|err| entry_error(err, &dir))?;
+                let filename = entry.file_name();
+                let filename = match filename.to_str().and_then(|f| self.parse_filename(f)) {
+                    Some(filename) => filename,
+                    None => continue,
+                };
+                let (id, changes) = match changes_by_id.remove_entry(filename.unique) {
+                    Some(changes) => changes,
+                    None => continue,
+                };
+
+                let prev_flags = filename.flags(&keywords);
+                let mut flags = prev_flags.clone();
+                for change in changes {
+                    match change {
+                        FlagChange::Add(flag) => flags.insert(flag.to_owned()),
+                        FlagChange::Remove(flag) => flags.remove(flag),
+                    };
+                }
+                if flags != prev_flags {
+                    let kept = unknown_letters(filename.info.unwrap_or_default(), &keywords);
+                    let info = encode_flags(&flags, &kept, &mut keywords);
+                    let next_path = cur.join(self.cur_filename(filename.unique, &info));
+                    renames.push((id, entry.path(), next_path));
+                }
+            }
+        }
+
+        if keywords != prev_keywords {
+            keywords.save(&self.path)?;
+        }
+
+        let mut from_new = false;
+        for (id, path, next_path) in renames {
+            if let Err(err) = fs::rename(&path, &next_path) {
+                errors.push((id, EverestError::UpdateMaildirFlagsError(err, path)));
+                continue;
+            }
+            from_new |= !path.starts_with(&cur);
+            if let Some(dedup) = &self.dedup {
+                dedup.rename(&path, &next_path);
+            }
+        }
+
+        if self.durability == Durability::Full {
+            let new = self.path.join("new");
+            let dirs = if from_new { vec![new, cur] } else { vec![cur] };
+            for dir in dirs {
+                sync_dir(&dir).map_err(|err| EverestError::UpdateMaildirFlagsError(err, dir))?;
+            }
+        }
+
+        let mut missing = changes_by_id.into_keys().collect::<Vec<_>>();
+        missing.sort_unstable();
+        errors.extend(
+            missing
+                .into_iter()
+                .map(|id| (id, EverestError::FindMaildirMsgError(id.to_owned()))),
+        );
+        Ok(errors)
+    }
+
+    /// Applies the given update to the flags of the message matching
+    /// the given id. A message from `new` is moved to `cur` as soon as
+    /// it gets its first flag, like MUAs do.
+    fn update_flags<F>(&self, id: &str, update: F) -> Result<(), EverestError>
+    where
+        F: FnOnce(&mut Flags),
+    {
+        let path = self.find(id)?;
+        let mut keywords = DovecotKeywords::load(&self.path)?;
+        let prev_keywords = keywords.clone();
+
+        let filename = path.file_name().unwrap_or_default().to_string_lossy();
+        let filename = self.parse_filename(&filename);
+        let info = filename
+            .and_then(|filename| filename.info)
+            .unwrap_or_default();
+        let prev_flags = decode_flags(info, &keywords);
+        let mut flags = prev_flags.clone();
+        update(&mut flags);
+        if flags == prev_flags {
+            return Ok(());
+        }
+
+        let kept = unknown_letters(info, &keywords);
+        let info = encode_flags(&flags, &kept, &mut keywords);
+        if keywords != prev_keywords {
+            keywords.save(&self.path)?;
+        }
+
+        let next_path = self.path.join("cur").join(self.cur_filename(id, &info));
+        fs::rename(&path, &next_path)
+            .map_err(|err| EverestError::UpdateMaildirFlagsError(err, path.to_owned()))?;
+        if let Some(dedup) = &self.dedup {
+            dedup.rename(&path, &next_path);
+        }
+        Ok(())
+    }
+}
+
+/// Builds the maildir info flags of the given flags, with the given
+/// letters no flag stands for kept as is, ordered by ASCII value as
+/// required by the maildir spec. Keywords are registered in the given
+/// Dovecot keywords when needed, keywords that cannot fit anymore are
+/// skipped.
+pub(super) fn encode_flags(flags: &Flags, kept: &str, keywords: &mut DovecotKeywords) -> String {
+    let mut letters = flags
+        .iter()
+        .filter_map(|flag| match flag {
+            Flag::Keyword(keyword) => keywords.letter_or_insert(keyword),
+            flag => encode_flag(flag),
+        })
+        .chain(kept.chars())
+        .collect::<Vec<_>>();
+    letters.sort_unstable();
+    letters.dedup();
+    letters.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use super::*;
+
+    #[test]
+    fn first_flag_moves_new_to_cur_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        let envelopes = mdir.envelopes().unwrap();
+        assert!(envelopes.get(id.as_str()).unwrap().flags.is_empty());
+
+        mdir.add_flag(&id, &Flag::Seen).unwrap();
+        assert!(!dir.path().join("new").join(&id).exists());
+        assert!(dir.path().join("cur").join(format!("{}:2,S", id)).is_file());
+
+        mdir.add_flag(&id, &Flag::Keyword("Work".into())).unwrap();
+        mdir.remove_flag(&id, &Flag::Seen).unwrap();
+        assert!(dir.path().join("cur").join(format!("{}:2,a", id)).is_file());
+
+        let envelopes = mdir.envelopes().unwrap();
+        assert_eq!(
+            Flags::from_iter([Flag::Keyword("Work".into())]),
+            envelopes.get(id.as_str()).unwrap().flags
+        );
+    }
+
+    #[test]
+    fn unknown_letters_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let cur = dir.path().join("cur");
+        fs::write(cur.join("123.M1P1.host:2,PS"), b"").unwrap();
+        fs::write(cur.join("124.M1P1.host:2,Sb"), b"").unwrap();
+
+        // passed and keywords of other clients survive flag changes
+        mdir.add_flag("123.M1P1.host", &Flag::Flagged).unwrap();
+        assert!(cur.join("123.M1P1.host:2,FPS").is_file());
+        let errors = mdir.update_flags_batch([
+            ("123.M1P1.host", FlagChange::Remove(&Flag::Seen)),
+            ("124.M1P1.host", FlagChange::Add(&Flag::Draft)),
+        ]);
+        assert!(errors.unwrap().is_empty());
+        assert!(cur.join("123.M1P1.host:2,FP").is_file());
+        assert!(cur.join("124.M1P1.host:2,DSb").is_file());
+    }
+
+    #[test]
+    fn info_separator_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_info_separator('!');
+        mdir.create_dirs().unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        mdir.add_flag(&id, &Flag::Seen).unwrap();
+        mdir.add_flag(&id, &Flag::Replied).unwrap();
+        assert!(dir
+            .path()
+            .join("cur")
+            .join(format!("{}!2,RS", id))
+            .is_file());
+
+        let envelopes = mdir.envelopes().unwrap();
+        assert_eq!(
+            Flags::from_iter([Flag::Seen, Flag::Replied]),
+            envelopes.get(id.as_str()).unwrap().flags
+        );
+    }
+
+    #[test]
+    fn update_flags_batch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_durability(Durability::Full);
+        mdir.create_dirs().unwrap();
+        let ids = (0..3)
+            .map(|_| mdir.add_msg(b"", &Flags::default()).unwrap())
+            .collect::<Vec<_>>();
+        mdir.add_flag(&ids[1], &Flag::Flagged).unwrap();
+        let work = Flag::Keyword("Work".into());
+
+        let errors = mdir.update_flags_batch([
+            (ids[0].as_str(), FlagChange::Add(&Flag::Seen)),
+            (ids[0].as_str(), FlagChange::Add(&work)),
+            (ids[1].as_str(), FlagChange::Remove(&Flag::Flagged)),
+            (ids[1].as_str(), FlagChange::Add(&Flag::Seen)),
+            (ids[2].as_str(), FlagChange::Remove(&Flag::Seen)),
+            ("unknown", FlagChange::Add(&Flag::Seen)),
+        ]);
+
+        let errors = errors.unwrap();
+        assert_eq!(1, errors.len());
+        assert!(matches!(
+            &errors[0],
+            ("unknown", EverestError::FindMaildirMsgError(id)) if id == "unknown"
+        ));
+        let envelopes = mdir.envelopes().unwrap();
+        assert_eq!(
+            Flags::from_iter([Flag::Seen, work]),
+            envelopes.get(ids[0].as_str()).unwrap().flags
+        );
+        assert_eq!(
+            Flags::from_iter([Flag::Seen]),
+            envelopes.get(ids[1].as_str()).unwrap().flags
+        );
+        // unchanged messages stay in new
+        assert!(dir.path().join("new").join(&ids[2]).is_file());
+    }
+
+    #[test]
+    fn remove_flag_keeps_new_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        mdir.remove_flag(&id, &Flag::Seen).unwrap();
+        assert!(dir.path().join("new").join(&id).is_file());
+
+        mdir.remove_msg(&id).unwrap();
+        assert!(mdir.envelopes().unwrap().is_empty());
+    }
+}
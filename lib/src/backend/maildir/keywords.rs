@@ -0,0 +1,164 @@
+use std::{fmt, fs, io, path::Path};
+
+use crate::EverestError;
+
+pub(super) const DOVECOT_KEYWORDS_FILENAME: &str = "dovecot-keywords";
+const MAX_KEYWORDS: usize = 26;
+
+/// Mapping between maildir custom flags (letters `a` to `z`) and
+/// IMAP keywords, stored by Dovecot in the `dovecot-keywords` file
+/// at the root of each maildir.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DovecotKeywords(Vec<Option<String>>);
+
+impl DovecotKeywords {
+    /// Loads the keywords of the given maildir. A missing file
+    /// means no keyword is defined yet.
+    pub fn load<P: AsRef<Path>>(mdir_path: P) -> Result<Self, EverestError> {
+        let path = mdir_path.as_ref().join(DOVECOT_KEYWORDS_FILENAME);
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Self::parse(&content)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(EverestError::ReadDovecotKeywordsError(err, path)),
+        }
+    }
+
+    /// Saves the keywords into the given maildir, replacing the
+    /// previous file atomically.
+    pub fn save<P: AsRef<Path>>(&self, mdir_path: P) -> Result<(), EverestError> {
+        let path = mdir_path.as_ref().join(DOVECOT_KEYWORDS_FILENAME);
+        let tmp_path = path.with_extension("tmp");
+        fs::write(&tmp_path, self.to_string())
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .map_err(|err| EverestError::WriteDovecotKeywordsError(err, path))
+    }
+
+    fn parse(content: &str) -> Self {
+        let mut keywords = Self::default();
+        for line in content.lines() {
+            // lines look like `<index> <keyword>`, invalid ones are
+            // ignored like Dovecot does
+            let (index, keyword) = match line.trim().split_once(' ') {
+                Some((index, keyword)) => (index, keyword.trim()),
+                None => continue,
+            };
+            let index = match index.parse::<usize>() {
+                Ok(index) if index < MAX_KEYWORDS && !keyword.is_empty() => index,
+                _ => continue,
+            };
+            if keywords.0.len() <= index {
+                keywords.0.resize(index + 1, None);
+            }
+            keywords.0[index] = Some(keyword.to_owned());
+        }
+        keywords
+    }
+
+    /// Returns the keyword associated to the given maildir flag
+    /// letter.
+    pub fn keyword(&self, letter: char) -> Option<&str> {
+        let index = letter_to_index(letter)?;
+        self.0.get(index)?.as_deref()
+    }
+
+    /// Returns the maildir flag letter associated to the given
+    /// keyword.
+    pub fn letter(&self, keyword: &str) -> Option<char> {
+        self.0
+            .iter()
+            .position(|k| k.as_deref() == Some(keyword))
+            .map(index_to_letter)
+    }
+
+    /// Returns the maildir flag letter associated to the given
+    /// keyword, registering it in the first free slot if needed.
+    /// Returns `None` when all 26 letters are already taken.
+    pub fn letter_or_insert(&mut self, keyword: &str) -> Option<char> {
+        if let Some(letter) = self.letter(keyword) {
+            return Some(letter);
+        }
+
+        let index = match self.0.iter().position(Option::is_none) {
+            Some(index) => index,
+            None if self.0.len() < MAX_KEYWORDS => {
+                self.0.push(None);
+                self.0.len() - 1
+            }
+            None => return None,
+        };
+        self.0[index] = Some(keyword.to_owned());
+        Some(index_to_letter(index))
+    }
+}
+
+impl fmt::Display for DovecotKeywords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (index, keyword) in self.0.iter().enumerate() {
+            if let Some(keyword) = keyword {
+                writeln!(f, "{} {}", index, keyword)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn letter_to_index(letter: char) -> Option<usize> {
+    match letter {
+        'a'..='z' => Some(letter as usize - 'a' as usize),
+        _ => None,
+    }
+}
+
+fn index_to_letter(index: usize) -> char {
+    (b'a' + index as u8) as char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let keywords = DovecotKeywords::parse("0 $Forwarded\n2 Work\ninvalid\n30 TooFar\n");
+
+        assert_eq!(Some("$Forwarded"), keywords.keyword('a'));
+        assert_eq!(None, keywords.keyword('b'));
+        assert_eq!(Some("Work"), keywords.keyword('c'));
+        assert_eq!(Some('c'), keywords.letter("Work"));
+        assert_eq!(None, keywords.letter("TooFar"));
+    }
+
+    #[test]
+    fn letter_or_insert_test() {
+        let mut keywords = DovecotKeywords::parse("1 Work\n");
+
+        assert_eq!(Some('b'), keywords.letter_or_insert("Work"));
+        assert_eq!(Some('a'), keywords.letter_or_insert("$Junk"));
+        assert_eq!(Some('c'), keywords.letter_or_insert("Home"));
+
+        for i in 3..MAX_KEYWORDS {
+            keywords.letter_or_insert(&format!("k{}", i));
+        }
+        assert_eq!(None, keywords.letter_or_insert("Overflow"));
+    }
+
+    #[test]
+    fn load_save_test() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(
+            DovecotKeywords::default(),
+            DovecotKeywords::load(&dir).unwrap()
+        );
+
+        let mut keywords = DovecotKeywords::default();
+        keywords.letter_or_insert("$Forwarded");
+        keywords.letter_or_insert("Work");
+        keywords.save(&dir).unwrap();
+
+        assert_eq!(
+            "0 $Forwarded\n1 Work\n",
+            fs::read_to_string(dir.path().join(DOVECOT_KEYWORDS_FILENAME)).unwrap()
+        );
+        assert_eq!(keywords, DovecotKeywords::load(&dir).unwrap());
+    }
+}
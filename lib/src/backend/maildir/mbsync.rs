@@ -0,0 +1,292 @@
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{EverestError, Flags};
+
+use super::Mdir;
+
+const UIDVALIDITY_FILENAME: &str = ".uidvalidity";
+const MBSYNCSTATE_FILENAME: &str = ".mbsyncstate";
+const MBSYNCSTATE_JOURNAL_FILENAME: &str = ".mbsyncstate.journal";
+
+/// Markers isync writes before the flags of a state entry, about the
+/// status of the entry rather than the flags of the message.
+const STATUS_MARKERS: &str = "<>^!~X+";
+
+/// Content of the `.uidvalidity` file isync keeps in each maildir to
+/// assign UIDs to local messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UidValidity {
+    pub validity: u32,
+    pub max_uid: u32,
+}
+
+/// Content of the `.mbsyncstate` file isync keeps in each maildir:
+/// the UIDVALIDITY of both sides and the mapping between IMAP UIDs
+/// (far side) and maildir UIDs (near side), with the flags of the
+/// last sync.
+///
+/// The state is only read, to import maildirs isync synced: isync
+/// owns it, and Everest never writes it.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MbsyncState {
+    pub far_uid_validity: u32,
+    pub near_uid_validity: u32,
+    pub max_pulled_uid: u32,
+    pub max_pushed_uid: u32,
+    pub entries: Vec<MbsyncStateEntry>,
+}
+
+/// A message known by both sides. A zero UID means the message does
+/// not exist (anymore) on that side.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct MbsyncStateEntry {
+    pub far_uid: u32,
+    pub near_uid: u32,
+    /// Status markers of the entry, kept as is, like `!` for a
+    /// message not propagated yet or `~` for an expired one. Empty
+    /// for messages in sync.
+    pub markers: String,
+    pub flags: Flags,
+}
+
+impl MbsyncState {
+    fn parse(content: &str) -> Result<Self, String> {
+        let mut state = Self::default();
+        let mut lines = content.lines();
+
+        // header, ended by an empty line
+        for line in lines.by_ref() {
+            if line.is_empty() {
+                break;
+            }
+            let (key, val) = line
+                .split_once(' ')
+                .ok_or_else(|| format!("invalid header line {:?}", line))?;
+            let val = val
+                .parse::<u32>()
+                .map_err(|err| format!("invalid header {}: {}", key, err))?;
+            match key {
+                "FarUidValidity" => state.far_uid_validity = val,
+                "NearUidValidity" => state.near_uid_validity = val,
+                "MaxPulledUid" => state.max_pulled_uid = val,
+                "MaxPushedUid" => state.max_pushed_uid = val,
+                // other headers (like MaxExpiredFarUid) are not used
+                _ => (),
+            }
+        }
+
+        for line in lines {
+            let mut parts = line.splitn(3, ' ');
+            let mut uid = || {
+                parts
+                    .next()
+                    .and_then(|uid| uid.parse::<u32>().ok())
+                    .ok_or_else(|| format!("invalid entry {:?}", line))
+            };
+            let far_uid = uid()?;
+            let near_uid = uid()?;
+            let info = parts.next().unwrap_or_default();
+            let (markers, flags) = info.split_at(
+                info.find(|c| !STATUS_MARKERS.contains(c))
+                    .unwrap_or(info.len()),
+            );
+            state.entries.push(MbsyncStateEntry {
+                far_uid,
+                near_uid,
+                markers: markers.to_owned(),
+                flags: Flags::from_chars(flags),
+            });
+        }
+
+        Ok(state)
+    }
+}
+
+impl Mdir {
+    /// Loads the isync `.uidvalidity` file, `None` if missing.
+    pub fn load_uid_validity(&self) -> Result<Option<UidValidity>, EverestError> {
+        let path = self.path.join(UIDVALIDITY_FILENAME);
+        let content = match read_optional(&path)? {
+            Some(content) => content,
+            None => return Ok(None),
+        };
+        parse_uid_validity(&content, &path).map(Some)
+    }
+
+    /// Reserves the next maildir UID, creating the `.uidvalidity` file
+    /// if needed, the way isync does: the file is locked while read
+    /// then rewritten in place, so that concurrent deliveries never
+    /// reserve the same UID.
+    pub(super) fn next_uid(&self) -> Result<u32, EverestError> {
+        let path = self.path.join(UIDVALIDITY_FILENAME);
+        let read_error = |err| EverestError::ReadMbsyncStateError(err, path.clone());
+        let write_error = |err| EverestError::WriteMbsyncStateError(err, path.clone());
+        let mut file = File::options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .map_err(write_error)?;
+        // the lock is released when the file is closed
+        file.lock().map_err(write_error)?;
+
+        let mut content = String::new();
+        file.read_to_string(&mut content).map_err(read_error)?;
+        let mut uid_validity = match content.is_empty() {
+            true => UidValidity {
+                validity: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs() as u32)
+                    .unwrap_or(1),
+                max_uid: 0,
+            },
+            false => parse_uid_validity(&content, &path)?,
+        };
+        uid_validity.max_uid += 1;
+
+        // the new content is never shorter, so that truncating it
+        // after writing never leaves an empty file behind
+        let content = format!("{}\n{}\n", uid_validity.validity, uid_validity.max_uid);
+        file.seek(SeekFrom::Start(0))
+            .and_then(|_| file.write_all(content.as_bytes()))
+            .and_then(|()| file.set_len(content.len() as u64))
+            .map_err(write_error)?;
+        Ok(uid_validity.max_uid)
+    }
+
+    /// Loads the isync `.mbsyncstate` file, `None` if missing. Fails if
+    /// isync left a journal behind, since the state would then be
+    /// outdated until isync replays it.
+    pub fn load_mbsync_state(&self) -> Result<Option<MbsyncState>, EverestError> {
+        let journal_path = self.path.join(MBSYNCSTATE_JOURNAL_FILENAME);
+        if journal_path.exists() {
+            return Err(EverestError::InvalidMbsyncStateError(
+                "unfinished isync journal found, run mbsync first".into(),
+                journal_path,
+            ));
+        }
+
+        let path = self.path.join(MBSYNCSTATE_FILENAME);
+        match read_optional(&path)? {
+            Some(content) => MbsyncState::parse(&content)
+                .map(Some)
+                .map_err(|err| EverestError::InvalidMbsyncStateError(err, path)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Extracts the isync UID of the given maildir message id, stored as a
+/// `,U=<uid>` suffix of the unique name.
+pub fn mbsync_uid(id: &str) -> Option<u32> {
+    let (_, uid) = id.rsplit_once(",U=")?;
+    let end = uid.find(|c: char| !c.is_ascii_digit()).unwrap_or(uid.len());
+    uid[..end].parse().ok()
+}
+
+fn read_optional(path: &Path) -> Result<Option<String>, EverestError> {
+    match fs::read_to_string(path) {
+        Ok(content) => Ok(Some(content)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(EverestError::ReadMbsyncStateError(err, path.to_owned())),
+    }
+}
+
+fn parse_uid_validity(content: &str, path: &Path) -> Result<UidValidity, EverestError> {
+    let mut lines = content.lines().map(|line| line.trim().parse::<u32>());
+    match (lines.next(), lines.next()) {
+        (Some(Ok(validity)), Some(Ok(max_uid))) => Ok(UidValidity { validity, max_uid }),
+        _ => Err(EverestError::InvalidMbsyncStateError(
+            "invalid uid validity".into(),
+            path.to_owned(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use crate::Flag;
+
+    use super::*;
+
+    const STATE: &str = "FarUidValidity 1234\nNearUidValidity 5678\nMaxPulledUid 12\nMaxPushedUid 3\nMaxExpiredFarUid 0\n\n11 1 FS\n12 2 \n0 3 R\n13 4 ~S\n14 0 !\n";
+
+    #[test]
+    fn mbsync_state_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        assert_eq!(None, mdir.load_mbsync_state().unwrap());
+
+        fs::write(dir.path().join(MBSYNCSTATE_FILENAME), STATE).unwrap();
+        let state = mdir.load_mbsync_state().unwrap().unwrap();
+        assert_eq!(1234, state.far_uid_validity);
+        assert_eq!(5678, state.near_uid_validity);
+        assert_eq!(12, state.max_pulled_uid);
+        assert_eq!(3, state.max_pushed_uid);
+        assert_eq!(
+            MbsyncStateEntry {
+                far_uid: 11,
+                near_uid: 1,
+                markers: String::new(),
+                flags: Flags::from_iter([Flag::Flagged, Flag::Seen]),
+            },
+            state.entries[0]
+        );
+        assert_eq!(5, state.entries.len());
+
+        // status markers are not flags
+        assert_eq!("~", state.entries[3].markers);
+        assert_eq!(Flags::from_iter([Flag::Seen]), state.entries[3].flags);
+        assert_eq!("!", state.entries[4].markers);
+        assert!(state.entries[4].flags.is_empty());
+
+        fs::write(dir.path().join(MBSYNCSTATE_JOURNAL_FILENAME), "").unwrap();
+        assert!(mdir.load_mbsync_state().is_err());
+    }
+
+    #[test]
+    fn uid_validity_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_mbsync_uids(true);
+        mdir.create_dirs().unwrap();
+        assert_eq!(None, mdir.load_uid_validity().unwrap());
+
+        let id1 = mdir.add_msg(b"", &Flags::default()).unwrap();
+        let id2 = mdir.add_msg(b"", &Flags::default()).unwrap();
+        assert_eq!(Some(1), mbsync_uid(&id1));
+        assert_eq!(Some(2), mbsync_uid(&id2));
+        assert_eq!(2, mdir.load_uid_validity().unwrap().unwrap().max_uid);
+
+        // concurrent deliveries reserve distinct UIDs
+        let threads = (0..4).map(|_| {
+            let mdir = mdir.clone();
+            std::thread::spawn(move || {
+                (0..10)
+                    .map(|_| mdir.next_uid().unwrap())
+                    .collect::<Vec<_>>()
+            })
+        });
+        let threads = threads.collect::<Vec<_>>();
+        let mut uids = threads
+            .into_iter()
+            .flat_map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        uids.sort_unstable();
+        assert_eq!((3..=42).collect::<Vec<_>>(), uids);
+    }
+
+    #[test]
+    fn mbsync_uid_test() {
+        assert_eq!(Some(42), mbsync_uid("1.M2P3.host,U=42"));
+        assert_eq!(Some(42), mbsync_uid("1.M2P3.host,U=42,S=123"));
+        assert_eq!(None, mbsync_uid("1.M2P3.host"));
+    }
+}
@@ -0,0 +1,69 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use crate::{message_id, EverestError};
+
+use super::Mdir;
+
+impl Mdir {
+    /// Lists the Message-IDs of the messages of `new` and `cur` by
+    /// message id, reading only their headers. Messages without
+    /// Message-ID are left out.
+    pub fn message_ids(&self) -> Result<HashMap<String, String>, EverestError> {
+        let mut message_ids = HashMap::new();
+        for entry in self.entries()? {
+            let entry = entry?;
+            let headers = read_headers(entry.path())?;
+            if let Some(message_id) = message_id::parse(&headers) {
+                message_ids.insert(entry.envelope().id.to_string(), message_id);
+            }
+        }
+        Ok(message_ids)
+    }
+}
+
+/// Reads the header section of the message at the given path, up to
+/// the first empty line.
+pub(crate) fn read_headers(path: &Path) -> Result<Vec<u8>, EverestError> {
+    let mut reader = fs::File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| EverestError::ReadMaildirMsgError(err, path.to_owned()))?;
+
+    let mut headers = vec![];
+    loop {
+        let len = reader
+            .read_until(b'\n', &mut headers)
+            .map_err(|err| EverestError::ReadMaildirMsgError(err, path.to_owned()))?;
+        if len == 0 || headers.ends_with(b"\n\n") || headers.ends_with(b"\n\r\n") {
+            break;
+        }
+    }
+    Ok(headers)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Flags;
+
+    use super::*;
+
+    #[test]
+    fn message_ids_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id = mdir
+            .add_msg(b"Message-ID: <a@b>\r\n\r\nbody\r\n", &Flags::default())
+            .unwrap();
+        mdir.add_msg(b"Subject: none\r\n\r\n", &Flags::default())
+            .unwrap();
+
+        let message_ids = mdir.message_ids().unwrap();
+        assert_eq!(1, message_ids.len());
+        assert_eq!("a@b", message_ids[&id]);
+    }
+}
@@ -0,0 +1,406 @@
+mod content;
+mod dedup;
+mod delivery;
+mod entries;
+mod filename;
+mod flags;
+mod keywords;
+mod mbsync;
+mod message_id;
+mod permissions;
+mod quota;
+mod repair;
+#[cfg(feature = "cache")]
+mod replica;
+mod scan;
+mod symlink;
+mod unique;
+#[cfg(feature = "watch")]
+mod watch;
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{plan::Backend, BackendError, Envelope, Envelopes, ErrorCode, ErrorKind, EverestError};
+
+pub use content::{MsgContent, MMAP_THRESHOLD};
+pub use dedup::DedupStore;
+pub use delivery::Durability;
+pub use filename::MdirFilename;
+pub use flags::FlagChange;
+pub use keywords::DovecotKeywords;
+pub use mbsync::{mbsync_uid, MbsyncState, MbsyncStateEntry, UidValidity};
+pub use permissions::Permissions;
+pub use quota::{QuotaPolicy, QuotaUsage};
+pub use repair::RepairReport;
+#[cfg(feature = "cache")]
+pub use replica::{MdirReplica, IDS_FILENAME};
+pub use scan::ScanCache;
+pub use symlink::SymlinkPolicy;
+pub use unique::HostnameSanitization;
+#[cfg(feature = "watch")]
+pub use watch::MdirWatcher;
+
+pub(crate) use filename::decode_flags;
+#[cfg(feature = "metadata")]
+pub(crate) use message_id::read_headers;
+
+/// The separator used by the maildir spec between the unique name and
+/// the info section of a filename.
+pub const DEFAULT_INFO_SEPARATOR: char = ':';
+
+/// A maildir folder, as seen by the sync engine.
+#[derive(Debug, Clone)]
+pub struct Mdir {
+    path: PathBuf,
+    durability: Durability,
+    info_separator: char,
+    hostname_sanitization: HostnameSanitization,
+    dedup: Option<DedupStore>,
+    mbsync_uids: bool,
+    quota_policy: Option<QuotaPolicy>,
+    maildirsize_path: Option<PathBuf>,
+    symlink_policy: SymlinkPolicy,
+    permissions: Permissions,
+}
+
+impl Mdir {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            durability: Durability::default(),
+            info_separator: DEFAULT_INFO_SEPARATOR,
+            hostname_sanitization: HostnameSanitization::default(),
+            dedup: None,
+            mbsync_uids: false,
+            quota_policy: None,
+            maildirsize_path: None,
+            symlink_policy: SymlinkPolicy::default(),
+            permissions: Permissions::default(),
+        }
+    }
+
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Changes the separator between the unique name and the info
+    /// section of filenames. The default `:` is illegal on NTFS,
+    /// Windows users usually pick `!` or `;` like Dovecot's
+    /// `maildir_filename_separator`.
+    pub fn with_info_separator(mut self, separator: char) -> Self {
+        self.info_separator = separator;
+        self
+    }
+
+    /// Changes how characters that cannot appear in unique names are
+    /// removed from the hostname part of the unique names.
+    pub fn with_hostname_sanitization(mut self, sanitization: HostnameSanitization) -> Self {
+        self.hostname_sanitization = sanitization;
+        self
+    }
+
+    /// Stores identical messages only once, by hardlinking messages
+    /// already present in the folders sharing the given store.
+    pub fn with_dedup(mut self, store: DedupStore) -> Self {
+        self.dedup = Some(store);
+        self
+    }
+
+    /// Assigns isync UIDs to delivered messages (`,U=<uid>` suffix
+    /// of the unique name, counter kept in `.uidvalidity`), so that
+    /// the maildir can still be synchronized by isync.
+    pub fn with_mbsync_uids(mut self, enabled: bool) -> Self {
+        self.mbsync_uids = enabled;
+        self
+    }
+
+    /// Takes the Maildir++ quota into account: deliveries are checked
+    /// against `maildirsize` according to the given policy, and both
+    /// deliveries and removals are accounted in it.
+    pub fn with_quota(mut self, policy: QuotaPolicy) -> Self {
+        self.quota_policy = Some(policy);
+        self
+    }
+
+    /// Changes the location of the `maildirsize` file, which defaults
+    /// to the folder root. Maildir++ subfolders share the file of the
+    /// root maildir.
+    pub fn with_maildirsize_path<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.maildirsize_path = Some(path.into());
+        self
+    }
+
+    pub fn with_symlink_policy(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlink_policy = policy;
+        self
+    }
+
+    /// Changes the permissions of the files and directories created
+    /// in the maildir.
+    pub fn with_permissions(mut self, permissions: Permissions) -> Self {
+        self.permissions = permissions;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Creates the `tmp`, `new` and `cur` directories if they do not
+    /// exist yet.
+    pub fn create_dirs(&self) -> Result<(), EverestError> {
+        for dir in ["tmp", "new", "cur"] {
+            self.create_dir(&self.path.join(dir))?;
+        }
+        Ok(())
+    }
+
+    /// Checks that the maildir can be synced, without creating
+    /// anything: `tmp`, `new` and `cur` must be readable directories,
+    /// and the Dovecot keywords, if any, must be readable.
+    pub fn check(&self) -> Result<(), EverestError> {
+        for dir in ["tmp", "new", "cur"] {
+            let dir = self.path.join(dir);
+            fs::read_dir(&dir).map_err(|err| EverestError::ReadMaildirDirError(err, dir))?;
+        }
+        DovecotKeywords::load(&self.path)?;
+        Ok(())
+    }
+
+    /// Lists the envelopes of both `new` and `cur`. Messages from
+    /// `new` have no info section, so they come without any flag.
+    pub fn envelopes(&self) -> Result<Envelopes, EverestError> {
+        let mut envelopes = Envelopes::default();
+        for entry in self.entries()? {
+            let envelope = entry?.envelope;
+            envelopes.insert(envelope.id.clone(), envelope);
+        }
+        Ok(envelopes)
+    }
+
+    /// Lists the entries of both `new` and `cur` lazily, without
+    /// holding the whole listing in memory.
+    pub fn entries(&self) -> Result<MdirEntries<'_>, EverestError> {
+        Ok(MdirEntries {
+            keywords: DovecotKeywords::load(&self.path)?,
+            dirs: vec![self.path.join("new"), self.path.join("cur")].into_iter(),
+            readdir: None,
+            mdir: self,
+        })
+    }
+
+    /// Builds the envelope matching the given filename, `None` for
+    /// files that are not messages. Listing stays a directory scan:
+    /// with the `metadata` feature, the metadata only holds the
+    /// delivery time of the unique name.
+    fn envelope(&self, filename: &str, keywords: &DovecotKeywords) -> Option<Envelope> {
+        let filename = self.parse_filename(filename)?;
+        let envelope = Envelope {
+            id: filename.unique.into(),
+            flags: filename.flags(keywords),
+            metadata: None,
+        };
+        #[cfg(feature = "metadata")]
+        let envelope = match filename.time() {
+            Some(secs) => envelope.with_metadata(crate::Metadata::from_delivery_time(secs)),
+            None => envelope,
+        };
+        Some(envelope)
+    }
+
+    /// Builds the filename of a message in `cur` from its id and its
+    /// info flags.
+    fn cur_filename(&self, id: &str, info: &str) -> String {
+        format!("{}{}2,{}", id, self.info_separator, info)
+    }
+
+    /// Finds the path of the message matching the given id, either in
+    /// `new` or in `cur`.
+    fn find(&self, id: &str) -> Result<PathBuf, EverestError> {
+        let path = self.path.join("new").join(id);
+        if path.is_file() {
+            return Ok(path);
+        }
+
+        let dir = self.path.join("cur");
+        let prefix = self.cur_filename(id, "");
+        for entry in
+            fs::read_dir(&dir).map_err(|err| EverestError::ReadMaildirDirError(err, dir.clone()))?
+        {
+            let entry = entry.map_err(|err| entry_error(err, &dir))?;
+            let filename = entry.file_name();
+            let filename = filename.to_string_lossy();
+            if filename == id || filename.starts_with(&prefix) {
+                return Ok(entry.path());
+            }
+        }
+
+        Err(EverestError::FindMaildirMsgError(id.to_owned()))
+    }
+
+    pub fn remove_msg(&self, id: &str) -> Result<(), EverestError> {
+        let path = self.find(id)?;
+        let size = match self.quota_policy {
+            Some(_) => fs::metadata(&path)
+                .map(|meta| meta.len())
+                .unwrap_or_default(),
+            None => 0,
+        };
+        fs::remove_file(&path)
+            .map_err(|err| EverestError::RemoveMaildirMsgError(err, path.clone()))?;
+        if let Some(dedup) = &self.dedup {
+            dedup.remove(&path);
+        }
+        self.record_quota(-(size as i64), -1)
+    }
+}
+
+/// A message of a maildir, with its envelope.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MdirEntry {
+    path: PathBuf,
+    envelope: Envelope,
+}
+
+impl MdirEntry {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn envelope(&self) -> &Envelope {
+        &self.envelope
+    }
+
+    pub fn into_envelope(self) -> Envelope {
+        self.envelope
+    }
+}
+
+/// Lazy iterator over the entries of a maildir, reading `new` then
+/// `cur` one directory entry at a time.
+pub struct MdirEntries<'a> {
+    mdir: &'a Mdir,
+    keywords: DovecotKeywords,
+    dirs: std::vec::IntoIter<PathBuf>,
+    readdir: Option<fs::ReadDir>,
+}
+
+impl Iterator for MdirEntries<'_> {
+    type Item = Result<MdirEntry, EverestError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let readdir = match self.readdir.as_mut() {
+                Some(readdir) => readdir,
+                None => {
+                    let dir = self.dirs.next()?;
+                    match self.mdir.follow_dir(&dir) {
+                        Ok(true) => (),
+                        Ok(false) => continue,
+                        Err(err) => return Some(Err(err)),
+                    }
+                    match fs::read_dir(&dir) {
+                        Ok(readdir) => self.readdir.insert(readdir),
+                        Err(err) => return Some(Err(EverestError::ReadMaildirDirError(err, dir))),
+                    }
+                }
+            };
+
+            let entry = match readdir.next() {
+                Some(Ok(entry)) => entry,
+                Some(Err(err)) => return Some(Err(entry_error(err, &self.mdir.path))),
+                None => {
+                    self.readdir = None;
+                    continue;
+                }
+            };
+
+            match self.mdir.follow_entry(&entry) {
+                Ok(true) => (),
+                Ok(false) => continue,
+                Err(err) => return Some(Err(err)),
+            }
+
+            let filename = entry.file_name();
+            if let Some(envelope) = self
+                .mdir
+                .envelope(&filename.to_string_lossy(), &self.keywords)
+            {
+                return Some(Ok(MdirEntry {
+                    path: entry.path(),
+                    envelope,
+                }));
+            }
+        }
+    }
+}
+
+pub(crate) fn sync_dir(path: &Path) -> io::Result<()> {
+    fs::File::open(path)?.sync_all()
+}
+
+/// Builds the error of an entry of the given directory that cannot be
+/// read.
+pub(crate) fn entry_error(err: io::Error, dir: &Path) -> EverestError {
+    BackendError::new(ErrorKind::Io, Backend::Maildir, "read entry")
+        .with_code(ErrorCode::MaildirEntryReadFailed)
+        .with_folder(dir.display().to_string())
+        .with_source(err)
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Flag, Flags};
+
+    use super::*;
+
+    #[test]
+    fn entries_delta_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id1 = mdir.add_msg(b"", &Flags::default()).unwrap();
+        let id2 = mdir.add_msg(b"", &Flags::default()).unwrap();
+        fs::write(dir.path().join("cur").join(".hidden"), "").unwrap();
+
+        let prev_envelopes = mdir.envelopes().unwrap();
+        assert_eq!(2, prev_envelopes.len());
+
+        mdir.add_flag(&id1, &Flag::Seen).unwrap();
+        mdir.remove_msg(&id2).unwrap();
+        let id3 = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        let entries = mdir.entries().unwrap();
+        let delta = prev_envelopes
+            .delta(entries.map(|entry| entry.map(MdirEntry::into_envelope)))
+            .unwrap();
+
+        assert_eq!(2, delta.upserted().len());
+        assert!(delta.upserted()[id1.as_str()].flags.contains(&Flag::Seen));
+        assert!(delta.upserted().contains_key(id3.as_str()));
+        assert_eq!(1, delta.removed().len());
+        assert!(delta.removed().contains(id2.as_str()));
+
+        let mut next_envelopes = prev_envelopes;
+        delta.apply(&mut next_envelopes);
+        assert_eq!(mdir.envelopes().unwrap(), next_envelopes);
+    }
+
+    #[test]
+    fn check_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path().join("INBOX"));
+        let err = mdir.check().unwrap_err();
+        assert_eq!(ErrorCode::MaildirDirReadFailed, err.code());
+        // checking creates nothing
+        assert!(!mdir.path().exists());
+
+        mdir.create_dirs().unwrap();
+        mdir.check().unwrap();
+    }
+}
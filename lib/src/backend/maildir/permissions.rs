@@ -0,0 +1,117 @@
+use std::{fs, io, path::Path};
+
+use crate::EverestError;
+
+use super::Mdir;
+
+/// Permissions given to the files and directories created in a
+/// maildir. Unset fields keep the system defaults (umask, primary
+/// group of the process), which usually means 0600/0700 and is wrong
+/// for maildirs shared between several users. Only applied on unix.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Permissions {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    /// Group id owning created files and directories.
+    pub group: Option<u32>,
+}
+
+impl Permissions {
+    /// Builds the permissions matching the given umask, like `0o007`
+    /// for group-shared maildirs.
+    pub fn from_umask(umask: u32) -> Self {
+        Self {
+            file_mode: Some(0o666 & !umask),
+            dir_mode: Some(0o777 & !umask),
+            group: None,
+        }
+    }
+
+    pub fn with_group(mut self, gid: u32) -> Self {
+        self.group = Some(gid);
+        self
+    }
+
+    fn apply(&self, file: &fs::File, mode: Option<u32>) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::{fchown, PermissionsExt};
+
+            if let Some(gid) = self.group {
+                fchown(file, None, Some(gid))?;
+            }
+            if let Some(mode) = mode {
+                file.set_permissions(fs::Permissions::from_mode(mode))?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (file, mode);
+        Ok(())
+    }
+
+    /// Applies the file permissions to the given created file.
+    pub(super) fn apply_to_file(&self, file: &fs::File) -> io::Result<()> {
+        self.apply(file, self.file_mode)
+    }
+
+    /// Applies the directory permissions to the given created
+    /// directory.
+    pub(super) fn apply_to_dir(&self, dir: &Path) -> io::Result<()> {
+        if *self == Self::default() {
+            return Ok(());
+        }
+        self.apply(&fs::File::open(dir)?, self.dir_mode)
+    }
+}
+
+impl Mdir {
+    /// Creates the given directory and its missing parents, applying
+    /// the directory permissions to the created ones.
+    pub(super) fn create_dir(&self, dir: &Path) -> Result<(), EverestError> {
+        if dir.is_dir() {
+            return Ok(());
+        }
+        if let Some(parent) = dir.parent() {
+            self.create_dir(parent)?;
+        }
+        match fs::create_dir(dir) {
+            Ok(()) => self
+                .permissions
+                .apply_to_dir(dir)
+                .map_err(|err| EverestError::CreateMaildirDirError(err, dir.to_owned())),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            Err(err) => Err(EverestError::CreateMaildirDirError(err, dir.to_owned())),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    use crate::Flags;
+
+    use super::*;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn permissions_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("shared");
+        let gid = fs::metadata(dir.path()).unwrap().gid();
+        let mdir =
+            Mdir::new(&root).with_permissions(Permissions::from_umask(0o027).with_group(gid));
+        mdir.create_dirs().unwrap();
+
+        assert_eq!(0o750, mode(&root));
+        assert_eq!(0o750, mode(&root.join("cur")));
+
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+        let path = root.join("new").join(id);
+        assert_eq!(0o640, mode(&path));
+        assert_eq!(gid, fs::metadata(&path).unwrap().gid());
+    }
+}
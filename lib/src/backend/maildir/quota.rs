@@ -0,0 +1,188 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::PathBuf,
+};
+
+use crate::EverestError;
+
+use super::Mdir;
+
+const MAILDIRSIZE_FILENAME: &str = "maildirsize";
+
+/// What to do when a delivery would exceed the quota defined by the
+/// Maildir++ `maildirsize` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum QuotaPolicy {
+    /// Deliver anyway, logging a warning.
+    Warn,
+    /// Refuse the delivery with [`EverestError::QuotaExceededError`].
+    Enforce,
+}
+
+/// Quota limits and current usage, as accounted in `maildirsize`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub max_bytes: Option<u64>,
+    pub max_count: Option<u64>,
+    pub bytes: u64,
+    pub count: u64,
+}
+
+impl QuotaUsage {
+    /// Returns `true` if adding the given amount of bytes and messages
+    /// would exceed the quota.
+    pub fn exceeded_by(&self, bytes: u64, count: u64) -> bool {
+        let bytes_exceeded = matches!(self.max_bytes, Some(max) if self.bytes + bytes > max);
+        let count_exceeded = matches!(self.max_count, Some(max) if self.count + count > max);
+        bytes_exceeded || count_exceeded
+    }
+
+    fn parse(content: &str) -> Self {
+        let mut usage = Self::default();
+        let mut lines = content.lines();
+
+        // the first line defines the quota, like `1000000S,1000C`
+        for limit in lines.next().unwrap_or_default().split(',') {
+            if let Some(bytes) = limit.strip_suffix('S') {
+                usage.max_bytes = bytes.parse().ok().filter(|max| *max > 0);
+            } else if let Some(count) = limit.strip_suffix('C') {
+                usage.max_count = count.parse().ok().filter(|max| *max > 0);
+            }
+        }
+
+        // other lines are `<bytes> <count>` deltas, possibly negative
+        let (mut bytes, mut count) = (0i64, 0i64);
+        for line in lines {
+            let mut parts = line
+                .split_whitespace()
+                .map(|n| n.parse::<i64>().unwrap_or(0));
+            bytes += parts.next().unwrap_or(0);
+            count += parts.next().unwrap_or(0);
+        }
+        usage.bytes = bytes.max(0) as u64;
+        usage.count = count.max(0) as u64;
+
+        usage
+    }
+}
+
+impl Mdir {
+    fn maildirsize_path(&self) -> PathBuf {
+        self.maildirsize_path
+            .clone()
+            .unwrap_or_else(|| self.path.join(MAILDIRSIZE_FILENAME))
+    }
+
+    /// Reads the quota usage from `maildirsize`, `None` when the file
+    /// does not exist (no quota).
+    pub fn quota_usage(&self) -> Result<Option<QuotaUsage>, EverestError> {
+        let path = self.maildirsize_path();
+        match fs::read_to_string(&path) {
+            Ok(content) => Ok(Some(QuotaUsage::parse(&content))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(EverestError::ReadMaildirsizeError(err, path)),
+        }
+    }
+
+    /// Checks whether adding the given amount of bytes and messages
+    /// fits in the quota, typically before applying a batch of added
+    /// messages. Returns `true` if it fits or if there is no quota.
+    pub fn check_quota(&self, bytes: u64, count: u64) -> Result<bool, EverestError> {
+        Ok(match self.quota_usage()? {
+            Some(usage) => !usage.exceeded_by(bytes, count),
+            None => true,
+        })
+    }
+
+    /// Applies the quota policy before delivering a message of the
+    /// given size.
+    pub(super) fn reserve_quota(&self, bytes: u64) -> Result<(), EverestError> {
+        let policy = match self.quota_policy {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        if self.check_quota(bytes, 1)? {
+            return Ok(());
+        }
+        match policy {
+            QuotaPolicy::Warn => {
+                log::warn!("maildir quota of {} exceeded", self.path.display());
+                Ok(())
+            }
+            QuotaPolicy::Enforce => Err(EverestError::QuotaExceededError(self.path.clone())),
+        }
+    }
+
+    /// Accounts the given delta in `maildirsize`, if it exists, so that
+    /// Dovecot's quota accounting stays correct.
+    pub(super) fn record_quota(&self, bytes: i64, count: i64) -> Result<(), EverestError> {
+        if self.quota_policy.is_none() {
+            return Ok(());
+        }
+        let path = self.maildirsize_path();
+        let file = fs::OpenOptions::new().append(true).open(&path);
+        let mut file = match file {
+            Ok(file) => file,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(EverestError::WriteMaildirsizeError(err, path)),
+        };
+        writeln!(file, "{} {}", bytes, count)
+            .map_err(|err| EverestError::WriteMaildirsizeError(err, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::Flags;
+
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let usage = QuotaUsage::parse("1000S,10C\n500 4\n-100 -1\n");
+        assert_eq!(Some(1000), usage.max_bytes);
+        assert_eq!(Some(10), usage.max_count);
+        assert_eq!(400, usage.bytes);
+        assert_eq!(3, usage.count);
+        assert!(!usage.exceeded_by(600, 1));
+        assert!(usage.exceeded_by(601, 1));
+        assert!(usage.exceeded_by(0, 8));
+
+        let usage = QuotaUsage::parse("1000S\n");
+        assert_eq!(None, usage.max_count);
+    }
+
+    #[test]
+    fn enforce_quota_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_quota(QuotaPolicy::Enforce);
+        mdir.create_dirs().unwrap();
+        fs::write(dir.path().join(MAILDIRSIZE_FILENAME), "10S\n").unwrap();
+
+        let id = mdir.add_msg(b"12345", &Flags::default()).unwrap();
+        assert_eq!(5, mdir.quota_usage().unwrap().unwrap().bytes);
+        assert!(!mdir.check_quota(6, 1).unwrap());
+        assert!(matches!(
+            mdir.add_msg(b"123456", &Flags::default()),
+            Err(EverestError::QuotaExceededError(_))
+        ));
+
+        mdir.remove_msg(&id).unwrap();
+        assert_eq!(0, mdir.quota_usage().unwrap().unwrap().bytes);
+        mdir.add_msg(b"123456", &Flags::default()).unwrap();
+    }
+
+    #[test]
+    fn no_maildirsize_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path()).with_quota(QuotaPolicy::Enforce);
+        mdir.create_dirs().unwrap();
+
+        mdir.add_msg(b"12345", &Flags::default()).unwrap();
+        assert_eq!(None, mdir.quota_usage().unwrap());
+        assert!(!dir.path().join(MAILDIRSIZE_FILENAME).exists());
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::EverestError;
+
+use super::{entry_error, Mdir};
+
+/// Files older than this in `tmp` are considered stranded by an
+/// interrupted delivery, and deleted as advised by the maildir spec.
+const STRANDED_TMP_AGE: Duration = Duration::from_secs(36 * 60 * 60);
+
+/// Fixes applied by [`Mdir::repair`].
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct RepairReport {
+    /// Messages with a malformed or duplicate name, renamed with a
+    /// fresh unique name, as `(before, after)` paths.
+    pub renamed: Vec<(PathBuf, PathBuf)>,
+    /// Files stranded in `tmp` by deliveries that never finished,
+    /// possibly truncated, deleted rather than delivered.
+    pub removed: Vec<PathBuf>,
+}
+
+impl RepairReport {
+    pub fn is_empty(&self) -> bool {
+        self.renamed.is_empty() && self.removed.is_empty()
+    }
+}
+
+impl Mdir {
+    /// Detects and fixes entries that would make the maildir unusable
+    /// by the sync: malformed filenames (not UTF-8, invalid info
+    /// section, missing info section in `cur`), unique names shared by
+    /// several messages, and messages stranded in `tmp`. Meant to be
+    /// run before syncing.
+    pub fn repair(&self) -> Result<RepairReport, EverestError> {
+        let mut report = RepairReport::default();
+        let mut ids = HashSet::new();
+
+        // cur first, so that duplicates are renamed in new
+        for dir in ["cur", "new"] {
+            let dir = self.path.join(dir);
+            for path in read_dir(&dir)? {
+                let filename = path
+                    .file_name()
+                    .and_then(|filename| filename.to_str())
+                    .unwrap_or_default()
+                    .to_owned();
+                let (id, info) = match self.parse_filename(&filename) {
+                    Some(filename) => (filename.unique, filename.info),
+                    None => continue,
+                };
+                let in_cur = dir.ends_with("cur");
+                let malformed = id.is_empty()
+                    || (in_cur && info.is_none())
+                    || (info.is_none() && filename.contains(self.info_separator));
+
+                if !malformed && ids.insert(id.to_owned()) {
+                    continue;
+                }
+
+                let id = self.unique_name(None, None);
+                let next_path = match (in_cur, info) {
+                    (true, Some(info)) => dir.join(self.cur_filename(&id, info)),
+                    (true, None) => dir.join(self.cur_filename(&id, "")),
+                    (false, _) => dir.join(&id),
+                };
+                rename(&path, &next_path)?;
+                ids.insert(id);
+                report.renamed.push((path, next_path));
+            }
+        }
+
+        let now = SystemTime::now();
+        for path in read_dir(&self.path.join("tmp"))? {
+            let stranded = fs::metadata(&path)
+                .and_then(|meta| meta.modified())
+                .map(|mtime| mtime + STRANDED_TMP_AGE < now)
+                .unwrap_or_default();
+            if stranded {
+                fs::remove_file(&path)
+                    .map_err(|err| EverestError::RepairMaildirError(err, path.clone()))?;
+                report.removed.push(path);
+            }
+        }
+
+        Ok(report)
+    }
+}
+
+fn read_dir(dir: &Path) -> Result<Vec<PathBuf>, EverestError> {
+    let entries =
+        fs::read_dir(dir).map_err(|err| EverestError::ReadMaildirDirError(err, dir.to_owned()))?;
+    entries
+        .map(|entry| {
+            entry
+                .map(|entry| entry.path())
+                .map_err(|err| entry_error(err, dir))
+        })
+        .collect()
+}
+
+fn rename(from: &Path, to: &Path) -> Result<(), EverestError> {
+    fs::rename(from, to).map_err(|err| EverestError::RepairMaildirError(err, from.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::FileTimes;
+
+    use crate::Flags;
+
+    use super::*;
+
+    #[test]
+    fn repair_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+        let cur = dir.path().join("cur");
+        fs::write(cur.join(format!("{}:2,S", id)), "").unwrap();
+        fs::write(cur.join("noinfo"), "").unwrap();
+        fs::write(cur.join("badinfo:1,S"), "").unwrap();
+        fs::write(cur.join("ok:2,S"), "").unwrap();
+
+        let tmp = dir.path().join("tmp");
+        let old = SystemTime::now() - 2 * STRANDED_TMP_AGE;
+        fs::File::create(tmp.join("stranded"))
+            .unwrap()
+            .set_times(FileTimes::new().set_modified(old))
+            .unwrap();
+        fs::write(tmp.join("delivering"), "").unwrap();
+
+        let report = mdir.repair().unwrap();
+        let renamed = report
+            .renamed
+            .iter()
+            .map(|(path, _)| path.file_name().unwrap().to_str().unwrap())
+            .collect::<HashSet<_>>();
+        assert_eq!(
+            HashSet::from([id.as_str(), "noinfo", "badinfo:1,S"]),
+            renamed
+        );
+        // stranded deliveries are never delivered
+        assert_eq!(vec![tmp.join("stranded")], report.removed);
+        assert!(!tmp.join("stranded").exists());
+        assert!(tmp.join("delivering").exists());
+
+        assert_eq!(5, mdir.envelopes().unwrap().len());
+        assert!(mdir.repair().unwrap().is_empty());
+    }
+}
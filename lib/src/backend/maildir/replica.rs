@@ -0,0 +1,305 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    sync::Arc,
+};
+
+use crate::{
+    backend::ids::ReplicaIds,
+    folder::FolderMapping,
+    health::{Check, Probe},
+    sync::Replica,
+    Envelopes, EverestError, Flag, Flags,
+};
+
+use super::{FlagChange, Mdir, ScanCache};
+
+/// File of each maildir mapping the ids of the messages the sync
+/// added to it, see [`ReplicaIds`].
+pub const IDS_FILENAME: &str = ".everest-ids";
+
+/// Maildirs of the folders of a sync, laid out by a
+/// [`FolderMapping`], as the right side of a
+/// [`crate::sync::Sync`].
+///
+/// Maildirs get their directories created the first time they are
+/// synced. Messages added by the sync get a unique name of the
+/// maildir: the ids of the other side they keep for the sync are
+/// mapped to them in the [`IDS_FILENAME`] file of the maildir.
+///
+/// Listing the envelopes of a folder again only rescans the
+/// directories of its maildir that changed, see [`ScanCache`].
+#[derive(Debug, Clone)]
+pub struct MdirReplica {
+    mapping: FolderMapping,
+    delimiter: Option<char>,
+    mdir: Mdir,
+    folders: HashMap<String, Folder>,
+}
+
+/// Maildir of a folder, with the state kept about it between syncs:
+/// the ids of the messages the sync added, and the scan cache sparing
+/// the directories that did not change since the previous sync.
+#[derive(Debug, Clone)]
+struct Folder {
+    mdir: Mdir,
+    ids: ReplicaIds,
+    scan: ScanCache,
+}
+
+impl MdirReplica {
+    pub fn new(mapping: FolderMapping) -> Self {
+        Self {
+            mapping,
+            delimiter: Some('/'),
+            mdir: Mdir::new(""),
+            folders: HashMap::new(),
+        }
+    }
+
+    /// Splits folders into hierarchy levels with the given delimiter,
+    /// the one announced by the IMAP server, `/` by default. `None`
+    /// for flat namespaces.
+    pub fn with_delimiter(mut self, delimiter: Option<char>) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Configures the maildirs like the given one, whatever its path,
+    /// like to change their durability or their info separator.
+    pub fn with_mdir(mut self, mdir: Mdir) -> Self {
+        self.mdir = mdir;
+        self
+    }
+
+    /// Returns the maildir of the given folder, without creating it.
+    pub fn mdir(&self, folder: &str) -> Mdir {
+        let mut mdir = self.mdir.clone();
+        mdir.path = self.mapping.local_path(folder, self.delimiter);
+        mdir
+    }
+
+    /// Returns the maildir of the given folder with its ids, creating
+    /// its directories the first time.
+    fn folder(&mut self, folder: &str) -> Result<&mut Folder, EverestError> {
+        match self.folders.entry(folder.to_owned()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                let mut mdir = self.mdir.clone();
+                mdir.path = self.mapping.local_path(folder, self.delimiter);
+                mdir.create_dirs()?;
+                let ids = ReplicaIds::load(mdir.path.join(IDS_FILENAME))?;
+                Ok(entry.insert(Folder {
+                    mdir,
+                    ids,
+                    scan: ScanCache::default(),
+                }))
+            }
+        }
+    }
+}
+
+impl Replica for MdirReplica {
+    fn envelopes(&mut self, folder: &str) -> Result<Envelopes, EverestError> {
+        let Folder { mdir, ids, scan } = self.folder(folder)?;
+        Ok(ids.map_envelopes(mdir.envelopes_with_cache(scan)?))
+    }
+
+    fn read_msg(&mut self, folder: &str, id: &str) -> Result<Vec<u8>, EverestError> {
+        let Folder { mdir, ids, .. } = self.folder(folder)?;
+        Ok(mdir.read_msg(ids.local(id))?.to_vec())
+    }
+
+    fn add_msg(
+        &mut self,
+        folder: &str,
+        id: &str,
+        raw: &[u8],
+        flags: &Flags,
+    ) -> Result<(), EverestError> {
+        let Folder { mdir, ids, .. } = self.folder(folder)?;
+        let local = mdir.add_msg(raw, flags)?;
+        ids.insert(id, &local);
+        Ok(())
+    }
+
+    fn remove_msg(&mut self, folder: &str, id: &str) -> Result<(), EverestError> {
+        let Folder { mdir, ids, .. } = self.folder(folder)?;
+        mdir.remove_msg(ids.local(id))?;
+        ids.remove(id);
+        Ok(())
+    }
+
+    fn add_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        let Folder { mdir, ids, .. } = self.folder(folder)?;
+        mdir.add_flag(ids.local(id), flag)
+    }
+
+    fn remove_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        let Folder { mdir, ids, .. } = self.folder(folder)?;
+        mdir.remove_flag(ids.local(id), flag)
+    }
+
+    /// Applies the flag changes with [`Mdir::update_flags_batch`], in
+    /// a single pass over the maildir of the folder. The error of a
+    /// message is shared by all its changes.
+    fn update_flags(
+        &mut self,
+        folder: &str,
+        changes: &[(&str, FlagChange)],
+    ) -> Vec<(usize, Arc<EverestError>)> {
+        let fail_all = |err: EverestError| {
+            let err = Arc::new(err);
+            (0..changes.len())
+                .map(|index| (index, err.clone()))
+                .collect()
+        };
+        let Folder { mdir, ids, .. } = match self.folder(folder) {
+            Ok(folder) => folder,
+            Err(err) => return fail_all(err),
+        };
+        let locals = changes.iter().map(|(id, change)| (ids.local(id), *change));
+        let failed = match mdir.update_flags_batch(locals) {
+            Ok(failed) => failed,
+            Err(err) => return fail_all(err),
+        };
+
+        let mut errors = vec![];
+        for (local, err) in failed {
+            let err = Arc::new(err);
+            let indexes = changes.iter().enumerate();
+            let indexes = indexes.filter(|(_, (id, _))| ids.local(id) == local);
+            errors.extend(indexes.map(|(index, _)| (index, err.clone())));
+        }
+        errors.sort_unstable_by_key(|(index, _)| *index);
+        errors
+    }
+
+    /// Saves the ids of the messages added to the maildir of the
+    /// given folder, see [`ReplicaIds::flush`].
+    fn flush(&mut self, folder: &str) -> Result<(), EverestError> {
+        match self.folders.get_mut(folder) {
+            Some(Folder { ids, .. }) => ids.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the maildirs of the given folders with [`Mdir::check`],
+    /// maildirs not created yet failing.
+    fn check(&mut self, folders: &[String]) -> Vec<Check> {
+        let check = |folder: &String| {
+            let probe = Probe::Folder(folder.clone());
+            Check::new(probe, self.mdir(folder).check())
+        };
+        folders.iter().map(check).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::MemoryCache,
+        sync::{MemoryReplica, Sync},
+    };
+
+    use super::*;
+
+    #[test]
+    fn replica_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapping = FolderMapping::new(dir.path());
+        let mut replica = MdirReplica::new(mapping.clone());
+        assert!(!replica.check(&["INBOX".into()])[0].is_ok());
+        assert!(replica.envelopes("INBOX").unwrap().is_empty());
+        assert!(replica.check(&["INBOX".into()])[0].is_ok());
+
+        // added messages keep the id of the other side
+        let raw = b"Subject: hello\r\n\r\n";
+        let flags = Flags::from_iter([Flag::Seen]);
+        replica.add_msg("INBOX", "42", raw, &flags).unwrap();
+        replica.add_flag("INBOX", "42", &Flag::Flagged).unwrap();
+        replica.remove_flag("INBOX", "42", &Flag::Seen).unwrap();
+        let envelopes = replica.envelopes("INBOX").unwrap();
+        let flags = Flags::from_iter([Flag::Flagged]);
+        assert_eq!(Some(&flags), envelopes.get("42").map(|e| &e.flags));
+        assert_eq!(raw.to_vec(), replica.read_msg("INBOX", "42").unwrap());
+        replica.flush("INBOX").unwrap();
+
+        // local messages keep their unique name, and ids survive
+        // replicas
+        let mdir = replica.mdir("INBOX");
+        let local = mdir.add_msg(b"Subject: local\r\n\r\n", &Flags::default());
+        let local = local.unwrap();
+        let mut replica = MdirReplica::new(mapping);
+        let envelopes = replica.envelopes("INBOX").unwrap();
+        let mut ids = envelopes
+            .keys()
+            .map(|id| id.to_string())
+            .collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(vec![local, "42".to_owned()], ids);
+
+        replica.remove_msg("INBOX", "42").unwrap();
+        replica.flush("INBOX").unwrap();
+        assert_eq!(1, mdir.envelopes().unwrap().len());
+        let ids = std::fs::read_to_string(mdir.path().join(IDS_FILENAME));
+        assert_eq!("", ids.unwrap());
+    }
+
+    #[test]
+    fn update_flags_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut replica = MdirReplica::new(FolderMapping::new(dir.path()));
+        replica
+            .add_msg("INBOX", "42", b"", &Flags::default())
+            .unwrap();
+        replica
+            .add_msg("INBOX", "43", b"", &Flags::default())
+            .unwrap();
+
+        // errors come back by change, shared by the changes of their
+        // message
+        let work = Flag::Keyword("Work".into());
+        let errors = replica.update_flags(
+            "INBOX",
+            &[
+                ("42", FlagChange::Add(&Flag::Seen)),
+                ("44", FlagChange::Add(&Flag::Seen)),
+                ("43", FlagChange::Add(&work)),
+                ("44", FlagChange::Remove(&Flag::Draft)),
+            ],
+        );
+        let indexes = errors.iter().map(|(index, _)| *index).collect::<Vec<_>>();
+        assert_eq!(vec![1, 3], indexes);
+        assert!(Arc::ptr_eq(&errors[0].1, &errors[1].1));
+
+        let envelopes = replica.envelopes("INBOX").unwrap();
+        let flags = |id| envelopes.get(id).map(|envelope| envelope.flags.clone());
+        assert_eq!(Some(Flags::from_iter([Flag::Seen])), flags("42"));
+        assert_eq!(Some(Flags::from_iter([work])), flags("43"));
+    }
+
+    #[test]
+    fn sync_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapping = FolderMapping::new(dir.path());
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[Flag::Seen]), ("2", &[])]))
+            .right(MdirReplica::new(mapping.clone()))
+            .cache_store(MemoryCache::new())
+            .folder("Lists/rust")
+            .build()
+            .unwrap();
+
+        let report = sync.run().unwrap();
+        assert!(report.errors.is_empty() && report.skipped.is_empty());
+        assert_eq!(2, report.right.added);
+        let mdir = MdirReplica::new(mapping).mdir("Lists/rust");
+        assert_eq!(dir.path().join("Lists").join("rust"), mdir.path());
+        assert_eq!(2, mdir.envelopes().unwrap().len());
+        let ids = std::fs::read_to_string(mdir.path().join(IDS_FILENAME));
+        assert_eq!(2, ids.unwrap().lines().count());
+
+        // both sides are in sync
+        assert_eq!(0, sync.run().unwrap().hunks);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use crate::{Envelopes, EverestError};
+
+use super::{DovecotKeywords, Mdir, MdirEntries};
+
+// mtimes have a coarse resolution on some filesystems: a directory
+// modified within this delay after its scan may have changed without
+// its mtime changing
+const MTIME_RESOLUTION: Duration = Duration::from_secs(1);
+
+/// Remembers the state of already scanned maildir directories, so
+/// that directories whose mtime did not change are not scanned again.
+/// A single cache can be shared between several maildir folders.
+#[derive(Default, Debug, Clone)]
+pub struct ScanCache {
+    dirs: HashMap<PathBuf, DirState>,
+}
+
+#[derive(Debug, Clone)]
+struct DirState {
+    mtime: SystemTime,
+    scanned_at: SystemTime,
+    keywords: DovecotKeywords,
+    envelopes: Envelopes,
+}
+
+impl DirState {
+    fn is_fresh(&self, mtime: SystemTime, keywords: &DovecotKeywords) -> bool {
+        self.mtime == mtime
+            && self.mtime + MTIME_RESOLUTION <= self.scanned_at
+            && &self.keywords == keywords
+    }
+}
+
+impl ScanCache {
+    /// Forgets the state of all directories, forcing the next scans
+    /// to read everything again.
+    pub fn clear(&mut self) {
+        self.dirs.clear()
+    }
+}
+
+impl Mdir {
+    /// Lists the envelopes of both `new` and `cur` like
+    /// [`Mdir::envelopes`], only rescanning the directories whose
+    /// mtime changed since the previous scan.
+    pub fn envelopes_with_cache(&self, cache: &mut ScanCache) -> Result<Envelopes, EverestError> {
+        let keywords = DovecotKeywords::load(&self.path)?;
+        let mut envelopes = Envelopes::default();
+        for dir in ["new", "cur"] {
+            let dir = self.scan_dir(self.path.join(dir), &keywords, cache)?;
+            envelopes.extend(dir.values().cloned());
+        }
+        Ok(envelopes)
+    }
+
+    fn scan_dir<'a>(
+        &self,
+        dir: PathBuf,
+        keywords: &DovecotKeywords,
+        cache: &'a mut ScanCache,
+    ) -> Result<&'a Envelopes, EverestError> {
+        let mtime = mtime(&dir)?;
+        let fresh = matches!(cache.dirs.get(&dir), Some(state) if state.is_fresh(mtime, keywords));
+
+        if !fresh {
+            let scanned_at = SystemTime::now();
+            let entries = MdirEntries {
+                mdir: self,
+                keywords: keywords.clone(),
+                dirs: vec![dir.clone()].into_iter(),
+                readdir: None,
+            };
+            let mut envelopes = Envelopes::default();
+            for entry in entries {
+                let envelope = entry?.envelope;
+                envelopes.insert(envelope.id.clone(), envelope);
+            }
+            let state = DirState {
+                mtime,
+                scanned_at,
+                keywords: keywords.clone(),
+                envelopes,
+            };
+            cache.dirs.insert(dir.clone(), state);
+        }
+
+        Ok(&cache.dirs[&dir].envelopes)
+    }
+}
+
+fn mtime(dir: &Path) -> Result<SystemTime, EverestError> {
+    fs::metadata(dir)
+        .and_then(|meta| meta.modified())
+        .map_err(|err| EverestError::ReadMaildirDirError(err, dir.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Flag, Flags};
+
+    use super::*;
+
+    #[test]
+    fn skip_unchanged_dirs_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        let mut cache = ScanCache::default();
+        assert_eq!(
+            mdir.envelopes().unwrap(),
+            mdir.envelopes_with_cache(&mut cache).unwrap()
+        );
+
+        // pretend the scan happened long after the last change, and
+        // tamper the cached envelopes to detect skipped scans
+        for state in cache.dirs.values_mut() {
+            state.scanned_at += 2 * MTIME_RESOLUTION;
+            state.envelopes.clear();
+        }
+        assert!(mdir.envelopes_with_cache(&mut cache).unwrap().is_empty());
+
+        // moving the message changes both directories
+        mdir.add_flag(&id, &Flag::Seen).unwrap();
+        assert_eq!(
+            mdir.envelopes().unwrap(),
+            mdir.envelopes_with_cache(&mut cache).unwrap()
+        );
+    }
+}
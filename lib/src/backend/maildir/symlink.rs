@@ -0,0 +1,134 @@
+use std::{fs, path::Path};
+
+use crate::EverestError;
+
+use super::Mdir;
+
+/// How symlinked messages and symlinked folder directories are
+/// handled while listing a maildir.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum SymlinkPolicy {
+    /// Follows symlinks. Symlinked messages whose target is missing or
+    /// is not a file are skipped.
+    #[default]
+    Follow,
+    /// Skips symlinked messages, and lists symlinked folders as empty.
+    Ignore,
+    /// Fails with [`EverestError::SymlinkError`].
+    Error,
+}
+
+impl Mdir {
+    /// Applies the symlink policy to the given symlink path. Returns
+    /// `true` if the path should be followed.
+    fn follow_symlink(&self, path: &Path) -> Result<bool, EverestError> {
+        match self.symlink_policy {
+            SymlinkPolicy::Follow => Ok(true),
+            SymlinkPolicy::Ignore => Ok(false),
+            SymlinkPolicy::Error => Err(EverestError::SymlinkError(path.to_owned())),
+        }
+    }
+
+    /// Returns `true` if the given directory of the maildir should be
+    /// listed, according to the symlink policy applied to both the
+    /// directory and the maildir root.
+    pub(super) fn follow_dir(&self, dir: &Path) -> Result<bool, EverestError> {
+        for path in [&self.path, dir] {
+            let is_symlink = fs::symlink_metadata(path)
+                .map(|meta| meta.file_type().is_symlink())
+                .unwrap_or_default();
+            if is_symlink && !self.follow_symlink(path)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Returns `true` if the given directory entry should be listed as
+    /// a message, according to the symlink policy.
+    pub(super) fn follow_entry(&self, entry: &fs::DirEntry) -> Result<bool, EverestError> {
+        let is_symlink = entry
+            .file_type()
+            .map(|file_type| file_type.is_symlink())
+            .unwrap_or_default();
+        self.follow_msg(&entry.path(), is_symlink)
+    }
+
+    /// Same as [`Mdir::follow_entry`], for the given path of an entry,
+    /// like the ones the watcher gets.
+    #[cfg(feature = "watch")]
+    pub(super) fn follow_path(&self, path: &Path) -> Result<bool, EverestError> {
+        let is_symlink = fs::symlink_metadata(path)
+            .map(|meta| meta.file_type().is_symlink())
+            .unwrap_or_default();
+        self.follow_msg(path, is_symlink)
+    }
+
+    fn follow_msg(&self, path: &Path, is_symlink: bool) -> Result<bool, EverestError> {
+        if !is_symlink {
+            return Ok(true);
+        }
+        Ok(self.follow_symlink(path)?
+            && fs::metadata(path)
+                .map(|meta| meta.is_file())
+                .unwrap_or_default())
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use crate::Flags;
+
+    use super::*;
+
+    #[test]
+    fn symlinked_msg_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path().join("mdir"));
+        mdir.create_dirs().unwrap();
+        mdir.add_msg(b"", &Flags::default()).unwrap();
+        fs::write(dir.path().join("msg"), "").unwrap();
+        symlink(dir.path().join("msg"), mdir.path().join("cur/link:2,S")).unwrap();
+        symlink(
+            dir.path().join("missing"),
+            mdir.path().join("cur/broken:2,S"),
+        )
+        .unwrap();
+
+        let envelopes = mdir.envelopes().unwrap();
+        assert_eq!(2, envelopes.len());
+        assert!(envelopes.contains_key("link"));
+
+        let mdir = mdir.with_symlink_policy(SymlinkPolicy::Ignore);
+        let envelopes = mdir.envelopes().unwrap();
+        assert_eq!(1, envelopes.len());
+
+        let mdir = mdir.with_symlink_policy(SymlinkPolicy::Error);
+        assert!(matches!(
+            mdir.envelopes(),
+            Err(EverestError::SymlinkError(_))
+        ));
+    }
+
+    #[test]
+    fn symlinked_folder_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path().join("mdir"));
+        mdir.create_dirs().unwrap();
+        mdir.add_msg(b"", &Flags::default()).unwrap();
+        symlink(dir.path().join("mdir"), dir.path().join("link")).unwrap();
+
+        let mdir = Mdir::new(dir.path().join("link"));
+        assert_eq!(1, mdir.envelopes().unwrap().len());
+
+        let mdir = mdir.with_symlink_policy(SymlinkPolicy::Ignore);
+        assert!(mdir.envelopes().unwrap().is_empty());
+
+        let mdir = mdir.with_symlink_policy(SymlinkPolicy::Error);
+        assert!(mdir.envelopes().is_err());
+    }
+}
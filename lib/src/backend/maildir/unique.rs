@@ -0,0 +1,102 @@
+use std::{
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use super::Mdir;
+
+// number of unique names generated by this process, so that two
+// names generated within the same microsecond still differ
+static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+/// How characters that are forbidden in unique names are removed
+/// from the hostname: `/`, `,`, the info separator, and `:` which is
+/// the standard info separator.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum HostnameSanitization {
+    /// Replaces forbidden characters by their octal escape, like
+    /// `\057` for `/`, as described by the maildir spec.
+    #[default]
+    Escape,
+    /// Replaces forbidden characters by the given character.
+    Replace(char),
+}
+
+impl Mdir {
+    /// Generates a unique name following the maildir spec, safe to
+    /// use across hosts sharing the maildir over NFS:
+    /// `<secs>.M<usecs>P<pid>Q<sequence>.<hostname>`. The seconds are
+    /// taken from the given date if any, otherwise from the current
+    /// time. The isync UID, if any, is appended as `,U=<uid>`.
+    pub(super) fn unique_name(&self, date: Option<SystemTime>, uid: Option<u32>) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let secs = date
+            .and_then(|date| date.duration_since(UNIX_EPOCH).ok())
+            .map(|date| date.as_secs())
+            .unwrap_or(now.as_secs());
+        let mut name = format!(
+            "{}.M{}P{}Q{}.{}",
+            secs,
+            now.subsec_micros(),
+            process::id(),
+            SEQUENCE.fetch_add(1, Ordering::Relaxed),
+            self.sanitize_hostname(&gethostname::gethostname().to_string_lossy()),
+        );
+        if let Some(uid) = uid {
+            name.push_str(&format!(",U={}", uid));
+        }
+        name
+    }
+
+    fn sanitize_hostname(&self, hostname: &str) -> String {
+        let mut sanitized = String::with_capacity(hostname.len());
+        for c in hostname.chars() {
+            // commas separate the isync UID
+            if c == '/' || c == ':' || c == ',' || c == self.info_separator {
+                match self.hostname_sanitization {
+                    HostnameSanitization::Escape => {
+                        sanitized.push_str(&format!("\\{:03o}", c as u32))
+                    }
+                    HostnameSanitization::Replace(replacement) => sanitized.push(replacement),
+                }
+            } else {
+                sanitized.push(c);
+            }
+        }
+        sanitized
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn sanitize_hostname_test() {
+        let mdir = Mdir::new("/tmp");
+        assert_eq!("a\\057b\\072c", mdir.sanitize_hostname("a/b:c"));
+
+        let mdir = Mdir::new("/tmp")
+            .with_info_separator('!')
+            .with_hostname_sanitization(HostnameSanitization::Replace('_'));
+        assert_eq!("a_b_c_d", mdir.sanitize_hostname("a/b:c!d"));
+    }
+
+    #[test]
+    fn unique_name_test() {
+        let mdir = Mdir::new("/tmp");
+        let names = (0..1000)
+            .map(|_| mdir.unique_name(None, None))
+            .collect::<HashSet<_>>();
+        assert_eq!(1000, names.len());
+
+        let name = mdir.unique_name(Some(UNIX_EPOCH), None);
+        assert!(name.starts_with("0.M"));
+        assert!(name.contains(&format!("P{}Q", process::id())));
+    }
+}
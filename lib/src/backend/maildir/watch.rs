@@ -0,0 +1,205 @@
+use notify::{RecursiveMode, Watcher};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::mpsc,
+    time::Duration,
+};
+
+use crate::{Envelopes, EverestError, Id};
+
+use super::{keywords::DOVECOT_KEYWORDS_FILENAME, DovecotKeywords, Mdir};
+
+/// Watches a maildir for local changes (deliveries, flag changes,
+/// removals) and keeps its envelopes up to date incrementally, so
+/// the next maildir snapshot is available without rescanning the
+/// whole maildir.
+pub struct MdirWatcher {
+    mdir: Mdir,
+    keywords: DovecotKeywords,
+    envelopes: Envelopes,
+    // current path of each message, used to discard events about
+    // paths a message has already been renamed from
+    paths: HashMap<Id, PathBuf>,
+    rx: mpsc::Receiver<notify::Result<notify::Event>>,
+    _watcher: notify::RecommendedWatcher,
+}
+
+impl MdirWatcher {
+    pub fn new(mdir: Mdir) -> Result<Self, EverestError> {
+        let (tx, rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)
+            .map_err(|err| EverestError::WatchMaildirError(err, mdir.path.clone()))?;
+        watcher
+            .watch(&mdir.path, RecursiveMode::Recursive)
+            .map_err(|err| EverestError::WatchMaildirError(err, mdir.path.clone()))?;
+
+        let mut watcher = Self {
+            keywords: DovecotKeywords::default(),
+            envelopes: Envelopes::default(),
+            paths: HashMap::new(),
+            mdir,
+            rx,
+            _watcher: watcher,
+        };
+        watcher.rescan()?;
+        Ok(watcher)
+    }
+
+    /// Returns the current snapshot of the maildir envelopes.
+    pub fn envelopes(&self) -> &Envelopes {
+        &self.envelopes
+    }
+
+    /// Applies the changes that happened since the last call without
+    /// blocking. Returns `true` if at least one change was applied.
+    pub fn poll(&mut self) -> Result<bool, EverestError> {
+        let mut changed = false;
+        while let Ok(event) = self.rx.try_recv() {
+            changed |= self.apply(event)?;
+        }
+        Ok(changed)
+    }
+
+    /// Blocks until a change happens or the timeout expires, then
+    /// applies all pending changes. Returns `true` if at least one
+    /// change was applied.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool, EverestError> {
+        match self.rx.recv_timeout(timeout) {
+            Ok(event) => Ok(self.apply(event)? | self.poll()?),
+            Err(_) => Ok(false),
+        }
+    }
+
+    fn rescan(&mut self) -> Result<(), EverestError> {
+        self.keywords = DovecotKeywords::load(&self.mdir.path)?;
+        self.envelopes.clear();
+        self.paths.clear();
+        for entry in self.mdir.entries()? {
+            let entry = entry?;
+            self.paths.insert(entry.envelope.id.clone(), entry.path);
+            self.envelopes
+                .insert(entry.envelope.id.clone(), entry.envelope);
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, event: notify::Result<notify::Event>) -> Result<bool, EverestError> {
+        let event =
+            event.map_err(|err| EverestError::WatchMaildirError(err, self.mdir.path.clone()))?;
+
+        if event.need_rescan() {
+            self.rescan()?;
+            return Ok(true);
+        }
+
+        let mut changed = false;
+        for path in event.paths {
+            changed |= self.apply_path(&path)?;
+        }
+        Ok(changed)
+    }
+
+    fn apply_path(&mut self, path: &Path) -> Result<bool, EverestError> {
+        let filename = match path.file_name() {
+            Some(filename) => filename.to_string_lossy(),
+            None => return Ok(false),
+        };
+
+        // keywords changed, letters may now point to other keywords
+        if path.parent() == Some(&self.mdir.path) && filename == DOVECOT_KEYWORDS_FILENAME {
+            self.rescan()?;
+            return Ok(true);
+        }
+
+        let in_mdir = ["new", "cur"]
+            .iter()
+            .any(|dir| path.parent() == Some(&self.mdir.path.join(dir)));
+        if !in_mdir {
+            return Ok(false);
+        }
+
+        let envelope = match self.mdir.envelope(&filename, &self.keywords) {
+            Some(envelope) => envelope,
+            None => return Ok(false),
+        };
+
+        // symlinked messages are listed like the scan does
+        if path.is_file() && self.mdir.follow_path(path)? {
+            let changed = self.envelopes.get(&envelope.id) != Some(&envelope);
+            self.paths.insert(envelope.id.clone(), path.to_owned());
+            self.envelopes.insert(envelope.id.clone(), envelope);
+            Ok(changed)
+        } else if self.paths.get(&envelope.id).map(PathBuf::as_path) == Some(path) {
+            self.paths.remove(&envelope.id);
+            self.envelopes.remove(&envelope.id);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use crate::{Flag, Flags};
+
+    use super::*;
+
+    fn wait_until<F: Fn(&Envelopes) -> bool>(watcher: &mut MdirWatcher, f: F) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            watcher.wait(Duration::from_millis(100)).unwrap();
+            if f(watcher.envelopes()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    #[test]
+    fn watch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let id1 = mdir.add_msg(b"", &Flags::default()).unwrap();
+
+        let mut watcher = MdirWatcher::new(mdir.clone()).unwrap();
+        assert!(watcher.envelopes().contains_key(id1.as_str()));
+
+        let id2 = mdir.add_msg(b"", &Flags::default()).unwrap();
+        assert!(wait_until(&mut watcher, |e| e.contains_key(id2.as_str())));
+
+        mdir.add_flag(&id1, &Flag::Seen).unwrap();
+        // the message briefly disappears while moving to cur
+        assert!(wait_until(&mut watcher, |e| e
+            .get(id1.as_str())
+            .is_some_and(|e| e.flags.contains(&Flag::Seen))));
+
+        mdir.remove_msg(&id2).unwrap();
+        assert!(wait_until(&mut watcher, |e| !e.contains_key(id2.as_str())));
+        assert_eq!(1, watcher.envelopes().len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_test() {
+        use super::super::SymlinkPolicy;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path().join("mdir")).with_symlink_policy(SymlinkPolicy::Ignore);
+        mdir.create_dirs().unwrap();
+        let mut watcher = MdirWatcher::new(mdir.clone()).unwrap();
+
+        // symlinks the scan refuses do not enter through the watcher
+        let target = dir.path().join("msg");
+        std::fs::write(&target, b"").unwrap();
+        let link = mdir.path().join("new").join("1663512456.M1P2.host");
+        std::os::unix::fs::symlink(&target, link).unwrap();
+        let id = mdir.add_msg(b"", &Flags::default()).unwrap();
+        assert!(wait_until(&mut watcher, |e| e.contains_key(id.as_str())));
+        assert!(!watcher.envelopes().contains_key("1663512456.M1P2.host"));
+    }
+}
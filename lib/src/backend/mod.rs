@@ -0,0 +1,13 @@
+//! Backends of the sides of a sync: an IMAP server on the left and a
+//! maildir on the right.
+
+#[cfg(feature = "cache")]
+pub mod ids;
+#[cfg(feature = "imap")]
+pub mod imap;
+#[cfg(all(feature = "imap", feature = "cache"))]
+pub mod imap_replica;
+#[cfg(feature = "imap")]
+pub mod imap_trace;
+#[cfg(feature = "maildir")]
+pub mod maildir;
@@ -0,0 +1,384 @@
+use std::{
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use ciborium::Value;
+
+use crate::{Envelope, EverestError, Flags, Id};
+
+#[cfg(feature = "encryption")]
+use super::Encryption;
+use super::{
+    format_flags, format_hunk, from_secs, parse_hunk, to_secs, Cache, CacheDir, JournalEntry,
+    Snapshot,
+};
+
+/// Version of the file format, kept under the `version` key. Files of
+/// the first releases have no such key and are version 1, version 3
+/// added the orphaned time of id mappings, version 4 the checksum
+/// line ending the file, version 5 the cursors, version 6 the content
+/// hashes, version 7 the journal, version 8 the errors of its
+/// entries.
+const VERSION: u32 = 8;
+
+/// Keeps the snapshots of the last sync in flat CBOR files, one file
+/// per folder in the given directory: a map of the `imap` and
+/// `maildir` envelopes (id to flags), the `ids` pairs, followed by
+/// their orphaned time if any, the content `hashes` (maildir id to
+/// hash), the known `cursors` and the `journal` of syncs (time, hunks
+/// and errors if any).
+#[derive(Debug, Clone)]
+pub struct CborCache {
+    dir: CacheDir,
+}
+
+impl CborCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        Self {
+            dir: CacheDir::new(dir.into(), "cbor"),
+        }
+    }
+
+    /// Encrypts the cache files at rest. Existing plain caches are
+    /// still read, and encrypted on the next save.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.dir.encryption = Some(encryption);
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Returns the path of the cache file of the given folder.
+    pub fn file_path(&self, folder: &str) -> PathBuf {
+        self.dir.file_path(folder)
+    }
+
+    /// Returns the path of the lock to take around a sync, see
+    /// [`CacheLock`](super::CacheLock).
+    pub fn lock_path(&self) -> PathBuf {
+        self.dir.lock_path()
+    }
+}
+
+impl Cache for CborCache {
+    fn load(&self, folder: &str) -> Result<Snapshot, EverestError> {
+        let content = match self.dir.read(folder)? {
+            Some(content) => content,
+            None => return Ok(Snapshot::default()),
+        };
+        let path = || self.file_path(folder);
+        let value = ciborium::from_reader::<Value, _>(content.data.as_slice())
+            .map_err(|err| EverestError::InvalidCacheError(err.to_string(), path()))?;
+        match version(&value) {
+            None => Err(EverestError::InvalidCacheError(
+                "invalid version".into(),
+                path(),
+            )),
+            Some(version) if version > VERSION => {
+                Err(EverestError::UnsupportedCacheVersionError(version, path()))
+            }
+            Some(version) if version >= 4 && !content.checked => {
+                Err(EverestError::CorruptedCacheError(path()))
+            }
+            // version 1 only lacks the version key
+            Some(_) => decode(value).map_err(|err| EverestError::InvalidCacheError(err, path())),
+        }
+    }
+
+    fn save(&self, folder: &str, snapshot: &Snapshot) -> Result<(), EverestError> {
+        let mut content = vec![];
+        ciborium::into_writer(&encode(snapshot), &mut content).map_err(|err| {
+            EverestError::InvalidCacheError(err.to_string(), self.file_path(folder))
+        })?;
+        self.dir.write(folder, &content)
+    }
+
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        self.dir.folders()
+    }
+
+    fn prune_history(&self, folder: &str, retention: Duration) -> Result<usize, EverestError> {
+        self.dir.prune_prev(folder, retention)
+    }
+}
+
+/// Returns the version of the given cache, `None` when its version
+/// key is not a valid version.
+fn version(value: &Value) -> Option<u32> {
+    let version = value
+        .as_map()
+        .and_then(|map| map.iter().find(|(key, _)| key.as_text() == Some("version")));
+    match version {
+        Some((_, version)) => version.as_integer().and_then(|v| u32::try_from(v).ok()),
+        None => Some(1),
+    }
+}
+
+fn encode(snapshot: &Snapshot) -> Value {
+    let mut sides = vec![(Value::Text("version".to_owned()), Value::from(VERSION))];
+    for (side, envelopes) in [("imap", &snapshot.imap), ("maildir", &snapshot.mdir)] {
+        let envelopes = envelopes
+            .values()
+            .map(|envelope| {
+                (
+                    Value::Text(envelope.id.to_string()),
+                    Value::Text(format_flags(&envelope.flags)),
+                )
+            })
+            .collect();
+        sides.push((Value::Text(side.to_owned()), Value::Map(envelopes)));
+    }
+    let ids = snapshot
+        .ids
+        .iter()
+        .map(|(imap_id, mdir_id)| {
+            let mut ids = vec![
+                Value::Text(imap_id.to_owned()),
+                Value::Text(mdir_id.to_owned()),
+            ];
+            if let Some(since) = snapshot.ids.orphaned_since(imap_id) {
+                ids.push(Value::from(to_secs(since)));
+            }
+            Value::Array(ids)
+        })
+        .collect();
+    sides.push((Value::Text("ids".to_owned()), Value::Array(ids)));
+    let hashes = snapshot
+        .hashes
+        .iter()
+        .map(|(mdir_id, hash)| (Value::from(mdir_id), Value::from(hash)))
+        .collect();
+    sides.push((Value::Text("hashes".to_owned()), Value::Map(hashes)));
+    let cursors = [
+        (
+            "uid_validity",
+            snapshot.cursors.uid_validity.map(Value::from),
+        ),
+        ("uid_next", snapshot.cursors.uid_next.map(Value::from)),
+        (
+            "highest_modseq",
+            snapshot.cursors.highest_modseq.map(Value::from),
+        ),
+    ]
+    .into_iter()
+    .filter_map(|(key, cursor)| Some((Value::Text(key.to_owned()), cursor?)))
+    .collect();
+    sides.push((Value::Text("cursors".to_owned()), Value::Map(cursors)));
+    let journal = snapshot
+        .journal
+        .iter()
+        .map(|entry| {
+            let hunks = entry
+                .patch
+                .iter()
+                .map(|hunk| Value::Text(format_hunk(hunk)))
+                .collect();
+            let mut entry_value = vec![Value::from(to_secs(entry.time)), Value::Array(hunks)];
+            if entry.has_errors() {
+                let errors = entry.errors.iter().map(|err| Value::from(err.as_str()));
+                entry_value.push(Value::Array(errors.collect()));
+            }
+            Value::Array(entry_value)
+        })
+        .collect();
+    sides.push((Value::Text("journal".to_owned()), Value::Array(journal)));
+    Value::Map(sides)
+}
+
+fn decode(value: Value) -> Result<Snapshot, String> {
+    let mut snapshot = Snapshot::default();
+
+    for (key, value) in value.into_map().map_err(|_| "invalid root")? {
+        let key = key.into_text().map_err(|_| "invalid root key")?;
+        if key == "version" {
+            continue;
+        }
+        if key == "journal" {
+            for entry in value.into_array().map_err(|_| "invalid journal")? {
+                let mut entry = entry
+                    .into_array()
+                    .map_err(|_| "invalid journal entry")?
+                    .into_iter();
+                // errors are left out when there are none
+                let (secs, hunks, errors) =
+                    match (entry.next(), entry.next(), entry.next(), entry.next()) {
+                        (Some(Value::Integer(secs)), Some(Value::Array(hunks)), None, None) => {
+                            (secs, hunks, vec![])
+                        }
+                        (
+                            Some(Value::Integer(secs)),
+                            Some(Value::Array(hunks)),
+                            Some(Value::Array(errors)),
+                            None,
+                        ) => (secs, hunks, errors),
+                        _ => return Err("invalid journal entry".into()),
+                    };
+                let secs = u64::try_from(secs).map_err(|_| "invalid journal time")?;
+                let patch = hunks
+                    .iter()
+                    .map(|hunk| hunk.as_text().and_then(parse_hunk))
+                    .collect::<Option<_>>()
+                    .ok_or("invalid journal hunk")?;
+                let errors = errors
+                    .into_iter()
+                    .map(Value::into_text)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| "invalid journal error")?;
+                let entry = JournalEntry {
+                    time: from_secs(secs),
+                    patch,
+                    errors,
+                };
+                snapshot.journal.push(entry);
+            }
+            continue;
+        }
+        if key == "hashes" {
+            for (mdir_id, hash) in value.into_map().map_err(|_| "invalid hashes")? {
+                match (mdir_id, hash) {
+                    (Value::Text(mdir_id), Value::Text(hash)) => {
+                        snapshot.hashes.insert(&mdir_id, &hash)
+                    }
+                    _ => return Err("invalid hashes entry".into()),
+                }
+            }
+            continue;
+        }
+        if key == "cursors" {
+            for (key, cursor) in value.into_map().map_err(|_| "invalid cursors")? {
+                let cursors = &mut snapshot.cursors;
+                let cursor = cursor.as_integer().ok_or("invalid cursor")?;
+                let invalid = |_| format!("invalid cursor {:?}", key);
+                match key.as_text() {
+                    Some("uid_validity") => {
+                        cursors.uid_validity = Some(u32::try_from(cursor).map_err(invalid)?)
+                    }
+                    Some("uid_next") => {
+                        cursors.uid_next = Some(u32::try_from(cursor).map_err(invalid)?)
+                    }
+                    Some("highest_modseq") => {
+                        cursors.highest_modseq = Some(u64::try_from(cursor).map_err(invalid)?)
+                    }
+                    _ => return Err(format!("invalid cursor {:?}", key)),
+                }
+            }
+            continue;
+        }
+        if key == "ids" {
+            for ids in value.into_array().map_err(|_| "invalid ids")? {
+                match ids.into_array().as_deref() {
+                    Ok([Value::Text(imap_id), Value::Text(mdir_id)]) => {
+                        snapshot.ids.insert(imap_id, mdir_id)
+                    }
+                    Ok([Value::Text(imap_id), Value::Text(mdir_id), Value::Integer(secs)]) => {
+                        let secs = u64::try_from(*secs).map_err(|_| "invalid orphaned time")?;
+                        snapshot.ids.insert(imap_id, mdir_id);
+                        snapshot.ids.set_orphaned_since(imap_id, from_secs(secs));
+                    }
+                    _ => return Err("invalid ids entry".into()),
+                }
+            }
+            continue;
+        }
+
+        let envelopes = snapshot
+            .envelopes_mut(&key)
+            .ok_or_else(|| format!("invalid side {:?}", key))?;
+        for (id, flags) in value.into_map().map_err(|_| "invalid envelopes")? {
+            match (id, flags) {
+                (Value::Text(id), Value::Text(flags)) => {
+                    let id: Id = id.into();
+                    let flags = Flags::from_imap(&flags);
+                    envelopes.insert(
+                        id.clone(),
+                        Envelope {
+                            id,
+                            flags,
+                            metadata: None,
+                        },
+                    );
+                }
+                _ => return Err(format!("invalid {} envelope", key)),
+            }
+        }
+    }
+
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use crate::{
+        cache::{envelopes, Cursors},
+        Flag, Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn save_load_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CborCache::new(dir.path());
+        assert_eq!(Snapshot::default(), cache.load("INBOX").unwrap());
+
+        let snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen, Flag::Keyword("Work".into())])]),
+            envelopes(&[("a", &[]), ("b", &[Flag::Trashed])]),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        cache.insert_ids("INBOX", "1", "a").unwrap();
+        assert_eq!(Snapshot::default(), cache.load("Sent").unwrap());
+        assert_eq!(vec!["INBOX"], cache.folders().unwrap());
+
+        let loaded = cache.load("INBOX").unwrap();
+        assert_eq!(snapshot.imap, loaded.imap);
+        assert_eq!(snapshot.mdir, loaded.mdir);
+        assert_eq!(Some("a"), loaded.ids.mdir_id("1"));
+
+        cache.insert_ids("INBOX", "2", "c").unwrap();
+        cache.gc("INBOX", Duration::from_secs(60)).unwrap();
+        let loaded = cache.load("INBOX").unwrap();
+        assert!(loaded.ids.orphaned_since("2").is_some());
+
+        let cursors = Cursors {
+            uid_validity: Some(42),
+            uid_next: None,
+            highest_modseq: Some(u64::MAX),
+        };
+        cache.save_cursors("INBOX", &cursors).unwrap();
+        assert_eq!(cursors, cache.cursors("INBOX").unwrap());
+        cache.insert_content_hash("INBOX", "a", "hash").unwrap();
+        assert_eq!(
+            Some("hash".into()),
+            cache.content_hash("INBOX", "a").unwrap()
+        );
+
+        let patch = vec![Hunk::new(
+            Side::Right,
+            HunkKind::AddFlag("a".into(), Flag::Seen),
+        )];
+        cache.record_sync("INBOX", &patch, &[]).unwrap();
+        cache
+            .record_sync("INBOX", &vec![], &["cannot\tsync".into()])
+            .unwrap();
+        let journal = cache.load("INBOX").unwrap().journal;
+        assert_eq!(2, journal.len());
+        assert_eq!(patch, journal[0].patch);
+        assert_eq!(vec!["cannot\tsync"], journal[1].errors);
+
+        let mut content = vec![];
+        let value = Value::Map(vec![(Value::from("version"), Value::from(VERSION + 1))]);
+        ciborium::into_writer(&value, &mut content).unwrap();
+        fs::write(cache.file_path("Sent"), content).unwrap();
+        assert!(matches!(
+            cache.load("Sent"),
+            Err(EverestError::UnsupportedCacheVersionError(..))
+        ));
+    }
+}
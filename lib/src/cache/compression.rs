@@ -0,0 +1,50 @@
+use std::path::Path;
+
+use crate::EverestError;
+
+/// Marks compressed cache files, followed by the zstd frame.
+const MAGIC: &[u8] = b"EVEREST-ZST1";
+
+/// Default zstd level, fast while shrinking the repetitive cache
+/// files a lot.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 3;
+
+pub(super) fn compress(content: &[u8], level: i32, path: &Path) -> Result<Vec<u8>, EverestError> {
+    let compressed = zstd::encode_all(content, level)
+        .map_err(|err| EverestError::CompressCacheError(err, path.to_owned()))?;
+    Ok([MAGIC, &compressed].concat())
+}
+
+/// Decompresses the given content. Uncompressed content is returned
+/// as is, so that caches written before enabling compression (or
+/// after disabling it) are still read.
+pub(super) fn decompress(content: Vec<u8>, path: &Path) -> Result<Vec<u8>, EverestError> {
+    match content.strip_prefix(MAGIC) {
+        Some(compressed) => zstd::decode_all(compressed)
+            .map_err(|err| EverestError::DecompressCacheError(err, path.to_owned())),
+        None => Ok(content),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compress_decompress_test() {
+        let path = Path::new("cache");
+        let plain = "imap\t1\tSeen\n".repeat(1000).into_bytes();
+
+        let content = compress(&plain, DEFAULT_COMPRESSION_LEVEL, path).unwrap();
+        assert!(content.starts_with(MAGIC));
+        assert!(content.len() < plain.len() / 10);
+        assert_eq!(plain, decompress(content.clone(), path).unwrap());
+        assert_eq!(plain, decompress(plain.clone(), path).unwrap());
+
+        let truncated = content[..content.len() / 2].to_vec();
+        assert!(matches!(
+            decompress(truncated, path),
+            Err(EverestError::DecompressCacheError(..))
+        ));
+    }
+}
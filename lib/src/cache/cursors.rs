@@ -0,0 +1,52 @@
+/// IMAP state of a folder at the end of the last sync, from which the
+/// next sync can fetch only what changed. `None` when unknown, like
+/// when the server does not support `CONDSTORE`.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cursors {
+    pub uid_validity: Option<u32>,
+    pub uid_next: Option<u32>,
+    pub highest_modseq: Option<u64>,
+}
+
+impl Cursors {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Tells whether the cursors still apply to a mailbox of the given
+    /// UIDVALIDITY. A changed UIDVALIDITY invalidates all UIDs, so
+    /// the folder needs a full sync.
+    pub fn is_valid(&self, uid_validity: Option<u32>) -> bool {
+        self.uid_validity.is_some() && self.uid_validity == uid_validity
+    }
+}
+
+#[cfg(feature = "imap")]
+impl From<&imap::types::Mailbox> for Cursors {
+    fn from(mailbox: &imap::types::Mailbox) -> Self {
+        Self {
+            uid_validity: mailbox.uid_validity,
+            uid_next: mailbox.uid_next,
+            highest_modseq: mailbox.highest_mod_seq,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_valid_test() {
+        let cursors = Cursors {
+            uid_validity: Some(42),
+            uid_next: Some(10),
+            highest_modseq: None,
+        };
+        assert!(cursors.is_valid(Some(42)));
+        assert!(!cursors.is_valid(Some(43)));
+        assert!(!cursors.is_valid(None));
+        assert!(!Cursors::default().is_valid(None));
+        assert!(Cursors::default().is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use std::collections::BTreeMap;
+
+/// Returns the delta turning the given previous body of a file cache
+/// into the given next body, empty when they hold the same entries.
+/// Each line of a body is an entry keyed by its kind and id: a delta
+/// removes entries with `-<key>` lines and adds or replaces entries
+/// with `+<line>` lines.
+pub(super) fn diff(prev: &str, next: &str) -> String {
+    let prev = entries(prev);
+    let next = entries(next);
+    let removed = prev
+        .keys()
+        .filter(|key| !next.contains_key(*key))
+        .map(|key| format!("-{}\n", key));
+    let added = next
+        .iter()
+        .filter(|(key, line)| prev.get(*key) != Some(*line))
+        .map(|(_, line)| format!("+{}\n", line));
+    removed.chain(added).collect()
+}
+
+/// Applies the given deltas to the given body, in order.
+pub(super) fn apply<'a>(
+    body: &str,
+    deltas: impl IntoIterator<Item = &'a str>,
+) -> Result<String, String> {
+    let mut entries = entries(body);
+    for line in deltas.into_iter().flat_map(str::lines) {
+        if let Some(key) = line.strip_prefix('-') {
+            entries.remove(key);
+        } else if let Some(line) = line.strip_prefix('+') {
+            entries.insert(key(line), line);
+        } else {
+            return Err(format!("invalid delta {:?}", line));
+        }
+    }
+    Ok(entries
+        .into_values()
+        .map(|line| format!("{}\n", line))
+        .collect())
+}
+
+fn entries(body: &str) -> BTreeMap<&str, &str> {
+    body.lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| (key(line), line))
+        .collect()
+}
+
+/// Returns the key of the given entry: its kind and id, or its kind
+/// only for the single cursors entry.
+fn key(line: &str) -> &str {
+    if line.starts_with("cursors\t") {
+        return "cursors";
+    }
+    match line.match_indices('\t').nth(1) {
+        Some((end, _)) => &line[..end],
+        None => line,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_apply_test() {
+        let prev = "cursors\t1\t2\t\nimap\t1\t\nimap\t2\t\\Seen\nids\t1\ta\n";
+        let next = "cursors\t1\t3\t\nimap\t1\t\\Seen\nimap\t3\t\nids\t1\ta\n";
+
+        let delta = diff(prev, next);
+        assert_eq!(
+            "-imap\t2\n+cursors\t1\t3\t\n+imap\t1\t\\Seen\n+imap\t3\t\n",
+            delta
+        );
+        assert_eq!(
+            entries(next),
+            entries(&apply(prev, [delta.as_str()]).unwrap())
+        );
+        assert_eq!("", diff(next, next));
+        assert!(apply(prev, ["imap\t1\t\n"]).is_err());
+    }
+}
@@ -0,0 +1,113 @@
+use std::{fmt, path::Path, sync::Arc};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+
+use crate::EverestError;
+
+/// Marks encrypted cache files, followed by the nonce then the
+/// ciphertext.
+const MAGIC: &[u8] = b"EVEREST-ENC1";
+const NONCE_LEN: usize = 12;
+
+/// Provides the 32-byte key encrypting the cache. Passphrases need to
+/// go through a key derivation function first.
+pub trait KeyProvider: Send + Sync {
+    fn key(&self) -> Result<[u8; 32], EverestError>;
+}
+
+impl KeyProvider for [u8; 32] {
+    fn key(&self) -> Result<[u8; 32], EverestError> {
+        Ok(*self)
+    }
+}
+
+impl<F> KeyProvider for F
+where
+    F: Fn() -> Result<[u8; 32], EverestError> + Send + Sync,
+{
+    fn key(&self) -> Result<[u8; 32], EverestError> {
+        self()
+    }
+}
+
+/// Encrypts cache files at rest with ChaCha20-Poly1305, using a key
+/// asked to the provider on every read and write.
+#[derive(Clone)]
+pub struct Encryption(Arc<dyn KeyProvider>);
+
+impl fmt::Debug for Encryption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Encryption")
+    }
+}
+
+impl Encryption {
+    pub fn new<P: KeyProvider + 'static>(provider: P) -> Self {
+        Self(Arc::new(provider))
+    }
+
+    fn cipher(&self) -> Result<ChaCha20Poly1305, EverestError> {
+        let key = self.0.key()?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    pub(super) fn encrypt(&self, plaintext: &[u8], path: &Path) -> Result<Vec<u8>, EverestError> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher()?
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| EverestError::EncryptCacheError(path.to_owned()))?;
+        Ok([MAGIC, nonce.as_slice(), &ciphertext].concat())
+    }
+
+    /// Decrypts the given content. Plain content is returned as is, so
+    /// that enabling encryption does not lose the existing cache: it
+    /// gets encrypted on the next save.
+    pub(super) fn decrypt(&self, content: Vec<u8>, path: &Path) -> Result<Vec<u8>, EverestError> {
+        let content = match content.strip_prefix(MAGIC) {
+            Some(content) if content.len() >= NONCE_LEN => content,
+            Some(_) => return Err(EverestError::DecryptCacheError(path.to_owned())),
+            None => return Ok(content),
+        };
+        let (nonce, ciphertext) = content.split_at(NONCE_LEN);
+        self.cipher()?
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| EverestError::DecryptCacheError(path.to_owned()))
+    }
+}
+
+/// Returns `true` if the given content is an encrypted cache.
+pub(super) fn is_encrypted(content: &[u8]) -> bool {
+    content.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt_test() {
+        let path = Path::new("cache");
+        let encryption = Encryption::new([7; 32]);
+
+        let content = encryption.encrypt(b"imap\t1\t", path).unwrap();
+        assert!(is_encrypted(&content));
+        assert_eq!(
+            b"imap\t1\t".to_vec(),
+            encryption.decrypt(content.clone(), path).unwrap()
+        );
+        assert_eq!(
+            b"plain".to_vec(),
+            encryption.decrypt(b"plain".to_vec(), path).unwrap()
+        );
+
+        let encryption = Encryption::new(|| Ok([8; 32]));
+        assert!(matches!(
+            encryption.decrypt(content, path),
+            Err(EverestError::DecryptCacheError(..))
+        ));
+    }
+}
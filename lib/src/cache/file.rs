@@ -0,0 +1,777 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{Envelope, EverestError, Flags, Id};
+
+#[cfg(feature = "encryption")]
+use super::Encryption;
+use super::{
+    delta, escape_error, format_flags, format_hunk, from_secs, parse_hunk, to_secs, unescape_error,
+    Cache, CacheDir, Cursors, JournalEntry, Snapshot,
+};
+
+/// Version of the file format, written in the first line. Files of
+/// the first releases have no version line and are version 1, version
+/// 3 added the orphaned time of id mappings, version 4 the checksum
+/// line ending the file, version 5 the cursors line, version 6 the
+/// content hashes, version 7 the checkpoint line, version 8 the
+/// journal, version 9 the errors of its entries.
+const VERSION: u32 = 9;
+
+/// Keeps the snapshots of the last sync in plain text files, one file
+/// per folder in the given directory, one envelope per line after a
+/// version line.
+#[derive(Debug, Clone)]
+pub struct FileCache {
+    dir: CacheDir,
+    deltas: CacheDir,
+    checkpoint_interval: usize,
+}
+
+impl FileCache {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        let dir = dir.into();
+        Self {
+            deltas: CacheDir::new(dir.clone(), "delta"),
+            dir: CacheDir::new(dir, "cache"),
+            checkpoint_interval: 0,
+        }
+    }
+
+    /// Encrypts the cache files at rest. Existing plain caches are
+    /// still read, and encrypted on the next save.
+    #[cfg(feature = "encryption")]
+    pub fn with_encryption(mut self, encryption: Encryption) -> Self {
+        self.deltas.encryption = Some(encryption.clone());
+        self.dir.encryption = Some(encryption);
+        self
+    }
+
+    /// Compresses the cache files with zstd at the given level (see
+    /// [`super::DEFAULT_COMPRESSION_LEVEL`]), which shrinks them a lot
+    /// for large folders. Existing uncompressed caches are still read,
+    /// and compressed on the next save.
+    #[cfg(feature = "compression")]
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.deltas.compression = Some(level);
+        self.dir.compression = Some(level);
+        self
+    }
+
+    /// Saves only the changes since the previous save in a delta file
+    /// next to the cache file, the whole snapshot being written (as a
+    /// checkpoint) once every given number of saves. This keeps saves
+    /// small for large folders, deltas being applied back on load.
+    /// Intervals below 2 save whole snapshots only, the default.
+    pub fn with_checkpoint_interval(mut self, interval: usize) -> Self {
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// Returns the path of the cache file of the given folder.
+    pub fn file_path(&self, folder: &str) -> PathBuf {
+        self.dir.file_path(folder)
+    }
+
+    /// Returns the path of the lock to take around a sync, see
+    /// [`CacheLock`](super::CacheLock).
+    pub fn lock_path(&self) -> PathBuf {
+        self.dir.lock_path()
+    }
+
+    /// Reads the checkpoint of the given folder: its generation and
+    /// body.
+    fn read_checkpoint(&self, folder: &str) -> Result<Option<(u64, String)>, EverestError> {
+        let path = || self.file_path(folder);
+        let content = match self.dir.read(folder)? {
+            Some(content) => content,
+            None => return Ok(None),
+        };
+        let checked = content.checked;
+        let content = String::from_utf8(content.data)
+            .map_err(|err| EverestError::InvalidCacheError(err.to_string(), path()))?;
+        let (version, body) =
+            split_version(&content).map_err(|err| EverestError::InvalidCacheError(err, path()))?;
+        if version > VERSION {
+            return Err(EverestError::UnsupportedCacheVersionError(version, path()));
+        }
+        // a missing checksum means a truncated file
+        if version >= 4 && !checked {
+            return Err(EverestError::CorruptedCacheError(path()));
+        }
+        // version 1 only lacks the version line, so bodies of all
+        // versions parse the same way for now
+        let (generation, body) = split_header(body, "checkpoint")
+            .map_err(|err| EverestError::InvalidCacheError(err, path()))?;
+        Ok(Some((generation, body.to_owned())))
+    }
+
+    /// Reads the deltas of the given folder: the generation of the
+    /// checkpoint they apply to and the deltas.
+    fn read_deltas(&self, folder: &str) -> Result<Option<(u64, Vec<String>)>, EverestError> {
+        let path = || self.deltas.file_path(folder);
+        let content = match self.deltas.read(folder)? {
+            Some(content) if content.checked => content,
+            Some(_) => return Err(EverestError::CorruptedCacheError(path())),
+            None => return Ok(None),
+        };
+        let content = String::from_utf8(content.data)
+            .map_err(|err| EverestError::InvalidCacheError(err.to_string(), path()))?;
+        let (base, deltas) = split_header(&content, "base")
+            .map_err(|err| EverestError::InvalidCacheError(err, path()))?;
+        let deltas = deltas
+            .split("delta\n")
+            .skip(1)
+            .map(ToOwned::to_owned)
+            .collect();
+        Ok(Some((base, deltas)))
+    }
+
+    /// Reads the deltas to apply to the checkpoint of the given
+    /// generation. Deltas of an older checkpoint are already part of
+    /// the checkpoint, deltas of a newer one mean the checkpoint is
+    /// not the last one written.
+    fn read_deltas_of(&self, folder: &str, generation: u64) -> Result<Vec<String>, EverestError> {
+        match self.read_deltas(folder)? {
+            Some((base, deltas)) if base == generation => Ok(deltas),
+            Some((base, _)) if base > generation => {
+                Err(EverestError::CorruptedCacheError(self.file_path(folder)))
+            }
+            _ => Ok(vec![]),
+        }
+    }
+
+    fn write_checkpoint(
+        &self,
+        folder: &str,
+        generation: u64,
+        body: &str,
+    ) -> Result<(), EverestError> {
+        let mut content = format!("version {}\n", VERSION);
+        if generation > 0 {
+            content.push_str(&format!("checkpoint {}\n", generation));
+        }
+        content.push_str(body);
+        self.dir.write(folder, content.as_bytes())?;
+        self.deltas.remove(folder)
+    }
+
+    fn write_deltas(
+        &self,
+        folder: &str,
+        generation: u64,
+        deltas: &[String],
+    ) -> Result<(), EverestError> {
+        let mut content = format!("base {}\n", generation);
+        for delta in deltas {
+            content.push_str("delta\n");
+            content.push_str(delta);
+        }
+        self.deltas.write(folder, content.as_bytes())
+    }
+}
+
+impl Cache for FileCache {
+    fn load(&self, folder: &str) -> Result<Snapshot, EverestError> {
+        let (generation, body) = self.read_checkpoint(folder)?.unwrap_or_default();
+        let deltas = self.read_deltas_of(folder, generation)?;
+        let body = delta::apply(&body, deltas.iter().map(String::as_str))
+            .map_err(|err| EverestError::InvalidCacheError(err, self.deltas.file_path(folder)))?;
+        parse(&body).map_err(|err| EverestError::InvalidCacheError(err, self.file_path(folder)))
+    }
+
+    fn save(&self, folder: &str, snapshot: &Snapshot) -> Result<(), EverestError> {
+        let body = format(snapshot);
+        let checkpoint = if self.checkpoint_interval > 1 {
+            self.read_checkpoint(folder)?
+        } else {
+            None
+        };
+
+        if let Some((generation, checkpoint)) = checkpoint {
+            let mut deltas = self.read_deltas_of(folder, generation)?;
+            if deltas.len() + 1 < self.checkpoint_interval {
+                let prev = delta::apply(&checkpoint, deltas.iter().map(String::as_str)).map_err(
+                    |err| EverestError::InvalidCacheError(err, self.deltas.file_path(folder)),
+                )?;
+                let delta = delta::diff(&prev, &body);
+                if delta.is_empty() {
+                    return Ok(());
+                }
+                deltas.push(delta);
+                return self.write_deltas(folder, generation, &deltas);
+            }
+            return self.write_checkpoint(folder, generation + 1, &body);
+        }
+
+        // the new checkpoint needs to be newer than leftover deltas,
+        // in case removing them fails
+        let generation = match self.read_deltas(folder) {
+            Ok(Some((base, _))) => base + 1,
+            _ => 0,
+        };
+        self.write_checkpoint(folder, generation, &body)
+    }
+
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        self.dir.folders()
+    }
+
+    /// Removes the previous generations of the cache file and of the
+    /// delta file.
+    fn prune_history(&self, folder: &str, retention: Duration) -> Result<usize, EverestError> {
+        Ok(self.dir.prune_prev(folder, retention)? + self.deltas.prune_prev(folder, retention)?)
+    }
+}
+
+/// Splits the `<name> <number>` header line from the given content,
+/// 0 if missing.
+fn split_header<'a>(content: &'a str, name: &str) -> Result<(u64, &'a str), String> {
+    let number = match content
+        .strip_prefix(name)
+        .and_then(|content| content.strip_prefix(' '))
+    {
+        Some(number) => number,
+        None => return Ok((0, content)),
+    };
+    let (number, body) = number.split_once('\n').unwrap_or((number, ""));
+    let number = number
+        .parse()
+        .map_err(|err| format!("invalid {} {:?}: {}", name, number, err))?;
+    Ok((number, body))
+}
+
+/// Splits the version line from the given content.
+fn split_version(content: &str) -> Result<(u32, &str), String> {
+    let version = match content.strip_prefix("version ") {
+        Some(version) => version,
+        None => return Ok((1, content)),
+    };
+    let (version, body) = version.split_once('\n').unwrap_or((version, ""));
+    let version = version
+        .parse()
+        .map_err(|err| format!("invalid version {:?}: {}", version, err))?;
+    Ok((version, body))
+}
+
+fn parse(content: &str) -> Result<Snapshot, String> {
+    let mut snapshot = Snapshot::default();
+    let mut journal = vec![];
+    let mut journal_errors = HashMap::new();
+
+    for line in content.lines().filter(|line| !line.is_empty()) {
+        if let Some(entry) = line.strip_prefix("journal\t") {
+            journal.push(
+                parse_journal_entry(entry).ok_or_else(|| format!("invalid entry {:?}", line))?,
+            );
+            continue;
+        }
+        if let Some(errors) = line.strip_prefix("errors\t") {
+            let mut errors = errors.split('\t');
+            let index = errors
+                .next()
+                .and_then(|index| index.parse::<usize>().ok())
+                .ok_or_else(|| format!("invalid entry {:?}", line))?;
+            journal_errors.insert(index, errors.map(unescape_error).collect());
+            continue;
+        }
+        if let Some(cursors) = line.strip_prefix("cursors\t") {
+            snapshot.cursors =
+                parse_cursors(cursors).ok_or_else(|| format!("invalid entry {:?}", line))?;
+            continue;
+        }
+        if let Some(hash) = line.strip_prefix("hash\t") {
+            match hash.split_once('\t') {
+                Some((mdir_id, hash)) if !mdir_id.is_empty() && !hash.is_empty() => {
+                    snapshot.hashes.insert(mdir_id, hash)
+                }
+                _ => return Err(format!("invalid entry {:?}", line)),
+            }
+            continue;
+        }
+        if let Some(ids) = line.strip_prefix("ids\t") {
+            let mut parts = ids.splitn(3, '\t');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(imap_id), Some(mdir_id), orphaned_since) => {
+                    snapshot.ids.insert(imap_id, mdir_id);
+                    if let Some(secs) = orphaned_since {
+                        let secs = secs
+                            .parse()
+                            .map_err(|err| format!("invalid entry {:?}: {}", line, err))?;
+                        snapshot.ids.set_orphaned_since(imap_id, from_secs(secs));
+                    }
+                }
+                _ => return Err(format!("invalid entry {:?}", line)),
+            }
+            continue;
+        }
+        let mut parts = line.splitn(3, '\t');
+        let envelopes = parts
+            .next()
+            .and_then(|side| snapshot.envelopes_mut(side))
+            .ok_or_else(|| format!("invalid entry {:?}", line))?;
+        let id = match parts.next() {
+            Some(id) if !id.is_empty() => Id::from(id),
+            _ => return Err(format!("invalid entry {:?}", line)),
+        };
+        let flags = Flags::from_imap(parts.next().unwrap_or_default());
+        envelopes.insert(
+            id.clone(),
+            Envelope {
+                id,
+                flags,
+                metadata: None,
+            },
+        );
+    }
+
+    // entries are numbered to keep their order
+    journal.sort_unstable_by_key(|(index, _)| *index);
+    for (index, mut entry) in journal {
+        entry.errors = journal_errors.remove(&index).unwrap_or_default();
+        snapshot.journal.push(entry);
+    }
+    if let Some(index) = journal_errors.keys().next() {
+        return Err(format!("errors of unknown journal entry {}", index));
+    }
+
+    Ok(snapshot)
+}
+
+fn format(snapshot: &Snapshot) -> String {
+    let envelopes = snapshot.sides().map(|(side, envelope)| {
+        format!(
+            "{}\t{}\t{}\n",
+            side,
+            envelope.id,
+            format_flags(&envelope.flags)
+        )
+    });
+    let ids =
+        snapshot.ids.iter().map(
+            |(imap_id, mdir_id)| match snapshot.ids.orphaned_since(imap_id) {
+                Some(since) => format!("ids\t{}\t{}\t{}\n", imap_id, mdir_id, to_secs(since)),
+                None => format!("ids\t{}\t{}\n", imap_id, mdir_id),
+            },
+        );
+    let hashes = snapshot
+        .hashes
+        .iter()
+        .map(|(mdir_id, hash)| format!("hash\t{}\t{}\n", mdir_id, hash));
+    let journal = snapshot
+        .journal
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| format_journal_entry(index, entry));
+    let cursors = Some(&snapshot.cursors)
+        .filter(|cursors| !cursors.is_empty())
+        .map(format_cursors);
+    cursors
+        .into_iter()
+        .chain(envelopes)
+        .chain(ids)
+        .chain(hashes)
+        .chain(journal)
+        .collect()
+}
+
+/// Parses the index, time and hunks of a journal line.
+fn parse_journal_entry(entry: &str) -> Option<(usize, JournalEntry)> {
+    let mut parts = entry.split('\t');
+    let index = parts.next()?.parse().ok()?;
+    let time = from_secs(parts.next()?.parse().ok()?);
+    let patch = parts.map(parse_hunk).collect::<Option<_>>()?;
+    // errors come in their own line
+    let errors = vec![];
+    Some((
+        index,
+        JournalEntry {
+            time,
+            patch,
+            errors,
+        },
+    ))
+}
+
+/// Formats the journal line of the given entry, followed by the line
+/// of its errors if any.
+fn format_journal_entry(index: usize, entry: &JournalEntry) -> String {
+    let mut lines = format!("journal\t{}\t{}", index, to_secs(entry.time));
+    for hunk in &entry.patch {
+        lines.push('\t');
+        lines.push_str(&format_hunk(hunk));
+    }
+    lines.push('\n');
+    if entry.has_errors() {
+        lines.push_str(&format!("errors\t{}", index));
+        for error in &entry.errors {
+            lines.push('\t');
+            lines.push_str(&escape_error(error));
+        }
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Parses the UIDVALIDITY, UIDNEXT and HIGHESTMODSEQ of the cursors
+/// line, empty when unknown.
+fn parse_cursors(cursors: &str) -> Option<Cursors> {
+    fn parse<T: FromStr>(cursor: Option<&str>) -> Option<Option<T>> {
+        match cursor? {
+            "" => Some(None),
+            cursor => cursor.parse().ok().map(Some),
+        }
+    }
+
+    let mut parts = cursors.split('\t');
+    let cursors = Cursors {
+        uid_validity: parse(parts.next())?,
+        uid_next: parse(parts.next())?,
+        highest_modseq: parse(parts.next())?,
+    };
+    Some(cursors).filter(|_| parts.next().is_none())
+}
+
+fn format_cursors(cursors: &Cursors) -> String {
+    fn format<T: ToString>(cursor: Option<T>) -> String {
+        cursor.map(|cursor| cursor.to_string()).unwrap_or_default()
+    }
+
+    format!(
+        "cursors\t{}\t{}\t{}\n",
+        format(cursors.uid_validity),
+        format(cursors.uid_next),
+        format(cursors.highest_modseq)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use crate::{
+        cache::{content_hash, envelopes},
+        Flag, Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn save_load_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path().join("everest"));
+        assert_eq!(Snapshot::default(), cache.load("INBOX").unwrap());
+
+        let snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen, Flag::Keyword("Work".into())])]),
+            envelopes(&[("a", &[]), ("b", &[Flag::Trashed, Flag::Replied])]),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        assert_eq!(Snapshot::default(), cache.load("INBOX/Sent").unwrap());
+
+        cache.insert_ids("INBOX", "1", "a").unwrap();
+        assert_eq!(Some("a".to_owned()), cache.mdir_id("INBOX", "1").unwrap());
+        assert_eq!(snapshot.imap, cache.load("INBOX").unwrap().imap);
+
+        let cursors = Cursors {
+            uid_validity: Some(42),
+            uid_next: Some(3),
+            highest_modseq: None,
+        };
+        cache.save_cursors("INBOX", &cursors).unwrap();
+        assert_eq!(cursors, cache.cursors("INBOX").unwrap());
+        let hash = content_hash(b"Subject: a\r\n\r\n");
+        cache.insert_content_hash("INBOX", "a", &hash).unwrap();
+        assert_eq!(Some(hash), cache.content_hash("INBOX", "a").unwrap());
+        assert_eq!(snapshot.imap, cache.load("INBOX").unwrap().imap);
+
+        cache.insert_ids("INBOX", "2", "c").unwrap();
+        let report = cache.gc("INBOX", Duration::from_secs(60)).unwrap();
+        assert_eq!(1, report.retained);
+        assert!(cache
+            .load("INBOX")
+            .unwrap()
+            .ids
+            .orphaned_since("2")
+            .is_some());
+
+        fs::write(cache.file_path("Sent"), "pop\t1\t\n").unwrap();
+        assert!(matches!(
+            cache.load("Sent"),
+            Err(EverestError::InvalidCacheError(..))
+        ));
+    }
+
+    #[test]
+    fn folders_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+        let snapshot = Snapshot::new(envelopes(&[("1", &[])]), Default::default());
+        assert!(cache.folders().unwrap().is_empty());
+
+        cache.save("INBOX/Sent", &snapshot).unwrap();
+        cache.save("..", &Snapshot::default()).unwrap();
+        cache.save("", &Snapshot::default()).unwrap();
+
+        assert_eq!(
+            dir.path().join("INBOX%2FSent.cache"),
+            cache.file_path("INBOX/Sent")
+        );
+        assert_eq!(snapshot, cache.load("INBOX/Sent").unwrap());
+        assert_eq!(3, fs::read_dir(dir.path()).unwrap().count());
+
+        cache.save("INBOX/Sent", &snapshot).unwrap();
+        cache.save("..", &Snapshot::default()).unwrap();
+        fs::remove_file(cache.file_path("..")).unwrap();
+        assert_eq!(vec!["", "..", "INBOX/Sent"], cache.folders().unwrap());
+    }
+
+    #[test]
+    fn checksum_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+        let snapshot = Snapshot::new(envelopes(&[("1", &[])]), envelopes(&[("1", &[])]));
+        cache.save("INBOX", &snapshot).unwrap();
+        let content = fs::read_to_string(cache.file_path("INBOX")).unwrap();
+
+        fs::write(cache.file_path("INBOX"), content.replace("imap", "maildir")).unwrap();
+        assert!(matches!(
+            cache.load("INBOX"),
+            Err(EverestError::CorruptedCacheError(..))
+        ));
+
+        fs::write(cache.file_path("INBOX"), &content[..content.len() / 2]).unwrap();
+        assert!(matches!(
+            cache.load("INBOX"),
+            Err(EverestError::CorruptedCacheError(..))
+        ));
+
+        let rebuilt = cache
+            .load_or_rebuild("INBOX", || Ok(snapshot.clone()))
+            .unwrap();
+        assert_eq!(snapshot, rebuilt);
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+    }
+
+    #[test]
+    fn deltas_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path()).with_checkpoint_interval(3);
+        let delta_path = dir.path().join("INBOX.delta");
+        let mut snapshot = Snapshot::new(
+            envelopes(&[("1", &[]), ("2", &[])]),
+            envelopes(&[("a", &[]), ("b", &[])]),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        assert!(!delta_path.exists());
+
+        snapshot.imap = envelopes(&[("1", &[Flag::Seen]), ("2", &[])]);
+        cache.save("INBOX", &snapshot).unwrap();
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        snapshot.mdir.remove("b");
+        snapshot.ids.insert("1", "a");
+        cache.save("INBOX", &snapshot).unwrap();
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        let deltas = fs::read_to_string(&delta_path).unwrap();
+        assert!(deltas.starts_with("base 0\ndelta\n"));
+        assert!(!deltas.contains("imap\t2"));
+
+        // a checkpoint older than the deltas is corrupted
+        let prev_checkpoint = fs::read(cache.file_path("INBOX")).unwrap();
+        snapshot.imap.remove("2");
+        cache.save("INBOX", &snapshot).unwrap();
+        assert!(!delta_path.exists());
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        cache.save("INBOX", &Snapshot::default()).unwrap();
+        fs::write(cache.file_path("INBOX"), prev_checkpoint).unwrap();
+        assert!(matches!(
+            cache.load("INBOX"),
+            Err(EverestError::CorruptedCacheError(..))
+        ));
+
+        // saving without deltas writes a checkpoint newer than them
+        let cache = FileCache::new(dir.path());
+        cache.save("INBOX", &snapshot).unwrap();
+        assert!(!delta_path.exists());
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+    }
+
+    #[test]
+    fn version_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+
+        fs::write(cache.file_path("INBOX"), "imap\t1\t\\Seen\n").unwrap();
+        assert_eq!(
+            envelopes(&[("1", &[Flag::Seen])]),
+            cache.load("INBOX").unwrap().imap
+        );
+
+        fs::write(
+            cache.file_path("INBOX"),
+            format!("version {}\n", VERSION + 1),
+        )
+        .unwrap();
+        assert!(matches!(
+            cache.load("INBOX"),
+            Err(EverestError::UnsupportedCacheVersionError(..))
+        ));
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn compression_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = Snapshot::new(envelopes(&[("1", &[Flag::Seen])]), Default::default());
+        let cache = FileCache::new(dir.path());
+        cache.save("INBOX", &snapshot).unwrap();
+
+        let cache = cache.with_compression(crate::cache::DEFAULT_COMPRESSION_LEVEL);
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        cache.save("INBOX", &snapshot).unwrap();
+        assert!(!fs::read(cache.file_path("INBOX"))
+            .unwrap()
+            .starts_with(b"version"));
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+
+        // compressed caches are read without enabling compression
+        let cache = FileCache::new(dir.path());
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+    }
+
+    #[cfg(feature = "encryption")]
+    #[test]
+    fn encryption_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let snapshot = Snapshot::new(envelopes(&[("1", &[Flag::Seen])]), Default::default());
+        let cache = FileCache::new(dir.path());
+        cache.save("INBOX", &snapshot).unwrap();
+
+        let cache = cache.with_encryption(Encryption::new([7; 32]));
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        cache.save("INBOX", &snapshot).unwrap();
+        assert!(!fs::read(cache.file_path("INBOX"))
+            .unwrap()
+            .starts_with(b"version"));
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+
+        let cache = FileCache::new(dir.path());
+        assert!(matches!(
+            cache.load("INBOX"),
+            Err(EverestError::DecryptCacheError(..))
+        ));
+    }
+
+    #[test]
+    fn build_patch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+        let imap = envelopes(&[("1", &[])]);
+        cache
+            .save("INBOX", &Snapshot::new(imap.clone(), imap.clone()))
+            .unwrap();
+
+        let patch = cache
+            .build_patch("INBOX", &imap, &envelopes(&[("1", &[Flag::Seen])]))
+            .unwrap();
+
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Left,
+                HunkKind::AddFlag("1".into(), Flag::Seen)
+            )],
+            patch
+        );
+    }
+
+    #[test]
+    fn undo_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+        let snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen]), ("2", &[])]),
+            envelopes(&[("1", &[Flag::Seen]), ("2", &[])]),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        let patch = vec![
+            Hunk::new(Side::Right, HunkKind::AddMsg("2".into())),
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("3".into())),
+            Hunk::new(Side::Right, HunkKind::AddFlag("1".into(), Flag::Seen)),
+        ];
+        cache.record_sync("INBOX", &patch, &[]).unwrap();
+        // syncs applying nothing are skipped
+        cache.record_sync("INBOX", &vec![], &[]).unwrap();
+        assert_eq!(patch, cache.load("INBOX").unwrap().journal[0].patch);
+
+        // the first hunk fails, the other two are done or skipped
+        let mut undone = vec![];
+        let err = cache.undo_last_sync("INBOX", |hunk| {
+            if let Hunk {
+                target: Side::Right,
+                kind: HunkKind::RemoveMsg(id),
+            } = hunk
+            {
+                return Err(EverestError::InvalidCacheError(
+                    id.to_string(),
+                    PathBuf::new(),
+                ));
+            }
+            undone.push(hunk.clone());
+            Ok(())
+        });
+        assert!(err.is_err());
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::RemoveFlag("1".into(), Flag::Seen)
+            )],
+            undone
+        );
+        let loaded = cache.load("INBOX").unwrap();
+        assert_eq!(envelopes(&[("1", &[]), ("2", &[])]), loaded.mdir);
+        assert_eq!(vec![patch[0].clone()], loaded.journal[0].patch);
+
+        let entry = cache.undo_last_sync("INBOX", |_| Ok(())).unwrap();
+        assert_eq!(Some(vec![patch[0].clone()]), entry.map(|entry| entry.patch));
+        assert_eq!(envelopes(&[("1", &[])]), cache.load("INBOX").unwrap().mdir);
+        assert_eq!(None, cache.undo_last_sync("INBOX", |_| Ok(())).unwrap());
+        assert_eq!(1, cache.load("INBOX").unwrap().journal.len());
+    }
+
+    #[test]
+    fn history_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path()).with_checkpoint_interval(3);
+        assert_eq!(None, cache.last_sync("INBOX").unwrap());
+
+        let patch = vec![Hunk::new(
+            Side::Left,
+            HunkKind::AddFlag("1".into(), Flag::Seen),
+        )];
+        let errors = vec!["cannot add flag\tto message 2".to_owned(), "\\".to_owned()];
+        cache.record_sync("INBOX", &patch, &errors).unwrap();
+        cache.record_sync("INBOX", &vec![], &[]).unwrap();
+
+        let history = cache.history("INBOX").unwrap();
+        assert_eq!(2, history.len());
+        assert_eq!(0, history[0].changes());
+        assert!(!history[0].has_errors());
+        assert_eq!(1, history[1].changes());
+        assert_eq!(errors, history[1].errors);
+        assert!(history[0].time >= history[1].time);
+        assert_eq!(
+            Some(&history[0]),
+            cache.last_sync("INBOX").unwrap().as_ref()
+        );
+        assert_eq!(None, cache.last_sync("Sent").unwrap());
+    }
+}
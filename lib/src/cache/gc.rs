@@ -0,0 +1,95 @@
+use std::time::{Duration, SystemTime};
+
+use super::Snapshot;
+
+/// Id mappings pruned by [`Snapshot::gc`], as `(imap_id, mdir_id)`.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct GcReport {
+    pub pruned: Vec<(String, String)>,
+    /// Orphaned mappings kept until their retention expires.
+    pub retained: usize,
+}
+
+impl GcReport {
+    pub fn is_empty(&self) -> bool {
+        self.pruned.is_empty()
+    }
+}
+
+impl Snapshot {
+    /// Prunes the id mappings whose messages are gone from both sides.
+    /// Such mappings are first marked as orphaned, then pruned once
+    /// orphaned for longer than the given retention, which leaves room
+    /// for messages temporarily missing (like a folder being rebuilt)
+    /// to reappear with their mapping. A zero retention prunes them
+    /// right away. Content hashes of pruned messages are pruned too.
+    /// The journal is left to [`Snapshot::prune`].
+    pub fn gc(&mut self, retention: Duration, now: SystemTime) -> GcReport {
+        let mut report = GcReport::default();
+        let ids = self
+            .ids
+            .iter()
+            .map(|(imap_id, mdir_id)| (imap_id.to_owned(), mdir_id.to_owned()))
+            .collect::<Vec<_>>();
+
+        for (imap_id, mdir_id) in ids {
+            if self.imap.contains_key(imap_id.as_str()) || self.mdir.contains_key(mdir_id.as_str())
+            {
+                self.ids.clear_orphaned(&imap_id);
+                continue;
+            }
+
+            let since = self.ids.orphaned_since(&imap_id).unwrap_or(now);
+            if now.duration_since(since).unwrap_or_default() >= retention {
+                self.ids.remove_imap_id(&imap_id);
+                report.pruned.push((imap_id, mdir_id));
+            } else {
+                self.ids.set_orphaned_since(&imap_id, since);
+                report.retained += 1;
+            }
+        }
+
+        // hashes of messages neither mapped nor synced are of no use
+        let (mdir, ids) = (&self.mdir, &self.ids);
+        self.hashes
+            .retain(|mdir_id| mdir.contains_key(mdir_id) || ids.imap_id(mdir_id).is_some());
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cache::envelopes;
+
+    use super::*;
+
+    #[test]
+    fn gc_test() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let mut snapshot = Snapshot::new(envelopes(&[("1", &[])]), envelopes(&[("b", &[])]));
+        snapshot.ids.insert("1", "a");
+        snapshot.ids.insert("2", "b");
+        snapshot.ids.insert("3", "c");
+        snapshot.hashes.insert("c", "hash");
+        snapshot.hashes.insert("d", "hash");
+
+        let report = snapshot.gc(day, now);
+        assert!(report.is_empty());
+        assert_eq!(Some("hash"), snapshot.hashes.get("c"));
+        assert_eq!(None, snapshot.hashes.get("d"));
+        assert_eq!(1, report.retained);
+        assert_eq!(Some(now), snapshot.ids.orphaned_since("3"));
+
+        let report = snapshot.gc(day, now + day);
+        assert_eq!(vec![("3".to_owned(), "c".to_owned())], report.pruned);
+        assert_eq!(2, snapshot.ids.len());
+        assert!(snapshot.hashes.is_empty());
+
+        snapshot.imap.clear();
+        snapshot.mdir.clear();
+        assert_eq!(2, snapshot.gc(Duration::ZERO, now).pruned.len());
+        assert!(snapshot.ids.is_empty());
+    }
+}
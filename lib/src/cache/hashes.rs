@@ -0,0 +1,122 @@
+use std::{collections::HashMap, sync::Arc};
+
+use sha2::{Digest, Sha256};
+
+/// Content hashes of the synced messages by maildir id, so that
+/// messages can be matched by content without downloading them again:
+/// duplicates, messages altered after the sync, or messages whose UID
+/// changed after a UIDVALIDITY reset. Clones share the hashes until
+/// changed, like [`crate::Envelopes`].
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct ContentHashes(Arc<HashMap<String, String>>);
+
+impl ContentHashes {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the content hash of the given maildir id.
+    pub fn get(&self, mdir_id: &str) -> Option<&str> {
+        self.0.get(mdir_id).map(String::as_str)
+    }
+
+    /// Records the content hash of the given maildir id, as returned
+    /// by [`content_hash`].
+    pub fn insert(&mut self, mdir_id: &str, hash: &str) {
+        Arc::make_mut(&mut self.0).insert(mdir_id.to_owned(), hash.to_owned());
+    }
+
+    pub fn remove(&mut self, mdir_id: &str) -> Option<String> {
+        Arc::make_mut(&mut self.0).remove(mdir_id)
+    }
+
+    /// Returns the maildir ids having the given content hash, sorted.
+    pub fn find(&self, hash: &str) -> Vec<&str> {
+        let mut ids = self
+            .0
+            .iter()
+            .filter(|(_, h)| *h == hash)
+            .map(|(id, _)| id.as_str())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    /// Returns the groups of maildir ids sharing the same content,
+    /// sorted.
+    pub fn duplicates(&self) -> Vec<Vec<&str>> {
+        let mut ids = HashMap::<&str, Vec<&str>>::new();
+        for (id, hash) in self.0.iter() {
+            ids.entry(hash).or_default().push(id);
+        }
+        let mut duplicates = ids
+            .into_values()
+            .filter(|ids| ids.len() > 1)
+            .map(|mut ids| {
+                ids.sort_unstable();
+                ids
+            })
+            .collect::<Vec<_>>();
+        duplicates.sort_unstable();
+        duplicates
+    }
+
+    /// Tells whether the given raw message still matches the recorded
+    /// content hash of the given maildir id, `None` if no hash is
+    /// recorded.
+    pub fn verify(&self, mdir_id: &str, raw: &[u8]) -> Option<bool> {
+        self.get(mdir_id).map(|hash| hash == content_hash(raw))
+    }
+
+    /// Iterates over the `(mdir_id, hash)` pairs, sorted by maildir id.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        let mut hashes = self
+            .0
+            .iter()
+            .map(|(id, hash)| (id.as_str(), hash.as_str()))
+            .collect::<Vec<_>>();
+        hashes.sort_unstable();
+        hashes.into_iter()
+    }
+
+    pub(super) fn retain(&mut self, mut f: impl FnMut(&str) -> bool) {
+        Arc::make_mut(&mut self.0).retain(|id, _| f(id))
+    }
+}
+
+/// Hashes the given raw message, as the hex SHA-256 of its content.
+pub fn content_hash(raw: &[u8]) -> String {
+    Sha256::digest(raw)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn content_hashes_test() {
+        let mut hashes = ContentHashes::default();
+        let hash = content_hash(b"Subject: a\r\n\r\n");
+        hashes.insert("a", &hash);
+        hashes.insert("b", &content_hash(b"Subject: b\r\n\r\n"));
+        hashes.insert("c", &hash);
+
+        assert_eq!(64, hash.len());
+        assert_eq!(vec!["a", "c"], hashes.find(&hash));
+        assert_eq!(vec![vec!["a", "c"]], hashes.duplicates());
+        assert_eq!(Some(true), hashes.verify("a", b"Subject: a\r\n\r\n"));
+        assert_eq!(Some(false), hashes.verify("b", b"Subject: a\r\n\r\n"));
+        assert_eq!(None, hashes.verify("d", b""));
+
+        hashes.remove("c");
+        assert!(hashes.duplicates().is_empty());
+        assert_eq!(2, hashes.len());
+    }
+}
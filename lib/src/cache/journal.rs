@@ -0,0 +1,243 @@
+use std::time::SystemTime;
+
+use crate::{
+    flag::{format_flag, parse_flag},
+    Envelope, Hunk, HunkId, HunkKind, Patch, Side,
+};
+
+use super::Snapshot;
+
+/// Sync of a folder kept in its journal, with the patch it applied so
+/// that it can be undone, and the errors it met. Syncs applying
+/// nothing are kept too, the journal being the sync history of the
+/// folder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub time: SystemTime,
+    pub patch: Patch,
+    pub errors: Vec<String>,
+}
+
+impl JournalEntry {
+    /// Returns the number of changes applied by the sync.
+    pub fn changes(&self) -> usize {
+        self.patch.len()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        !self.errors.is_empty()
+    }
+}
+
+impl Hunk {
+    /// Returns the hunk reverting this one, `None` for removed messages
+    /// which cannot be brought back.
+    pub fn inverse(&self) -> Option<Hunk> {
+        let inverse = |kind: &HunkKind| match kind {
+            HunkKind::AddMsg(id) => Some(HunkKind::RemoveMsg(id.clone())),
+            HunkKind::RemoveMsg(_) => None,
+            HunkKind::AddFlag(id, flag) => Some(HunkKind::RemoveFlag(id.clone(), flag.clone())),
+            HunkKind::RemoveFlag(id, flag) => Some(HunkKind::AddFlag(id.clone(), flag.clone())),
+        };
+        inverse(&self.kind).map(|kind| Hunk::new(self.target, kind))
+    }
+}
+
+impl Snapshot {
+    /// Records a sync made at the given time, with the patch it
+    /// applied and the errors it met.
+    pub fn record(&mut self, patch: Patch, errors: Vec<String>, time: SystemTime) {
+        self.journal.push(JournalEntry {
+            time,
+            patch,
+            errors,
+        });
+    }
+
+    /// Applies the given hunk to the envelopes of its side, so that
+    /// the snapshot follows changes made outside of a sync.
+    pub fn apply_hunk(&mut self, hunk: &Hunk) {
+        let envelopes = match hunk.target {
+            Side::Left => &mut self.imap,
+            Side::Right => &mut self.mdir,
+        };
+        let id = hunk.kind.id().target_or_source();
+        match &hunk.kind {
+            HunkKind::AddMsg(_) => {
+                envelopes.entry(id.clone()).or_insert_with(|| Envelope {
+                    id: id.clone(),
+                    flags: Default::default(),
+                    metadata: None,
+                });
+            }
+            HunkKind::RemoveMsg(_) => {
+                envelopes.remove(id);
+            }
+            HunkKind::AddFlag(_, flag) => {
+                if let Some(envelope) = envelopes.get_mut(id) {
+                    envelope.flags.insert(flag.clone());
+                }
+            }
+            HunkKind::RemoveFlag(_, flag) => {
+                if let Some(envelope) = envelopes.get_mut(id) {
+                    envelope.flags.remove(flag);
+                }
+            }
+        }
+    }
+}
+
+/// Formats the given hunk as `<side> <change> <id> [flag]`, the way
+/// caches store journals, with ids rendered like [`HunkId`] does.
+/// Sides keep the names of the backends they held when hunks were
+/// per backend, for existing caches to load.
+pub(super) fn format_hunk(hunk: &Hunk) -> String {
+    let side = match hunk.target {
+        Side::Left => "imap",
+        Side::Right => "maildir",
+    };
+    match &hunk.kind {
+        HunkKind::AddMsg(id) => format!("{} add-msg {}", side, id),
+        HunkKind::RemoveMsg(id) => format!("{} remove-msg {}", side, id),
+        HunkKind::AddFlag(id, flag) => {
+            format!("{} add-flag {} {}", side, id, format_flag(flag))
+        }
+        HunkKind::RemoveFlag(id, flag) => {
+            format!("{} remove-flag {} {}", side, id, format_flag(flag))
+        }
+    }
+}
+
+/// Escapes the tabs, newlines and backslashes of the given error, so
+/// that it fits in a single field of a line.
+pub(super) fn escape_error(error: &str) -> String {
+    error
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+pub(super) fn unescape_error(error: &str) -> String {
+    let mut unescaped = String::with_capacity(error.len());
+    let mut chars = error.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('t')) => unescaped.push('\t'),
+            ('\\', Some('n')) => unescaped.push('\n'),
+            ('\\', Some('\\')) => unescaped.push('\\'),
+            _ => {
+                unescaped.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    unescaped
+}
+
+pub(super) fn parse_hunk(hunk: &str) -> Option<Hunk> {
+    let mut parts = hunk.split(' ');
+    let side = parts.next()?;
+    let change = parts.next()?;
+    let id = parse_hunk_id(parts.next()?)?;
+    let flag = parts.next().filter(|flag| !flag.is_empty()).map(parse_flag);
+    if parts.next().is_some() {
+        return None;
+    }
+    let kind = match (change, flag) {
+        ("add-msg", None) => HunkKind::AddMsg(id),
+        ("remove-msg", None) => HunkKind::RemoveMsg(id),
+        ("add-flag", Some(flag)) => HunkKind::AddFlag(id, flag),
+        ("remove-flag", Some(flag)) => HunkKind::RemoveFlag(id, flag),
+        _ => return None,
+    };
+    match side {
+        "imap" => Some(Hunk::new(Side::Left, kind)),
+        "maildir" => Some(Hunk::new(Side::Right, kind)),
+        _ => None,
+    }
+}
+
+/// Parses ids rendered by [`HunkId`], like `42`, `42>7` or `42>`.
+fn parse_hunk_id(id: &str) -> Option<HunkId> {
+    match id.split_once('>') {
+        None if !id.is_empty() => Some(HunkId::shared(id)),
+        Some((source, "")) if !source.is_empty() => Some(HunkId::unmapped(source)),
+        Some((source, target)) if !source.is_empty() && !target.contains('>') => Some(HunkId {
+            source: source.into(),
+            target: Some(target.into()),
+        }),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::envelopes, Flag};
+
+    use super::*;
+
+    #[test]
+    fn hunk_test() {
+        let hunks = [
+            Hunk::new(Side::Left, HunkKind::AddMsg("1".into())),
+            Hunk::new(Side::Right, HunkKind::RemoveMsg("a".into())),
+            Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Seen)),
+            Hunk::new(
+                Side::Right,
+                HunkKind::RemoveFlag("a".into(), Flag::Keyword("Work".into())),
+            ),
+            Hunk::new(Side::Right, HunkKind::AddMsg(HunkId::unmapped("2"))),
+            Hunk::new(
+                Side::Left,
+                HunkKind::RemoveMsg(HunkId {
+                    source: "b".into(),
+                    target: Some("3".into()),
+                }),
+            ),
+        ];
+        for hunk in &hunks {
+            assert_eq!(Some(hunk), parse_hunk(&format_hunk(hunk)).as_ref());
+        }
+        assert_eq!("maildir remove-flag a Work", format_hunk(&hunks[3]));
+        assert_eq!(None, parse_hunk("imap add-flag 1"));
+        assert_eq!(None, parse_hunk("pop add-msg 1"));
+        assert_eq!("maildir add-msg 2>", format_hunk(&hunks[4]));
+        assert_eq!("imap remove-msg b>3", format_hunk(&hunks[5]));
+        assert_eq!(None, parse_hunk("imap remove-msg >3"));
+
+        let error = "cannot read C:\\mail\tfolder\n";
+        assert_eq!("cannot read C:\\\\mail\\tfolder\\n", escape_error(error));
+        assert_eq!(error, unescape_error(&escape_error(error)));
+
+        assert_eq!(
+            Some(Hunk::new(Side::Left, HunkKind::RemoveMsg("1".into()))),
+            hunks[0].inverse()
+        );
+        assert_eq!(None, hunks[1].inverse());
+        assert_eq!(
+            Some(Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag("a".into(), Flag::Keyword("Work".into()))
+            )),
+            hunks[3].inverse()
+        );
+    }
+
+    #[test]
+    fn apply_hunk_test() {
+        let mut snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen])]),
+            envelopes(&[("1", &[Flag::Seen])]),
+        );
+        snapshot.apply_hunk(&Hunk::new(
+            Side::Right,
+            HunkKind::RemoveFlag("1".into(), Flag::Seen),
+        ));
+        snapshot.apply_hunk(&Hunk::new(Side::Left, HunkKind::AddMsg("2".into())));
+        snapshot.apply_hunk(&Hunk::new(Side::Left, HunkKind::RemoveMsg("1".into())));
+
+        assert_eq!(envelopes(&[("2", &[])]), snapshot.imap);
+        assert_eq!(envelopes(&[("1", &[])]), snapshot.mdir);
+    }
+}
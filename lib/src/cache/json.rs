@@ -0,0 +1,297 @@
+use std::io;
+
+use serde_json::{json, Map, Value};
+
+use crate::{Envelope, EverestError, Flags, Id};
+
+use super::{
+    format_flags, format_hunk, from_secs, parse_hunk, to_secs, Cache, Cursors, JournalEntry,
+    Snapshot,
+};
+
+/// Version of the export structure, kept under the `version` key.
+const VERSION: u64 = 1;
+
+pub(super) fn export<C: Cache, W: io::Write>(
+    cache: &C,
+    folders: &[&str],
+    writer: W,
+) -> Result<(), EverestError> {
+    let mut encoded = Map::new();
+    for folder in folders {
+        encoded.insert(folder.to_string(), encode(&cache.load(folder)?));
+    }
+    let value = json!({ "version": VERSION, "folders": encoded });
+    serde_json::to_writer_pretty(writer, &value).map_err(EverestError::ExportCacheError)
+}
+
+pub(super) fn import<C: Cache, R: io::Read>(
+    cache: &C,
+    reader: R,
+) -> Result<Vec<String>, EverestError> {
+    let value: Value = serde_json::from_reader(reader)
+        .map_err(|err| EverestError::ImportCacheError(err.to_string()))?;
+    match value.get("version").and_then(Value::as_u64) {
+        Some(VERSION) => (),
+        Some(version) => {
+            return Err(EverestError::ImportCacheError(format!(
+                "unsupported version {}",
+                version
+            )))
+        }
+        None => return Err(EverestError::ImportCacheError("invalid version".into())),
+    }
+    let folders = value
+        .get("folders")
+        .and_then(Value::as_object)
+        .ok_or_else(|| EverestError::ImportCacheError("invalid folders".into()))?;
+
+    // decodes everything first so that an invalid export saves nothing
+    let snapshots = folders
+        .iter()
+        .map(|(folder, value)| {
+            decode(value)
+                .map(|snapshot| (folder.clone(), snapshot))
+                .map_err(|err| EverestError::ImportCacheError(format!("{}: {}", folder, err)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut imported = Vec::with_capacity(snapshots.len());
+    for (folder, snapshot) in snapshots {
+        cache.save(&folder, &snapshot)?;
+        imported.push(folder);
+    }
+    Ok(imported)
+}
+
+fn encode(snapshot: &Snapshot) -> Value {
+    let mut encoded = json!({ "imap": {}, "maildir": {} });
+    for (side, envelope) in snapshot.sides() {
+        let flags = format_flags(&envelope.flags)
+            .split_whitespace()
+            .map(Value::from)
+            .collect();
+        encoded[side][&*envelope.id] = Value::Array(flags);
+    }
+    encoded["ids"] = snapshot
+        .ids
+        .iter()
+        .map(|(imap_id, mdir_id)| {
+            let mut ids = json!({ "imap": imap_id, "maildir": mdir_id });
+            if let Some(since) = snapshot.ids.orphaned_since(imap_id) {
+                ids["orphaned_since"] = to_secs(since).into();
+            }
+            ids
+        })
+        .collect();
+    for (mdir_id, hash) in snapshot.hashes.iter() {
+        encoded["hashes"][mdir_id] = hash.into();
+    }
+    let cursors = &snapshot.cursors;
+    for (key, cursor) in [
+        ("uid_validity", cursors.uid_validity.map(u64::from)),
+        ("uid_next", cursors.uid_next.map(u64::from)),
+        ("highest_modseq", cursors.highest_modseq),
+    ] {
+        if let Some(cursor) = cursor {
+            encoded["cursors"][key] = cursor.into();
+        }
+    }
+    encoded["journal"] = snapshot
+        .journal
+        .iter()
+        .map(|entry| {
+            let patch = entry.patch.iter().map(format_hunk).collect::<Vec<_>>();
+            let mut encoded = json!({ "time": to_secs(entry.time), "patch": patch });
+            if entry.has_errors() {
+                encoded["errors"] = entry.errors.clone().into();
+            }
+            encoded
+        })
+        .collect();
+    encoded
+}
+
+fn decode(value: &Value) -> Result<Snapshot, String> {
+    let mut snapshot = Snapshot::default();
+
+    for side in ["imap", "maildir"] {
+        let envelopes = match value.get(side) {
+            Some(Value::Object(envelopes)) => envelopes,
+            None => continue,
+            Some(_) => return Err(format!("invalid {} envelopes", side)),
+        };
+        for (id, flags) in envelopes {
+            let flags = flags
+                .as_array()
+                .and_then(|flags| flags.iter().map(Value::as_str).collect::<Option<Vec<_>>>())
+                .ok_or_else(|| format!("invalid flags of {} envelope {:?}", side, id))?;
+            let id = Id::from(id.as_str());
+            let envelope = Envelope {
+                id: id.clone(),
+                flags: Flags::from_imap(&flags.join(" ")),
+                metadata: None,
+            };
+            if let Some(envelopes) = snapshot.envelopes_mut(side) {
+                envelopes.insert(id, envelope);
+            }
+        }
+    }
+
+    for ids in value
+        .get("ids")
+        .map(|ids| ids.as_array().ok_or("invalid ids"))
+        .transpose()?
+        .into_iter()
+        .flatten()
+    {
+        let (imap_id, mdir_id) = match (
+            ids.get("imap").and_then(Value::as_str),
+            ids.get("maildir").and_then(Value::as_str),
+        ) {
+            (Some(imap_id), Some(mdir_id)) => (imap_id, mdir_id),
+            _ => return Err(format!("invalid ids {}", ids)),
+        };
+        snapshot.ids.insert(imap_id, mdir_id);
+        if let Some(secs) = ids.get("orphaned_since") {
+            let secs = secs
+                .as_u64()
+                .ok_or_else(|| format!("invalid ids {}", ids))?;
+            snapshot.ids.set_orphaned_since(imap_id, from_secs(secs));
+        }
+    }
+
+    if let Some(hashes) = value.get("hashes") {
+        let hashes = hashes.as_object().ok_or("invalid hashes")?;
+        for (mdir_id, hash) in hashes {
+            let hash = hash
+                .as_str()
+                .ok_or_else(|| format!("invalid hash of {:?}", mdir_id))?;
+            snapshot.hashes.insert(mdir_id, hash);
+        }
+    }
+
+    if let Some(cursors) = value.get("cursors") {
+        let cursor = |key| match cursors.get(key) {
+            None => Ok(None),
+            Some(cursor) => cursor
+                .as_u64()
+                .map(Some)
+                .ok_or_else(|| format!("invalid cursor {:?}", key)),
+        };
+        let cursor_u32 = |key| match cursor(key)? {
+            None => Ok(None),
+            Some(cursor) => u32::try_from(cursor)
+                .map(Some)
+                .map_err(|_| format!("invalid cursor {:?}", key)),
+        };
+        snapshot.cursors = Cursors {
+            uid_validity: cursor_u32("uid_validity")?,
+            uid_next: cursor_u32("uid_next")?,
+            highest_modseq: cursor("highest_modseq")?,
+        };
+    }
+
+    for entry in value
+        .get("journal")
+        .map(|journal| journal.as_array().ok_or("invalid journal"))
+        .transpose()?
+        .into_iter()
+        .flatten()
+    {
+        let invalid = || format!("invalid journal entry {}", entry);
+        let secs = entry
+            .get("time")
+            .and_then(Value::as_u64)
+            .ok_or_else(invalid)?;
+        let patch = entry
+            .get("patch")
+            .and_then(Value::as_array)
+            .and_then(|patch| {
+                patch
+                    .iter()
+                    .map(|hunk| hunk.as_str().and_then(parse_hunk))
+                    .collect::<Option<_>>()
+            })
+            .ok_or_else(invalid)?;
+        let errors = match entry.get("errors") {
+            None => vec![],
+            Some(errors) => errors
+                .as_array()
+                .and_then(|errors| {
+                    errors
+                        .iter()
+                        .map(|err| err.as_str().map(ToOwned::to_owned))
+                        .collect::<Option<_>>()
+                })
+                .ok_or_else(invalid)?,
+        };
+        snapshot.journal.push(JournalEntry {
+            time: from_secs(secs),
+            patch,
+            errors,
+        });
+    }
+
+    Ok(snapshot)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::{envelopes, FileCache},
+        Flag, Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn export_import_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path().join("a"));
+        let mut snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen, Flag::Keyword("Work".into())])]),
+            envelopes(&[("a", &[Flag::Seen])]),
+        );
+        snapshot.ids.insert("1", "a");
+        snapshot.cursors.uid_validity = Some(42);
+        snapshot.hashes.insert("a", "hash");
+        snapshot.record(
+            vec![Hunk::new(Side::Left, HunkKind::AddMsg("1".into()))],
+            vec!["cannot sync".into()],
+            from_secs(1_700_000_000),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        cache.insert_ids("Sent", "2", "b").unwrap();
+        cache
+            .gc("Sent", std::time::Duration::from_secs(60))
+            .unwrap();
+
+        let mut export = vec![];
+        cache.export_json(&["INBOX", "Sent"], &mut export).unwrap();
+        let value: Value = serde_json::from_slice(&export).unwrap();
+        assert_eq!(
+            json!(["Work", "\\Seen"]),
+            value["folders"]["INBOX"]["imap"]["1"]
+        );
+
+        let other = FileCache::new(dir.path().join("b"));
+        assert_eq!(
+            vec!["INBOX".to_owned(), "Sent".to_owned()],
+            other.import_json(export.as_slice()).unwrap()
+        );
+        assert_eq!(snapshot, other.load("INBOX").unwrap());
+        assert_eq!(cache.load("Sent").unwrap(), other.load("Sent").unwrap());
+
+        let invalid = json!({
+            "version": VERSION,
+            "folders": { "Drafts": {}, "Trash": { "imap": { "1": "\\Seen" } } },
+        });
+        assert!(matches!(
+            other.import_json(invalid.to_string().as_bytes()),
+            Err(EverestError::ImportCacheError(..))
+        ));
+        assert_eq!(Snapshot::default(), other.load("Drafts").unwrap());
+        assert!(!other.file_path("Drafts").exists());
+    }
+}
@@ -0,0 +1,78 @@
+use std::{
+    fs::{self, File, TryLockError},
+    path::{Path, PathBuf},
+};
+
+use crate::EverestError;
+
+/// Advisory lock keeping concurrent runs from using the same cache at
+/// once, released when dropped (or when the process dies). Other
+/// processes only see it if they take it too.
+#[derive(Debug)]
+pub struct CacheLock {
+    path: PathBuf,
+    // the lock lives as long as the file stays open
+    _file: File,
+}
+
+impl CacheLock {
+    /// Takes the lock at the given path, waiting for other runs to
+    /// release it.
+    pub fn acquire<P: Into<PathBuf>>(path: P) -> Result<Self, EverestError> {
+        let path = path.into();
+        let file = open(&path)?;
+        file.lock()
+            .map_err(|err| EverestError::LockCacheError(err, path.clone()))?;
+        Ok(Self { path, _file: file })
+    }
+
+    /// Takes the lock at the given path, failing right away with
+    /// [`EverestError::CacheLockedError`] if another run holds it.
+    pub fn try_acquire<P: Into<PathBuf>>(path: P) -> Result<Self, EverestError> {
+        let path = path.into();
+        let file = open(&path)?;
+        match file.try_lock() {
+            Ok(()) => Ok(Self { path, _file: file }),
+            Err(TryLockError::WouldBlock) => Err(EverestError::CacheLockedError(path)),
+            Err(TryLockError::Error(err)) => Err(EverestError::LockCacheError(err, path)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn open(path: &Path) -> Result<File, EverestError> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .map_err(|err| EverestError::LockCacheError(err, path.to_owned()))?;
+    }
+    File::options()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .map_err(|err| EverestError::LockCacheError(err, path.to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("everest").join("cache.lock");
+
+        let lock = CacheLock::try_acquire(&path).unwrap();
+        assert!(matches!(
+            CacheLock::try_acquire(&path),
+            Err(EverestError::CacheLockedError(..))
+        ));
+        drop(lock);
+
+        let lock = CacheLock::acquire(&path).unwrap();
+        assert_eq!(path, lock.path());
+    }
+}
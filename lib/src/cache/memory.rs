@@ -0,0 +1,60 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use crate::EverestError;
+
+use super::{Cache, Snapshot};
+
+/// Keeps the snapshots in memory only, for one-shot syncs and tests
+/// where nothing should outlive the process. Every run then syncs
+/// like the first one.
+#[derive(Default, Debug)]
+pub struct MemoryCache {
+    snapshots: Mutex<HashMap<String, Snapshot>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Cache for MemoryCache {
+    fn load(&self, folder: &str) -> Result<Snapshot, EverestError> {
+        let snapshots = self.snapshots.lock().unwrap_or_else(|err| err.into_inner());
+        Ok(snapshots.get(folder).cloned().unwrap_or_default())
+    }
+
+    fn save(&self, folder: &str, snapshot: &Snapshot) -> Result<(), EverestError> {
+        let mut snapshots = self.snapshots.lock().unwrap_or_else(|err| err.into_inner());
+        snapshots.insert(folder.to_owned(), snapshot.clone());
+        Ok(())
+    }
+
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        let snapshots = self.snapshots.lock().unwrap_or_else(|err| err.into_inner());
+        let mut folders = snapshots.keys().cloned().collect::<Vec<_>>();
+        folders.sort_unstable();
+        Ok(folders)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::envelopes, Flag};
+
+    use super::*;
+
+    #[test]
+    fn save_load_test() {
+        let cache = MemoryCache::new();
+        assert_eq!(Snapshot::default(), cache.load("INBOX").unwrap());
+
+        let snapshot = Snapshot::new(envelopes(&[("1", &[Flag::Seen])]), Default::default());
+        cache.save("INBOX", &snapshot).unwrap();
+        cache.insert_ids("INBOX", "1", "a").unwrap();
+        assert_eq!(snapshot.imap, cache.load("INBOX").unwrap().imap);
+        assert_eq!(Some("a".to_owned()), cache.mdir_id("INBOX", "1").unwrap());
+        assert_eq!(Snapshot::default(), cache.load("Sent").unwrap());
+        assert_eq!(vec!["INBOX"], cache.folders().unwrap());
+    }
+}
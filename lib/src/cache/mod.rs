@@ -0,0 +1,891 @@
+#[cfg(feature = "cbor")]
+mod cbor;
+#[cfg(feature = "compression")]
+mod compression;
+mod cursors;
+mod delta;
+#[cfg(feature = "encryption")]
+mod encryption;
+mod file;
+mod gc;
+mod hashes;
+mod journal;
+#[cfg(feature = "json")]
+mod json;
+mod lock;
+mod memory;
+mod reassociate;
+mod retention;
+mod root;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+mod xdg;
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::{
+    backend::maildir::sync_dir,
+    build_patch,
+    folder::{escape_level, unescape_level},
+    iter_patch, Envelope, Envelopes, EverestError, Flags, Hunk, Patch,
+};
+
+#[cfg(feature = "cbor")]
+pub use cbor::CborCache;
+#[cfg(feature = "compression")]
+pub use compression::DEFAULT_COMPRESSION_LEVEL;
+pub use cursors::Cursors;
+#[cfg(feature = "encryption")]
+pub use encryption::{Encryption, KeyProvider};
+pub use file::FileCache;
+pub use gc::GcReport;
+pub use hashes::{content_hash, ContentHashes};
+pub use journal::JournalEntry;
+use journal::{escape_error, format_hunk, parse_hunk, unescape_error};
+pub use lock::CacheLock;
+pub use memory::MemoryCache;
+pub use retention::{PruneReport, RetentionPolicy};
+pub use root::CacheRoot;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteCache;
+pub use xdg::default_dir;
+
+/// Storage of the sync state between two runs: the snapshot of the
+/// last sync, the mapping between IMAP and maildir ids, the content
+/// hashes, the IMAP cursors and the journal of applied patches,
+/// partitioned by folder.
+///
+/// Caches do not guard against concurrent runs, which need to hold a
+/// [`CacheLock`] for the whole sync.
+///
+/// Only [`Cache::load`] and [`Cache::save`] are required, other
+/// operations default to loading and saving the whole snapshot of
+/// the folder. Stores able to update them in place
+/// should override them.
+pub trait Cache {
+    /// Loads the snapshot of the last sync of the given folder, an
+    /// empty one if the folder was never synced.
+    fn load(&self, folder: &str) -> Result<Snapshot, EverestError>;
+
+    /// Replaces the cached snapshot of the given folder, leaving other
+    /// folders untouched.
+    fn save(&self, folder: &str, snapshot: &Snapshot) -> Result<(), EverestError>;
+
+    /// Returns the folders known to the cache, sorted. Folders whose
+    /// snapshot is empty may be left out. Caches unable to list their
+    /// folders return none.
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        Ok(vec![])
+    }
+
+    /// Builds the patch between the cached snapshot of the given
+    /// folder and the given next envelopes. Once the patch is applied,
+    /// the envelopes of both sides need to be saved with
+    /// [`Cache::save`].
+    fn build_patch(
+        &self,
+        folder: &str,
+        next_imap_envelopes: &Envelopes,
+        next_mdir_envelopes: &Envelopes,
+    ) -> Result<Patch, EverestError> {
+        Ok(self
+            .load(folder)?
+            .build_patch(next_imap_envelopes, next_mdir_envelopes))
+    }
+
+    /// Returns the maildir id mapped to the given IMAP id.
+    fn mdir_id(&self, folder: &str, imap_id: &str) -> Result<Option<String>, EverestError> {
+        Ok(self
+            .load(folder)?
+            .ids
+            .mdir_id(imap_id)
+            .map(ToOwned::to_owned))
+    }
+
+    /// Returns the IMAP id mapped to the given maildir id.
+    fn imap_id(&self, folder: &str, mdir_id: &str) -> Result<Option<String>, EverestError> {
+        Ok(self
+            .load(folder)?
+            .ids
+            .imap_id(mdir_id)
+            .map(ToOwned::to_owned))
+    }
+
+    /// Maps the given IMAP id to the given maildir id, replacing any
+    /// previous mapping of either id.
+    fn insert_ids(&self, folder: &str, imap_id: &str, mdir_id: &str) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        snapshot.ids.insert(imap_id, mdir_id);
+        self.save(folder, &snapshot)
+    }
+
+    /// Removes the mapping of the given IMAP id.
+    fn remove_imap_id(&self, folder: &str, imap_id: &str) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        if snapshot.ids.remove_imap_id(imap_id).is_some() {
+            self.save(folder, &snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Removes the mapping of the given maildir id.
+    fn remove_mdir_id(&self, folder: &str, mdir_id: &str) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        if snapshot.ids.remove_mdir_id(mdir_id).is_some() {
+            self.save(folder, &snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the content hash recorded for the given maildir id.
+    fn content_hash(&self, folder: &str, mdir_id: &str) -> Result<Option<String>, EverestError> {
+        Ok(self
+            .load(folder)?
+            .hashes
+            .get(mdir_id)
+            .map(ToOwned::to_owned))
+    }
+
+    /// Records the content hash of the given maildir id, see
+    /// [`content_hash`].
+    fn insert_content_hash(
+        &self,
+        folder: &str,
+        mdir_id: &str,
+        hash: &str,
+    ) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        snapshot.hashes.insert(mdir_id, hash);
+        self.save(folder, &snapshot)
+    }
+
+    /// Returns the IMAP cursors of the given folder.
+    fn cursors(&self, folder: &str) -> Result<Cursors, EverestError> {
+        Ok(self.load(folder)?.cursors)
+    }
+
+    /// Replaces the IMAP cursors of the given folder.
+    fn save_cursors(&self, folder: &str, cursors: &Cursors) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        if snapshot.cursors != *cursors {
+            snapshot.cursors = *cursors;
+            self.save(folder, &snapshot)?;
+        }
+        Ok(())
+    }
+
+    /// Loads the snapshot of the given folder like [`Cache::load`], but
+    /// rebuilds it with the given function when the cache is found
+    /// corrupted, typically with [`Snapshot::reassociate`]. The
+    /// rebuilt snapshot is saved right away.
+    fn load_or_rebuild<F>(&self, folder: &str, rebuild: F) -> Result<Snapshot, EverestError>
+    where
+        Self: Sized,
+        F: FnOnce() -> Result<Snapshot, EverestError>,
+    {
+        match self.load(folder) {
+            Err(err @ EverestError::InvalidCacheError(..))
+            | Err(err @ EverestError::CorruptedCacheError(..)) => {
+                log::warn!("{}, rebuilding it", err);
+                let snapshot = rebuild()?;
+                self.save(folder, &snapshot)?;
+                Ok(snapshot)
+            }
+            result => result,
+        }
+    }
+
+    /// Exports the snapshots of the given folders as JSON, to inspect
+    /// the sync state or move it to another machine. See
+    /// [`Cache::import_json`] for the structure.
+    #[cfg(feature = "json")]
+    fn export_json<W: io::Write>(&self, folders: &[&str], writer: W) -> Result<(), EverestError>
+    where
+        Self: Sized,
+    {
+        json::export(self, folders, writer)
+    }
+
+    /// Imports snapshots exported with [`Cache::export_json`],
+    /// replacing the cached snapshots of the folders it contains, and
+    /// returns these folders. Nothing is saved unless the whole export
+    /// is valid. The export is structured as follows, flags being IMAP
+    /// flags or keywords, `orphaned_since` seconds since the epoch and
+    /// unknown cursors left out:
+    ///
+    /// ```json
+    /// {
+    ///   "version": 1,
+    ///   "folders": {
+    ///     "INBOX": {
+    ///       "imap": { "1": ["\\Seen"] },
+    ///       "maildir": { "1663512456.R1.host": ["\\Seen"] },
+    ///       "ids": [
+    ///         { "imap": "1", "maildir": "1663512456.R1.host" },
+    ///         { "imap": "2", "maildir": "1663512457.R2.host", "orphaned_since": 1663512458 }
+    ///       ],
+    ///       "hashes": { "1663512456.R1.host": "<hex sha-256 of the message>" },
+    ///       "cursors": { "uid_validity": 1663512000, "uid_next": 3, "highest_modseq": 12 },
+    ///       "journal": [
+    ///         { "time": 1663512458, "patch": ["imap add-flag 1 \\Seen", "maildir remove-msg 1663512457.R2.host"] },
+    ///         { "time": 1663512470, "patch": [], "errors": ["cannot fetch envelopes"] }
+    ///       ]
+    ///     }
+    ///   }
+    /// }
+    /// ```
+    #[cfg(feature = "json")]
+    fn import_json<R: io::Read>(&self, reader: R) -> Result<Vec<String>, EverestError>
+    where
+        Self: Sized,
+    {
+        json::import(self, reader)
+    }
+
+    /// Records a sync of the given folder in its journal, with the
+    /// patch it just applied and the errors it met.
+    fn record_sync(
+        &self,
+        folder: &str,
+        patch: &Patch,
+        errors: &[String],
+    ) -> Result<(), EverestError> {
+        let mut snapshot = self.load(folder)?;
+        snapshot.record(patch.clone(), errors.to_vec(), SystemTime::now());
+        self.save(folder, &snapshot)
+    }
+
+    /// Returns the syncs of the given folder recorded in its journal,
+    /// latest first, for frontends to show the sync history. The
+    /// journal is bounded by [`Cache::prune`].
+    fn history(&self, folder: &str) -> Result<Vec<JournalEntry>, EverestError> {
+        let mut journal = self.load(folder)?.journal;
+        journal.reverse();
+        Ok(journal)
+    }
+
+    /// Returns the last sync of the given folder recorded in its
+    /// journal, `None` if it was never synced or its journal was
+    /// pruned.
+    fn last_sync(&self, folder: &str) -> Result<Option<JournalEntry>, EverestError> {
+        Ok(self.load(folder)?.journal.pop())
+    }
+
+    /// Undoes the last sync of the given folder that applied changes,
+    /// by applying the inverse of its patch with the given function,
+    /// hunk by hunk in reverse order, and returns the undone entry.
+    /// Removed messages cannot be brought back and are left out.
+    ///
+    /// The snapshot follows the undone hunks, so that the next sync
+    /// does not redo them nor propagate them to the other side. If a
+    /// hunk fails, the hunks left to undo stay in the journal.
+    fn undo_last_sync<F>(
+        &self,
+        folder: &str,
+        mut apply: F,
+    ) -> Result<Option<JournalEntry>, EverestError>
+    where
+        Self: Sized,
+        F: FnMut(&Hunk) -> Result<(), EverestError>,
+    {
+        let mut snapshot = self.load(folder)?;
+        let index = match snapshot
+            .journal
+            .iter()
+            .rposition(|entry| !entry.patch.is_empty())
+        {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+        let entry = snapshot.journal.remove(index);
+
+        let mut hunks = entry.patch.clone();
+        while let Some(hunk) = hunks.pop() {
+            let inverse = match hunk.inverse() {
+                Some(inverse) => inverse,
+                None => {
+                    log::warn!("cannot undo {:?}: message removed", hunk);
+                    continue;
+                }
+            };
+            if let Err(err) = apply(&inverse) {
+                hunks.push(hunk);
+                let entry = JournalEntry {
+                    patch: hunks,
+                    ..entry
+                };
+                snapshot.journal.insert(index, entry);
+                self.save(folder, &snapshot)?;
+                return Err(err);
+            }
+            snapshot.apply_hunk(&inverse);
+        }
+
+        self.save(folder, &snapshot)?;
+        Ok(Some(entry))
+    }
+
+    /// Prunes the id mappings of the given folder whose messages are
+    /// gone from both sides for longer than the given retention. See
+    /// [`Snapshot::gc`].
+    fn gc(&self, folder: &str, retention: Duration) -> Result<GcReport, EverestError> {
+        let mut snapshot = self.load(folder)?;
+        let prev = snapshot.clone();
+        let report = snapshot.gc(retention, SystemTime::now());
+        if snapshot != prev {
+            self.save(folder, &snapshot)?;
+        }
+        Ok(report)
+    }
+
+    /// Drops the journal entries and the historical snapshots of the
+    /// given folder older than the given policy allows.
+    fn prune(&self, folder: &str, policy: &RetentionPolicy) -> Result<PruneReport, EverestError> {
+        let mut report = PruneReport::default();
+        let mut snapshot = self.load(folder)?;
+        report.journal = snapshot.prune(policy, SystemTime::now());
+        if report.journal > 0 {
+            self.save(folder, &snapshot)?;
+        }
+        if let Some(retention) = policy.history {
+            report.history = self.prune_history(folder, retention)?;
+        }
+        Ok(report)
+    }
+
+    /// Removes the historical snapshots of the given folder older than
+    /// the given retention, and returns how many were removed. Caches
+    /// keeping no history have none to remove.
+    fn prune_history(&self, _folder: &str, _retention: Duration) -> Result<usize, EverestError> {
+        Ok(0)
+    }
+}
+
+/// Envelopes of both sides as they were at the end of the last sync,
+/// used as the previous envelopes of the next sync.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub imap: Envelopes,
+    pub mdir: Envelopes,
+    pub ids: IdMapping,
+    pub hashes: ContentHashes,
+    pub cursors: Cursors,
+    /// Patches applied by the last syncs, oldest first.
+    pub journal: Vec<JournalEntry>,
+}
+
+impl Snapshot {
+    pub fn new(imap: Envelopes, mdir: Envelopes) -> Self {
+        Self {
+            imap,
+            mdir,
+            ids: IdMapping::default(),
+            hashes: ContentHashes::default(),
+            cursors: Cursors::default(),
+            journal: Vec::new(),
+        }
+    }
+
+    pub fn with_ids(mut self, ids: IdMapping) -> Self {
+        self.ids = ids;
+        self
+    }
+
+    pub fn with_cursors(mut self, cursors: Cursors) -> Self {
+        self.cursors = cursors;
+        self
+    }
+
+    /// Builds the patch between this snapshot and the given next
+    /// envelopes.
+    pub fn build_patch(
+        &self,
+        next_imap_envelopes: &Envelopes,
+        next_mdir_envelopes: &Envelopes,
+    ) -> Patch {
+        build_patch(
+            &self.imap,
+            next_imap_envelopes,
+            &self.mdir,
+            next_mdir_envelopes,
+        )
+    }
+
+    /// Iterates over the hunks of the patch between this snapshot and
+    /// the given next envelopes, see [`iter_patch`].
+    pub fn iter_patch<'a>(
+        &'a self,
+        next_imap_envelopes: &'a Envelopes,
+        next_mdir_envelopes: &'a Envelopes,
+    ) -> impl Iterator<Item = Hunk> + 'a {
+        iter_patch(
+            &self.imap,
+            next_imap_envelopes,
+            &self.mdir,
+            next_mdir_envelopes,
+        )
+    }
+
+    fn envelopes_mut(&mut self, side: &str) -> Option<&mut Envelopes> {
+        match side {
+            "imap" => Some(&mut self.imap),
+            "maildir" => Some(&mut self.mdir),
+            _ => None,
+        }
+    }
+
+    /// Iterates over the envelopes of both sides, sorted by side then
+    /// by id.
+    fn sides(&self) -> impl Iterator<Item = (&'static str, &Envelope)> {
+        [("imap", &self.imap), ("maildir", &self.mdir)]
+            .into_iter()
+            .flat_map(|(side, envelopes)| {
+                let mut envelopes = envelopes.values().collect::<Vec<_>>();
+                envelopes.sort_by(|a, b| a.id.cmp(&b.id));
+                envelopes.into_iter().map(move |envelope| (side, envelope))
+            })
+    }
+}
+
+/// Bidirectional mapping between the IMAP id and the maildir id of
+/// the same message. Clones share the mapping until changed, like
+/// [`Envelopes`].
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct IdMapping(Arc<IdMaps>);
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+struct IdMaps {
+    mdir_ids: HashMap<String, String>,
+    imap_ids: HashMap<String, String>,
+    /// When mappings, by IMAP id, were first found without message on
+    /// either side.
+    orphaned: HashMap<String, SystemTime>,
+}
+
+impl IdMapping {
+    pub fn len(&self) -> usize {
+        self.0.mdir_ids.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.mdir_ids.is_empty()
+    }
+
+    pub fn mdir_id(&self, imap_id: &str) -> Option<&str> {
+        self.0.mdir_ids.get(imap_id).map(String::as_str)
+    }
+
+    pub fn imap_id(&self, mdir_id: &str) -> Option<&str> {
+        self.0.imap_ids.get(mdir_id).map(String::as_str)
+    }
+
+    /// Maps the given ids, replacing any previous mapping of either
+    /// id.
+    pub fn insert(&mut self, imap_id: &str, mdir_id: &str) {
+        self.remove_imap_id(imap_id);
+        self.remove_mdir_id(mdir_id);
+        let maps = Arc::make_mut(&mut self.0);
+        maps.mdir_ids.insert(imap_id.to_owned(), mdir_id.to_owned());
+        maps.imap_ids.insert(mdir_id.to_owned(), imap_id.to_owned());
+    }
+
+    /// Removes the mapping of the given IMAP id, returning the maildir
+    /// id it was mapped to.
+    pub fn remove_imap_id(&mut self, imap_id: &str) -> Option<String> {
+        if !self.0.mdir_ids.contains_key(imap_id) {
+            return None;
+        }
+        let maps = Arc::make_mut(&mut self.0);
+        let mdir_id = maps.mdir_ids.remove(imap_id)?;
+        maps.imap_ids.remove(&mdir_id);
+        maps.orphaned.remove(imap_id);
+        Some(mdir_id)
+    }
+
+    /// Removes the mapping of the given maildir id, returning the IMAP
+    /// id it was mapped to.
+    pub fn remove_mdir_id(&mut self, mdir_id: &str) -> Option<String> {
+        if !self.0.imap_ids.contains_key(mdir_id) {
+            return None;
+        }
+        let maps = Arc::make_mut(&mut self.0);
+        let imap_id = maps.imap_ids.remove(mdir_id)?;
+        maps.mdir_ids.remove(&imap_id);
+        maps.orphaned.remove(&imap_id);
+        Some(imap_id)
+    }
+
+    /// Returns when the mapping of the given IMAP id was first found
+    /// without message on either side, see [`Snapshot::gc`].
+    pub fn orphaned_since(&self, imap_id: &str) -> Option<SystemTime> {
+        self.0.orphaned.get(imap_id).copied()
+    }
+
+    fn set_orphaned_since(&mut self, imap_id: &str, since: SystemTime) {
+        if self.0.mdir_ids.contains_key(imap_id) {
+            Arc::make_mut(&mut self.0)
+                .orphaned
+                .insert(imap_id.to_owned(), since);
+        }
+    }
+
+    fn clear_orphaned(&mut self, imap_id: &str) {
+        if self.0.orphaned.contains_key(imap_id) {
+            Arc::make_mut(&mut self.0).orphaned.remove(imap_id);
+        }
+    }
+
+    /// Iterates over the `(imap_id, mdir_id)` pairs, sorted by IMAP id.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        let mut ids = self
+            .0
+            .mdir_ids
+            .iter()
+            .map(|(imap_id, mdir_id)| (imap_id.as_str(), mdir_id.as_str()))
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.into_iter()
+    }
+}
+
+/// Directory backing the file-based caches, holding one file per
+/// folder, optionally compressed and encrypted.
+#[derive(Debug, Clone)]
+struct CacheDir {
+    path: PathBuf,
+    extension: &'static str,
+    /// zstd level the files are compressed with, if any.
+    #[cfg(feature = "compression")]
+    compression: Option<i32>,
+    #[cfg(feature = "encryption")]
+    encryption: Option<Encryption>,
+}
+
+impl CacheDir {
+    fn new(path: PathBuf, extension: &'static str) -> Self {
+        Self {
+            path,
+            extension,
+            #[cfg(feature = "compression")]
+            compression: None,
+            #[cfg(feature = "encryption")]
+            encryption: None,
+        }
+    }
+
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the file of the given folder. The extension
+    /// keeps special names like `.` or an empty folder from clashing
+    /// with the directory itself.
+    fn file_path(&self, folder: &str) -> PathBuf {
+        self.path
+            .join(format!("{}.{}", escape_level(folder), self.extension))
+    }
+
+    /// Returns the path of the lock shared by the folders. Not being a
+    /// folder file, it cannot clash with one.
+    fn lock_path(&self) -> PathBuf {
+        self.path.join(".lock")
+    }
+
+    /// Returns the folders having a file, sorted. Folders only left
+    /// with their previous generation are included, since
+    /// [`CacheDir::read`] falls back to it.
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(EverestError::ReadCacheError(err, self.path.clone())),
+        };
+        let extension = format!(".{}", self.extension);
+        let mut folders = BTreeSet::new();
+        for entry in entries {
+            let entry =
+                entry.map_err(|err| EverestError::ReadCacheError(err, self.path.clone()))?;
+            // escaped folders are always valid UTF-8
+            if let Some(name) = entry.file_name().to_str() {
+                let name = name.strip_suffix(".prev").unwrap_or(name);
+                if let Some(folder) = name.strip_suffix(&extension) {
+                    folders.insert(unescape_level(folder));
+                }
+            }
+        }
+        Ok(folders.into_iter().collect())
+    }
+
+    /// Returns the path of the previous generation of the file of the
+    /// given folder.
+    fn prev_path(&self, folder: &str) -> PathBuf {
+        let mut path = self.file_path(folder).into_os_string();
+        path.push(".prev");
+        PathBuf::from(path)
+    }
+
+    /// Removes the previous generation of the file of the given folder
+    /// if older than the given retention, and returns how many files
+    /// were removed.
+    fn prune_prev(&self, folder: &str, retention: Duration) -> Result<usize, EverestError> {
+        let path = self.prev_path(folder);
+        let modified = match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(EverestError::ReadCacheError(err, path)),
+        };
+        if !retention::is_expired(modified, retention, SystemTime::now()) {
+            return Ok(0);
+        }
+        fs::remove_file(&path).map_err(|err| EverestError::WriteCacheError(err, path))?;
+        Ok(1)
+    }
+
+    /// Reads the file of the given folder, `None` if the folder was
+    /// never synced. The checksum ending the file is verified and
+    /// stripped.
+    ///
+    /// The previous generation is read instead when the file is
+    /// missing, corrupted or truncated, which happens when a save is
+    /// interrupted.
+    fn read(&self, folder: &str) -> Result<Option<CacheContent>, EverestError> {
+        let prev = || match self.read_path(self.prev_path(folder)) {
+            Ok(Some(content)) if content.checked => {
+                log::warn!(
+                    "cache {} missing or corrupted, reading its previous generation",
+                    self.file_path(folder).display()
+                );
+                Some(content)
+            }
+            _ => None,
+        };
+
+        match self.read_path(self.file_path(folder)) {
+            Ok(None) => Ok(prev()),
+            Ok(Some(content)) if !content.checked => Ok(prev().or(Some(content))),
+            Err(err @ EverestError::CorruptedCacheError(..)) => prev().map(Some).ok_or(err),
+            result => result,
+        }
+    }
+
+    fn read_path(&self, path: PathBuf) -> Result<Option<CacheContent>, EverestError> {
+        let content = match fs::read(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(EverestError::ReadCacheError(err, path)),
+        };
+
+        #[cfg(feature = "encryption")]
+        let content = match &self.encryption {
+            Some(encryption) => encryption.decrypt(content, &path)?,
+            None if encryption::is_encrypted(&content) => {
+                return Err(EverestError::DecryptCacheError(path))
+            }
+            None => content,
+        };
+
+        // compressed files are read whether compression is enabled or
+        // not, the file telling
+        #[cfg(feature = "compression")]
+        let content = compression::decompress(content, &path)?;
+
+        match split_checksum(&content) {
+            Some((data, checksum)) if checksum == self::checksum(data).as_bytes() => {
+                Ok(Some(CacheContent {
+                    data: data.to_vec(),
+                    checked: true,
+                }))
+            }
+            Some(_) => Err(EverestError::CorruptedCacheError(path)),
+            // files of older versions have no checksum
+            None => Ok(Some(CacheContent {
+                data: content,
+                checked: false,
+            })),
+        }
+    }
+
+    /// Removes the file of the given folder and its previous
+    /// generation.
+    fn remove(&self, folder: &str) -> Result<(), EverestError> {
+        for path in [self.file_path(folder), self.prev_path(folder)] {
+            match fs::remove_file(&path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                    return Err(EverestError::WriteCacheError(err, path))
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the file of the given folder. The content is written to
+    /// a temporary file, synced to disk then renamed, so a crash never
+    /// leaves a truncated cache. The replaced file is kept as previous
+    /// generation.
+    fn write(&self, folder: &str, content: &[u8]) -> Result<(), EverestError> {
+        let path = self.file_path(folder);
+        let content = [content, checksum(content).as_bytes()].concat();
+        let content = content.as_slice();
+
+        // compressing after encrypting would not gain anything
+        #[cfg(feature = "compression")]
+        let compressed;
+        #[cfg(feature = "compression")]
+        let content = match self.compression {
+            Some(level) => {
+                compressed = compression::compress(content, level, &path)?;
+                &compressed
+            }
+            None => content,
+        };
+
+        #[cfg(feature = "encryption")]
+        let encrypted;
+        #[cfg(feature = "encryption")]
+        let content = match &self.encryption {
+            Some(encryption) => {
+                encrypted = encryption.encrypt(content, &path)?;
+                &encrypted
+            }
+            None => content,
+        };
+
+        fs::create_dir_all(&self.path)
+            .map_err(|err| EverestError::WriteCacheError(err, path.clone()))?;
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let prev_path = self.prev_path(folder);
+
+        fs::File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(content)?;
+                file.sync_all()
+            })
+            .and_then(|()| match fs::rename(&path, &prev_path) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            })
+            .and_then(|()| fs::rename(&tmp_path, &path))
+            .and_then(|()| sync_dir(&self.path))
+            .map_err(|err| EverestError::WriteCacheError(err, path))
+    }
+}
+
+/// Content of a cache file, without its checksum.
+struct CacheContent {
+    data: Vec<u8>,
+    /// Whether the content had a checksum, files of older versions
+    /// having none.
+    checked: bool,
+}
+
+const CHECKSUM_PREFIX: &[u8] = b"sha256 ";
+const CHECKSUM_LEN: usize = CHECKSUM_PREFIX.len() + 64 + 1;
+
+/// Builds the checksum line ending cache files.
+fn checksum(data: &[u8]) -> String {
+    format!("sha256 {}\n", content_hash(data))
+}
+
+/// Splits the given content into data and checksum line, `None` if
+/// the content does not end with a checksum line.
+fn split_checksum(content: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (data, checksum) = content.split_at(content.len().checked_sub(CHECKSUM_LEN)?);
+    if checksum.starts_with(CHECKSUM_PREFIX) && checksum.ends_with(b"\n") {
+        Some((data, checksum))
+    } else {
+        None
+    }
+}
+
+/// Converts the given time to seconds since the epoch, the way caches
+/// store times.
+fn to_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn from_secs(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+fn format_flags(flags: &Flags) -> String {
+    flags.to_imap_flags().join(" ")
+}
+
+#[cfg(test)]
+pub(crate) fn envelopes(envelopes: &[(&str, &[crate::Flag])]) -> Envelopes {
+    envelopes
+        .iter()
+        .map(|(id, flags)| Envelope::new(*id).with_flags(flags.iter().cloned()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn id_mapping_test() {
+        let mut ids = IdMapping::default();
+        ids.insert("1", "a");
+        ids.insert("2", "b");
+        ids.insert("3", "a");
+
+        assert_eq!(2, ids.len());
+        assert_eq!(None, ids.mdir_id("1"));
+        assert_eq!(Some("3"), ids.imap_id("a"));
+        assert_eq!(Some("2".to_owned()), ids.remove_mdir_id("b"));
+        assert_eq!(vec![("3", "a")], ids.iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn snapshot_copy_on_write_test() {
+        let mut prev = Snapshot::new(envelopes(&[("1", &[])]), envelopes(&[("1", &[])]));
+        prev.ids.insert("1", "a");
+        let mut next = prev.clone();
+        assert!(next.imap.is_shared_with(&prev.imap));
+
+        next.imap.insert("2".into(), Envelope::new("2"));
+        next.ids.remove_imap_id("1");
+        assert!(!next.imap.is_shared_with(&prev.imap));
+        assert!(next.mdir.is_shared_with(&prev.mdir));
+        assert_eq!(envelopes(&[("1", &[])]), prev.imap);
+        assert_eq!(Some("a"), prev.ids.mdir_id("1"));
+        assert_eq!(None, next.ids.mdir_id("1"));
+    }
+
+    #[test]
+    fn generations_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = CacheDir::new(dir.path().to_owned(), "cache");
+        let read = |folder| cache.read(folder).unwrap().map(|content| content.data);
+
+        cache.write("INBOX", b"first").unwrap();
+        assert!(!cache.prev_path("INBOX").exists());
+        cache.write("INBOX", b"second").unwrap();
+        assert_eq!(Some(b"second".to_vec()), read("INBOX"));
+
+        // interrupted saves fall back to the previous generation
+        fs::write(cache.file_path("INBOX"), b"").unwrap();
+        assert_eq!(Some(b"first".to_vec()), read("INBOX"));
+        fs::write(cache.file_path("INBOX"), b"garbage").unwrap();
+        assert_eq!(Some(b"first".to_vec()), read("INBOX"));
+        fs::remove_file(cache.file_path("INBOX")).unwrap();
+        assert_eq!(Some(b"first".to_vec()), read("INBOX"));
+
+        fs::remove_file(cache.prev_path("INBOX")).unwrap();
+        assert_eq!(None, read("INBOX"));
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+}
@@ -0,0 +1,107 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{Envelope, Envelopes};
+
+use super::Snapshot;
+
+impl Snapshot {
+    /// Rebuilds a snapshot from the current envelopes of both sides,
+    /// for when the cache is lost or corrupted: messages having the
+    /// same Message-ID are paired, as if they had been synced before.
+    /// Unpaired messages are left out, so the next sync copies them
+    /// instead of every message being duplicated.
+    ///
+    /// Paired messages are given the flags both sides agree on, so
+    /// that flags set on one side only are propagated to the other
+    /// rather than lost.
+    pub fn reassociate(
+        imap_envelopes: &Envelopes,
+        imap_message_ids: &HashMap<String, String>,
+        mdir_envelopes: &Envelopes,
+        mdir_message_ids: &HashMap<String, String>,
+    ) -> Self {
+        let mut snapshot = Self::default();
+
+        // duplicate Message-IDs are paired in id order
+        let mut mdir_ids = BTreeMap::<&str, Vec<&str>>::new();
+        let mut sorted_mdir_ids = mdir_message_ids.iter().collect::<Vec<_>>();
+        sorted_mdir_ids.sort_unstable();
+        for (mdir_id, message_id) in sorted_mdir_ids.into_iter().rev() {
+            if mdir_envelopes.contains_key(mdir_id.as_str()) {
+                mdir_ids.entry(message_id).or_default().push(mdir_id);
+            }
+        }
+
+        let mut imap_ids = imap_message_ids.iter().collect::<Vec<_>>();
+        imap_ids.sort_unstable();
+        for (imap_id, message_id) in imap_ids {
+            let imap_envelope = match imap_envelopes.get(imap_id.as_str()) {
+                Some(envelope) => envelope,
+                None => continue,
+            };
+            let mdir_envelope = match mdir_ids.get_mut(message_id.as_str()).and_then(Vec::pop) {
+                Some(mdir_id) => &mdir_envelopes[mdir_id],
+                None => continue,
+            };
+
+            let flags = imap_envelope.flags.intersection(&mdir_envelope.flags);
+            for (envelopes, id) in [
+                (&mut snapshot.imap, &imap_envelope.id),
+                (&mut snapshot.mdir, &mdir_envelope.id),
+            ] {
+                envelopes.insert(
+                    id.clone(),
+                    Envelope {
+                        id: id.clone(),
+                        flags: flags.clone(),
+                        metadata: None,
+                    },
+                );
+            }
+            snapshot.ids.insert(&imap_envelope.id, &mdir_envelope.id);
+        }
+
+        snapshot
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{cache::envelopes, Flag};
+
+    use super::*;
+
+    #[test]
+    fn reassociate_test() {
+        let imap = envelopes(&[
+            ("1", &[Flag::Seen]),
+            ("2", &[Flag::Seen, Flag::Flagged]),
+            ("3", &[]),
+        ]);
+        let mdir = envelopes(&[("a", &[Flag::Seen]), ("b", &[Flag::Seen]), ("c", &[])]);
+        let imap_message_ids = HashMap::from([
+            ("1".to_owned(), "x@y".to_owned()),
+            ("2".to_owned(), "dup@y".to_owned()),
+            ("3".to_owned(), "dup@y".to_owned()),
+        ]);
+        let mdir_message_ids = HashMap::from([
+            ("a".to_owned(), "x@y".to_owned()),
+            ("b".to_owned(), "dup@y".to_owned()),
+            ("c".to_owned(), "z@y".to_owned()),
+        ]);
+
+        let snapshot = Snapshot::reassociate(&imap, &imap_message_ids, &mdir, &mdir_message_ids);
+
+        assert_eq!(Some("a"), snapshot.ids.mdir_id("1"));
+        assert_eq!(Some("b"), snapshot.ids.mdir_id("2"));
+        assert_eq!(2, snapshot.ids.len());
+        assert_eq!(
+            envelopes(&[("1", &[Flag::Seen]), ("2", &[Flag::Seen])]),
+            snapshot.imap
+        );
+        assert_eq!(
+            envelopes(&[("a", &[Flag::Seen]), ("b", &[Flag::Seen])]),
+            snapshot.mdir
+        );
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    ops::AddAssign,
+    time::{Duration, SystemTime},
+};
+
+use super::Snapshot;
+
+/// How long caches keep their history, to bound their disk usage: the
+/// entries of the journal (see [`Cache::undo_last_sync`]) and the
+/// historical snapshots kept as previous generations. `None` keeps
+/// them forever.
+///
+/// [`Cache::undo_last_sync`]: super::Cache::undo_last_sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    pub journal: Option<Duration>,
+    pub history: Option<Duration>,
+}
+
+impl RetentionPolicy {
+    /// Keeps both the journal and the history for the given number of
+    /// days, forever when too long for a [`Duration`].
+    pub fn days(days: u64) -> Self {
+        let retention = days.checked_mul(24 * 60 * 60).map(Duration::from_secs);
+        Self {
+            journal: retention,
+            history: retention,
+        }
+    }
+
+    pub fn keep_all() -> Self {
+        Self {
+            journal: None,
+            history: None,
+        }
+    }
+}
+
+impl Default for RetentionPolicy {
+    /// Keeps 30 days of journal and history.
+    fn default() -> Self {
+        Self::days(30)
+    }
+}
+
+/// History dropped by [`Cache::prune`](super::Cache::prune).
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruneReport {
+    /// Number of journal entries dropped.
+    pub journal: usize,
+    /// Number of historical snapshots dropped.
+    pub history: usize,
+}
+
+impl PruneReport {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl AddAssign for PruneReport {
+    fn add_assign(&mut self, other: Self) {
+        self.journal += other.journal;
+        self.history += other.history;
+    }
+}
+
+impl Snapshot {
+    /// Drops the journal entries older than the given policy allows,
+    /// and returns how many were dropped.
+    pub fn prune(&mut self, policy: &RetentionPolicy, now: SystemTime) -> usize {
+        let retention = match policy.journal {
+            Some(retention) => retention,
+            None => return 0,
+        };
+        let len = self.journal.len();
+        self.journal
+            .retain(|entry| now.duration_since(entry.time).unwrap_or_default() < retention);
+        len - self.journal.len()
+    }
+}
+
+/// Tells whether the given historical snapshot, last modified at the
+/// given time, is older than the given retention.
+pub(super) fn is_expired(modified: SystemTime, retention: Duration, now: SystemTime) -> bool {
+    now.duration_since(modified).unwrap_or_default() >= retention
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{
+        cache::{Cache, FileCache},
+        Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn prune_test() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        let patch = vec![Hunk::new(Side::Left, HunkKind::AddMsg("1".into()))];
+        let mut snapshot = Snapshot::default();
+        snapshot.record(patch.clone(), vec![], now - 3 * day);
+        snapshot.record(patch.clone(), vec![], now - day);
+
+        assert_eq!(0, snapshot.prune(&RetentionPolicy::keep_all(), now));
+        assert_eq!(1, snapshot.prune(&RetentionPolicy::days(2), now));
+        assert_eq!(now - day, snapshot.journal[0].time);
+        assert_eq!(1, snapshot.prune(&RetentionPolicy::days(0), now));
+    }
+
+    #[test]
+    fn prune_history_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = FileCache::new(dir.path());
+        cache.save("INBOX", &Snapshot::default()).unwrap();
+        cache.save("INBOX", &Snapshot::default()).unwrap();
+        cache
+            .record_sync(
+                "INBOX",
+                &vec![Hunk::new(Side::Left, HunkKind::AddMsg("1".into()))],
+                &[],
+            )
+            .unwrap();
+
+        let mut prev = cache.file_path("INBOX").into_os_string();
+        prev.push(".prev");
+        let old = SystemTime::now() - Duration::from_secs(3 * 24 * 60 * 60);
+        fs::File::options()
+            .write(true)
+            .open(&prev)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        let policy = RetentionPolicy {
+            journal: None,
+            history: Some(Duration::from_secs(24 * 60 * 60)),
+        };
+        let report = cache.prune("INBOX", &policy).unwrap();
+        assert_eq!(
+            PruneReport {
+                journal: 0,
+                history: 1
+            },
+            report
+        );
+        assert!(!fs::exists(&prev).unwrap());
+        assert!(cache.prune("INBOX", &policy).unwrap().is_empty());
+
+        let report = cache.prune("INBOX", &RetentionPolicy::days(0)).unwrap();
+        assert_eq!(1, report.journal);
+        assert!(cache.load("INBOX").unwrap().journal.is_empty());
+    }
+}
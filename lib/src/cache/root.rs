@@ -0,0 +1,178 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    folder::{escape_level, unescape_level},
+    EverestError,
+};
+
+#[cfg(feature = "sqlite")]
+use super::SqliteCache;
+use super::{Cache, FileCache, PruneReport, RetentionPolicy};
+
+/// Directory holding the caches of several accounts, one directory
+/// per account, so that a single process can sync many accounts
+/// without their caches colliding. Account names are escaped, so any
+/// name (like an email address) is a valid identifier.
+///
+/// The history kept by the caches is bounded by a retention policy,
+/// which can be set per account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheRoot {
+    path: PathBuf,
+    retention: RetentionPolicy,
+    account_retentions: HashMap<String, RetentionPolicy>,
+}
+
+impl CacheRoot {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self {
+            path: path.into(),
+            retention: RetentionPolicy::default(),
+            account_retentions: HashMap::new(),
+        }
+    }
+
+    /// Sets the retention policy of the accounts without their own,
+    /// 30 days by default.
+    pub fn with_retention(mut self, policy: RetentionPolicy) -> Self {
+        self.retention = policy;
+        self
+    }
+
+    /// Sets the retention policy of the given account.
+    pub fn with_account_retention<S: Into<String>>(
+        mut self,
+        account: S,
+        policy: RetentionPolicy,
+    ) -> Self {
+        self.account_retentions.insert(account.into(), policy);
+        self
+    }
+
+    /// Returns the retention policy of the given account.
+    pub fn retention(&self, account: &str) -> RetentionPolicy {
+        self.account_retentions
+            .get(account)
+            .copied()
+            .unwrap_or(self.retention)
+    }
+
+    /// Prunes all the folders of the given cache of the given account
+    /// following its retention policy. See [`Cache::prune`].
+    pub fn prune<C: Cache>(&self, account: &str, cache: &C) -> Result<PruneReport, EverestError> {
+        let policy = self.retention(account);
+        let mut report = PruneReport::default();
+        for folder in cache.folders()? {
+            report += cache.prune(&folder, &policy)?;
+        }
+        Ok(report)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the cache directory of the given account.
+    pub fn account_dir(&self, account: &str) -> PathBuf {
+        self.path.join(escape_level(account))
+    }
+
+    /// Returns the file cache of the given account.
+    pub fn file_cache(&self, account: &str) -> FileCache {
+        FileCache::new(self.account_dir(account))
+    }
+
+    /// Opens the SQLite cache of the given account, at `cache.db` in
+    /// its directory.
+    #[cfg(feature = "sqlite")]
+    pub fn sqlite_cache(&self, account: &str) -> Result<SqliteCache, EverestError> {
+        SqliteCache::open(self.account_dir(account).join("cache.db"))
+    }
+
+    /// Returns the accounts having a cache directory, sorted.
+    pub fn accounts(&self) -> Result<Vec<String>, EverestError> {
+        let entries = match fs::read_dir(&self.path) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(EverestError::ReadCacheError(err, self.path.clone())),
+        };
+        let mut accounts = BTreeSet::new();
+        for entry in entries {
+            let entry =
+                entry.map_err(|err| EverestError::ReadCacheError(err, self.path.clone()))?;
+            let is_dir = entry
+                .file_type()
+                .map_err(|err| EverestError::ReadCacheError(err, entry.path()))?
+                .is_dir();
+            // escaped accounts are always valid UTF-8
+            if let (true, Some(name)) = (is_dir, entry.file_name().to_str()) {
+                accounts.insert(unescape_level(name));
+            }
+        }
+        Ok(accounts.into_iter().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{
+        cache::{Cache, Snapshot},
+        Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn accounts_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CacheRoot::new(dir.path().join("everest"));
+        assert!(root.accounts().unwrap().is_empty());
+
+        let me = root.file_cache("me@example.org");
+        let other = root.file_cache("../other");
+        me.save("INBOX", &Snapshot::default()).unwrap();
+        other.save("INBOX", &Snapshot::default()).unwrap();
+        me.save("Sent", &Snapshot::default()).unwrap();
+        fs::write(root.path().join("notes"), "").unwrap();
+
+        assert_eq!(root.path().join("..%2Fother"), other.path());
+        assert_eq!(vec!["../other", "me@example.org"], root.accounts().unwrap());
+        assert_eq!(vec!["INBOX", "Sent"], me.folders().unwrap());
+        assert_eq!(vec!["INBOX"], other.folders().unwrap());
+    }
+
+    #[test]
+    fn retention_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = CacheRoot::new(dir.path())
+            .with_retention(RetentionPolicy::keep_all())
+            .with_account_retention("me", RetentionPolicy::days(1));
+        assert_eq!(RetentionPolicy::days(1), root.retention("me"));
+        assert_eq!(RetentionPolicy::keep_all(), root.retention("other"));
+
+        let mut snapshot = Snapshot::default();
+        let patch = vec![Hunk::new(Side::Left, HunkKind::AddMsg("1".into()))];
+        snapshot.record(
+            patch,
+            vec![],
+            SystemTime::now() - Duration::from_secs(2 * 24 * 60 * 60),
+        );
+        for account in ["me", "other"] {
+            let cache = root.file_cache(account);
+            cache.save("INBOX", &snapshot).unwrap();
+            cache.save("Sent", &snapshot).unwrap();
+        }
+
+        assert_eq!(2, root.prune("me", &root.file_cache("me")).unwrap().journal);
+        assert!(root
+            .prune("other", &root.file_cache("other"))
+            .unwrap()
+            .is_empty());
+    }
+}
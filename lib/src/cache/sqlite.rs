@@ -0,0 +1,598 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use rusqlite::{params, Connection, ErrorCode, OptionalExtension, ToSql};
+
+use crate::{Envelope, EverestError, Flags, Id};
+
+use super::{
+    escape_error, format_flags, format_hunk, from_secs, parse_hunk, to_secs, unescape_error, Cache,
+    Cursors, JournalEntry, Snapshot,
+};
+
+/// Migrations of the database schema, the schema version (kept in
+/// the `user_version` pragma) being the number of migrations applied.
+/// Migrations must never be edited once released, only appended.
+const MIGRATIONS: &[&str] = &[
+    // the first databases had no version, hence the IF NOT EXISTS
+    "CREATE TABLE IF NOT EXISTS envelopes (
+        side TEXT NOT NULL,
+        id TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        PRIMARY KEY (side, id)
+    );",
+    "CREATE TABLE IF NOT EXISTS ids (
+        imap_id TEXT NOT NULL PRIMARY KEY,
+        mdir_id TEXT NOT NULL UNIQUE
+    );",
+    // partitions by folder, the state of unpartitioned databases
+    // going to INBOX, the only folder every server has
+    "CREATE TABLE folder_envelopes (
+        folder TEXT NOT NULL,
+        side TEXT NOT NULL,
+        id TEXT NOT NULL,
+        flags TEXT NOT NULL,
+        PRIMARY KEY (folder, side, id)
+    );
+    INSERT INTO folder_envelopes SELECT 'INBOX', side, id, flags FROM envelopes;
+    DROP TABLE envelopes;
+    ALTER TABLE folder_envelopes RENAME TO envelopes;
+    CREATE TABLE folder_ids (
+        folder TEXT NOT NULL,
+        imap_id TEXT NOT NULL,
+        mdir_id TEXT NOT NULL,
+        PRIMARY KEY (folder, imap_id),
+        UNIQUE (folder, mdir_id)
+    );
+    INSERT INTO folder_ids SELECT 'INBOX', imap_id, mdir_id FROM ids;
+    DROP TABLE ids;
+    ALTER TABLE folder_ids RENAME TO ids;",
+    "ALTER TABLE ids ADD COLUMN orphaned_since INTEGER;",
+    "CREATE TABLE cursors (
+        folder TEXT NOT NULL PRIMARY KEY,
+        uid_validity INTEGER,
+        uid_next INTEGER,
+        highest_modseq INTEGER
+    );",
+    "CREATE TABLE hashes (
+        folder TEXT NOT NULL,
+        mdir_id TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (folder, mdir_id)
+    );
+    CREATE INDEX hashes_hash ON hashes (folder, hash);",
+    // patches keep one hunk per line
+    "CREATE TABLE journal (
+        folder TEXT NOT NULL,
+        seq INTEGER NOT NULL,
+        time INTEGER NOT NULL,
+        patch TEXT NOT NULL,
+        PRIMARY KEY (folder, seq)
+    );",
+    // errors are escaped, one per line
+    "ALTER TABLE journal ADD COLUMN errors TEXT NOT NULL DEFAULT '';",
+];
+
+/// Keeps the snapshots of the last sync of all folders in a SQLite
+/// database. Saves happen in a single transaction, so a crash always
+/// leaves either the previous or the next snapshot. This is the
+/// default cache.
+#[derive(Debug)]
+pub struct SqliteCache {
+    path: PathBuf,
+    conn: Connection,
+}
+
+impl SqliteCache {
+    /// Opens the database at the given path, creating it if needed
+    /// and migrating its schema to the current version.
+    pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self, EverestError> {
+        let path = path.into();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)
+                .map_err(|err| EverestError::WriteCacheError(err, path.clone()))?;
+        }
+        let conn = Connection::open(&path)
+            .map_err(|err| EverestError::SqliteCacheError(err, path.clone()))?;
+        let cache = Self { path, conn };
+        cache.check()?;
+        cache.migrate()?;
+        Ok(cache)
+    }
+
+    /// Checks the integrity of the database, so that a corrupted
+    /// cache is reported as such and can be rebuilt.
+    fn check(&self) -> Result<(), EverestError> {
+        let corrupted = || EverestError::CorruptedCacheError(self.path.clone());
+        let result: String = self
+            .conn
+            .query_row("PRAGMA quick_check", [], |row| row.get(0))
+            .map_err(|err| match err.sqlite_error_code() {
+                Some(ErrorCode::DatabaseCorrupt | ErrorCode::NotADatabase) => corrupted(),
+                _ => self.error(err),
+            })?;
+        if result != "ok" {
+            return Err(corrupted());
+        }
+        Ok(())
+    }
+
+    /// Returns the schema version of the database.
+    pub fn version(&self) -> Result<u32, EverestError> {
+        self.conn
+            .query_row("PRAGMA user_version", [], |row| row.get(0))
+            .map_err(|err| self.error(err))
+    }
+
+    /// Applies the missing migrations in a single transaction. A
+    /// database written by a newer version is refused rather than
+    /// misread.
+    fn migrate(&self) -> Result<(), EverestError> {
+        let version = self.version()?;
+        if version as usize > MIGRATIONS.len() {
+            return Err(EverestError::UnsupportedCacheVersionError(
+                version,
+                self.path.clone(),
+            ));
+        }
+        if version as usize == MIGRATIONS.len() {
+            return Ok(());
+        }
+
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|err| self.error(err))?;
+        for migration in &MIGRATIONS[version as usize..] {
+            tx.execute_batch(migration).map_err(|err| self.error(err))?;
+        }
+        tx.pragma_update(None, "user_version", MIGRATIONS.len() as u32)
+            .map_err(|err| self.error(err))?;
+        tx.commit().map_err(|err| self.error(err))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the path of the lock to take around a sync, see
+    /// [`CacheLock`](super::CacheLock). Transactions keep the database
+    /// consistent, but not a whole sync.
+    pub fn lock_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".lock");
+        PathBuf::from(path)
+    }
+
+    fn error(&self, err: rusqlite::Error) -> EverestError {
+        EverestError::SqliteCacheError(err, self.path.clone())
+    }
+
+    fn query_id(&self, sql: &str, folder: &str, id: &str) -> Result<Option<String>, EverestError> {
+        self.conn
+            .query_row(sql, [folder, id], |row| row.get(0))
+            .optional()
+            .map_err(|err| self.error(err))
+    }
+
+    fn execute(&self, sql: &str, params: &[&dyn ToSql]) -> Result<(), EverestError> {
+        self.conn
+            .execute(sql, params)
+            .map(|_| ())
+            .map_err(|err| self.error(err))
+    }
+}
+
+impl Cache for SqliteCache {
+    fn load(&self, folder: &str) -> Result<Snapshot, EverestError> {
+        let mut snapshot = Snapshot::default();
+        let mut stmt = self
+            .conn
+            .prepare("SELECT side, id, flags FROM envelopes WHERE folder = ?1")
+            .map_err(|err| self.error(err))?;
+        let rows = stmt
+            .query_map([folder], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(|err| self.error(err))?;
+        for row in rows {
+            let (side, id, flags) = row.map_err(|err| self.error(err))?;
+            let envelopes = snapshot.envelopes_mut(&side).ok_or_else(|| {
+                EverestError::InvalidCacheError(
+                    format!("invalid side {:?}", side),
+                    self.path.clone(),
+                )
+            })?;
+            let id = Id::from(id);
+            let flags = Flags::from_imap(&flags);
+            envelopes.insert(
+                id.clone(),
+                Envelope {
+                    id,
+                    flags,
+                    metadata: None,
+                },
+            );
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT imap_id, mdir_id, orphaned_since FROM ids WHERE folder = ?1")
+            .map_err(|err| self.error(err))?;
+        let rows = stmt
+            .query_map([folder], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<i64>>(2)?,
+                ))
+            })
+            .map_err(|err| self.error(err))?;
+        for row in rows {
+            let (imap_id, mdir_id, orphaned_since) = row.map_err(|err| self.error(err))?;
+            snapshot.ids.insert(&imap_id, &mdir_id);
+            if let Some(secs) = orphaned_since {
+                snapshot
+                    .ids
+                    .set_orphaned_since(&imap_id, from_secs(secs as u64));
+            }
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT mdir_id, hash FROM hashes WHERE folder = ?1")
+            .map_err(|err| self.error(err))?;
+        let rows = stmt
+            .query_map([folder], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+            })
+            .map_err(|err| self.error(err))?;
+        for row in rows {
+            let (mdir_id, hash) = row.map_err(|err| self.error(err))?;
+            snapshot.hashes.insert(&mdir_id, &hash);
+        }
+
+        let mut stmt = self
+            .conn
+            .prepare("SELECT time, patch, errors FROM journal WHERE folder = ?1 ORDER BY seq")
+            .map_err(|err| self.error(err))?;
+        let rows = stmt
+            .query_map([folder], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })
+            .map_err(|err| self.error(err))?;
+        for row in rows {
+            let (secs, patch, errors) = row.map_err(|err| self.error(err))?;
+            let patch = patch.lines().map(parse_hunk).collect::<Option<_>>();
+            let patch = patch.ok_or_else(|| {
+                EverestError::InvalidCacheError("invalid journal patch".into(), self.path.clone())
+            })?;
+            snapshot.journal.push(JournalEntry {
+                time: from_secs(secs as u64),
+                patch,
+                errors: errors.lines().map(unescape_error).collect(),
+            });
+        }
+
+        snapshot.cursors = self.cursors(folder)?;
+        Ok(snapshot)
+    }
+
+    /// Replaces the cached snapshot of the given folder in a single
+    /// transaction.
+    fn save(&self, folder: &str, snapshot: &Snapshot) -> Result<(), EverestError> {
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .map_err(|err| self.error(err))?;
+        tx.execute("DELETE FROM envelopes WHERE folder = ?1", [folder])
+            .and_then(|_| tx.execute("DELETE FROM ids WHERE folder = ?1", [folder]))
+            .and_then(|_| tx.execute("DELETE FROM hashes WHERE folder = ?1", [folder]))
+            .and_then(|_| tx.execute("DELETE FROM journal WHERE folder = ?1", [folder]))
+            .map_err(|err| self.error(err))?;
+        self.save_cursors(folder, &snapshot.cursors)?;
+        {
+            let mut stmt = tx
+                .prepare("INSERT INTO envelopes (folder, side, id, flags) VALUES (?1, ?2, ?3, ?4)")
+                .map_err(|err| self.error(err))?;
+            for (side, envelope) in snapshot.sides() {
+                stmt.execute(params![
+                    folder,
+                    side,
+                    envelope.id,
+                    format_flags(&envelope.flags)
+                ])
+                .map_err(|err| self.error(err))?;
+            }
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO ids (folder, imap_id, mdir_id, orphaned_since)
+                    VALUES (?1, ?2, ?3, ?4)",
+                )
+                .map_err(|err| self.error(err))?;
+            for (imap_id, mdir_id) in snapshot.ids.iter() {
+                let orphaned_since = snapshot
+                    .ids
+                    .orphaned_since(imap_id)
+                    .map(|since| to_secs(since) as i64);
+                stmt.execute(params![folder, imap_id, mdir_id, orphaned_since])
+                    .map_err(|err| self.error(err))?;
+            }
+            let mut stmt = tx
+                .prepare("INSERT INTO hashes (folder, mdir_id, hash) VALUES (?1, ?2, ?3)")
+                .map_err(|err| self.error(err))?;
+            for (mdir_id, hash) in snapshot.hashes.iter() {
+                stmt.execute(params![folder, mdir_id, hash])
+                    .map_err(|err| self.error(err))?;
+            }
+            let mut stmt = tx
+                .prepare(
+                    "INSERT INTO journal (folder, seq, time, patch, errors)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                )
+                .map_err(|err| self.error(err))?;
+            for (seq, entry) in snapshot.journal.iter().enumerate() {
+                let patch = entry.patch.iter().map(format_hunk).collect::<Vec<_>>();
+                let errors = entry.errors.iter().map(|err| escape_error(err));
+                let time = to_secs(entry.time) as i64;
+                stmt.execute(params![
+                    folder,
+                    seq as i64,
+                    time,
+                    patch.join("\n"),
+                    errors.collect::<Vec<_>>().join("\n")
+                ])
+                .map_err(|err| self.error(err))?;
+            }
+        }
+        tx.commit().map_err(|err| self.error(err))
+    }
+
+    /// Returns the folders having rows in any table, empty snapshots
+    /// leaving none.
+    fn folders(&self) -> Result<Vec<String>, EverestError> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT folder FROM envelopes
+                UNION SELECT folder FROM ids
+                UNION SELECT folder FROM hashes
+                UNION SELECT folder FROM cursors
+                UNION SELECT folder FROM journal
+                ORDER BY folder",
+            )
+            .map_err(|err| self.error(err))?;
+        let rows = stmt
+            .query_map([], |row| row.get(0))
+            .map_err(|err| self.error(err))?;
+        rows.collect::<Result<_, _>>()
+            .map_err(|err| self.error(err))
+    }
+
+    fn mdir_id(&self, folder: &str, imap_id: &str) -> Result<Option<String>, EverestError> {
+        self.query_id(
+            "SELECT mdir_id FROM ids WHERE folder = ?1 AND imap_id = ?2",
+            folder,
+            imap_id,
+        )
+    }
+
+    fn imap_id(&self, folder: &str, mdir_id: &str) -> Result<Option<String>, EverestError> {
+        self.query_id(
+            "SELECT imap_id FROM ids WHERE folder = ?1 AND mdir_id = ?2",
+            folder,
+            mdir_id,
+        )
+    }
+
+    fn insert_ids(&self, folder: &str, imap_id: &str, mdir_id: &str) -> Result<(), EverestError> {
+        // REPLACE drops the rows conflicting on either id
+        self.execute(
+            "INSERT OR REPLACE INTO ids (folder, imap_id, mdir_id) VALUES (?1, ?2, ?3)",
+            params![folder, imap_id, mdir_id],
+        )
+    }
+
+    fn remove_imap_id(&self, folder: &str, imap_id: &str) -> Result<(), EverestError> {
+        self.execute(
+            "DELETE FROM ids WHERE folder = ?1 AND imap_id = ?2",
+            params![folder, imap_id],
+        )
+    }
+
+    fn remove_mdir_id(&self, folder: &str, mdir_id: &str) -> Result<(), EverestError> {
+        self.execute(
+            "DELETE FROM ids WHERE folder = ?1 AND mdir_id = ?2",
+            params![folder, mdir_id],
+        )
+    }
+
+    fn content_hash(&self, folder: &str, mdir_id: &str) -> Result<Option<String>, EverestError> {
+        self.query_id(
+            "SELECT hash FROM hashes WHERE folder = ?1 AND mdir_id = ?2",
+            folder,
+            mdir_id,
+        )
+    }
+
+    fn insert_content_hash(
+        &self,
+        folder: &str,
+        mdir_id: &str,
+        hash: &str,
+    ) -> Result<(), EverestError> {
+        self.execute(
+            "INSERT OR REPLACE INTO hashes (folder, mdir_id, hash) VALUES (?1, ?2, ?3)",
+            params![folder, mdir_id, hash],
+        )
+    }
+
+    fn cursors(&self, folder: &str) -> Result<Cursors, EverestError> {
+        self.conn
+            .query_row(
+                "SELECT uid_validity, uid_next, highest_modseq FROM cursors WHERE folder = ?1",
+                [folder],
+                |row| {
+                    Ok(Cursors {
+                        uid_validity: row.get(0)?,
+                        uid_next: row.get(1)?,
+                        highest_modseq: row.get::<_, Option<i64>>(2)?.map(|modseq| modseq as u64),
+                    })
+                },
+            )
+            .optional()
+            .map(Option::unwrap_or_default)
+            .map_err(|err| self.error(err))
+    }
+
+    fn save_cursors(&self, folder: &str, cursors: &Cursors) -> Result<(), EverestError> {
+        if cursors.is_empty() {
+            return self.execute("DELETE FROM cursors WHERE folder = ?1", params![folder]);
+        }
+        self.execute(
+            "INSERT OR REPLACE INTO cursors (folder, uid_validity, uid_next, highest_modseq)
+            VALUES (?1, ?2, ?3, ?4)",
+            params![
+                folder,
+                cursors.uid_validity,
+                cursors.uid_next,
+                cursors.highest_modseq.map(|modseq| modseq as i64)
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{cache::envelopes, Flag, Hunk, HunkKind, Side};
+
+    use super::*;
+
+    #[test]
+    fn save_load_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SqliteCache::open(dir.path().join("everest").join("cache.db")).unwrap();
+        assert_eq!(Snapshot::default(), cache.load("INBOX").unwrap());
+
+        let mut snapshot = Snapshot::new(
+            envelopes(&[("1", &[Flag::Seen, Flag::Keyword("Work".into())])]),
+            envelopes(&[("a", &[]), ("b", &[Flag::Trashed])]),
+        )
+        .with_cursors(Cursors {
+            uid_validity: Some(42),
+            uid_next: Some(2),
+            highest_modseq: Some(u64::MAX),
+        });
+        snapshot.record(
+            vec![
+                Hunk::new(Side::Left, HunkKind::AddMsg("1".into())),
+                Hunk::new(Side::Right, HunkKind::RemoveFlag("b".into(), Flag::Seen)),
+            ],
+            vec!["cannot sync\nmessage 2".into(), "cannot sync".into()],
+            from_secs(1_700_000_000),
+        );
+        cache.save("INBOX", &snapshot).unwrap();
+        cache.save("INBOX", &snapshot).unwrap();
+        cache.save("Sent", &Snapshot::default()).unwrap();
+        drop(cache);
+
+        let cache = SqliteCache::open(dir.path().join("everest").join("cache.db")).unwrap();
+        assert_eq!(snapshot, cache.load("INBOX").unwrap());
+        assert_eq!(Snapshot::default(), cache.load("Sent").unwrap());
+        assert_eq!(vec!["INBOX"], cache.folders().unwrap());
+
+        cache.insert_content_hash("INBOX", "a", "hash").unwrap();
+        cache.insert_content_hash("INBOX", "a", "other").unwrap();
+        assert_eq!(
+            Some("other".into()),
+            cache.content_hash("INBOX", "a").unwrap()
+        );
+        assert_eq!(None, cache.content_hash("Sent", "a").unwrap());
+
+        cache.save_cursors("INBOX", &Cursors::default()).unwrap();
+        assert_eq!(Cursors::default(), cache.cursors("INBOX").unwrap());
+        assert_eq!(snapshot.imap, cache.load("INBOX").unwrap().imap);
+    }
+
+    #[test]
+    fn migrate_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.db");
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        conn.execute(
+            "INSERT INTO envelopes (side, id, flags) VALUES ('imap', '1', '\\Seen')",
+            [],
+        )
+        .unwrap();
+        drop(conn);
+
+        let cache = SqliteCache::open(&path).unwrap();
+        assert_eq!(MIGRATIONS.len() as u32, cache.version().unwrap());
+        assert_eq!(
+            envelopes(&[("1", &[Flag::Seen])]),
+            cache.load("INBOX").unwrap().imap
+        );
+        cache.insert_ids("INBOX", "1", "a").unwrap();
+
+        cache
+            .conn
+            .pragma_update(None, "user_version", MIGRATIONS.len() as u32 + 1)
+            .unwrap();
+        drop(cache);
+        assert!(matches!(
+            SqliteCache::open(&path),
+            Err(EverestError::UnsupportedCacheVersionError(..))
+        ));
+
+        fs::write(
+            &path,
+            b"not a database, but long enough to look like a header",
+        )
+        .unwrap();
+        assert!(matches!(
+            SqliteCache::open(&path),
+            Err(EverestError::CorruptedCacheError(..))
+        ));
+    }
+
+    #[test]
+    fn ids_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SqliteCache::open(dir.path().join("cache.db")).unwrap();
+
+        cache.insert_ids("INBOX", "1", "a").unwrap();
+        cache.insert_ids("INBOX", "2", "b").unwrap();
+        cache.insert_ids("INBOX", "3", "a").unwrap();
+        cache.insert_ids("Sent", "1", "a").unwrap();
+        cache.remove_mdir_id("INBOX", "b").unwrap();
+
+        assert_eq!(None, cache.mdir_id("INBOX", "1").unwrap());
+        assert_eq!(Some("3".to_owned()), cache.imap_id("INBOX", "a").unwrap());
+        assert_eq!(Some("1".to_owned()), cache.imap_id("Sent", "a").unwrap());
+        assert_eq!(1, cache.load("INBOX").unwrap().ids.len());
+
+        let report = cache.gc("INBOX", Duration::from_secs(60)).unwrap();
+        assert_eq!(1, report.retained);
+        assert!(cache
+            .load("INBOX")
+            .unwrap()
+            .ids
+            .orphaned_since("3")
+            .is_some());
+        assert_eq!(1, cache.gc("INBOX", Duration::ZERO).unwrap().pruned.len());
+        assert_eq!(None, cache.imap_id("INBOX", "a").unwrap());
+    }
+}
@@ -0,0 +1,54 @@
+use std::{env, ffi::OsString, path::PathBuf};
+
+use crate::EverestError;
+
+use super::CacheRoot;
+
+/// Returns the default cache directory of the given account,
+/// `$XDG_STATE_HOME/everest/<account>`, so that accounts never share
+/// their sync state. Without `XDG_STATE_HOME`, falls back to
+/// `~/.local/state` on Linux and to the local data directory
+/// elsewhere. File caches keep one file per folder in it, the SQLite
+/// cache is expected at `cache.db` in it.
+pub fn default_dir(account: &str) -> Result<PathBuf, EverestError> {
+    Ok(CacheRoot::from_state_dir()?.account_dir(account))
+}
+
+impl CacheRoot {
+    /// Returns the default root of the account caches,
+    /// `$XDG_STATE_HOME/everest`, see [`default_dir`].
+    pub fn from_state_dir() -> Result<Self, EverestError> {
+        state_dir(env::var_os("XDG_STATE_HOME"))
+            .map(|dir| Self::new(dir.join("everest")))
+            .ok_or(EverestError::FindStateDirError)
+    }
+}
+
+fn state_dir(xdg_state_home: Option<OsString>) -> Option<PathBuf> {
+    // relative paths are invalid per the XDG spec and must be ignored
+    xdg_state_home
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(dirs::state_dir)
+        .or_else(dirs::data_local_dir)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_dir_test() {
+        assert_eq!(
+            Some(PathBuf::from("/state")),
+            state_dir(Some("/state".into()))
+        );
+        assert_ne!(
+            Some(PathBuf::from("state")),
+            state_dir(Some("state".into()))
+        );
+
+        let dir = default_dir("../me@example.org").unwrap();
+        assert!(dir.ends_with("everest/..%2Fme@example.org"));
+    }
+}
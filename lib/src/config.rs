@@ -0,0 +1,415 @@
+//! Configuration of accounts, loaded from TOML with the `config`
+//! feature, shared by the CLI and library embedders.
+//!
+//! ```toml
+//! [accounts.work]
+//! folders = ["INBOX", "Sent"]
+//! conflict = "prefer_right"
+//!
+//! [accounts.work.left]
+//! backend = "imap"
+//! host = "imap.example.org"
+//! login = "alice@example.org"
+//! passwd_cmd = "pass show work"
+//!
+//! [accounts.work.right]
+//! backend = "maildir"
+//! path = "~/Mail/work"
+//! layout = { flatten = "." }
+//! ```
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::maildir::{Durability, QuotaPolicy, SymlinkPolicy},
+    cache::{self, RetentionPolicy},
+    folder::FolderLayout,
+    plan::Backend,
+    sync::ConflictPolicy,
+    EverestError,
+};
+
+/// Port of IMAP servers over TLS.
+const DEFAULT_IMAP_PORT: u16 = 993;
+
+/// Configuration of everest: its accounts, by name.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub accounts: BTreeMap<String, AccountConfig>,
+}
+
+/// Account synced between two backends, with its folders, policies
+/// and cache.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    pub left: BackendConfig,
+    pub right: BackendConfig,
+    /// Folders to sync, `INBOX` by default.
+    #[serde(default = "default_folders")]
+    pub folders: Vec<String>,
+    #[serde(default)]
+    pub conflict: ConflictPolicy,
+    /// Directory of the cache, see [`cache::default_dir`] by default.
+    pub cache: Option<PathBuf>,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+}
+
+/// Backend of a side of an account, told apart by its `backend` key.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "backend", rename_all = "snake_case")]
+pub enum BackendConfig {
+    Imap(ImapConfig),
+    Maildir(MaildirConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ImapConfig {
+    pub host: String,
+    #[serde(default = "default_imap_port")]
+    pub port: u16,
+    /// Upgrades a plain connection with `STARTTLS` instead of
+    /// connecting over TLS.
+    #[serde(default)]
+    pub starttls: bool,
+    pub login: String,
+    /// Command printing the password, like `pass show work`.
+    pub passwd_cmd: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaildirConfig {
+    /// Root of the maildirs, a leading `~` standing for the home
+    /// directory.
+    pub path: PathBuf,
+    #[serde(default)]
+    pub layout: FolderLayout,
+    #[serde(default)]
+    pub durability: Durability,
+    #[serde(default)]
+    pub symlinks: SymlinkPolicy,
+    /// Policy of the Maildir++ quota, ignored by default.
+    pub quota: Option<QuotaPolicy>,
+}
+
+/// Days the cache keeps its journal and history, 30 by default.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetentionConfig {
+    pub journal_days: Option<u64>,
+    pub history_days: Option<u64>,
+}
+
+fn default_folders() -> Vec<String> {
+    vec!["INBOX".into()]
+}
+
+fn default_imap_port() -> u16 {
+    DEFAULT_IMAP_PORT
+}
+
+impl Config {
+    /// Loads and validates the configuration file at the given path.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self, EverestError> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .map_err(|err| EverestError::ReadConfigError(err, path.to_owned()))?;
+        Self::from_toml(&toml).map_err(|err| match err {
+            EverestError::ParseConfigError(err, _) => {
+                EverestError::ParseConfigError(err, Some(path.to_owned()))
+            }
+            err => err,
+        })
+    }
+
+    /// Parses and validates the given TOML configuration. Paths
+    /// starting with `~` are expanded.
+    pub fn from_toml(toml: &str) -> Result<Self, EverestError> {
+        let mut config: Self =
+            toml::from_str(toml).map_err(|err| EverestError::ParseConfigError(err, None))?;
+        for account in config.accounts.values_mut() {
+            for backend in [&mut account.left, &mut account.right] {
+                if let BackendConfig::Maildir(maildir) = backend {
+                    maildir.path = expand_home(&maildir.path);
+                }
+            }
+            account.cache = account.cache.as_deref().map(expand_home);
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns the account of the given name.
+    pub fn account(&self, name: &str) -> Result<&AccountConfig, EverestError> {
+        self.accounts.get(name).ok_or_else(|| {
+            let names = self.accounts.keys().cloned().collect::<Vec<_>>();
+            invalid(format!(
+                "account {} not found, known accounts are: {}",
+                name,
+                names.join(", ")
+            ))
+        })
+    }
+
+    /// Checks the parts of the configuration TOML cannot, like empty
+    /// hosts or accounts sharing a cache.
+    pub fn validate(&self) -> Result<(), EverestError> {
+        if self.accounts.is_empty() {
+            return Err(invalid(
+                "no account found, add an [accounts.<name>] table".into(),
+            ));
+        }
+        let mut caches = HashSet::new();
+        for (name, account) in &self.accounts {
+            account
+                .validate()
+                .map_err(|reason| invalid(format!("account {}: {}", name, reason)))?;
+            if let Some(cache) = &account.cache {
+                if !caches.insert(cache) {
+                    return Err(invalid(format!(
+                        "account {}: cache {} is used by another account",
+                        name,
+                        cache.display()
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl AccountConfig {
+    /// Returns the directory of the cache of the account of the given
+    /// name.
+    pub fn cache_dir(&self, name: &str) -> Result<PathBuf, EverestError> {
+        match &self.cache {
+            Some(dir) => Ok(dir.clone()),
+            None => cache::default_dir(name),
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        self.left
+            .validate()
+            .map_err(|reason| format!("left {}", reason))?;
+        self.right
+            .validate()
+            .map_err(|reason| format!("right {}", reason))?;
+        if let (BackendConfig::Maildir(left), BackendConfig::Maildir(right)) =
+            (&self.left, &self.right)
+        {
+            if left.path == right.path {
+                return Err("left and right maildirs share the same path".into());
+            }
+        }
+
+        if self.folders.is_empty() {
+            return Err("folders must not be empty".into());
+        }
+        let mut folders = HashSet::new();
+        for folder in &self.folders {
+            if folder.trim().is_empty() {
+                return Err("folder names must not be empty".into());
+            }
+            if !folders.insert(folder) {
+                return Err(format!("folder {} is listed twice", folder));
+            }
+        }
+
+        for (key, days) in [
+            ("journal_days", self.retention.journal_days),
+            ("history_days", self.retention.history_days),
+        ] {
+            match days {
+                Some(0) => return Err(format!("retention {} must be at least 1", key)),
+                Some(days) if days_duration(days).is_none() => {
+                    let max = u64::MAX / SECS_PER_DAY;
+                    return Err(format!("retention {} must be at most {}", key, max));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl BackendConfig {
+    pub fn backend(&self) -> Backend {
+        match self {
+            Self::Imap(_) => Backend::Imap,
+            Self::Maildir(_) => Backend::Maildir,
+        }
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match self {
+            Self::Imap(imap) if imap.host.trim().is_empty() => {
+                Err("imap host must not be empty".into())
+            }
+            Self::Imap(imap) if imap.port == 0 => Err("imap port must not be 0".into()),
+            Self::Imap(imap) if imap.login.trim().is_empty() => {
+                Err("imap login must not be empty".into())
+            }
+            Self::Maildir(maildir) if maildir.path.as_os_str().is_empty() => {
+                Err("maildir path must not be empty".into())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl RetentionConfig {
+    /// Returns the retention policy of the cache, keeping 30 days of
+    /// what is not configured. Retentions too long for a [`Duration`],
+    /// rejected by [`Config::validate`], keep everything.
+    pub fn policy(&self) -> RetentionPolicy {
+        let default = RetentionPolicy::default();
+        RetentionPolicy {
+            journal: self
+                .journal_days
+                .map(days_duration)
+                .unwrap_or(default.journal),
+            history: self
+                .history_days
+                .map(days_duration)
+                .unwrap_or(default.history),
+        }
+    }
+}
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Returns the given number of days as a duration, `None` when too
+/// long.
+fn days_duration(days: u64) -> Option<Duration> {
+    days.checked_mul(SECS_PER_DAY).map(Duration::from_secs)
+}
+
+fn invalid(reason: String) -> EverestError {
+    EverestError::InvalidConfigError(reason)
+}
+
+/// Replaces a leading `~` with the home directory, when known.
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), dirs::home_dir()) {
+        (Ok(rest), Some(home)) => home.join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        [accounts.work]
+        folders = ["INBOX", "Sent"]
+        conflict = "prefer_right"
+        cache = "/cache/work"
+        retention = { journal_days = 7 }
+
+        [accounts.work.left]
+        backend = "imap"
+        host = "imap.example.org"
+        login = "alice@example.org"
+        passwd_cmd = "pass show work"
+
+        [accounts.work.right]
+        backend = "maildir"
+        path = "/mail/work"
+        layout = { flatten = "." }
+        quota = "enforce"
+    "#;
+
+    #[test]
+    fn config_test() {
+        let config = Config::from_toml(TOML).unwrap();
+        let account = config.account("work").unwrap();
+        assert_eq!(vec!["INBOX", "Sent"], account.folders);
+        assert_eq!(ConflictPolicy::PreferRight, account.conflict);
+        assert_eq!(
+            PathBuf::from("/cache/work"),
+            account.cache_dir("work").unwrap()
+        );
+        assert_eq!(
+            Some(Duration::from_secs(7 * 24 * 60 * 60)),
+            account.retention.policy().journal
+        );
+        assert_eq!(
+            RetentionPolicy::default().history,
+            account.retention.policy().history
+        );
+        let retention = RetentionConfig {
+            journal_days: Some(u64::MAX),
+            history_days: None,
+        };
+        assert_eq!(None, retention.policy().journal);
+
+        let BackendConfig::Imap(imap) = &account.left else {
+            panic!("left backend should be imap");
+        };
+        assert_eq!(DEFAULT_IMAP_PORT, imap.port);
+        assert_eq!(Some("pass show work"), imap.passwd_cmd.as_deref());
+        let BackendConfig::Maildir(maildir) = &account.right else {
+            panic!("right backend should be maildir");
+        };
+        assert_eq!(FolderLayout::Flatten(".".into()), maildir.layout);
+        assert_eq!(Some(QuotaPolicy::Enforce), maildir.quota);
+        assert_eq!(Durability::File, maildir.durability);
+
+        let err = config.account("home").unwrap_err();
+        assert_eq!(
+            "invalid config: account home not found, known accounts are: work",
+            err.to_string()
+        );
+    }
+
+    #[test]
+    fn invalid_config_test() {
+        let err = |toml: &str| Config::from_toml(toml).unwrap_err().to_string();
+        assert_eq!(
+            "invalid config: no account found, add an [accounts.<name>] table",
+            err("")
+        );
+        assert_eq!(
+            "invalid config: account work: left imap host must not be empty",
+            err(&TOML.replace("imap.example.org", " "))
+        );
+        assert_eq!(
+            "invalid config: account work: folder INBOX is listed twice",
+            err(&TOML.replace("\"Sent\"", "\"INBOX\""))
+        );
+        assert_eq!(
+            "invalid config: account work: retention journal_days must be at least 1",
+            err(&TOML.replace("journal_days = 7", "journal_days = 0"))
+        );
+        assert_eq!(
+            "invalid config: account work: retention journal_days must be at most 213503982334601",
+            err(&TOML.replace("journal_days = 7", "journal_days = 213503982334602"))
+        );
+
+        let toml = TOML
+            .replace("[accounts.work", "[accounts.home")
+            .replace("/mail/work", "/mail/home");
+        assert_eq!(
+            "invalid config: account work: cache /cache/work is used by another account",
+            err(&format!("{}{}", toml, TOML))
+        );
+
+        let err = Config::from_toml(&TOML.replace("host =", "hots =")).unwrap_err();
+        assert_eq!(crate::ErrorKind::Config, err.kind());
+        assert!(err.to_string().starts_with("cannot parse config"));
+    }
+}
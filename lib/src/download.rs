@@ -0,0 +1,364 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+#[cfg(feature = "imap")]
+use crate::{plan::Backend, BackendError, ErrorCode, ErrorKind};
+use crate::{pool::WorkerPool, EverestError};
+
+/// Default size of the parts bodies are downloaded in: 1 MiB.
+pub const DEFAULT_CHUNK_SIZE: u64 = 1024 * 1024;
+
+/// Source of message bodies readable in parts, like an IMAP
+/// connection with partial `FETCH`, the folder of the bodies being
+/// selected.
+pub trait BodySource {
+    /// Returns the size of the body of the given message, like its
+    /// `RFC822.SIZE`.
+    fn body_size(&mut self, uid: u32) -> Result<u64, EverestError>;
+
+    /// Fetches at most `len` bytes of the body of the given message,
+    /// from `offset`. Fewer bytes mean the end of the body.
+    fn fetch_part(&mut self, uid: u32, offset: u64, len: u64) -> Result<Vec<u8>, EverestError>;
+}
+
+#[cfg(feature = "imap")]
+impl<T: std::io::Read + Write> BodySource for imap::Session<T> {
+    fn body_size(&mut self, uid: u32) -> Result<u64, EverestError> {
+        let fetches = self
+            .uid_fetch(uid.to_string(), "RFC822.SIZE")
+            .map_err(|err| EverestError::FetchImapBodyError(err, uid))?;
+        let fetch = fetches.iter().find(|fetch| fetch.uid == Some(uid));
+        match fetch.and_then(|fetch| fetch.size) {
+            Some(size) => Ok(size.into()),
+            None => Err(missing_msg(uid)),
+        }
+    }
+
+    fn fetch_part(&mut self, uid: u32, offset: u64, len: u64) -> Result<Vec<u8>, EverestError> {
+        let query = format!("BODY.PEEK[]<{}.{}>", offset, len);
+        let span = crate::trace::span!(DEBUG, "imap_command", command = "UID FETCH", uid);
+        let _span = span.entered();
+        let fetches = self
+            .uid_fetch(uid.to_string(), query)
+            .map_err(|err| EverestError::FetchImapBodyError(err, uid))?;
+        // no fetch at all means the message is gone, like expunged
+        // since the download started
+        match fetches.iter().find(|fetch| fetch.uid == Some(uid)) {
+            Some(fetch) => Ok(fetch.body().unwrap_or_default().to_vec()),
+            None => Err(missing_msg(uid)),
+        }
+    }
+}
+
+/// Builds the error of a message the server sent nothing about.
+#[cfg(feature = "imap")]
+fn missing_msg(uid: u32) -> EverestError {
+    let err = BackendError::new(ErrorKind::NotFound, Backend::Imap, "fetch body");
+    let err = err.with_code(ErrorCode::ImapMsgMissing);
+    err.with_id(uid.to_string()).into()
+}
+
+/// Downloads the message bodies of a folder to a directory, part by
+/// part. Parts are appended to `<uid>.part` files as they come, so
+/// that a dropped connection resumes the download where it stopped
+/// instead of starting over, even in another run. Bodies of the size
+/// their source announced are renamed to `<uid>.eml`.
+///
+/// Files are kept in a `<folder>/<uidvalidity>` subdirectory, so that
+/// UIDs reused by another folder or once the UIDVALIDITY of the
+/// folder changed never resume the download of another message.
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    dir: PathBuf,
+    chunk_size: u64,
+    pool: Option<WorkerPool>,
+}
+
+impl Downloader {
+    /// Builds a downloader of the bodies of the given folder as of
+    /// the given UIDVALIDITY, the one of the folder selected by the
+    /// sources.
+    pub fn new<P: AsRef<Path>>(dir: P, folder: &str, uid_validity: u32) -> Self {
+        // folders are kept flat, hierarchies being escaped
+        let folder = folder.replace('%', "%25").replace('/', "%2F");
+        Self {
+            dir: dir.as_ref().join(folder).join(uid_validity.to_string()),
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            pool: None,
+        }
+    }
+
+    /// Sets the size of the parts bodies are downloaded in, 1 MiB by
+    /// default.
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Runs the downloads of [`Downloader::download_all`] on the
+    /// given pool, which bounds the number of sources used at once.
+    /// Each source gets its own thread otherwise.
+    pub fn with_pool(mut self, pool: WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Returns the directory of the bodies of the folder.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the path of the complete body of the given message.
+    pub fn body_path(&self, uid: u32) -> PathBuf {
+        self.dir.join(format!("{}.eml", uid))
+    }
+
+    fn part_path(&self, uid: u32) -> PathBuf {
+        self.dir.join(format!("{}.part", uid))
+    }
+
+    /// Downloads the body of the given message from the given source,
+    /// resuming a previous download, and returns the path of the
+    /// complete body. Bodies already downloaded are not fetched again.
+    ///
+    /// Fails when the body does not get the size the source announced,
+    /// like when the message is expunged during the download. Parts
+    /// longer than announced are removed, the next download starting
+    /// over.
+    pub fn download<S: BodySource>(
+        &self,
+        source: &mut S,
+        uid: u32,
+    ) -> Result<PathBuf, EverestError> {
+        let body_path = self.body_path(uid);
+        if body_path.is_file() {
+            return Ok(body_path);
+        }
+
+        let part_path = self.part_path(uid);
+        let write_error = |err| EverestError::DownloadBodyError(err, part_path.clone());
+        fs::create_dir_all(&self.dir).map_err(write_error)?;
+        let mut part = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&part_path)
+            .map_err(write_error)?;
+        let mut offset = part.metadata().map_err(write_error)?.len();
+
+        let size = source.body_size(uid)?;
+        while offset < size {
+            let len = self.chunk_size.min(size - offset);
+            let chunk = source.fetch_part(uid, offset, len)?;
+            if chunk.is_empty() {
+                break;
+            }
+            // each part is kept as soon as it is fetched
+            part.write_all(&chunk).map_err(write_error)?;
+            offset += chunk.len() as u64;
+        }
+
+        part.sync_all().map_err(write_error)?;
+        if offset != size {
+            if offset > size {
+                drop(part);
+                fs::remove_file(&part_path).map_err(write_error)?;
+            }
+            return Err(EverestError::IncompleteBodyError(uid, offset, size));
+        }
+        fs::rename(&part_path, &body_path).map_err(write_error)?;
+        Ok(body_path)
+    }
+
+    /// Downloads the bodies of the given messages on the given sources
+    /// (like several IMAP connections), each source downloading one
+    /// body at a time. A failing download does not stop the others:
+    /// results are returned in the order of the given messages. Nothing
+    /// is downloaded without sources.
+    pub fn download_all<S: BodySource + Send>(
+        &self,
+        sources: &mut [S],
+        uids: &[u32],
+    ) -> Vec<Result<PathBuf, EverestError>> {
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(uids.len()));
+        let sources = sources.iter_mut().map(Mutex::new).collect::<Vec<_>>();
+        let next_source = AtomicUsize::new(0);
+        let pool = match &self.pool {
+            Some(pool) => pool.clone(),
+            None => WorkerPool::new(sources.len()),
+        };
+
+        pool.run(sources.len(), || {
+            // each worker takes its own source
+            let mut source = match sources.get(next_source.fetch_add(1, Ordering::Relaxed)) {
+                Some(source) => source.lock().unwrap(),
+                None => return,
+            };
+            loop {
+                let i = next.fetch_add(1, Ordering::Relaxed);
+                let uid = match uids.get(i) {
+                    Some(&uid) => uid,
+                    None => break,
+                };
+                let result = self.download(&mut **source, uid);
+                results.lock().unwrap().push((i, result));
+            }
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io};
+
+    use crate::ErrorCode;
+
+    use super::*;
+
+    /// Source serving bodies from memory, dropping its connection
+    /// after the given number of parts. Sizes default to the ones of
+    /// the bodies.
+    #[derive(Default)]
+    struct MemorySource {
+        bodies: HashMap<u32, Vec<u8>>,
+        sizes: HashMap<u32, u64>,
+        parts_left: Option<usize>,
+        offsets: Vec<u64>,
+    }
+
+    impl BodySource for MemorySource {
+        fn body_size(&mut self, uid: u32) -> Result<u64, EverestError> {
+            Ok(self
+                .sizes
+                .get(&uid)
+                .copied()
+                .unwrap_or(self.bodies[&uid].len() as u64))
+        }
+
+        fn fetch_part(&mut self, uid: u32, offset: u64, len: u64) -> Result<Vec<u8>, EverestError> {
+            if let Some(parts_left) = self.parts_left.as_mut() {
+                if *parts_left == 0 {
+                    let err = io::Error::from(io::ErrorKind::ConnectionReset);
+                    return Err(EverestError::DownloadBodyError(err, PathBuf::new()));
+                }
+                *parts_left -= 1;
+            }
+            self.offsets.push(offset);
+            let body = &self.bodies[&uid];
+            let start = (offset as usize).min(body.len());
+            let end = (start + len as usize).min(body.len());
+            Ok(body[start..end].to_vec())
+        }
+    }
+
+    #[test]
+    fn download_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new(dir.path(), "INBOX", 1).with_chunk_size(4);
+        let body = b"Subject: resume\r\n\r\n".to_vec();
+        let mut source = MemorySource {
+            bodies: HashMap::from([(1, body.clone())]),
+            parts_left: Some(3),
+            ..Default::default()
+        };
+
+        // the connection drops after 3 parts, which are kept
+        assert!(downloader.download(&mut source, 1).is_err());
+        assert!(!downloader.body_path(1).exists());
+        assert_eq!(vec![0, 4, 8], source.offsets);
+
+        source.parts_left = None;
+        source.offsets.clear();
+        let path = downloader.download(&mut source, 1).unwrap();
+        assert_eq!(body, fs::read(&path).unwrap());
+        assert_eq!(vec![12, 16], source.offsets);
+        let expected = dir.path().join("INBOX").join("1").join("1.eml");
+        assert_eq!(expected, path);
+
+        // complete bodies are not fetched again
+        source.offsets.clear();
+        downloader.download(&mut source, 1).unwrap();
+        assert!(source.offsets.is_empty());
+
+        // another folder or UIDVALIDITY does not resume the body
+        let downloader = Downloader::new(dir.path(), "Lists/rust", 1);
+        assert!(!downloader.body_path(1).exists());
+        assert_eq!(dir.path().join("Lists%2Frust").join("1"), downloader.dir());
+        let downloader = Downloader::new(dir.path(), "INBOX", 2);
+        assert!(!downloader.body_path(1).exists());
+    }
+
+    #[test]
+    fn incomplete_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new(dir.path(), "INBOX", 1).with_chunk_size(4);
+        let mut source = MemorySource {
+            bodies: HashMap::from([(1, b"Subject: gone".to_vec())]),
+            sizes: HashMap::from([(1, 20)]),
+            ..Default::default()
+        };
+
+        // a body shorter than announced is not complete
+        let err = downloader.download(&mut source, 1).unwrap_err();
+        assert_eq!(ErrorCode::BodyIncomplete, err.code());
+        assert_eq!(
+            "cannot download body of message 1: got 13 bytes out of 20",
+            err.to_string()
+        );
+        assert!(!downloader.body_path(1).exists());
+
+        // a longer one is downloaded again
+        source.sizes.insert(1, 8);
+        assert!(downloader.download(&mut source, 1).is_err());
+        source.sizes.clear();
+        source.offsets.clear();
+        let path = downloader.download(&mut source, 1).unwrap();
+        assert_eq!(b"Subject: gone".to_vec(), fs::read(path).unwrap());
+        assert_eq!(0, source.offsets[0]);
+    }
+
+    #[cfg(feature = "imap")]
+    #[test]
+    fn imap_missing_test() {
+        use crate::backend::imap::Script;
+
+        let script = "a1 OK logged in\r\na2 OK fetched\r\n";
+        let client = imap::Client::new(Script(io::Cursor::new(script.into())));
+        let mut session = client.login("alice", "secret").map_err(|(err, _)| err);
+        let err = session.as_mut().unwrap().fetch_part(7, 0, 4).unwrap_err();
+        assert_eq!(ErrorCode::ImapMsgMissing, err.code());
+    }
+
+    #[test]
+    fn download_all_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let downloader = Downloader::new(dir.path(), "INBOX", 1).with_chunk_size(2);
+        let mut bodies = (1..=10)
+            .map(|uid| (uid, format!("body {}", uid).into_bytes()))
+            .collect::<HashMap<_, _>>();
+        bodies.insert(42, vec![]);
+        let mut sources = [0, 1].map(|_| MemorySource {
+            bodies: bodies.clone(),
+            ..Default::default()
+        });
+
+        let uids = [10, 3, 42, 1, 2, 4, 5, 6, 7, 8, 9];
+        let results = downloader.download_all(&mut sources, &uids);
+
+        assert_eq!(uids.len(), results.len());
+        for (uid, result) in uids.iter().zip(results) {
+            assert_eq!(bodies[uid], fs::read(result.unwrap()).unwrap());
+        }
+    }
+}
@@ -0,0 +1,343 @@
+//! Envelopes of messages: their ids and flags, as seen by one side
+//! of a sync.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ops::{Deref, DerefMut},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use crate::{flag::hash, EverestError, Flag, Flags, Metadata};
+
+/// Id of an envelope, shared between the envelope, the keys of its
+/// map and the hunks of patches so that it is allocated only once.
+pub type Id = Arc<str>;
+
+/// Message as seen by the sync: its id (the UID for IMAP, the unique
+/// name for maildirs), its flags and, with the `metadata` feature,
+/// its [`Metadata`].
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Envelope {
+    pub(crate) id: Id,
+    pub(crate) flags: Flags,
+    /// Boxed so that envelopes without metadata, the most common ones,
+    /// stay small.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub(crate) metadata: Option<Box<Metadata>>,
+}
+
+impl Envelope {
+    /// Builds the envelope of the given id, without flags.
+    pub fn new<I: Into<Id>>(id: I) -> Self {
+        Self {
+            id: id.into(),
+            flags: Flags::default(),
+            metadata: None,
+        }
+    }
+
+    /// Sets the metadata of the envelope, dropped when empty.
+    pub fn with_metadata(mut self, metadata: Metadata) -> Self {
+        self.metadata = Some(Box::new(metadata)).filter(|metadata| !metadata.is_empty());
+        self
+    }
+
+    pub fn with_flag(mut self, flag: Flag) -> Self {
+        self.flags.insert(flag);
+        self
+    }
+
+    /// Adds the given flags to the flags of the envelope.
+    pub fn with_flags<I: IntoIterator<Item = Flag>>(mut self, flags: I) -> Self {
+        self.flags.extend(flags);
+        self
+    }
+
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_deref()
+    }
+
+    pub fn date(&self) -> Option<SystemTime> {
+        self.metadata()?.date
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.metadata()?.subject.as_deref()
+    }
+
+    pub fn from(&self) -> Option<&str> {
+        self.metadata()?.from.as_deref()
+    }
+
+    pub fn message_id(&self) -> Option<&str> {
+        self.metadata()?.message_id.as_deref()
+    }
+
+    pub fn size(&self) -> Option<u64> {
+        self.metadata()?.size
+    }
+}
+
+/// Envelopes of a folder, by id.
+///
+/// Envelopes are copied on write: clones share the same map until
+/// one of them is changed, so that keeping the previous and next
+/// envelopes of several folders (in the cache, the diff and the
+/// snapshots of sessions) does not multiply the memory.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Envelopes(Arc<HashMap<Id, Envelope>>);
+
+impl Deref for Envelopes {
+    type Target = HashMap<Id, Envelope>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Copies the map first if it is shared with clones.
+impl DerefMut for Envelopes {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        Arc::make_mut(&mut self.0)
+    }
+}
+
+impl From<HashMap<Id, Envelope>> for Envelopes {
+    fn from(envelopes: HashMap<Id, Envelope>) -> Self {
+        Self(Arc::new(envelopes))
+    }
+}
+
+/// Envelopes are keyed by their own id: envelopes sharing an id
+/// replace each other.
+impl FromIterator<Envelope> for Envelopes {
+    fn from_iter<I: IntoIterator<Item = Envelope>>(envelopes: I) -> Self {
+        let mut result = Envelopes::default();
+        result.extend(envelopes);
+        result
+    }
+}
+
+impl Extend<Envelope> for Envelopes {
+    fn extend<I: IntoIterator<Item = Envelope>>(&mut self, envelopes: I) {
+        let envelopes = envelopes.into_iter();
+        let map = Arc::make_mut(&mut self.0);
+        map.reserve(envelopes.size_hint().0);
+        for envelope in envelopes {
+            map.insert(envelope.id.clone(), envelope);
+        }
+    }
+}
+
+/// Iterates over the envelopes, in no particular order.
+impl IntoIterator for Envelopes {
+    type Item = Envelope;
+    type IntoIter = std::collections::hash_map::IntoValues<Id, Envelope>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        Arc::unwrap_or_clone(self.0).into_values()
+    }
+}
+
+/// Iterates over the envelopes, in no particular order.
+impl<'a> IntoIterator for &'a Envelopes {
+    type Item = &'a Envelope;
+    type IntoIter = std::collections::hash_map::Values<'a, Id, Envelope>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.0.values()
+    }
+}
+
+/// Cheap summary of a set of envelopes: their count and the xor of
+/// the hashes of their ids and flags. Equal envelopes always have the
+/// same fingerprint, different ones almost never.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Fingerprint {
+    count: usize,
+    hash: u64,
+}
+
+impl Envelope {
+    /// Hashes the id and flags of the envelope, whatever the order of
+    /// its keywords.
+    fn fingerprint_hash(&self) -> u64 {
+        hash((&self.id, self.flags.digest()))
+    }
+}
+
+impl Envelopes {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self::from(HashMap::with_capacity(capacity))
+    }
+
+    /// Returns `true` if both envelopes share the same map, a clone
+    /// not changed since.
+    pub fn is_shared_with(&self, other: &Envelopes) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+
+    /// Computes the fingerprint of the envelopes, in a single pass
+    /// without allocating.
+    pub fn fingerprint(&self) -> Fingerprint {
+        Fingerprint {
+            count: self.len(),
+            hash: self
+                .values()
+                .fold(0, |hash, envelope| hash ^ envelope.fingerprint_hash()),
+        }
+    }
+
+    /// Computes the changes between this snapshot and the given next
+    /// envelopes, consumed one by one so that the next snapshot never
+    /// needs to be fully held in memory.
+    pub fn delta<I>(&self, next_envelopes: I) -> Result<EnvelopesDelta, EverestError>
+    where
+        I: IntoIterator<Item = Result<Envelope, EverestError>>,
+    {
+        let mut delta = EnvelopesDelta::default();
+        let mut seen_ids = HashSet::new();
+
+        for envelope in next_envelopes {
+            let envelope = envelope?;
+            if self.get(&envelope.id) != Some(&envelope) {
+                seen_ids.insert(envelope.id.clone());
+                delta.upserted.insert(envelope.id.clone(), envelope);
+            } else {
+                seen_ids.insert(envelope.id);
+            }
+        }
+
+        delta
+            .removed
+            .extend(self.keys().filter(|id| !seen_ids.contains(*id)).cloned());
+
+        Ok(delta)
+    }
+}
+
+/// Changes between two snapshots of envelopes: new or updated
+/// envelopes, and ids that disappeared.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct EnvelopesDelta {
+    upserted: Envelopes,
+    removed: HashSet<Id>,
+}
+
+impl EnvelopesDelta {
+    pub fn upserted(&self) -> &Envelopes {
+        &self.upserted
+    }
+
+    pub fn removed(&self) -> &HashSet<Id> {
+        &self.removed
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upserted.is_empty() && self.removed.is_empty()
+    }
+
+    /// Applies the changes to the given snapshot, turning it into the
+    /// next snapshot.
+    pub fn apply(&self, envelopes: &mut Envelopes) {
+        for id in &self.removed {
+            envelopes.remove(id);
+        }
+        for (id, envelope) in self.upserted.iter() {
+            envelopes.insert(id.clone(), envelope.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use crate::build_patch;
+
+    use super::*;
+
+    #[test]
+    fn envelope_test() {
+        let envelope = Envelope::new("1")
+            .with_flag(Flag::Seen)
+            .with_flags([Flag::Flagged, Flag::Keyword("Work".into())]);
+        assert_eq!("1", &**envelope.id());
+        assert_eq!(
+            &Flags::from_imap("\\Flagged  Work \\Seen"),
+            envelope.flags()
+        );
+
+        assert_eq!(
+            Flags::from_iter([Flag::Seen, Flag::Draft]),
+            Flags::from_chars("DSa")
+        );
+        assert!(Flags::from_imap("").is_empty());
+
+        assert_eq!(None, envelope.metadata());
+        let envelope = envelope.with_metadata(Metadata {
+            subject: Some("Hello".into()),
+            size: Some(42),
+            ..Metadata::default()
+        });
+        assert_eq!(Some("Hello"), envelope.subject());
+        assert_eq!(Some(42), envelope.size());
+        assert_eq!(None, envelope.date());
+        assert_eq!(None, envelope.with_metadata(Metadata::default()).metadata());
+    }
+
+    #[test]
+    fn fingerprint_test() {
+        let work = Flag::Keyword("Work".into());
+        let home = Flag::Keyword("Home".into());
+        let envelopes = |flags: &[Flag]| {
+            Envelopes::from_iter([Envelope::new("1").with_flags(flags.iter().cloned())])
+        };
+
+        let prev = envelopes(&[Flag::Seen, work.clone(), home.clone()]);
+        let next = envelopes(&[home, work, Flag::Seen]);
+        assert_eq!(prev.fingerprint(), next.fingerprint());
+        assert_ne!(prev.fingerprint(), envelopes(&[Flag::Seen]).fingerprint());
+        assert_ne!(prev.fingerprint(), Envelopes::default().fingerprint());
+        assert!(build_patch(&prev, &next, &next, &prev).is_empty());
+    }
+
+    #[test]
+    fn envelopes_iter_test() {
+        let envelope = |id: &str, flags: &[Flag]| Envelope::new(id).with_flags(flags.to_vec());
+
+        // envelopes sharing an id replace each other
+        let mut envelopes = Envelopes::from_iter([envelope("1", &[]), envelope("2", &[])]);
+        envelopes.extend([envelope("2", &[Flag::Seen]), envelope("3", &[])]);
+        assert_eq!(3, envelopes.len());
+        assert_eq!(Some(&envelope("2", &[Flag::Seen])), envelopes.get("2"));
+
+        let mut ids = (&envelopes)
+            .into_iter()
+            .map(|e| e.id.clone())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(vec![Id::from("1"), "2".into(), "3".into()], ids);
+        assert_eq!(envelopes, envelopes.clone().into_iter().collect());
+
+        // clones share their envelopes until changed
+        let mut clone = envelopes.clone();
+        assert!(clone.is_shared_with(&envelopes));
+        clone.remove("1");
+        assert!(!clone.is_shared_with(&envelopes));
+        assert_eq!(3, envelopes.len());
+    }
+}
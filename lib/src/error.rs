@@ -0,0 +1,427 @@
+//! Structured errors, for callers to tell failures apart without
+//! parsing their messages.
+
+use std::{error::Error, fmt, io, time::Duration};
+
+#[cfg(feature = "imap")]
+use crate::throttle::is_throttling_response;
+use crate::{plan::Backend, throttle::Backoff, EverestError};
+
+/// Delay before retrying to lock a cache used by another run.
+const LOCKED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Category of an [`crate::EverestError`], for programmatic matching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum ErrorKind {
+    /// A file system operation failed.
+    Io,
+    /// A message, file or directory could not be found.
+    NotFound,
+    /// Data read from a backend or the cache is invalid or corrupted.
+    InvalidData,
+    /// An IMAP command failed.
+    Imap,
+    /// The quota of a maildir is exceeded.
+    QuotaExceeded,
+    /// The cache is used by another run.
+    Locked,
+    /// The configuration does not allow the operation, or misses a
+    /// required part.
+    Config,
+    /// The cache cannot be encrypted, decrypted, compressed or
+    /// serialized.
+    Cache,
+    /// Any other failure, like watching a maildir.
+    Other,
+}
+
+/// Declares the error codes with their numbers, and their names.
+macro_rules! error_codes {
+    ($($(#[$attr:meta])* $name:ident = $number:literal,)+) => {
+        /// Stable code of an [`crate::EverestError`], like `E0101
+        /// UidMissing`, for scripts and GUIs to branch on failures
+        /// without parsing messages.
+        ///
+        /// Codes never change once released: new failures get new
+        /// codes, and codes of removed failures are not reused. The
+        /// hundreds tell the area of the failure: `E01xx` for IMAP,
+        /// `E02xx` for maildirs, `E03xx` for the cache, `E04xx` for
+        /// the configuration and the sync itself, and `E05xx` for
+        /// downloads.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[non_exhaustive]
+        #[repr(u16)]
+        pub enum ErrorCode {
+            $($(#[$attr])* $name = $number,)+
+        }
+
+        impl ErrorCode {
+            /// All the codes, ordered by number.
+            pub const ALL: &'static [ErrorCode] = &[$(Self::$name,)+];
+
+            /// Returns the name of the code, like `UidMissing`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Self::$name => stringify!($name),)+
+                }
+            }
+        }
+    };
+}
+
+error_codes! {
+    /// IMAP failure of a backend not telling its code.
+    ImapFailed = 100,
+    UidMissing = 101,
+    ImapBodyFetchFailed = 102,
+    ImapTraceOpenFailed = 103,
+    ImapMsgMissing = 104,
+    /// Maildir failure of a backend not telling its code.
+    MaildirFailed = 200,
+    MaildirEntryReadFailed = 201,
+    DovecotKeywordsReadFailed = 202,
+    DovecotKeywordsWriteFailed = 203,
+    MaildirDirCreateFailed = 204,
+    MaildirMsgDeliverFailed = 205,
+    MaildirDirReadFailed = 206,
+    MaildirMsgReadFailed = 207,
+    MaildirMsgMissing = 208,
+    MaildirFlagsUpdateFailed = 209,
+    MaildirMsgRemoveFailed = 210,
+    MbsyncStateReadFailed = 211,
+    MbsyncStateWriteFailed = 212,
+    MbsyncStateInvalid = 213,
+    MaildirsizeReadFailed = 214,
+    MaildirsizeWriteFailed = 215,
+    QuotaExceeded = 216,
+    MaildirRepairFailed = 217,
+    SymlinkNotAllowed = 218,
+    MaildirWatchFailed = 219,
+    CacheReadFailed = 301,
+    CacheWriteFailed = 302,
+    CacheInvalid = 303,
+    CacheVersionUnsupported = 304,
+    CacheCorrupted = 305,
+    CacheEncryptFailed = 306,
+    CacheDecryptFailed = 307,
+    CacheCompressFailed = 308,
+    CacheDecompressFailed = 309,
+    StateDirMissing = 310,
+    CacheLockFailed = 311,
+    CacheLocked = 312,
+    SqliteCacheFailed = 313,
+    CacheExportFailed = 314,
+    CacheImportFailed = 315,
+    ConfigReadFailed = 401,
+    ConfigParseFailed = 402,
+    ConfigInvalid = 403,
+    SyncIncomplete = 404,
+    SessionApplied = 405,
+    EventLogOpenFailed = 406,
+    NotifyCommandFailed = 407,
+    WebhookPostFailed = 408,
+    PatchWriteFailed = 409,
+    PatchInvalid = 410,
+    ReplicaIdsReadFailed = 411,
+    ReplicaIdsWriteFailed = 412,
+    BodyWriteFailed = 501,
+    BodyIncomplete = 502,
+}
+
+impl ErrorCode {
+    /// Returns the number of the code, like `101` for `E0101`.
+    pub fn number(self) -> u16 {
+        self as u16
+    }
+}
+
+/// Renders codes like `E0101 UidMissing`.
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{:04} {}", self.number(), self.name())
+    }
+}
+
+/// Failed operation of a backend, with the folder and message it
+/// failed on when known, and the underlying error as its source.
+#[derive(Debug)]
+pub struct BackendError {
+    kind: ErrorKind,
+    code: Option<ErrorCode>,
+    backend: Backend,
+    operation: &'static str,
+    folder: Option<String>,
+    id: Option<String>,
+    source: Option<Box<dyn Error + Send + Sync>>,
+}
+
+impl BackendError {
+    /// Builds the error of the given operation, like `"read entry"`,
+    /// phrased to follow "cannot".
+    pub fn new(kind: ErrorKind, backend: Backend, operation: &'static str) -> Self {
+        Self {
+            kind,
+            code: None,
+            backend,
+            operation,
+            folder: None,
+            id: None,
+            source: None,
+        }
+    }
+
+    /// Sets the code of the error, [`ErrorCode::ImapFailed`] or
+    /// [`ErrorCode::MaildirFailed`] by default.
+    pub fn with_code(mut self, code: ErrorCode) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub fn with_folder<F: Into<String>>(mut self, folder: F) -> Self {
+        self.folder = Some(folder.into());
+        self
+    }
+
+    pub fn with_id<I: Into<String>>(mut self, id: I) -> Self {
+        self.id = Some(id.into());
+        self
+    }
+
+    /// Sets the underlying error, like an [`imap::Error`] or an
+    /// [`std::io::Error`].
+    pub fn with_source<E: Into<Box<dyn Error + Send + Sync>>>(mut self, source: E) -> Self {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        match (self.code, self.backend) {
+            (Some(code), _) => code,
+            (None, Backend::Imap) => ErrorCode::ImapFailed,
+            (None, Backend::Maildir) => ErrorCode::MaildirFailed,
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    pub fn folder(&self) -> Option<&str> {
+        self.folder.as_deref()
+    }
+
+    pub fn id(&self) -> Option<&str> {
+        self.id.as_deref()
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot {} on {}", self.operation, self.backend)?;
+        if let Some(folder) = &self.folder {
+            write!(f, " folder {}", folder)?;
+        }
+        if let Some(id) = &self.id {
+            write!(f, " message {}", id)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for BackendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.source.as_deref().map(|source| source as _)
+    }
+}
+
+impl EverestError {
+    /// Returns `true` if the operation may succeed when retried, like
+    /// after a network blip, a throttling response or while another
+    /// run holds the cache. Failures like a rejected authentication
+    /// or a corrupted cache are not worth retrying. Syncs retry the
+    /// calls to their replicas failing with such errors when built
+    /// with [`crate::sync::SyncBuilder::retry`].
+    pub fn is_transient(&self) -> bool {
+        if let Self::CacheLockedError(_) = self {
+            return true;
+        }
+        sources(self).any(|source| {
+            #[cfg(feature = "imap")]
+            if let Some(err) = source.downcast_ref::<imap::Error>() {
+                return is_transient_imap(err);
+            }
+            source
+                .downcast_ref::<io::Error>()
+                .is_some_and(is_transient_io)
+        })
+    }
+
+    /// Returns how long to wait before retrying the operation, `None`
+    /// if it is not worth retrying. Throttled requests wait for the
+    /// initial delay of the default [`Backoff`], callers holding a
+    /// [`crate::throttle::Throttle`] should rather report them to it.
+    pub fn retry_after(&self) -> Option<Duration> {
+        if !self.is_transient() {
+            return None;
+        }
+        if let Self::CacheLockedError(_) = self {
+            return Some(LOCKED_RETRY_AFTER);
+        }
+        #[cfg(feature = "imap")]
+        let throttled = sources(self).any(|source| {
+            source
+                .downcast_ref::<imap::Error>()
+                .is_some_and(is_throttling_imap)
+        });
+        // only IMAP servers throttle clients
+        #[cfg(not(feature = "imap"))]
+        let throttled = false;
+        match throttled {
+            true => Some(Backoff::default().initial),
+            false => Some(Duration::ZERO),
+        }
+    }
+}
+
+/// Iterates over the chain of sources of the given error.
+fn sources<'a>(err: &'a (dyn Error + 'static)) -> impl Iterator<Item = &'a (dyn Error + 'static)> {
+    std::iter::successors(err.source(), |err| (*err).source())
+}
+
+fn is_transient_io(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe
+            | io::ErrorKind::UnexpectedEof
+    )
+}
+
+/// Lost connections and servers hanging up are worth a reconnection,
+/// unlike `NO` and `BAD` responses, authentication failures included,
+/// unless they throttle the client.
+#[cfg(feature = "imap")]
+fn is_transient_imap(err: &imap::Error) -> bool {
+    match err {
+        imap::Error::Io(err) => is_transient_io(err),
+        imap::Error::ConnectionLost | imap::Error::Bye(_) => true,
+        err => is_throttling_imap(err),
+    }
+}
+
+#[cfg(feature = "imap")]
+pub(crate) fn is_throttling_imap(err: &imap::Error) -> bool {
+    match err {
+        imap::Error::No(no) => is_throttling_response(&no.information),
+        imap::Error::Bad(bad) => is_throttling_response(&bad.information),
+        imap::Error::Bye(bye) => is_throttling_response(&bye.information),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn backend_error_test() {
+        let err = BackendError::new(ErrorKind::Io, Backend::Maildir, "read entry")
+            .with_folder("/mail/INBOX")
+            .with_source(io::Error::other("disk failure"));
+        assert_eq!(
+            "cannot read entry on maildir folder /mail/INBOX",
+            err.to_string()
+        );
+        assert_eq!("disk failure", err.source().unwrap().to_string());
+
+        let err = EverestError::from(
+            BackendError::new(ErrorKind::InvalidData, Backend::Imap, "find uid").with_id("42"),
+        );
+        assert_eq!(ErrorKind::InvalidData, err.kind());
+        assert_eq!("cannot find uid on imap message 42", err.to_string());
+        assert!(err.source().is_none());
+        assert_eq!(ErrorCode::ImapFailed, err.code());
+        assert_eq!(ErrorKind::NotFound, EverestError::FindStateDirError.kind());
+    }
+
+    #[test]
+    fn code_test() {
+        assert_eq!("E0101 UidMissing", ErrorCode::UidMissing.to_string());
+        assert_eq!(
+            "E0501 BodyWriteFailed",
+            ErrorCode::BodyWriteFailed.to_string()
+        );
+        let err = EverestError::CacheLockedError(PathBuf::from("cache"));
+        assert_eq!(ErrorCode::CacheLocked, err.code());
+        let err = BackendError::new(ErrorKind::Io, Backend::Maildir, "read entry")
+            .with_code(ErrorCode::MaildirEntryReadFailed);
+        assert_eq!(201, EverestError::from(err).code().number());
+
+        // codes are unique and ordered, so that numbers tell them apart
+        let numbers = ErrorCode::ALL.iter().map(|code| code.number());
+        let numbers = numbers.collect::<Vec<_>>();
+        assert!(numbers.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(numbers.iter().all(|number| (100..600).contains(number)));
+    }
+
+    #[test]
+    fn transient_test() {
+        let path = PathBuf::from("cache");
+        let io_err = |kind| EverestError::ReadCacheError(io::Error::from(kind), path.clone());
+
+        let err = io_err(io::ErrorKind::TimedOut);
+        assert!(err.is_transient());
+        assert_eq!(Some(Duration::ZERO), err.retry_after());
+        let err = io_err(io::ErrorKind::PermissionDenied);
+        assert!(!err.is_transient());
+        assert_eq!(None, err.retry_after());
+
+        let err = EverestError::CacheLockedError(path.clone());
+        assert_eq!(Some(LOCKED_RETRY_AFTER), err.retry_after());
+        assert!(!EverestError::CorruptedCacheError(path.clone()).is_transient());
+
+        let err = BackendError::new(ErrorKind::Io, Backend::Maildir, "read entry")
+            .with_source(io::Error::from(io::ErrorKind::Interrupted));
+        assert!(EverestError::from(err).is_transient());
+    }
+
+    #[cfg(feature = "imap")]
+    #[test]
+    fn imap_transient_test() {
+        use crate::backend::imap::Script;
+
+        let imap_err = |err| EverestError::FetchImapBodyError(err, 1);
+        assert!(imap_err(imap::Error::ConnectionLost).is_transient());
+        let err = imap::Error::Io(io::Error::from(io::ErrorKind::ConnectionReset));
+        assert!(imap_err(err).is_transient());
+        assert!(!imap_err(imap::Error::Append).is_transient());
+
+        // limits of the server are not throttling
+        let no = |response: &str| {
+            let script = format!("a1 OK logged in\r\na2 {}\r\n", response);
+            let stream = Script(io::Cursor::new(script.into_bytes()));
+            let session = imap::Client::new(stream).login("alice", "secret");
+            session.map_err(|(err, _)| err).unwrap().noop().unwrap_err()
+        };
+        assert!(imap_err(no("NO [THROTTLED] Slow down")).is_transient());
+        let err = imap_err(no("NO [LIMIT] Message too large"));
+        assert!(!err.is_transient());
+        assert_eq!(None, err.retry_after());
+    }
+}
@@ -0,0 +1,267 @@
+//! Log of the events of the sync as JSON lines, with the `json`
+//! feature, for users to post-process sync activity with `jq` and
+//! keep an audit trail.
+//!
+//! Each line is an object with the `timestamp` of the event (seconds
+//! since the epoch), the `account` when known, the `event` name, the
+//! `folder` and the fields of the event:
+//!
+//! ```text
+//! {"timestamp":1700000000.5,"account":"work","event":"hunk_applied","folder":"INBOX","hunk":{"side":"right","kind":"add_msg","id":"42"},"outcome":"applied"}
+//! ```
+
+use std::{
+    error::Error,
+    fs::{File, OpenOptions},
+    io::{LineWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Map, Value};
+
+use crate::{
+    flag::format_flag,
+    observer::SyncObserver,
+    report::{SideReport, SyncReport},
+    Envelopes, EverestError, Hunk, HunkKind, Side,
+};
+
+/// Observer writing the events of the sync as JSON lines, registered
+/// with [`crate::sync::SyncBuilder::observer`].
+///
+/// Failing to write a line does not fail the sync: the line is lost
+/// and a warning is logged.
+pub struct JsonLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+    account: Option<String>,
+}
+
+impl JsonLog {
+    /// Builds a log writing its lines to the given writer.
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(Box::new(writer)),
+            account: None,
+        }
+    }
+
+    /// Opens the log file at the given path, appending lines to it and
+    /// creating it if needed.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, EverestError> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| EverestError::OpenEventLogError(err, path.to_owned()))?;
+        Ok(Self::new(LineWriter::<File>::new(file)))
+    }
+
+    /// Tags the lines with the name of the given account, for logs
+    /// shared by several accounts.
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Writes the line of the given event, with its fields.
+    fn write(&self, event: &str, folder: &str, fields: Value) {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64();
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        if let Some(account) = &self.account {
+            line.insert("account".into(), account.as_str().into());
+        }
+        line.insert("event".into(), event.into());
+        line.insert("folder".into(), folder.into());
+        if let Value::Object(fields) = fields {
+            line.extend(fields);
+        }
+
+        let mut line = Value::Object(line).to_string();
+        line.push('\n');
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(err) = writer.write_all(line.as_bytes()) {
+            log::warn!("cannot write sync event to log: {}", err);
+        }
+    }
+}
+
+impl SyncObserver for JsonLog {
+    fn folder_started(&self, folder: &str) {
+        self.write("folder_started", folder, json!({}))
+    }
+
+    fn envelopes_listed(&self, folder: &str, side: Side, envelopes: &Envelopes) {
+        let fields = json!({ "side": side.to_string(), "count": envelopes.len() });
+        self.write("envelopes_listed", folder, fields)
+    }
+
+    fn hunk_generated(&self, folder: &str, hunk: &Hunk) {
+        self.write(
+            "hunk_generated",
+            folder,
+            json!({ "hunk": encode_hunk(hunk) }),
+        )
+    }
+
+    fn hunk_applied(&self, folder: &str, hunk: &Hunk) {
+        let fields = json!({ "hunk": encode_hunk(hunk), "outcome": "applied" });
+        self.write("hunk_applied", folder, fields)
+    }
+
+    fn error(&self, folder: &str, err: &(dyn Error + 'static)) {
+        let mut fields = json!({ "outcome": "failed", "error": encode_error(err) });
+        if let Some(err) = err.downcast_ref::<crate::report::HunkError>() {
+            fields["hunk"] = encode_hunk(&err.hunk);
+        }
+        self.write("error", folder, fields)
+    }
+
+    fn folder_finished(&self, folder: &str, report: &SyncReport) {
+        let fields = json!({
+            "outcome": report.outcome().name(),
+            "hunks": report.hunks,
+            "errors": report.errors.len(),
+            "left": encode_side(&report.left),
+            "right": encode_side(&report.right),
+            "duration": report.timings.total().as_secs_f64(),
+        });
+        self.write("folder_finished", folder, fields)
+    }
+}
+
+/// Encodes an error with its sources, like `cannot apply hunk: cannot
+/// find maildir message 42`.
+pub(crate) fn encode_error(err: &(dyn Error + 'static)) -> String {
+    let mut message = err.to_string();
+    let mut source = err.source();
+    while let Some(err) = source {
+        message.push_str(&format!(": {}", err));
+        source = err.source();
+    }
+    message
+}
+
+/// Encodes the changes applied to a side, like `{"added":1,
+/// "removed":0,"flags_changed":2,"bytes":1024}`.
+pub(crate) fn encode_side(report: &SideReport) -> Value {
+    json!({
+        "added": report.added,
+        "removed": report.removed,
+        "flags_changed": report.flags_changed,
+        "bytes": report.bytes,
+    })
+}
+
+/// Encodes a hunk like `{"side":"right","kind":"add_flag","id":"42",
+/// "flag":"\\Seen"}`.
+pub(crate) fn encode_hunk(hunk: &Hunk) -> Value {
+    let (kind, flag) = match &hunk.kind {
+        HunkKind::AddMsg(_) => ("add_msg", None),
+        HunkKind::RemoveMsg(_) => ("remove_msg", None),
+        HunkKind::AddFlag(_, flag) => ("add_flag", Some(flag)),
+        HunkKind::RemoveFlag(_, flag) => ("remove_flag", Some(flag)),
+    };
+    let mut hunk = json!({
+        "side": hunk.target.to_string(),
+        "kind": kind,
+        "id": hunk.kind.id().to_string(),
+    });
+    if let Some(flag) = flag {
+        hunk["flag"] = format_flag(flag).into();
+    }
+    hunk
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        cache::MemoryCache,
+        sync::{MemoryReplica, Sync},
+        Flag,
+    };
+
+    use super::*;
+
+    /// Writer sharing its buffer with the test.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn json_log_test() {
+        let buffer = Buffer::default();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[Flag::Seen])]))
+            .right(MemoryReplica::new(&[("2", &[Flag::Seen])]).with_failing("1"))
+            .cache_store(MemoryCache::new())
+            .observer(JsonLog::new(buffer.clone()).with_account("work"))
+            .build()
+            .unwrap();
+        sync.run().unwrap();
+
+        let lines = buffer.0.lock().unwrap();
+        let lines = String::from_utf8(lines.clone()).unwrap();
+        let mut lines = lines.lines().map(|line| {
+            let mut line: Value = serde_json::from_str(line).unwrap();
+            assert!(line["timestamp"].as_f64().unwrap() > 0.0);
+            assert_eq!("work", line["account"]);
+            assert_eq!("INBOX", line["folder"]);
+            line.as_object_mut().unwrap().remove("timestamp");
+            line
+        });
+
+        let line = lines.next().unwrap();
+        assert_eq!("folder_started", line["event"]);
+        let line = lines.next().unwrap();
+        assert_eq!("envelopes_listed", line["event"]);
+        assert_eq!("left", line["side"]);
+        assert_eq!(1, line["count"]);
+
+        let lines = lines.collect::<Vec<_>>();
+        let events = lines.iter().map(|line| line["event"].as_str().unwrap());
+        assert_eq!(
+            vec![
+                "envelopes_listed",
+                "hunk_generated",
+                "hunk_generated",
+                "error",
+                "hunk_applied",
+                "folder_finished"
+            ],
+            events.collect::<Vec<_>>()
+        );
+        assert_eq!(
+            json!({ "side": "right", "kind": "add_msg", "id": "1" }),
+            lines[3]["hunk"]
+        );
+        assert_eq!("failed", lines[3]["outcome"]);
+        assert_eq!(
+            json!({ "side": "left", "kind": "add_msg", "id": "2" }),
+            lines[4]["hunk"]
+        );
+        assert_eq!("partial", lines[5]["outcome"]);
+        assert_eq!(1, lines[5]["left"]["added"]);
+        assert_eq!(1, lines[5]["left"]["bytes"]);
+    }
+}
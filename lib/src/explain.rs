@@ -0,0 +1,200 @@
+//! Evidence of the hunks of a patch, for users debugging unexpected
+//! changes, like deletions, to see why the diff decided to act.
+//!
+//! The diff only looks at the four snapshots of a message: its
+//! envelopes on both sides at the previous sync and now. An
+//! [`Explanation`] tells where the message, and the flag of flag
+//! hunks, was found in each of them:
+//!
+//! ```text
+//! right: - msg 42
+//!   because: msg removed from left since the previous sync
+//!   msg: left was present, is absent; right was present, is present
+//! ```
+
+use std::fmt;
+
+use crate::{Envelope, Envelopes, Flag, Hunk, HunkKind, Side};
+
+/// Presence of a message, or of a flag of a message, in the four
+/// snapshots the diff compares.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Presence {
+    pub prev_left: bool,
+    pub next_left: bool,
+    pub prev_right: bool,
+    pub next_right: bool,
+}
+
+impl Presence {
+    fn new<F: Fn(&Envelope) -> bool>(envelopes: [Option<&Envelope>; 4], f: F) -> Self {
+        let [prev_left, next_left, prev_right, next_right] = envelopes.map(|e| e.is_some_and(&f));
+        Self {
+            prev_left,
+            next_left,
+            prev_right,
+            next_right,
+        }
+    }
+
+    /// Returns the presence on the given side at the previous sync and
+    /// now.
+    pub fn side(&self, side: Side) -> (bool, bool) {
+        match side {
+            Side::Left => (self.prev_left, self.next_left),
+            Side::Right => (self.prev_right, self.next_right),
+        }
+    }
+}
+
+/// Hunk of a patch with the evidence that produced it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    pub hunk: Hunk,
+    /// Presence of the message of the hunk.
+    pub msg: Presence,
+    /// Presence of the flag of the hunk, for flag hunks.
+    pub flag: Option<Presence>,
+}
+
+impl Explanation {
+    /// Returns why the diff built the hunk: a change of the message
+    /// or of its flag on the other side since the previous sync.
+    pub fn reason(&self) -> String {
+        let source = self.hunk.target.opposite();
+        match &self.hunk.kind {
+            HunkKind::AddMsg(_) => format!("msg new on {}", source),
+            HunkKind::RemoveMsg(_) => {
+                format!("msg removed from {} since the previous sync", source)
+            }
+            HunkKind::AddFlag(_, flag) => {
+                format!("flag {} added on {} since the previous sync", flag, source)
+            }
+            HunkKind::RemoveFlag(_, flag) => {
+                format!(
+                    "flag {} removed from {} since the previous sync",
+                    flag, source
+                )
+            }
+        }
+    }
+}
+
+/// Renders the hunk, why it was built and where its message and flag
+/// were found, one per line.
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.hunk)?;
+        write!(f, "  because: {}", self.reason())?;
+        write!(f, "\n  msg: ")?;
+        write_presence(f, &self.msg, "present", "absent")?;
+        if let (Some(presence), HunkKind::AddFlag(_, flag) | HunkKind::RemoveFlag(_, flag)) =
+            (&self.flag, &self.hunk.kind)
+        {
+            write!(f, "\n  flag {}: ", flag)?;
+            write_presence(f, presence, "set", "unset")?;
+        }
+        Ok(())
+    }
+}
+
+fn write_presence(f: &mut fmt::Formatter, presence: &Presence, yes: &str, no: &str) -> fmt::Result {
+    let state = |present: bool| if present { yes } else { no };
+    for (i, side) in [Side::Left, Side::Right].into_iter().enumerate() {
+        if i > 0 {
+            f.write_str("; ")?;
+        }
+        let (prev, next) = presence.side(side);
+        write!(f, "{} was {}, is {}", side, state(prev), state(next))?;
+    }
+    Ok(())
+}
+
+/// Explains the given hunk of the patch built by
+/// [`crate::build_patch`] from the given envelopes, the left side
+/// being the IMAP one.
+pub fn explain(
+    hunk: &Hunk,
+    prev_imap_envelopes: &Envelopes,
+    next_imap_envelopes: &Envelopes,
+    prev_mdir_envelopes: &Envelopes,
+    next_mdir_envelopes: &Envelopes,
+) -> Explanation {
+    let id = &hunk.kind.id().source;
+    let envelopes = [
+        prev_imap_envelopes.get(id),
+        next_imap_envelopes.get(id),
+        prev_mdir_envelopes.get(id),
+        next_mdir_envelopes.get(id),
+    ];
+    let flag = match &hunk.kind {
+        HunkKind::AddFlag(_, flag) | HunkKind::RemoveFlag(_, flag) => Some(flag),
+        HunkKind::AddMsg(_) | HunkKind::RemoveMsg(_) => None,
+    };
+    Explanation {
+        hunk: hunk.clone(),
+        msg: Presence::new(envelopes, |_| true),
+        flag: flag.map(|flag: &Flag| Presence::new(envelopes, |e| e.flags.contains(flag))),
+    }
+}
+
+/// Explains the hunks of the given patch, see [`explain`].
+pub fn explain_patch(
+    patch: &[Hunk],
+    prev_imap_envelopes: &Envelopes,
+    next_imap_envelopes: &Envelopes,
+    prev_mdir_envelopes: &Envelopes,
+    next_mdir_envelopes: &Envelopes,
+) -> Vec<Explanation> {
+    patch
+        .iter()
+        .map(|hunk| {
+            explain(
+                hunk,
+                prev_imap_envelopes,
+                next_imap_envelopes,
+                prev_mdir_envelopes,
+                next_mdir_envelopes,
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{build_patch, Envelope, Envelopes};
+
+    use super::*;
+
+    #[test]
+    fn explain_test() {
+        let prev_imap =
+            Envelopes::from_iter([Envelope::new("1").with_flag(Flag::Seen), Envelope::new("2")]);
+        let next_imap = Envelopes::from_iter([Envelope::new("1"), Envelope::new("3")]);
+        let prev_mdir = prev_imap.clone();
+        let next_mdir =
+            Envelopes::from_iter([Envelope::new("1").with_flag(Flag::Seen), Envelope::new("2")]);
+
+        let patch = build_patch(&prev_imap, &next_imap, &prev_mdir, &next_mdir);
+        let explanations = explain_patch(&patch, &prev_imap, &next_imap, &prev_mdir, &next_mdir);
+        let explanations = explanations
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "right: - flag Seen on 1\n  \
+                 because: flag Seen removed from left since the previous sync\n  \
+                 msg: left was present, is present; right was present, is present\n  \
+                 flag Seen: left was set, is unset; right was set, is set",
+                "right: - msg 2\n  \
+                 because: msg removed from left since the previous sync\n  \
+                 msg: left was present, is absent; right was present, is present",
+                "right: + msg 3\n  \
+                 because: msg new on left\n  \
+                 msg: left was absent, is present; right was absent, is absent",
+            ],
+            explanations
+        );
+    }
+}
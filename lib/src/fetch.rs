@@ -0,0 +1,164 @@
+//! Fetching of envelopes: the items they are fetched with, and the
+//! sizing of the chunks they are fetched in.
+
+use std::time::Duration;
+
+use crate::trace::event;
+
+/// Items to `UID FETCH` envelopes with, the ones filling their
+/// [`crate::Metadata`] included with the `metadata` feature.
+#[cfg(not(feature = "metadata"))]
+pub const ENVELOPES_QUERY: &str = "(UID FLAGS)";
+#[cfg(feature = "metadata")]
+pub const ENVELOPES_QUERY: &str = "(UID FLAGS RFC822.SIZE INTERNALDATE ENVELOPE)";
+
+/// Number of messages fetched by the first `UID FETCH` of a folder.
+pub const DEFAULT_FETCH_CHUNK_SIZE: usize = 500;
+
+/// Tunes the number of messages each `UID FETCH` asks for from the
+/// round-trip time and size of the previous responses, instead of
+/// using a fixed number.
+///
+/// Chunks grow until a fetch takes about the target duration, so that
+/// the round trip stays small compared to the transfer on high-latency
+/// links, while fast servers (like a Dovecot on the LAN) quickly get
+/// the largest chunks. Chunks also shrink to keep responses below a
+/// maximum size, so that messages with many keywords do not blow the
+/// memory up.
+#[derive(Debug, Clone)]
+pub struct ChunkSizer {
+    size: usize,
+    min: usize,
+    max: usize,
+    target: Duration,
+    max_bytes: u64,
+}
+
+impl Default for ChunkSizer {
+    /// Starts at 500 messages, between 50 and 10,000, aiming at fetches
+    /// of 1 second and responses of at most 8 MiB.
+    fn default() -> Self {
+        Self {
+            size: DEFAULT_FETCH_CHUNK_SIZE,
+            min: 50,
+            max: 10_000,
+            target: Duration::from_secs(1),
+            max_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl ChunkSizer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the bounds of the chunk size, at least one message.
+    pub fn with_bounds(mut self, min: usize, max: usize) -> Self {
+        self.min = min.max(1);
+        self.max = max.max(self.min);
+        self.size = self.size.clamp(self.min, self.max);
+        self
+    }
+
+    /// Sets the duration fetches should take.
+    pub fn with_target(mut self, target: Duration) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Sets the size responses should stay below.
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Returns the number of messages the next fetch should ask for.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Splits the chunk of the next fetch off the given UIDs, and
+    /// returns it with the UIDs left.
+    pub fn split<'a>(&self, uids: &'a [u32]) -> (&'a [u32], &'a [u32]) {
+        uids.split_at(self.size.min(uids.len()))
+    }
+
+    /// Records a fetch of the given number of messages, which took
+    /// `elapsed` to return a response of `bytes` bytes, and adjusts
+    /// the size of the next chunks. The size changes by a factor of 2
+    /// at most, so that a single slow response does not collapse it.
+    pub fn record(&mut self, count: usize, bytes: u64, elapsed: Duration) {
+        if count == 0 {
+            return;
+        }
+        let count = count as f64;
+        let mut size = match elapsed.as_secs_f64() {
+            secs if secs > 0.0 => count * self.target.as_secs_f64() / secs,
+            _ => f64::INFINITY,
+        };
+        if bytes > 0 {
+            size = size.min(count * self.max_bytes as f64 / bytes as f64);
+        }
+        let size = size.clamp(count / 2.0, count * 2.0) as usize;
+        self.size = size.clamp(self.min, self.max);
+        event!(
+            TRACE,
+            count,
+            bytes,
+            elapsed_ms = elapsed.as_millis() as u64,
+            next_size = self.size,
+            "fetch recorded"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Simulates fetches to a server with the given round-trip time and
+    /// transfer time per message, and returns the sizes of the chunks.
+    fn simulate(
+        sizer: &mut ChunkSizer,
+        rtt: Duration,
+        per_msg: Duration,
+        bytes: u64,
+    ) -> Vec<usize> {
+        let uids = (1..=50_000).collect::<Vec<u32>>();
+        let mut uids = uids.as_slice();
+        let mut sizes = vec![];
+        while !uids.is_empty() {
+            let (chunk, rest) = sizer.split(uids);
+            sizes.push(chunk.len());
+            let elapsed = rtt + per_msg * chunk.len() as u32;
+            sizer.record(chunk.len(), bytes * chunk.len() as u64, elapsed);
+            uids = rest;
+        }
+        sizes
+    }
+
+    #[test]
+    fn chunk_sizer_test() {
+        let ms = Duration::from_millis;
+        let us = Duration::from_micros;
+
+        // fast servers reach the largest chunks
+        let sizes = simulate(&mut ChunkSizer::new(), us(200), us(10), 100);
+        assert_eq!(vec![500, 1000, 2000, 4000, 8000, 10_000], sizes[..6]);
+
+        // chunks of high-latency links grow until fetches take about
+        // the target duration
+        let sizes = simulate(&mut ChunkSizer::new(), ms(600), us(200), 100);
+        assert!((1800..=2200).contains(&sizes[10]), "{}", sizes[10]);
+
+        // large responses shrink chunks
+        let mut sizer = ChunkSizer::new().with_max_bytes(1024 * 1024);
+        let sizes = simulate(&mut sizer, us(200), us(10), 1024);
+        assert_eq!(1024, sizes[10]);
+
+        let sizer = ChunkSizer::new().with_bounds(0, 10);
+        assert_eq!(10, sizer.size());
+        assert_eq!((&[1][..], &[][..]), sizer.split(&[1]));
+    }
+}
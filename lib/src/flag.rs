@@ -0,0 +1,395 @@
+//! Flags of messages, and their IMAP and maildir names.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt,
+    hash::{Hash, Hasher},
+};
+
+/// Flag of a message: the standard IMAP ones, or a custom keyword.
+/// Flags are ordered as declared, keywords last by name.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Flag {
+    Draft,
+    Flagged,
+    Replied,
+    Seen,
+    Trashed,
+    Keyword(String),
+}
+
+/// Standard flags, in the order of their bits.
+static STANDARD_FLAGS: [Flag; 5] = [
+    Flag::Draft,
+    Flag::Flagged,
+    Flag::Replied,
+    Flag::Seen,
+    Flag::Trashed,
+];
+
+bitflags::bitflags! {
+    #[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+    struct StandardFlags: u8 {
+        const DRAFT = 1;
+        const FLAGGED = 1 << 1;
+        const REPLIED = 1 << 2;
+        const SEEN = 1 << 3;
+        const TRASHED = 1 << 4;
+    }
+}
+
+impl StandardFlags {
+    fn from_flag(flag: &Flag) -> Option<Self> {
+        match flag {
+            Flag::Draft => Some(Self::DRAFT),
+            Flag::Flagged => Some(Self::FLAGGED),
+            Flag::Replied => Some(Self::REPLIED),
+            Flag::Seen => Some(Self::SEEN),
+            Flag::Trashed => Some(Self::TRASHED),
+            Flag::Keyword(_) => None,
+        }
+    }
+}
+
+/// Set of flags of an envelope. Standard flags are kept as bits and
+/// keywords in a vector, envelopes rarely having more than a few of
+/// them, so that most sets do not allocate.
+///
+/// The set keeps a small hash of its keywords up to date, so that
+/// comparing sets (like when diffing the flags of millions of
+/// unchanged envelopes) mostly compares two integers.
+#[derive(Default, Debug, Clone)]
+pub struct Flags {
+    standard: StandardFlags,
+    /// Only [`Flag::Keyword`]s, without duplicates.
+    keywords: Vec<Flag>,
+    /// Xor of the hashes of the keywords, whatever their order.
+    keywords_hash: u64,
+}
+
+impl Flags {
+    /// Adds the given flag, and returns whether it was not already in
+    /// the set.
+    pub fn insert(&mut self, flag: Flag) -> bool {
+        match StandardFlags::from_flag(&flag) {
+            Some(bit) => {
+                let inserted = !self.standard.contains(bit);
+                self.standard.insert(bit);
+                inserted
+            }
+            None if self.keywords.contains(&flag) => false,
+            None => {
+                self.keywords_hash ^= hash(&flag);
+                self.keywords.push(flag);
+                true
+            }
+        }
+    }
+
+    /// Removes the given flag, and returns whether it was in the set.
+    pub fn remove(&mut self, flag: &Flag) -> bool {
+        match StandardFlags::from_flag(flag) {
+            Some(bit) => {
+                let removed = self.standard.contains(bit);
+                self.standard.remove(bit);
+                removed
+            }
+            None => match self.keywords.iter().position(|keyword| keyword == flag) {
+                Some(i) => {
+                    self.keywords_hash ^= hash(self.keywords.swap_remove(i));
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    pub fn contains(&self, flag: &Flag) -> bool {
+        match StandardFlags::from_flag(flag) {
+            Some(bit) => self.standard.contains(bit),
+            None => self.keywords.contains(flag),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.standard.bits().count_ones() as usize + self.keywords.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.standard.is_empty() && self.keywords.is_empty()
+    }
+
+    /// Returns a small hash of the set: equal sets always have the
+    /// same digest, whatever the order their flags were inserted in.
+    /// Sets of standard flags only have different digests.
+    pub fn digest(&self) -> u64 {
+        self.keywords_hash ^ self.standard.bits() as u64
+    }
+
+    /// Returns the flags in both this set and the given one.
+    pub fn intersection(&self, other: &Flags) -> Flags {
+        let mut flags = Flags {
+            standard: self.standard & other.standard,
+            ..Flags::default()
+        };
+        flags.extend(
+            self.keywords
+                .iter()
+                .filter(|flag| other.keywords.contains(flag))
+                .cloned(),
+        );
+        flags
+    }
+
+    /// Returns the flags in this set, the given one or both.
+    pub fn union(&self, other: &Flags) -> Flags {
+        let mut flags = self.clone();
+        flags.standard |= other.standard;
+        flags.extend(other.keywords.iter().cloned());
+        flags
+    }
+
+    /// Returns the flags in this set but not in the given one.
+    pub fn difference(&self, other: &Flags) -> Flags {
+        let mut flags = Flags {
+            standard: self.standard - other.standard,
+            ..Flags::default()
+        };
+        flags.extend(
+            self.keywords
+                .iter()
+                .filter(|flag| !other.keywords.contains(flag))
+                .cloned(),
+        );
+        flags
+    }
+
+    /// Returns the flags in exactly one of this set and the given one,
+    /// like the flags to add or remove to turn one into the other.
+    pub fn symmetric_difference(&self, other: &Flags) -> Flags {
+        let mut flags = self.difference(other);
+        flags.standard |= other.standard - self.standard;
+        flags.extend(
+            other
+                .keywords
+                .iter()
+                .filter(|flag| !self.keywords.contains(flag))
+                .cloned(),
+        );
+        flags
+    }
+
+    /// Iterates over the standard flags, then over the keywords.
+    pub fn iter(&self) -> impl Iterator<Item = &Flag> + '_ {
+        STANDARD_FLAGS
+            .iter()
+            .filter(|flag| self.contains(flag))
+            .chain(&self.keywords)
+    }
+}
+
+impl PartialEq for Flags {
+    fn eq(&self, other: &Self) -> bool {
+        // sets of different hashes cannot be equal, and sets without
+        // keywords are equal when their hashes are
+        self.standard == other.standard
+            && self.keywords_hash == other.keywords_hash
+            && self.keywords.len() == other.keywords.len()
+            && self
+                .keywords
+                .iter()
+                .all(|flag| other.keywords.contains(flag))
+    }
+}
+
+impl Eq for Flags {}
+
+impl Extend<Flag> for Flags {
+    fn extend<I: IntoIterator<Item = Flag>>(&mut self, flags: I) {
+        for flag in flags {
+            self.insert(flag);
+        }
+    }
+}
+
+impl Flags {
+    /// Builds the flags matching the given maildir info letters, like
+    /// `"FS"`. Keyword letters need the Dovecot keywords of their
+    /// maildir to be resolved, and are left out.
+    pub fn from_chars(info: &str) -> Self {
+        info.bytes().filter_map(decode_flag).collect()
+    }
+
+    /// Returns the maildir info letters of the standard flags, ordered
+    /// by ASCII value like `"FS"`. Keyword letters depend on the
+    /// Dovecot keywords of the maildir, and are left out.
+    pub fn to_maildir_string(&self) -> String {
+        self.iter().filter_map(encode_flag).collect()
+    }
+
+    /// Builds the flags matching the given space-separated IMAP flags,
+    /// like `"\\Seen $Important"`. Other names are keywords.
+    pub fn from_imap(flags: &str) -> Self {
+        flags.split_whitespace().map(parse_flag).collect()
+    }
+
+    /// Returns the IMAP names of the flags, like `\\Seen`, sorted so
+    /// that equal sets always give the same names.
+    pub fn to_imap_flags(&self) -> Vec<&str> {
+        let mut flags = self.iter().map(format_flag).collect::<Vec<_>>();
+        flags.sort_unstable();
+        flags
+    }
+}
+
+/// Returns the flag of the given IMAP name, other names being
+/// keywords.
+pub(crate) fn parse_flag(flag: &str) -> Flag {
+    match flag {
+        "\\Draft" => Flag::Draft,
+        "\\Flagged" => Flag::Flagged,
+        "\\Answered" => Flag::Replied,
+        "\\Seen" => Flag::Seen,
+        "\\Deleted" => Flag::Trashed,
+        keyword => Flag::Keyword(keyword.to_owned()),
+    }
+}
+
+/// Returns the IMAP name of the given flag.
+pub(crate) fn format_flag(flag: &Flag) -> &str {
+    match flag {
+        Flag::Draft => "\\Draft",
+        Flag::Flagged => "\\Flagged",
+        Flag::Replied => "\\Answered",
+        Flag::Seen => "\\Seen",
+        Flag::Trashed => "\\Deleted",
+        Flag::Keyword(keyword) => keyword,
+    }
+}
+
+/// Returns the standard flag of the given maildir info letter, `None`
+/// for keyword letters and unknown ones.
+pub(crate) fn decode_flag(letter: u8) -> Option<Flag> {
+    match letter {
+        b'S' => Some(Flag::Seen),
+        b'R' => Some(Flag::Replied),
+        b'F' => Some(Flag::Flagged),
+        b'T' => Some(Flag::Trashed),
+        b'D' => Some(Flag::Draft),
+        _ => None,
+    }
+}
+
+/// Returns the maildir info letter of the given standard flag, `None`
+/// for keywords whose letters depend on the Dovecot keywords.
+pub(crate) fn encode_flag(flag: &Flag) -> Option<char> {
+    match flag {
+        Flag::Draft => Some('D'),
+        Flag::Flagged => Some('F'),
+        Flag::Replied => Some('R'),
+        Flag::Seen => Some('S'),
+        Flag::Trashed => Some('T'),
+        Flag::Keyword(_) => None,
+    }
+}
+
+impl FromIterator<Flag> for Flags {
+    fn from_iter<I: IntoIterator<Item = Flag>>(flags: I) -> Self {
+        let mut set = Self::default();
+        set.extend(flags);
+        set
+    }
+}
+
+/// Renders flags by name, like `Seen`, keywords as they are.
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Draft => f.write_str("Draft"),
+            Self::Flagged => f.write_str("Flagged"),
+            Self::Replied => f.write_str("Replied"),
+            Self::Seen => f.write_str("Seen"),
+            Self::Trashed => f.write_str("Trashed"),
+            Self::Keyword(keyword) => f.write_str(keyword),
+        }
+    }
+}
+
+pub(crate) fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+
+    use super::*;
+
+    #[test]
+    fn flags_test() {
+        let work = Flag::Keyword("Work".into());
+        let mut flags = Flags::default();
+        assert!(flags.is_empty());
+        assert!(flags.insert(Flag::Seen));
+        assert!(!flags.insert(Flag::Seen));
+        assert!(flags.insert(work.clone()));
+        assert!(!flags.insert(work.clone()));
+        flags.insert(Flag::Draft);
+
+        assert_eq!(3, flags.len());
+        assert!(flags.contains(&Flag::Seen));
+        assert!(!flags.contains(&Flag::Flagged));
+        assert_eq!(
+            vec![&Flag::Draft, &Flag::Seen, &work],
+            flags.iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            Flags::from_iter([work.clone(), Flag::Keyword("Home".into()), Flag::Seen]),
+            Flags::from_iter([Flag::Keyword("Home".into()), Flag::Seen, work.clone()])
+        );
+        assert_eq!(
+            Flags::from_iter([Flag::Seen, work.clone()]),
+            flags.intersection(&Flags::from_iter([Flag::Seen, Flag::Flagged, work.clone()]))
+        );
+
+        let other = Flags::from_iter([Flag::Seen, Flag::Flagged, Flag::Keyword("Home".into())]);
+        assert_eq!(
+            Flags::from_iter([
+                Flag::Draft,
+                Flag::Flagged,
+                Flag::Seen,
+                work.clone(),
+                Flag::Keyword("Home".into())
+            ]),
+            flags.union(&other)
+        );
+        assert_eq!(
+            Flags::from_iter([Flag::Draft, work.clone()]),
+            flags.difference(&other)
+        );
+        assert_eq!(
+            Flags::from_iter([
+                Flag::Draft,
+                Flag::Flagged,
+                work.clone(),
+                Flag::Keyword("Home".into())
+            ]),
+            flags.symmetric_difference(&other)
+        );
+        assert_eq!("DS", flags.to_maildir_string());
+        assert_eq!(vec!["Work", "\\Draft", "\\Seen"], flags.to_imap_flags());
+
+        assert!(flags.remove(&work));
+        assert!(!flags.remove(&work));
+        assert!(flags.remove(&Flag::Draft));
+        assert_eq!(Flags::from_iter([Flag::Seen]), flags);
+        assert_eq!(Flags::from_iter([Flag::Seen]).digest(), flags.digest());
+        assert_ne!(Flags::from_iter([Flag::Draft]).digest(), flags.digest());
+        assert_eq!(
+            Flags::from_iter([work.clone(), Flag::Keyword("Home".into())]).digest(),
+            Flags::from_iter([Flag::Keyword("Home".into()), work]).digest()
+        );
+    }
+}
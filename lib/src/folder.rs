@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::backend::maildir::Mdir;
+
+/// How the IMAP folder hierarchy is laid out on the filesystem.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum FolderLayout {
+    /// Each hierarchy level is a directory: `Lists/rust` lands in
+    /// `<root>/Lists/rust`.
+    #[default]
+    Nest,
+    /// All levels are joined into a single directory with the given
+    /// separator: `Lists/rust` lands in `<root>/Lists.rust` with `.`.
+    Flatten(String),
+}
+
+/// Maps IMAP folders to local maildir directories.
+#[derive(Debug, Clone)]
+pub struct FolderMapping {
+    root: PathBuf,
+    layout: FolderLayout,
+    strip_prefix: Option<String>,
+    overrides: HashMap<String, PathBuf>,
+    case_insensitive: bool,
+}
+
+impl FolderMapping {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+            layout: FolderLayout::default(),
+            strip_prefix: None,
+            overrides: HashMap::new(),
+            case_insensitive: false,
+        }
+    }
+
+    pub fn with_layout(mut self, layout: FolderLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    /// Strips the given hierarchy prefix from IMAP folders before
+    /// mapping them, like `INBOX/` for servers nesting everything
+    /// under the inbox. The prefix must include the delimiter.
+    pub fn with_strip_prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.strip_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Maps the given IMAP folder to the given path, relative to the
+    /// root unless absolute, whatever the layout.
+    pub fn with_override<S: Into<String>, P: Into<PathBuf>>(mut self, folder: S, path: P) -> Self {
+        self.overrides.insert(folder.into(), path.into());
+        self
+    }
+
+    /// Matches IMAP folders against local directories ignoring case,
+    /// so that `Sent` pairs with an existing `sent` directory instead
+    /// of creating a second one. Overrides are matched ignoring case
+    /// as well.
+    pub fn with_case_insensitive(mut self, enabled: bool) -> Self {
+        self.case_insensitive = enabled;
+        self
+    }
+
+    /// Returns the local directory of the given IMAP folder. The
+    /// delimiter is the hierarchy delimiter announced by the server,
+    /// `None` for flat namespaces.
+    pub fn local_path(&self, folder: &str, delimiter: Option<char>) -> PathBuf {
+        if let Some(path) = self.override_path(folder) {
+            return self.root.join(path);
+        }
+
+        let name = match &self.strip_prefix {
+            Some(prefix) => folder.strip_prefix(prefix.as_str()).unwrap_or(folder),
+            None => folder,
+        };
+        let levels = match delimiter {
+            Some(delimiter) => name.split(delimiter).collect(),
+            None => vec![name],
+        };
+        let levels = levels
+            .into_iter()
+            .filter(|level| !level.is_empty())
+            .collect::<Vec<_>>();
+
+        match &self.layout {
+            FolderLayout::Nest => levels.into_iter().fold(self.root.clone(), |path, level| {
+                self.join_level(&path, escape_level(level))
+            }),
+            FolderLayout::Flatten(separator) => {
+                self.join_level(&self.root, escape_level(&levels.join(separator)))
+            }
+        }
+    }
+
+    fn override_path(&self, folder: &str) -> Option<&PathBuf> {
+        match self.overrides.get(folder) {
+            Some(path) => Some(path),
+            None if self.case_insensitive => {
+                let folder = folder.to_lowercase();
+                self.overrides
+                    .iter()
+                    .filter(|(name, _)| name.to_lowercase() == folder)
+                    .min_by_key(|(name, _)| *name)
+                    .map(|(_, path)| path)
+            }
+            None => None,
+        }
+    }
+
+    /// Joins the given level to the given directory. When matching
+    /// ignoring case, an existing directory differing only in case is
+    /// reused, the smallest name winning when several match.
+    fn join_level(&self, dir: &Path, level: String) -> PathBuf {
+        let path = dir.join(&level);
+        if !self.case_insensitive || path.exists() {
+            return path;
+        }
+
+        let level = level.to_lowercase();
+        fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .flatten()
+            .filter(|entry| entry.file_name().to_string_lossy().to_lowercase() == level)
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.path())
+            .min()
+            .unwrap_or(path)
+    }
+
+    pub fn mdir(&self, folder: &str, delimiter: Option<char>) -> Mdir {
+        Mdir::new(self.local_path(folder, delimiter))
+    }
+}
+
+/// Prevents a hierarchy level from escaping the root, by escaping
+/// path separators and special `.`/`..` levels.
+pub(crate) fn escape_level(level: &str) -> String {
+    let level = level.replace('%', "%25").replace(['/', '\\'], "%2F");
+    match Path::new(&level).components().next() {
+        Some(Component::Normal(_)) => level,
+        _ => level.replace('.', "%2E"),
+    }
+}
+
+/// Reverts [`escape_level`], backslashes coming back as slashes.
+/// Only the cache lists escaped levels.
+#[cfg_attr(not(feature = "cache"), allow(dead_code))]
+pub(crate) fn unescape_level(level: &str) -> String {
+    let mut unescaped = String::with_capacity(level.len());
+    let mut rest = level;
+    while let Some(i) = rest.find('%') {
+        unescaped.push_str(&rest[..i]);
+        rest = &rest[i..];
+        let c = match rest.get(..3) {
+            Some("%25") => '%',
+            Some("%2F") => '/',
+            Some("%2E") => '.',
+            _ => {
+                unescaped.push('%');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        unescaped.push(c);
+        rest = &rest[3..];
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nest_test() {
+        let mapping = FolderMapping::new("/mail");
+        assert_eq!(
+            PathBuf::from("/mail/INBOX/Lists/rust"),
+            mapping.local_path("INBOX/Lists/rust", Some('/'))
+        );
+        assert_eq!(
+            PathBuf::from("/mail/INBOX.Lists"),
+            mapping.local_path("INBOX.Lists", None)
+        );
+    }
+
+    #[test]
+    fn flatten_strip_prefix_test() {
+        let mapping = FolderMapping::new("/mail")
+            .with_layout(FolderLayout::Flatten("-".into()))
+            .with_strip_prefix("INBOX.");
+        assert_eq!(
+            PathBuf::from("/mail/Lists-rust"),
+            mapping.local_path("INBOX.Lists.rust", Some('.'))
+        );
+        assert_eq!(
+            PathBuf::from("/mail/INBOX"),
+            mapping.local_path("INBOX", Some('.'))
+        );
+    }
+
+    #[test]
+    fn override_test() {
+        let mapping = FolderMapping::new("/mail")
+            .with_override("INBOX/Lists/rust", "lists-rust")
+            .with_override("Archive", "/archive");
+        assert_eq!(
+            PathBuf::from("/mail/lists-rust"),
+            mapping.local_path("INBOX/Lists/rust", Some('/'))
+        );
+        assert_eq!(
+            PathBuf::from("/archive"),
+            mapping.local_path("Archive", Some('/'))
+        );
+    }
+
+    #[test]
+    fn escape_test() {
+        let mapping = FolderMapping::new("/mail");
+        assert_eq!(
+            PathBuf::from("/mail/%2E%2E/%2E/a%2Fb"),
+            mapping
+                .local_path("../.", Some('/'))
+                .join(escape_level("a/b"))
+        );
+        let mapping = mapping.with_layout(FolderLayout::Flatten(".".into()));
+        assert_eq!(
+            PathBuf::from("/mail/%2E%2E"),
+            mapping.local_path("/../", Some('/'))
+        );
+
+        for level in ["..", ".", "a/b", "%2F", "50%", ".hidden", ""] {
+            assert_eq!(level, unescape_level(&escape_level(level)));
+        }
+        assert_eq!("a/b", unescape_level(&escape_level("a\\b")));
+    }
+
+    #[test]
+    fn case_insensitive_test() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("sent")).unwrap();
+        fs::create_dir_all(dir.path().join("Lists").join("Rust")).unwrap();
+
+        let mapping = FolderMapping::new(dir.path());
+        assert_eq!(
+            dir.path().join("Sent"),
+            mapping.local_path("Sent", Some('/'))
+        );
+
+        let mapping = mapping
+            .with_case_insensitive(true)
+            .with_override("archive", "old");
+        assert_eq!(
+            dir.path().join("sent"),
+            mapping.local_path("Sent", Some('/'))
+        );
+        assert_eq!(
+            dir.path().join("Lists").join("Rust"),
+            mapping.local_path("lists/rust", Some('/'))
+        );
+        assert_eq!(
+            dir.path().join("Drafts"),
+            mapping.local_path("Drafts", Some('/'))
+        );
+        assert_eq!(
+            dir.path().join("old"),
+            mapping.local_path("Archive", Some('/'))
+        );
+    }
+}
@@ -0,0 +1,171 @@
+//! Health checks of the sides and the cache of a sync, for
+//! `doctor`-like commands to tell what prevents a sync from running.
+//!
+//! Checks never modify anything: they only reach the servers, list
+//! the folders and read the cache. A [`Diagnosis`] renders one check
+//! per line:
+//!
+//! ```text
+//! left:
+//!   ok       connection
+//!   failed   folder Archive: cannot examine on imap folder Archive
+//! right:
+//!   ok       folder INBOX
+//!   skipped  folder Archive
+//! cache:
+//!   ok       snapshot of INBOX
+//! ```
+
+use std::{fmt, sync::Arc};
+
+use crate::{EverestError, Side};
+
+/// What a check verifies.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Probe {
+    /// The server can be reached.
+    Connection,
+    /// The credentials are accepted by the server.
+    Authentication,
+    /// The folder exists and can be listed.
+    Folder(String),
+    /// The cached snapshot of the folder can be read.
+    Snapshot(String),
+}
+
+impl fmt::Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Connection => f.write_str("connection"),
+            Self::Authentication => f.write_str("authentication"),
+            Self::Folder(folder) => write!(f, "folder {}", folder),
+            Self::Snapshot(folder) => write!(f, "snapshot of {}", folder),
+        }
+    }
+}
+
+/// Outcome of a check.
+#[derive(Debug, Clone)]
+pub enum Status {
+    Ok,
+    Failed(Arc<EverestError>),
+    /// The check was not run because a check it depends on failed,
+    /// like folders when the server cannot be reached.
+    Skipped,
+}
+
+impl Status {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Ok => "ok",
+            Self::Failed(_) => "failed",
+            Self::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub probe: Probe,
+    pub status: Status,
+}
+
+impl Check {
+    /// Builds the check of the given probe from the result of the
+    /// operation verifying it.
+    pub fn new<T>(probe: Probe, result: Result<T, EverestError>) -> Self {
+        let status = match result {
+            Ok(_) => Status::Ok,
+            Err(err) => Status::Failed(Arc::new(err)),
+        };
+        Self { probe, status }
+    }
+
+    pub fn skipped(probe: Probe) -> Self {
+        Self {
+            probe,
+            status: Status::Skipped,
+        }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        matches!(self.status, Status::Ok)
+    }
+
+    pub fn error(&self) -> Option<&EverestError> {
+        match &self.status {
+            Status::Failed(err) => Some(err),
+            Status::Ok | Status::Skipped => None,
+        }
+    }
+}
+
+/// Checks of both sides and of the cache of a sync, built by
+/// [`crate::sync::Sync::check`].
+#[derive(Debug, Clone, Default)]
+pub struct Diagnosis {
+    pub left: Vec<Check>,
+    pub right: Vec<Check>,
+    pub cache: Vec<Check>,
+}
+
+impl Diagnosis {
+    pub fn side(&self, side: Side) -> &[Check] {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    /// Returns `true` if every check passed.
+    pub fn is_healthy(&self) -> bool {
+        self.checks().all(|(_, check)| check.is_ok())
+    }
+
+    /// Returns the checks that did not pass, failed or skipped, with
+    /// the name of their section.
+    pub fn failures(&self) -> impl Iterator<Item = (&'static str, &Check)> {
+        self.checks().filter(|(_, check)| !check.is_ok())
+    }
+
+    fn checks(&self) -> impl Iterator<Item = (&'static str, &Check)> {
+        let sections = [
+            ("left", &self.left),
+            ("right", &self.right),
+            ("cache", &self.cache),
+        ];
+        sections
+            .into_iter()
+            .flat_map(|(name, checks)| checks.iter().map(move |check| (name, check)))
+    }
+}
+
+/// Renders the checks by section, one per line with the error of the
+/// failed ones, see the [module](self) documentation.
+impl fmt::Display for Diagnosis {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let sections = [
+            ("left", &self.left),
+            ("right", &self.right),
+            ("cache", &self.cache),
+        ];
+        let mut first = true;
+        for (name, checks) in sections {
+            if checks.is_empty() {
+                continue;
+            }
+            if !first {
+                writeln!(f)?;
+            }
+            first = false;
+            write!(f, "{}:", name)?;
+            for check in checks {
+                write!(f, "\n  {:<8} {}", check.status.name(), check.probe)?;
+                if let Some(err) = check.error() {
+                    write!(f, ": {}", err)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
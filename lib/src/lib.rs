@@ -1,470 +1,356 @@
-use std::{
-    collections::{HashMap, HashSet},
-    ops::{Deref, DerefMut},
-};
+//! Synchronization of IMAP mailboxes with maildirs.
+//!
+//! The core of the crate diffs [`Envelopes`] (the ids and [`Flags`] of
+//! the messages of a folder) as seen by both sides before and after a
+//! sync, and builds with [`build_patch`] the [`Patch`] of [`Hunk`]s
+//! bringing both sides back in sync. Modules provide the rest: the
+//! `cache` of the previous envelopes, the `backend::maildir` backend,
+//! and the tools to plan and run syncs.
+//!
+//! The core types live in [`flag`], [`envelope`] and [`patch`], and
+//! are re-exported at the root.
+//!
+//! The core (data types, diff and planning) has no dependency on
+//! IMAP, maildirs or the filesystem. The `imap`, `maildir` and
+//! `cache` features, on by default, add the backends and the cache:
+//! embedders only needing the diff can build the crate with
+//! `default-features = false`.
+
+pub mod backend;
+#[cfg(feature = "cache")]
+pub mod cache;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod download;
+pub mod envelope;
+pub mod error;
+#[cfg(feature = "json")]
+pub mod eventlog;
+pub mod explain;
+pub mod fetch;
+pub mod flag;
+#[cfg(feature = "maildir")]
+pub mod folder;
+pub mod health;
+pub mod message_id;
+pub mod metadata;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+#[cfg(feature = "notification")]
+pub mod notification;
+#[cfg(feature = "json")]
+pub mod notifier;
+pub mod observer;
+#[cfg(feature = "opentelemetry")]
+pub mod otel;
+pub mod patch;
+#[cfg(any(feature = "json", feature = "cbor"))]
+pub mod patchfile;
+pub mod pipeline;
+pub mod plan;
+pub mod pool;
+pub mod prelude;
+pub mod progress;
+pub mod report;
+#[cfg(feature = "serde")]
+mod ser;
+#[cfg(feature = "cache")]
+pub mod sync;
+#[cfg(feature = "synthetic")]
+pub mod synthetic;
+pub mod throttle;
+mod trace;
+pub mod verify;
+
+use std::{io, path::PathBuf};
 use thiserror::Error;
 
+pub use envelope::{Envelope, Envelopes, EnvelopesDelta, Fingerprint, Id};
+pub use error::{BackendError, ErrorCode, ErrorKind};
+pub use flag::{Flag, Flags};
+pub use metadata::Metadata;
+pub use patch::{
+    build_changed_patch, build_patch, display_patch, iter_patch, summarize_patch, DisplayPatch,
+    Hunk, HunkId, HunkKind, Patch, PatchSummary, Side, SideSummary,
+};
+
+/// Errors of the crate. Variants are added as features grow, so
+/// matching them needs a wildcard arm.
 #[derive(Debug, Error)]
+#[non_exhaustive]
 pub enum EverestError {
-    #[error("cannot find uid on imap message {0}")]
-    MissingImapUidError(u32),
-    #[error("cannot get maildir entry: {0}")]
-    InvalidMaildirEntryError(String),
+    #[error(transparent)]
+    BackendError(#[from] BackendError),
+    #[error("cannot read dovecot keywords file {}", .1.display())]
+    ReadDovecotKeywordsError(#[source] io::Error, PathBuf),
+    #[error("cannot write dovecot keywords file {}", .1.display())]
+    WriteDovecotKeywordsError(#[source] io::Error, PathBuf),
+    #[error("cannot create maildir directory {}", .1.display())]
+    CreateMaildirDirError(#[source] io::Error, PathBuf),
+    #[error("cannot deliver maildir message {}", .1.display())]
+    DeliverMaildirMsgError(#[source] io::Error, PathBuf),
+    #[error("cannot read maildir directory {}", .1.display())]
+    ReadMaildirDirError(#[source] io::Error, PathBuf),
+    #[error("cannot read maildir message {}", .1.display())]
+    ReadMaildirMsgError(#[source] io::Error, PathBuf),
+    #[error("cannot find maildir message {0}")]
+    FindMaildirMsgError(String),
+    #[error("cannot update flags of maildir message {}", .1.display())]
+    UpdateMaildirFlagsError(#[source] io::Error, PathBuf),
+    #[error("cannot remove maildir message {}", .1.display())]
+    RemoveMaildirMsgError(#[source] io::Error, PathBuf),
+    #[error("cannot read mbsync state file {}", .1.display())]
+    ReadMbsyncStateError(#[source] io::Error, PathBuf),
+    #[error("cannot write mbsync state file {}", .1.display())]
+    WriteMbsyncStateError(#[source] io::Error, PathBuf),
+    #[error("invalid mbsync state file {}: {0}", .1.display())]
+    InvalidMbsyncStateError(String, PathBuf),
+    #[error("cannot read maildirsize file {}", .1.display())]
+    ReadMaildirsizeError(#[source] io::Error, PathBuf),
+    #[error("cannot write maildirsize file {}", .1.display())]
+    WriteMaildirsizeError(#[source] io::Error, PathBuf),
+    #[error("cannot deliver message: quota of maildir {} exceeded", .0.display())]
+    QuotaExceededError(PathBuf),
+    #[error("cannot repair maildir entry {}", .1.display())]
+    RepairMaildirError(#[source] io::Error, PathBuf),
+    #[error("cannot list maildir: symlink {} not allowed", .0.display())]
+    SymlinkError(PathBuf),
+    #[error("cannot read cache file {}", .1.display())]
+    ReadCacheError(#[source] io::Error, PathBuf),
+    #[error("cannot write cache file {}", .1.display())]
+    WriteCacheError(#[source] io::Error, PathBuf),
+    #[error("invalid cache file {}: {0}", .1.display())]
+    InvalidCacheError(String, PathBuf),
+    #[error("cannot load cache {}: unsupported version {0}", .1.display())]
+    UnsupportedCacheVersionError(u32, PathBuf),
+    #[error("cannot load cache {}: cache corrupted", .0.display())]
+    CorruptedCacheError(PathBuf),
+    #[error("cannot encrypt cache {}", .0.display())]
+    EncryptCacheError(PathBuf),
+    #[error("cannot decrypt cache {}: wrong key or corrupted cache", .0.display())]
+    DecryptCacheError(PathBuf),
+    #[cfg(feature = "compression")]
+    #[error("cannot compress cache {}", .1.display())]
+    CompressCacheError(#[source] io::Error, PathBuf),
+    #[cfg(feature = "compression")]
+    #[error("cannot decompress cache {}: corrupted cache", .1.display())]
+    DecompressCacheError(#[source] io::Error, PathBuf),
+    #[error("cannot find state directory: set XDG_STATE_HOME or HOME")]
+    FindStateDirError,
+    #[error("cannot lock cache {}", .1.display())]
+    LockCacheError(#[source] io::Error, PathBuf),
+    #[error("cannot lock cache {}: cache used by another run", .0.display())]
+    CacheLockedError(PathBuf),
+    #[cfg(feature = "sqlite")]
+    #[error("cannot access sqlite cache {}", .1.display())]
+    SqliteCacheError(#[source] rusqlite::Error, PathBuf),
+    #[cfg(feature = "json")]
+    #[error("cannot export cache")]
+    ExportCacheError(#[source] serde_json::Error),
+    #[cfg(feature = "json")]
+    #[error("cannot import cache: {0}")]
+    ImportCacheError(String),
+    #[cfg(feature = "watch")]
+    #[error("cannot watch maildir {}", .1.display())]
+    WatchMaildirError(#[source] notify::Error, PathBuf),
+    #[cfg(feature = "json")]
+    #[error("cannot open event log {}", .1.display())]
+    OpenEventLogError(#[source] io::Error, PathBuf),
+    #[cfg(feature = "json")]
+    #[error("cannot run notify command {1}")]
+    RunNotifyCommandError(#[source] io::Error, String),
+    #[cfg(feature = "json")]
+    #[error("cannot notify sync report: command {0} exited with {1}")]
+    NotifyCommandStatusError(String, std::process::ExitStatus),
+    #[cfg(feature = "webhook")]
+    #[error("cannot post sync report to webhook {1}")]
+    PostWebhookError(#[source] Box<ureq::Error>, String),
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    #[error("cannot write patch: {0}")]
+    WritePatchError(String),
+    #[cfg(any(feature = "json", feature = "cbor"))]
+    #[error("cannot read patch: {0}")]
+    ReadPatchError(String),
+    #[cfg(feature = "config")]
+    #[error("cannot read config file {}", .1.display())]
+    ReadConfigError(#[source] io::Error, PathBuf),
+    #[cfg(feature = "config")]
+    #[error("cannot parse config{}", .1.as_ref().map(|path| format!(" file {}", path.display())).unwrap_or_default())]
+    ParseConfigError(#[source] toml::de::Error, Option<PathBuf>),
+    #[cfg(feature = "config")]
+    #[error("invalid config: {0}")]
+    InvalidConfigError(String),
+    #[cfg(feature = "imap")]
+    #[error("cannot fetch body of imap message {1}")]
+    FetchImapBodyError(#[source] imap::Error, u32),
+    #[cfg(feature = "imap")]
+    #[error("cannot open imap trace {}", .1.display())]
+    OpenImapTraceError(#[source] io::Error, PathBuf),
+    #[error("cannot write downloaded body {}", .1.display())]
+    DownloadBodyError(#[source] io::Error, PathBuf),
+    #[error("cannot download body of message {0}: got {1} bytes out of {2}")]
+    IncompleteBodyError(u32, u64, u64),
+    #[cfg(feature = "cache")]
+    #[error("cannot read replica ids file {}", .1.display())]
+    ReadReplicaIdsError(#[source] io::Error, PathBuf),
+    #[cfg(feature = "cache")]
+    #[error("cannot write replica ids file {}", .1.display())]
+    WriteReplicaIdsError(#[source] io::Error, PathBuf),
+    #[error("cannot build sync: missing {0}")]
+    BuildSyncError(&'static str),
+    #[error("cannot fetch envelopes of folder {0}: patch already applied")]
+    SessionAppliedError(String),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash)]
-enum Flag {
-    Draft,
-    Flagged,
-    Replied,
-    Seen,
-    Trashed,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
-struct Flags(HashSet<Flag>);
-
-impl Deref for Flags {
-    type Target = HashSet<Flag>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for Flags {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
-struct Envelope {
-    id: String,
-    flags: Flags,
-}
-
-#[derive(Default, Debug, Clone, PartialEq, Eq)]
-struct Envelopes(HashMap<String, Envelope>);
-
-impl Deref for Envelopes {
-    type Target = HashMap<String, Envelope>;
-
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
-impl DerefMut for Envelopes {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
-    }
-}
-
-impl TryFrom<imap::types::Fetches> for Envelopes {
-    type Error = EverestError;
-
-    fn try_from(fetches: imap::types::Fetches) -> Result<Self, Self::Error> {
-        let mut envelopes = Envelopes::default();
-        for fetch in fetches.iter() {
-            let id = fetch
-                .uid
-                .ok_or_else(|| EverestError::MissingImapUidError(fetch.message))?
-                .to_string();
-            let flags = fetch
-                .flags()
-                .iter()
-                .fold(Flags::default(), |mut flags, flag| {
-                    match flag {
-                        imap::types::Flag::Seen => flags.insert(Flag::Seen),
-                        imap::types::Flag::Answered => flags.insert(Flag::Replied),
-                        imap::types::Flag::Flagged => flags.insert(Flag::Flagged),
-                        imap::types::Flag::Deleted => flags.insert(Flag::Trashed),
-                        imap::types::Flag::Draft => flags.insert(Flag::Draft),
-                        _ => false,
-                    };
-                    flags
-                });
-            envelopes.insert(id.clone(), Envelope { id, flags });
-        }
-        Ok(envelopes)
-    }
-}
-
-impl TryFrom<maildir::MailEntries> for Envelopes {
-    type Error = EverestError;
-
-    fn try_from(entries: maildir::MailEntries) -> Result<Self, Self::Error> {
-        let mut envelopes = Envelopes::default();
-        for entry in entries {
-            let entry = entry.map_err(|e| EverestError::InvalidMaildirEntryError(e.to_string()))?;
-            let id = entry.id().to_owned();
-            let flags = entry
-                .flags()
-                .chars()
-                .fold(Flags::default(), |mut flags, c| {
-                    match c {
-                        'S' => flags.insert(Flag::Seen),
-                        'R' => flags.insert(Flag::Replied),
-                        'F' => flags.insert(Flag::Flagged),
-                        'T' => flags.insert(Flag::Trashed),
-                        'D' => flags.insert(Flag::Draft),
-                        _ => false,
-                    };
-                    flags
-                });
-            envelopes.insert(id.clone(), Envelope { id, flags });
+/// Result of the fallible operations of the crate.
+pub type Result<T, E = EverestError> = std::result::Result<T, E>;
+
+impl EverestError {
+    /// Returns the category of the error.
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::BackendError(err) => err.kind(),
+            Self::ReadDovecotKeywordsError(..)
+            | Self::WriteDovecotKeywordsError(..)
+            | Self::CreateMaildirDirError(..)
+            | Self::DeliverMaildirMsgError(..)
+            | Self::ReadMaildirDirError(..)
+            | Self::ReadMaildirMsgError(..)
+            | Self::UpdateMaildirFlagsError(..)
+            | Self::RemoveMaildirMsgError(..)
+            | Self::ReadMbsyncStateError(..)
+            | Self::WriteMbsyncStateError(..)
+            | Self::ReadMaildirsizeError(..)
+            | Self::WriteMaildirsizeError(..)
+            | Self::RepairMaildirError(..)
+            | Self::ReadCacheError(..)
+            | Self::WriteCacheError(..)
+            | Self::LockCacheError(..)
+            | Self::DownloadBodyError(..) => ErrorKind::Io,
+            Self::FindMaildirMsgError(_) | Self::FindStateDirError => ErrorKind::NotFound,
+            Self::InvalidMbsyncStateError(..)
+            | Self::InvalidCacheError(..)
+            | Self::UnsupportedCacheVersionError(..)
+            | Self::CorruptedCacheError(_)
+            | Self::DecryptCacheError(_)
+            | Self::IncompleteBodyError(..) => ErrorKind::InvalidData,
+            #[cfg(feature = "imap")]
+            Self::FetchImapBodyError(..) => ErrorKind::Imap,
+            #[cfg(feature = "imap")]
+            Self::OpenImapTraceError(..) => ErrorKind::Io,
+            Self::QuotaExceededError(_) => ErrorKind::QuotaExceeded,
+            Self::CacheLockedError(_) => ErrorKind::Locked,
+            #[cfg(feature = "cache")]
+            Self::ReadReplicaIdsError(..) | Self::WriteReplicaIdsError(..) => ErrorKind::Io,
+            Self::SymlinkError(_) | Self::BuildSyncError(_) => ErrorKind::Config,
+            Self::SessionAppliedError(_) => ErrorKind::Other,
+            Self::EncryptCacheError(_) => ErrorKind::Cache,
+            #[cfg(feature = "compression")]
+            Self::CompressCacheError(..) => ErrorKind::Cache,
+            #[cfg(feature = "compression")]
+            Self::DecompressCacheError(..) => ErrorKind::InvalidData,
+            #[cfg(feature = "sqlite")]
+            Self::SqliteCacheError(..) => ErrorKind::Cache,
+            #[cfg(feature = "json")]
+            Self::ExportCacheError(_) => ErrorKind::Cache,
+            #[cfg(feature = "json")]
+            Self::ImportCacheError(_) => ErrorKind::InvalidData,
+            #[cfg(feature = "json")]
+            Self::OpenEventLogError(..) => ErrorKind::Io,
+            #[cfg(feature = "json")]
+            Self::RunNotifyCommandError(..) => ErrorKind::Io,
+            #[cfg(feature = "json")]
+            Self::NotifyCommandStatusError(..) => ErrorKind::Other,
+            #[cfg(feature = "webhook")]
+            Self::PostWebhookError(..) => ErrorKind::Other,
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Self::WritePatchError(_) => ErrorKind::Io,
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Self::ReadPatchError(_) => ErrorKind::InvalidData,
+            #[cfg(feature = "watch")]
+            Self::WatchMaildirError(..) => ErrorKind::Other,
+            #[cfg(feature = "config")]
+            Self::ReadConfigError(..) => ErrorKind::Io,
+            #[cfg(feature = "config")]
+            Self::ParseConfigError(..) | Self::InvalidConfigError(_) => ErrorKind::Config,
         }
-        Ok(envelopes)
     }
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum Hunk {
-    Imap(HunkKind),
-    Maildir(HunkKind),
-}
-
-#[derive(Debug, Clone, PartialEq, Eq)]
-enum HunkKind {
-    AddMsg(String),
-    RemoveMsg(String),
-    AddFlag(String, Flag),
-    RemoveFlag(String, Flag),
-}
-
-type Patch = Vec<Hunk>;
-
-fn build_patch(
-    prev_imap_envelopes: Envelopes,
-    next_imap_envelopes: Envelopes,
-    prev_mdir_envelopes: Envelopes,
-    next_mdir_envelopes: Envelopes,
-) -> Patch {
-    let mut ids = HashSet::new();
-    ids.extend(next_imap_envelopes.iter().map(|(id, _)| id.as_str()));
-    ids.extend(prev_imap_envelopes.iter().map(|(id, _)| id.as_str()));
-    ids.extend(next_mdir_envelopes.iter().map(|(id, _)| id.as_str()));
-    ids.extend(prev_mdir_envelopes.iter().map(|(id, _)| id.as_str()));
-
-    let mut patch = vec![];
-
-    for id in ids {
-        // id present only in imap
-        if next_imap_envelopes.contains_key(id)
-            && !prev_imap_envelopes.contains_key(id)
-            && !next_mdir_envelopes.contains_key(id)
-            && !prev_mdir_envelopes.contains_key(id)
-        {
-            // add maildir msg
-            patch.push(Hunk::Maildir(HunkKind::AddMsg(id.to_owned())))
-        }
-
-        // id present only in maildir
-        if !next_imap_envelopes.contains_key(id)
-            && !prev_imap_envelopes.contains_key(id)
-            && next_mdir_envelopes.contains_key(id)
-            && !prev_mdir_envelopes.contains_key(id)
-        {
-            // add imap msg
-            patch.push(Hunk::Imap(HunkKind::AddMsg(id.to_owned())))
-        }
 
-        // id everywhere except in imap
-        if !next_imap_envelopes.contains_key(id)
-            && prev_imap_envelopes.contains_key(id)
-            && next_mdir_envelopes.contains_key(id)
-            && prev_mdir_envelopes.contains_key(id)
-        {
-            // remove maildir msg
-            patch.push(Hunk::Maildir(HunkKind::RemoveMsg(id.to_owned())))
-        }
-
-        // id everywhere except in maildir
-        if next_imap_envelopes.contains_key(id)
-            && prev_imap_envelopes.contains_key(id)
-            && !next_mdir_envelopes.contains_key(id)
-            && prev_mdir_envelopes.contains_key(id)
-        {
-            // remove imap msg
-            patch.push(Hunk::Imap(HunkKind::RemoveMsg(id.to_owned())))
-        }
-
-        // id everywhere
-        if next_imap_envelopes.contains_key(id)
-            && prev_imap_envelopes.contains_key(id)
-            && next_mdir_envelopes.contains_key(id)
-            && prev_mdir_envelopes.contains_key(id)
-        {
-            let imap_envelope = next_imap_envelopes.get(id).unwrap();
-            let imap_cache_envelope = prev_imap_envelopes.get(id).unwrap();
-            let mdir_envelope = next_mdir_envelopes.get(id).unwrap();
-            let mdir_cache_envelope = prev_mdir_envelopes.get(id).unwrap();
-
-            for ref flag in [
-                Flag::Draft,
-                Flag::Flagged,
-                Flag::Replied,
-                Flag::Seen,
-                Flag::Trashed,
-            ] {
-                // flag in imap but not in imap cache
-                if imap_envelope.flags.contains(flag) && !imap_cache_envelope.flags.contains(flag) {
-                    // add maildir flag
-                    patch.push(Hunk::Maildir(HunkKind::AddFlag(
-                        id.to_owned(),
-                        flag.to_owned(),
-                    )))
-                }
-
-                // flag not in imap but in imap cache
-                if !imap_envelope.flags.contains(flag) && imap_cache_envelope.flags.contains(flag) {
-                    // remove maildir flag
-                    patch.push(Hunk::Maildir(HunkKind::RemoveFlag(
-                        id.to_owned(),
-                        flag.to_owned(),
-                    )))
-                }
-
-                // flag present only in maildir
-                if !imap_envelope.flags.contains(flag)
-                    && !imap_cache_envelope.flags.contains(flag)
-                    && mdir_envelope.flags.contains(flag)
-                    && !mdir_cache_envelope.flags.contains(flag)
-                {
-                    // add imap flag
-                    patch.push(Hunk::Imap(HunkKind::AddFlag(
-                        id.to_owned(),
-                        flag.to_owned(),
-                    )))
-                }
-
-                // flag everywhere except in maildir
-                if imap_envelope.flags.contains(flag)
-                    && imap_cache_envelope.flags.contains(flag)
-                    && !mdir_envelope.flags.contains(flag)
-                    && mdir_cache_envelope.flags.contains(flag)
-                {
-                    // remove imap flag
-                    patch.push(Hunk::Imap(HunkKind::RemoveFlag(
-                        id.to_owned(),
-                        flag.to_owned(),
-                    )))
-                }
+    /// Returns the stable code of the error, like `E0101 UidMissing`.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::BackendError(err) => err.code(),
+            Self::ReadDovecotKeywordsError(..) => ErrorCode::DovecotKeywordsReadFailed,
+            Self::WriteDovecotKeywordsError(..) => ErrorCode::DovecotKeywordsWriteFailed,
+            Self::CreateMaildirDirError(..) => ErrorCode::MaildirDirCreateFailed,
+            Self::DeliverMaildirMsgError(..) => ErrorCode::MaildirMsgDeliverFailed,
+            Self::ReadMaildirDirError(..) => ErrorCode::MaildirDirReadFailed,
+            Self::ReadMaildirMsgError(..) => ErrorCode::MaildirMsgReadFailed,
+            Self::FindMaildirMsgError(_) => ErrorCode::MaildirMsgMissing,
+            Self::UpdateMaildirFlagsError(..) => ErrorCode::MaildirFlagsUpdateFailed,
+            Self::RemoveMaildirMsgError(..) => ErrorCode::MaildirMsgRemoveFailed,
+            Self::ReadMbsyncStateError(..) => ErrorCode::MbsyncStateReadFailed,
+            Self::WriteMbsyncStateError(..) => ErrorCode::MbsyncStateWriteFailed,
+            Self::InvalidMbsyncStateError(..) => ErrorCode::MbsyncStateInvalid,
+            Self::ReadMaildirsizeError(..) => ErrorCode::MaildirsizeReadFailed,
+            Self::WriteMaildirsizeError(..) => ErrorCode::MaildirsizeWriteFailed,
+            Self::QuotaExceededError(_) => ErrorCode::QuotaExceeded,
+            Self::RepairMaildirError(..) => ErrorCode::MaildirRepairFailed,
+            Self::SymlinkError(_) => ErrorCode::SymlinkNotAllowed,
+            Self::ReadCacheError(..) => ErrorCode::CacheReadFailed,
+            Self::WriteCacheError(..) => ErrorCode::CacheWriteFailed,
+            Self::InvalidCacheError(..) => ErrorCode::CacheInvalid,
+            Self::UnsupportedCacheVersionError(..) => ErrorCode::CacheVersionUnsupported,
+            Self::CorruptedCacheError(_) => ErrorCode::CacheCorrupted,
+            Self::EncryptCacheError(_) => ErrorCode::CacheEncryptFailed,
+            Self::DecryptCacheError(_) => ErrorCode::CacheDecryptFailed,
+            #[cfg(feature = "compression")]
+            Self::CompressCacheError(..) => ErrorCode::CacheCompressFailed,
+            #[cfg(feature = "compression")]
+            Self::DecompressCacheError(..) => ErrorCode::CacheDecompressFailed,
+            Self::FindStateDirError => ErrorCode::StateDirMissing,
+            Self::LockCacheError(..) => ErrorCode::CacheLockFailed,
+            Self::CacheLockedError(_) => ErrorCode::CacheLocked,
+            #[cfg(feature = "sqlite")]
+            Self::SqliteCacheError(..) => ErrorCode::SqliteCacheFailed,
+            #[cfg(feature = "json")]
+            Self::ExportCacheError(_) => ErrorCode::CacheExportFailed,
+            #[cfg(feature = "json")]
+            Self::ImportCacheError(_) => ErrorCode::CacheImportFailed,
+            #[cfg(feature = "json")]
+            Self::OpenEventLogError(..) => ErrorCode::EventLogOpenFailed,
+            #[cfg(feature = "json")]
+            Self::RunNotifyCommandError(..) | Self::NotifyCommandStatusError(..) => {
+                ErrorCode::NotifyCommandFailed
             }
+            #[cfg(feature = "webhook")]
+            Self::PostWebhookError(..) => ErrorCode::WebhookPostFailed,
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Self::WritePatchError(_) => ErrorCode::PatchWriteFailed,
+            #[cfg(any(feature = "json", feature = "cbor"))]
+            Self::ReadPatchError(_) => ErrorCode::PatchInvalid,
+            #[cfg(feature = "watch")]
+            Self::WatchMaildirError(..) => ErrorCode::MaildirWatchFailed,
+            #[cfg(feature = "config")]
+            Self::ReadConfigError(..) => ErrorCode::ConfigReadFailed,
+            #[cfg(feature = "config")]
+            Self::ParseConfigError(..) => ErrorCode::ConfigParseFailed,
+            #[cfg(feature = "config")]
+            Self::InvalidConfigError(_) => ErrorCode::ConfigInvalid,
+            #[cfg(feature = "imap")]
+            Self::FetchImapBodyError(..) => ErrorCode::ImapBodyFetchFailed,
+            #[cfg(feature = "imap")]
+            Self::OpenImapTraceError(..) => ErrorCode::ImapTraceOpenFailed,
+            Self::DownloadBodyError(..) => ErrorCode::BodyWriteFailed,
+            Self::IncompleteBodyError(..) => ErrorCode::BodyIncomplete,
+            #[cfg(feature = "cache")]
+            Self::ReadReplicaIdsError(..) => ErrorCode::ReplicaIdsReadFailed,
+            #[cfg(feature = "cache")]
+            Self::WriteReplicaIdsError(..) => ErrorCode::ReplicaIdsWriteFailed,
+            Self::BuildSyncError(_) => ErrorCode::SyncIncomplete,
+            Self::SessionAppliedError(_) => ErrorCode::SessionApplied,
         }
     }
-
-    patch
-}
-
-#[cfg(test)]
-mod tests {
-    use std::iter::FromIterator;
-
-    use super::*;
-
-    #[test]
-    fn add_imap_msg_test() {
-        let env1 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([Flag::Seen])),
-        };
-        let env2 = Envelope {
-            id: "2".into(),
-            flags: Flags(HashSet::from_iter([Flag::Flagged])),
-        };
-
-        let prev_imap_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let next_imap_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let prev_mdir_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let next_mdir_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-
-        let patch = build_patch(
-            prev_imap_envelopes,
-            next_imap_envelopes,
-            prev_mdir_envelopes,
-            next_mdir_envelopes,
-        );
-
-        assert_eq!(vec![Hunk::Imap(HunkKind::AddMsg("2".into()))], patch);
-    }
-
-    #[test]
-    fn remove_imap_msg_test() {
-        let env1 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([Flag::Seen])),
-        };
-        let env2 = Envelope {
-            id: "2".into(),
-            flags: Flags(HashSet::from_iter([Flag::Flagged])),
-        };
-
-        let prev_imap_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let next_imap_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let prev_mdir_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let next_mdir_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-
-        let patch = build_patch(
-            prev_imap_envelopes,
-            next_imap_envelopes,
-            prev_mdir_envelopes,
-            next_mdir_envelopes,
-        );
-
-        assert_eq!(vec![Hunk::Imap(HunkKind::RemoveMsg("2".into()))], patch);
-    }
-
-    #[test]
-    fn add_mdir_msg_test() {
-        let env1 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([Flag::Seen])),
-        };
-        let env2 = Envelope {
-            id: "2".into(),
-            flags: Flags(HashSet::from_iter([Flag::Flagged])),
-        };
-
-        let prev_imap_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let next_imap_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let prev_mdir_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let next_mdir_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-
-        let patch = build_patch(
-            prev_imap_envelopes,
-            next_imap_envelopes,
-            prev_mdir_envelopes,
-            next_mdir_envelopes,
-        );
-
-        assert_eq!(vec![Hunk::Maildir(HunkKind::AddMsg("2".into()))], patch);
-    }
-
-    #[test]
-    fn remove_mdir_msg_test() {
-        let env1 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([Flag::Seen])),
-        };
-        let env2 = Envelope {
-            id: "2".into(),
-            flags: Flags(HashSet::from_iter([Flag::Flagged])),
-        };
-
-        let prev_imap_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let next_imap_envelopes = Envelopes(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
-        let prev_mdir_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-        let next_mdir_envelopes = Envelopes(HashMap::from_iter([
-            (env1.id.clone(), env1.clone()),
-            (env2.id.clone(), env2.clone()),
-        ]));
-
-        let patch = build_patch(
-            prev_imap_envelopes,
-            next_imap_envelopes,
-            prev_mdir_envelopes,
-            next_mdir_envelopes,
-        );
-
-        assert_eq!(vec![Hunk::Maildir(HunkKind::RemoveMsg("2".into()))], patch);
-    }
-
-    #[test]
-    fn single_add_remove_flag_tests() {
-        let e1 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([Flag::Seen, Flag::Replied])),
-        };
-        let e2 = Envelope {
-            id: "1".into(),
-            flags: Flags(HashSet::from_iter([
-                Flag::Seen,
-                Flag::Flagged,
-                Flag::Replied,
-            ])),
-        };
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        assert_eq!(
-            vec![Hunk::Imap(HunkKind::AddFlag("1".into(), Flag::Flagged))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        assert_eq!(
-            vec![Hunk::Maildir(HunkKind::AddFlag("1".into(), Flag::Flagged))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        assert_eq!(
-            vec![Hunk::Maildir(HunkKind::RemoveFlag(
-                "1".into(),
-                Flag::Flagged
-            ))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        assert_eq!(
-            vec![Hunk::Imap(HunkKind::RemoveFlag("1".into(), Flag::Flagged))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        assert_eq!(
-            vec![Hunk::Maildir(HunkKind::AddFlag("1".into(), Flag::Flagged))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-
-        let imap_prev = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        let imap_next = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_prev = Envelopes(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
-        let mdir_next = Envelopes(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
-        assert_eq!(
-            vec![Hunk::Maildir(HunkKind::RemoveFlag(
-                "1".into(),
-                Flag::Flagged
-            ))],
-            build_patch(imap_prev, imap_next, mdir_prev, mdir_next),
-        );
-    }
 }
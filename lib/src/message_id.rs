@@ -0,0 +1,101 @@
+#[cfg(feature = "imap")]
+use std::collections::HashMap;
+
+#[cfg(feature = "imap")]
+use crate::EverestError;
+
+/// Extracts the Message-ID from the given raw message or header
+/// section, without the surrounding angle brackets.
+pub fn parse(raw: &[u8]) -> Option<String> {
+    header(raw, "message-id").and_then(|value| normalize(&value))
+}
+
+/// Extracts the unfolded value of the first header of the given name,
+/// case-insensitive, from the given raw message or header section.
+pub(crate) fn header(raw: &[u8], name: &str) -> Option<String> {
+    let raw = String::from_utf8_lossy(raw);
+    let mut lines = raw.split('\n').map(|line| line.trim_end_matches('\r'));
+
+    while let Some(line) = lines.next() {
+        // headers end at the first empty line
+        if line.is_empty() {
+            break;
+        }
+        let value = match line.split_once(':') {
+            Some((key, value)) if key.trim().eq_ignore_ascii_case(name) => value,
+            _ => continue,
+        };
+
+        // the value may be folded over several lines
+        let mut value = value.to_owned();
+        for line in lines.by_ref() {
+            if !line.starts_with([' ', '\t']) {
+                break;
+            }
+            value.push_str(line);
+        }
+        return Some(value);
+    }
+
+    None
+}
+
+/// Lists the Message-IDs of the given fetches by UID, taken from the
+/// `ENVELOPE` or the `BODY.PEEK[HEADER]` (or any header section
+/// including `Message-ID`) of the fetches. Messages without
+/// Message-ID are left out.
+#[cfg(feature = "imap")]
+pub fn from_fetches(
+    fetches: &imap::types::Fetches,
+) -> Result<HashMap<String, String>, EverestError> {
+    let mut message_ids = HashMap::new();
+    for fetch in fetches.iter() {
+        let uid = fetch
+            .uid
+            .ok_or_else(|| crate::backend::imap::missing_uid(fetch.message))?;
+        let message_id = fetch
+            .envelope()
+            .and_then(|envelope| envelope.message_id.as_deref())
+            .and_then(|message_id| normalize(&String::from_utf8_lossy(message_id)))
+            .or_else(|| fetch.header().and_then(parse));
+        if let Some(message_id) = message_id {
+            message_ids.insert(uid.to_string(), message_id);
+        }
+    }
+    Ok(message_ids)
+}
+
+pub(crate) fn normalize(value: &str) -> Option<String> {
+    let value = value.trim();
+    let value = match (value.find('<'), value.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &value[start + 1..end],
+        _ => value,
+    };
+    let value = value.split_whitespace().collect::<String>();
+    if value.is_empty() {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(
+            Some("abc@example.org".to_owned()),
+            parse(b"Subject: test\r\nMessage-Id:  <abc@example.org>\r\n\r\nbody")
+        );
+        assert_eq!(
+            Some("abc@example.org".to_owned()),
+            parse(b"MESSAGE-ID:\n <abc@\n example.org>\nTo: a@b\n\n")
+        );
+        assert_eq!(
+            None,
+            parse(b"Subject: test\n\nMessage-ID: <abc@example.org>\n")
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! Optional metadata of messages, for policies needing more than
+//! flags, like date horizons and dedup.
+//!
+//! Envelopes only carry flags by default. With the `metadata`
+//! feature, backends also fill the metadata they can get cheaply:
+//! IMAP envelopes from the items of [`crate::fetch::ENVELOPES_QUERY`],
+//! maildir envelopes from the delivery time of their unique name, or
+//! from their headers when listed with the `maildir` crate.
+
+#[cfg(all(feature = "metadata", feature = "maildir"))]
+use std::fs;
+#[cfg(all(feature = "metadata", any(feature = "imap", feature = "maildir")))]
+use std::time::Duration;
+use std::time::SystemTime;
+
+#[cfg(all(feature = "metadata", any(feature = "imap", feature = "maildir")))]
+use crate::message_id;
+#[cfg(all(feature = "metadata", feature = "maildir"))]
+use crate::{backend::maildir::read_headers, EverestError};
+
+/// Metadata of a message, each part being unknown until filled by a
+/// backend.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Metadata {
+    /// Internal date for IMAP, delivery or `Date` header date for
+    /// maildirs.
+    pub date: Option<SystemTime>,
+    pub subject: Option<String>,
+    /// Address of the author, like `alice@example.org`.
+    pub from: Option<String>,
+    /// Message-ID, without the surrounding angle brackets.
+    pub message_id: Option<String>,
+    /// Size of the raw message, in bytes.
+    pub size: Option<u64>,
+}
+
+impl Metadata {
+    /// Returns `true` if no part of the metadata is known.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+#[cfg(all(feature = "metadata", feature = "imap"))]
+impl Metadata {
+    /// Builds the metadata of the given fetch, from its
+    /// `INTERNALDATE`, `RFC822.SIZE` and `ENVELOPE` items.
+    pub(crate) fn from_fetch(fetch: &imap::types::Fetch) -> Self {
+        let envelope = fetch.envelope();
+        let text = |value: Option<&[u8]>| value.map(|v| String::from_utf8_lossy(v).into_owned());
+        Self {
+            date: fetch
+                .internal_date()
+                .and_then(|date| from_secs(date.timestamp())),
+            subject: text(envelope.and_then(|envelope| envelope.subject.as_deref())),
+            from: envelope
+                .and_then(|envelope| envelope.from.as_ref()?.first())
+                .and_then(|address| {
+                    let mailbox = text(address.mailbox.as_deref())?;
+                    match text(address.host.as_deref()) {
+                        Some(host) => Some(format!("{}@{}", mailbox, host)),
+                        None => Some(mailbox),
+                    }
+                }),
+            message_id: text(envelope.and_then(|envelope| envelope.message_id.as_deref()))
+                .and_then(|message_id| message_id::normalize(&message_id)),
+            size: fetch.size.map(u64::from),
+        }
+    }
+}
+
+#[cfg(all(feature = "metadata", feature = "maildir"))]
+impl Metadata {
+    /// Builds the metadata of the given maildir entry, from its
+    /// headers and its file.
+    pub(crate) fn from_mail_entry(entry: &mut maildir::MailEntry) -> Result<Self, EverestError> {
+        let path = entry.path().to_owned();
+        let headers = read_headers(&path)?;
+        let size = fs::metadata(&path)
+            .map_err(|err| EverestError::ReadMaildirMsgError(err, path.clone()))?
+            .len();
+        let header = |name| message_id::header(&headers, name).map(|v| v.trim().to_owned());
+        Ok(Self {
+            // messages without a valid Date header are still listed
+            date: entry.date().ok().and_then(from_secs),
+            subject: header("subject"),
+            from: header("from").map(|from| match (from.find('<'), from.rfind('>')) {
+                (Some(start), Some(end)) if start < end => from[start + 1..end].to_owned(),
+                _ => from,
+            }),
+            message_id: message_id::parse(&headers),
+            size: Some(size),
+        })
+    }
+
+    /// Builds the metadata of a maildir message listed from its
+    /// filename, which only tells its delivery time.
+    pub(crate) fn from_delivery_time(secs: u64) -> Self {
+        Self {
+            date: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            ..Self::default()
+        }
+    }
+}
+
+/// Converts seconds since the epoch, `None` for dates before it.
+#[cfg(all(feature = "metadata", any(feature = "imap", feature = "maildir")))]
+fn from_secs(secs: i64) -> Option<SystemTime> {
+    let secs = u64::try_from(secs).ok()?;
+    Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(all(test, feature = "metadata", feature = "maildir"))]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use crate::{backend::maildir::Mdir, Envelopes, Flags};
+
+    #[test]
+    fn maildir_metadata_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        let raw = b"Date: Thu, 1 Jan 2015 00:00:00 +0000\r\n\
+            From: Alice <alice@example.org>\r\n\
+            Subject:  Hello\r\n\
+            Message-ID: <a@b>\r\n\r\nbody\r\n";
+        let id = mdir.add_msg(raw, &Flags::default()).unwrap();
+
+        let entries = maildir::Maildir::from(dir.path().to_owned()).list_new();
+        let envelopes = Envelopes::try_from(entries).unwrap();
+        let envelope = &envelopes[id.as_str()];
+        assert_eq!(
+            Some(SystemTime::UNIX_EPOCH + Duration::from_secs(1_420_070_400)),
+            envelope.date()
+        );
+        assert_eq!(Some("alice@example.org"), envelope.from());
+        assert_eq!(Some("Hello"), envelope.subject());
+        assert_eq!(Some("a@b"), envelope.message_id());
+        assert_eq!(Some(raw.len() as u64), envelope.size());
+
+        // scans only read the delivery time of unique names
+        let envelopes = mdir.envelopes().unwrap();
+        let envelope = &envelopes[id.as_str()];
+        assert!(envelope.date().is_some());
+        assert_eq!(None, envelope.subject());
+    }
+}
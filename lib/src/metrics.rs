@@ -0,0 +1,217 @@
+//! Metrics of the sync, published through the [`metrics`] facade with
+//! the `metrics` feature, so that server-side deployments can alert
+//! on sync failures. Installing a recorder, like the Prometheus
+//! exporter of `metrics-exporter-prometheus`, is up to the
+//! application.
+//!
+//! Metrics are labelled with the `folder` and, when set, the
+//! `account`:
+//!
+//! - `everest_syncs_total`: folders synced;
+//! - `everest_hunks_applied_total`: hunks applied, by `side`;
+//! - `everest_failures_total`: hunks or folders failing to sync, by
+//!   `scope` (`hunk` or `folder`);
+//! - `everest_bytes_copied_total`: size of the messages copied, by
+//!   `side`;
+//! - `everest_phase_duration_seconds`: histogram of the time spent in
+//!   each phase, by `phase`;
+//! - `everest_last_sync_timestamp_seconds`: time of the last sync of
+//!   the folder, to alert on stalled syncs.
+
+use std::{
+    error::Error,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use ::metrics::{counter, gauge, histogram};
+
+use crate::{
+    observer::SyncObserver,
+    report::{HunkError, SyncReport},
+    Hunk, Side,
+};
+
+/// Observer publishing the metrics of the sync, registered with
+/// [`crate::sync::SyncBuilder::observer`].
+#[derive(Debug, Clone, Default)]
+pub struct MetricsObserver {
+    account: Option<String>,
+}
+
+impl MetricsObserver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Labels the metrics with the name of the given account, for
+    /// deployments syncing several accounts.
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Returns the labels of the metrics of the given folder, followed
+    /// by the given ones.
+    fn labels(&self, folder: &str, labels: &[(&'static str, &str)]) -> Vec<(&'static str, String)> {
+        let mut all = Vec::with_capacity(labels.len() + 2);
+        if let Some(account) = &self.account {
+            all.push(("account", account.clone()));
+        }
+        all.push(("folder", folder.to_owned()));
+        all.extend(labels.iter().map(|(key, value)| (*key, value.to_string())));
+        all
+    }
+}
+
+impl SyncObserver for MetricsObserver {
+    fn hunk_applied(&self, folder: &str, hunk: &Hunk) {
+        let side = hunk.target.to_string();
+        let labels = self.labels(folder, &[("side", &side)]);
+        counter!("everest_hunks_applied_total", &labels).increment(1);
+    }
+
+    fn error(&self, folder: &str, err: &(dyn Error + 'static)) {
+        let scope = match err.is::<HunkError>() {
+            true => "hunk",
+            false => "folder",
+        };
+        let labels = self.labels(folder, &[("scope", scope)]);
+        counter!("everest_failures_total", &labels).increment(1);
+    }
+
+    fn folder_finished(&self, folder: &str, report: &SyncReport) {
+        let labels = self.labels(folder, &[]);
+        counter!("everest_syncs_total", &labels).increment(1);
+        for side in [Side::Left, Side::Right] {
+            let side_name = side.to_string();
+            let labels = self.labels(folder, &[("side", &side_name)]);
+            counter!("everest_bytes_copied_total", &labels).increment(report.side(side).bytes);
+        }
+        for (phase, duration) in report.timings.iter() {
+            let labels = self.labels(folder, &[("phase", phase.name())]);
+            histogram!("everest_phase_duration_seconds", &labels).record(duration.as_secs_f64());
+        }
+        let now = SystemTime::now().duration_since(UNIX_EPOCH);
+        let now = now.unwrap_or_default().as_secs_f64();
+        gauge!("everest_last_sync_timestamp_seconds", &labels).set(now);
+    }
+}
+
+#[cfg(all(test, feature = "cache"))]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        sync::{Arc, Mutex},
+    };
+
+    use ::metrics::{
+        Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata,
+        Recorder, SharedString, Unit,
+    };
+
+    use crate::{
+        cache::MemoryCache,
+        sync::{MemoryReplica, Sync},
+    };
+
+    use super::*;
+
+    /// Recorder summing the values of each metric, keyed by name and
+    /// labels like `name{folder=INBOX}`.
+    #[derive(Clone, Default)]
+    struct Sums(Arc<Mutex<BTreeMap<String, f64>>>);
+
+    struct Sum(Sums, String);
+
+    impl Sum {
+        fn add(&self, value: f64) {
+            *self.0 .0.lock().unwrap().entry(self.1.clone()).or_default() += value;
+        }
+    }
+
+    impl CounterFn for Sum {
+        fn increment(&self, value: u64) {
+            self.add(value as f64)
+        }
+
+        fn absolute(&self, _value: u64) {}
+    }
+
+    impl GaugeFn for Sum {
+        fn increment(&self, value: f64) {
+            self.add(value)
+        }
+
+        fn decrement(&self, value: f64) {
+            self.add(-value)
+        }
+
+        fn set(&self, value: f64) {
+            self.0 .0.lock().unwrap().insert(self.1.clone(), value);
+        }
+    }
+
+    impl HistogramFn for Sum {
+        fn record(&self, value: f64) {
+            self.add(value)
+        }
+    }
+
+    impl Sums {
+        fn sum(&self, key: &Key) -> Arc<Sum> {
+            let labels = key.labels().map(|l| format!("{}={}", l.key(), l.value()));
+            let labels = labels.collect::<Vec<_>>().join(",");
+            Arc::new(Sum(self.clone(), format!("{}{{{}}}", key.name(), labels)))
+        }
+
+        fn get(&self, key: &str) -> Option<f64> {
+            self.0.lock().unwrap().get(key).copied()
+        }
+    }
+
+    impl Recorder for Sums {
+        fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+        fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+        fn register_counter(&self, key: &Key, _: &Metadata<'_>) -> Counter {
+            Counter::from_arc(self.sum(key))
+        }
+
+        fn register_gauge(&self, key: &Key, _: &Metadata<'_>) -> Gauge {
+            Gauge::from_arc(self.sum(key))
+        }
+
+        fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+            Histogram::from_arc(self.sum(key))
+        }
+    }
+
+    #[test]
+    fn metrics_test() {
+        let sums = Sums::default();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::new(&[("22", &[])]).with_failing("1"))
+            .cache_store(MemoryCache::new())
+            .observer(MetricsObserver::new().with_account("work"))
+            .build()
+            .unwrap();
+        ::metrics::with_local_recorder(&sums, || sync.run().unwrap());
+
+        let syncs = sums.get("everest_syncs_total{account=work,folder=INBOX}");
+        assert_eq!(Some(1.0), syncs);
+        let get = |key: &str, label: &str| {
+            sums.get(&format!("{}{{account=work,folder=INBOX,{}}}", key, label))
+        };
+        assert_eq!(Some(1.0), get("everest_hunks_applied_total", "side=left"));
+        assert_eq!(None, get("everest_hunks_applied_total", "side=right"));
+        assert_eq!(Some(1.0), get("everest_failures_total", "scope=hunk"));
+        assert_eq!(Some(2.0), get("everest_bytes_copied_total", "side=left"));
+        assert_eq!(Some(0.0), get("everest_bytes_copied_total", "side=right"));
+        assert!(get("everest_phase_duration_seconds", "phase=diff").is_some());
+        assert!(sums
+            .get("everest_last_sync_timestamp_seconds{account=work,folder=INBOX}")
+            .is_some_and(|time| time > 0.0));
+    }
+}
@@ -0,0 +1,543 @@
+//! Async variants of the fetching, diffing and apply phases of a
+//! sync, for GUI applications and daemons driving syncs without
+//! blocking their threads. Sides of the sync are [`AsyncReplica`]s,
+//! blocking ones running off the async threads.
+//!
+//! The async layer only relies on the `futures` traits: blocking work
+//! (like reading maildirs) and timers go through a [`Runtime`], so
+//! that the crate runs on any executor. [`ThreadRuntime`] works
+//! everywhere, and `TokioRuntime` uses the blocking pool and timers
+//! of tokio with the `async-tokio` feature.
+
+#[cfg(feature = "cache")]
+use std::sync::{Arc, Mutex};
+use std::{
+    future::Future,
+    panic::{self, AssertUnwindSafe},
+    thread,
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::BoxFuture,
+    stream::{self, StreamExt},
+    FutureExt,
+};
+
+use crate::{
+    backend::maildir::Mdir,
+    plan::{lanes, Batch},
+    Envelopes, EverestError, Flag, Flags, Hunk, HunkKind, Patch, Side,
+};
+
+/// Services of the async runtime the async layer needs, injected by
+/// the caller.
+pub trait Runtime {
+    /// Runs the given blocking function off the async threads, and
+    /// resolves to its result. Panics are propagated to the caller.
+    fn spawn_blocking<F, T>(&self, f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+
+    /// Resolves once the given duration elapsed.
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+/// Runtime running blocking work and timers on their own threads, for
+/// executors without a blocking pool.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ThreadRuntime;
+
+impl Runtime for ThreadRuntime {
+    fn spawn_blocking<F, T>(&self, f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(f)));
+        });
+        async move {
+            // the sender is always used, unless the thread is killed
+            match rx.await.expect("blocking thread stopped") {
+                Ok(output) => output,
+                Err(payload) => panic::resume_unwind(payload),
+            }
+        }
+        .boxed()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.spawn_blocking(move || thread::sleep(duration))
+    }
+}
+
+/// Runtime relying on the blocking pool and timers of the current
+/// tokio runtime.
+#[cfg(feature = "async-tokio")]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioRuntime;
+
+#[cfg(feature = "async-tokio")]
+impl Runtime for TokioRuntime {
+    fn spawn_blocking<F, T>(&self, f: F) -> BoxFuture<'static, T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        tokio::task::spawn_blocking(f)
+            .map(|output| output.unwrap_or_else(|err| panic::resume_unwind(err.into_panic())))
+            .boxed()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// Async variant of [`crate::sync::Replica`], for sides of a sync
+/// talking to their backend without blocking, like IMAP clients built
+/// on an async runtime. Blocking replicas become async ones with
+/// [`BlockingReplica`].
+pub trait AsyncReplica {
+    /// Lists the envelopes of the given folder.
+    fn envelopes<'a>(
+        &'a mut self,
+        folder: &'a str,
+    ) -> BoxFuture<'a, Result<Envelopes, EverestError>>;
+
+    /// Reads the raw content of the given message, to copy it to the
+    /// other side.
+    fn read_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<u8>, EverestError>>;
+
+    /// Adds the given message with the given id and flags.
+    fn add_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        raw: &'a [u8],
+        flags: &'a Flags,
+    ) -> BoxFuture<'a, Result<(), EverestError>>;
+
+    fn remove_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<(), EverestError>>;
+
+    fn add_flag<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        flag: &'a Flag,
+    ) -> BoxFuture<'a, Result<(), EverestError>>;
+
+    fn remove_flag<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        flag: &'a Flag,
+    ) -> BoxFuture<'a, Result<(), EverestError>>;
+
+    /// Saves the state the replica keeps in memory about the given
+    /// folder, see [`crate::sync::Replica::flush`]. Does nothing by
+    /// default.
+    fn flush<'a>(&'a mut self, _folder: &'a str) -> BoxFuture<'a, Result<(), EverestError>> {
+        futures::future::ok(()).boxed()
+    }
+}
+
+/// Blocking replica made async by running its calls with
+/// [`Runtime::spawn_blocking`], one at a time.
+#[cfg(feature = "cache")]
+pub struct BlockingReplica<R, Rt> {
+    replica: Arc<Mutex<R>>,
+    rt: Rt,
+}
+
+#[cfg(feature = "cache")]
+impl<R, Rt> BlockingReplica<R, Rt>
+where
+    R: crate::sync::Replica + Send + 'static,
+    Rt: Runtime,
+{
+    pub fn new(replica: R, rt: Rt) -> Self {
+        Self {
+            replica: Arc::new(Mutex::new(replica)),
+            rt,
+        }
+    }
+
+    /// Runs the given call of the replica off the async threads.
+    fn call<F, T>(&self, f: F) -> BoxFuture<'static, Result<T, EverestError>>
+    where
+        F: FnOnce(&mut R) -> Result<T, EverestError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let replica = self.replica.clone();
+        // a panicking call poisons the lock, but the panic is
+        // propagated to the caller anyway
+        self.rt
+            .spawn_blocking(move || f(&mut replica.lock().unwrap()))
+    }
+}
+
+#[cfg(feature = "cache")]
+impl<R, Rt> AsyncReplica for BlockingReplica<R, Rt>
+where
+    R: crate::sync::Replica + Send + 'static,
+    Rt: Runtime,
+{
+    fn envelopes<'a>(
+        &'a mut self,
+        folder: &'a str,
+    ) -> BoxFuture<'a, Result<Envelopes, EverestError>> {
+        let folder = folder.to_owned();
+        self.call(move |replica| replica.envelopes(&folder))
+    }
+
+    fn read_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<Vec<u8>, EverestError>> {
+        let (folder, id) = (folder.to_owned(), id.to_owned());
+        self.call(move |replica| replica.read_msg(&folder, &id))
+    }
+
+    fn add_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        raw: &'a [u8],
+        flags: &'a Flags,
+    ) -> BoxFuture<'a, Result<(), EverestError>> {
+        let (folder, id) = (folder.to_owned(), id.to_owned());
+        let (raw, flags) = (raw.to_vec(), flags.clone());
+        self.call(move |replica| replica.add_msg(&folder, &id, &raw, &flags))
+    }
+
+    fn remove_msg<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+    ) -> BoxFuture<'a, Result<(), EverestError>> {
+        let (folder, id) = (folder.to_owned(), id.to_owned());
+        self.call(move |replica| replica.remove_msg(&folder, &id))
+    }
+
+    fn add_flag<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        flag: &'a Flag,
+    ) -> BoxFuture<'a, Result<(), EverestError>> {
+        let (folder, id, flag) = (folder.to_owned(), id.to_owned(), flag.clone());
+        self.call(move |replica| replica.add_flag(&folder, &id, &flag))
+    }
+
+    fn remove_flag<'a>(
+        &'a mut self,
+        folder: &'a str,
+        id: &'a str,
+        flag: &'a Flag,
+    ) -> BoxFuture<'a, Result<(), EverestError>> {
+        let (folder, id, flag) = (folder.to_owned(), id.to_owned(), flag.clone());
+        self.call(move |replica| replica.remove_flag(&folder, &id, &flag))
+    }
+
+    fn flush<'a>(&'a mut self, folder: &'a str) -> BoxFuture<'a, Result<(), EverestError>> {
+        let folder = folder.to_owned();
+        self.call(move |replica| replica.flush(&folder))
+    }
+}
+
+/// Applies the given hunk of the given folder to its side, messages
+/// being copied from the other side with the flags listed in its
+/// given envelopes. Returns the size of the copied message, 0 for
+/// other hunks.
+pub async fn apply<L: AsyncReplica, R: AsyncReplica>(
+    left: &mut L,
+    right: &mut R,
+    folder: &str,
+    hunk: &Hunk,
+    left_envelopes: &Envelopes,
+    right_envelopes: &Envelopes,
+) -> Result<u64, EverestError> {
+    let (target, source, source_envelopes): (&mut dyn AsyncReplica, &mut dyn AsyncReplica, _) =
+        match hunk.target {
+            Side::Left => (left, right, right_envelopes),
+            Side::Right => (right, left, left_envelopes),
+        };
+    match &hunk.kind {
+        HunkKind::AddMsg(id) => {
+            let raw = source.read_msg(folder, &id.source).await?;
+            let flags = source_envelopes
+                .get(&id.source)
+                .map(|envelope| envelope.flags.clone())
+                .unwrap_or_default();
+            target
+                .add_msg(folder, id.target_or_source(), &raw, &flags)
+                .await?;
+            return Ok(raw.len() as u64);
+        }
+        HunkKind::RemoveMsg(id) => target.remove_msg(folder, id.target_or_source()).await?,
+        HunkKind::AddFlag(id, flag) => target.add_flag(folder, id.target_or_source(), flag).await?,
+        HunkKind::RemoveFlag(id, flag) => {
+            target
+                .remove_flag(folder, id.target_or_source(), flag)
+                .await?
+        }
+    }
+    Ok(0)
+}
+
+/// Async variant of [`Mdir::envelopes`].
+pub async fn envelopes<R: Runtime>(rt: &R, mdir: &Mdir) -> Result<Envelopes, EverestError> {
+    let mdir = mdir.clone();
+    rt.spawn_blocking(move || mdir.envelopes()).await
+}
+
+/// Async variant of [`crate::build_patch`], taking ownership of the
+/// envelopes so that they can be diffed off the async threads.
+pub async fn build_patch<R: Runtime>(
+    rt: &R,
+    prev_imap_envelopes: Envelopes,
+    next_imap_envelopes: Envelopes,
+    prev_mdir_envelopes: Envelopes,
+    next_mdir_envelopes: Envelopes,
+) -> Patch {
+    rt.spawn_blocking(move || {
+        crate::build_patch(
+            &prev_imap_envelopes,
+            &next_imap_envelopes,
+            &prev_mdir_envelopes,
+            &next_mdir_envelopes,
+        )
+    })
+    .await
+}
+
+/// Async variant of [`crate::plan::execute`]: batches of the same
+/// backend and folder are applied one after the other, in their
+/// order, while up to `concurrency` of them (at least one) are
+/// polled concurrently. Nothing is spawned, so the returned future
+/// runs on whatever executor polls it.
+pub async fn execute<'a, F, Fut, E>(batches: &'a [Batch], concurrency: usize, apply: F) -> Vec<E>
+where
+    F: Fn(&'a Batch) -> Fut,
+    Fut: Future<Output = Result<(), E>>,
+{
+    let lanes = lanes(batches);
+    let apply = &apply;
+    let mut errors = stream::iter(lanes)
+        .map(|lane| async move {
+            let mut errors = vec![];
+            for i in lane {
+                if let Err(err) = apply(&batches[i]).await {
+                    errors.push((i, err));
+                }
+            }
+            errors
+        })
+        .buffer_unordered(concurrency.max(1))
+        .concat()
+        .await;
+
+    errors.sort_by_key(|(i, _)| *i);
+    errors.into_iter().map(|(_, err)| err).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::{Cell, RefCell},
+        task::Poll,
+        time::Instant,
+    };
+
+    use futures::{executor::block_on, future::poll_fn};
+
+    #[cfg(feature = "cache")]
+    use crate::{cache, sync::MemoryReplica};
+
+    use crate::{
+        plan::{plan, Backend, BatchOp},
+        Envelope, Flag, Hunk, HunkKind, Side,
+    };
+
+    use super::*;
+
+    #[test]
+    fn build_patch_test() {
+        let mut next = Envelopes::default();
+        next.insert(
+            "1".into(),
+            Envelope {
+                id: "1".into(),
+                flags: Default::default(),
+                metadata: None,
+            },
+        );
+
+        let patch = block_on(build_patch(
+            &ThreadRuntime,
+            Envelopes::default(),
+            next,
+            Envelopes::default(),
+            Envelopes::default(),
+        ));
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::AddMsg("1".into()))],
+            patch
+        );
+    }
+
+    #[test]
+    fn envelopes_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mdir = Mdir::new(dir.path());
+        mdir.create_dirs().unwrap();
+        assert!(block_on(envelopes(&ThreadRuntime, &mdir))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn runtime_test() {
+        let now = Instant::now();
+        block_on(ThreadRuntime.sleep(Duration::from_millis(10)));
+        assert!(now.elapsed() >= Duration::from_millis(10));
+
+        let panicked = panic::catch_unwind(|| {
+            block_on(ThreadRuntime.spawn_blocking(|| panic!("blocking panic")))
+        });
+        assert!(panicked.is_err());
+    }
+
+    #[cfg(feature = "async-tokio")]
+    #[test]
+    fn tokio_runtime_test() {
+        let rt = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        rt.block_on(async {
+            TokioRuntime.sleep(Duration::from_millis(1)).await;
+            assert_eq!(2, TokioRuntime.spawn_blocking(|| 1 + 1).await);
+        });
+    }
+
+    #[cfg(feature = "cache")]
+    #[test]
+    fn apply_test() {
+        let mut left =
+            BlockingReplica::new(MemoryReplica::new(&[("1", &[Flag::Seen])]), ThreadRuntime);
+        let mut right = BlockingReplica::new(MemoryReplica::default(), ThreadRuntime);
+        let left_envelopes = block_on(left.envelopes("INBOX")).unwrap();
+        let right_envelopes = Envelopes::default();
+        assert_eq!(cache::envelopes(&[("1", &[Flag::Seen])]), left_envelopes);
+
+        let hunks = [
+            Hunk::new(Side::Right, HunkKind::AddMsg("1".into())),
+            Hunk::new(Side::Right, HunkKind::RemoveFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Flagged)),
+        ];
+        let mut bytes = vec![];
+        for hunk in &hunks {
+            let applied = apply(
+                &mut left,
+                &mut right,
+                "INBOX",
+                hunk,
+                &left_envelopes,
+                &right_envelopes,
+            );
+            bytes.push(block_on(applied).unwrap());
+        }
+        assert_eq!(vec![1, 0, 0], bytes);
+        assert_eq!(
+            cache::envelopes(&[("1", &[Flag::Seen, Flag::Flagged])]),
+            block_on(left.envelopes("INBOX")).unwrap()
+        );
+        assert_eq!(
+            cache::envelopes(&[("1", &[])]),
+            block_on(right.envelopes("INBOX")).unwrap()
+        );
+
+        // failing calls fail the hunk
+        let hunk = Hunk::new(Side::Right, HunkKind::RemoveMsg("2".into()));
+        let mut right =
+            BlockingReplica::new(MemoryReplica::default().with_failing("2"), ThreadRuntime);
+        let applied = apply(
+            &mut left,
+            &mut right,
+            "INBOX",
+            &hunk,
+            &left_envelopes,
+            &right_envelopes,
+        );
+        assert!(block_on(applied).is_err());
+    }
+
+    #[test]
+    fn execute_test() {
+        let patch = vec![
+            Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("2".into())),
+        ];
+        let folders = ["INBOX", "Sent", "Trash", "Archive"];
+        let batches = plan(folders.map(|folder| (folder, &patch)));
+
+        let running = Cell::new(0);
+        let max_running = Cell::new(0);
+        let applied = RefCell::new(Vec::new());
+        let errors = block_on(execute(&batches, 2, |batch| {
+            let (running, max_running, applied) = (&running, &max_running, &applied);
+            async move {
+                running.set(running.get() + 1);
+                max_running.set(max_running.get().max(running.get()));
+                // yields once, so that other lanes get polled
+                let mut yielded = false;
+                poll_fn(|cx| {
+                    if yielded {
+                        return Poll::Ready(());
+                    }
+                    yielded = true;
+                    cx.waker().wake_by_ref();
+                    Poll::Pending
+                })
+                .await;
+                applied.borrow_mut().push(batch);
+                running.set(running.get() - 1);
+                match batch.op {
+                    BatchOp::RemoveMsgs => Err(batch.folder.as_str()),
+                    _ => Ok(()),
+                }
+            }
+        }));
+
+        assert_eq!(folders.to_vec(), errors);
+        assert_eq!(2, max_running.get());
+        let applied = applied.into_inner();
+        assert_eq!(batches.len(), applied.len());
+        for folder in folders {
+            let ops = applied
+                .iter()
+                .filter(|batch| batch.backend == Backend::Imap && batch.folder == folder)
+                .map(|batch| &batch.op)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                vec![&BatchOp::AddFlag(Flag::Seen), &BatchOp::RemoveMsgs],
+                ops
+            );
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! Desktop notifications of new mail, with the `notification`
+//! feature, for users running the sync as a daemon to be told when
+//! messages arrive, like `biff` did.
+
+use notify_rust::Notification;
+
+use crate::{observer::SyncObserver, report::SyncReport, Side};
+
+/// Observer showing a desktop notification when the sync of a folder
+/// pulls new messages, registered with
+/// [`crate::sync::SyncBuilder::observer`].
+///
+/// New messages are the ones added to the right side, usually the
+/// local maildir. Failing to show a notification does not fail the
+/// sync: a warning is logged.
+#[derive(Debug, Clone)]
+pub struct DesktopNotifier {
+    side: Side,
+    account: Option<String>,
+    icon: Option<String>,
+}
+
+impl Default for DesktopNotifier {
+    fn default() -> Self {
+        Self {
+            side: Side::Right,
+            account: None,
+            icon: None,
+        }
+    }
+}
+
+impl DesktopNotifier {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Notifies messages added to the given side instead, like the
+    /// left one when the local side is on the left.
+    pub fn with_side(mut self, side: Side) -> Self {
+        self.side = side;
+        self
+    }
+
+    /// Names the account in the notifications, for users syncing
+    /// several accounts.
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Shows the given icon, a name of the icon theme or a path.
+    pub fn with_icon<I: Into<String>>(mut self, icon: I) -> Self {
+        self.icon = Some(icon.into());
+        self
+    }
+
+    /// Returns the summary and the body of the notification of the
+    /// given report, none when no message arrived.
+    fn message(&self, folder: &str, report: &SyncReport) -> Option<(String, String)> {
+        let count = report.side(self.side).added;
+        if count == 0 {
+            return None;
+        }
+        let summary = match count {
+            1 => "1 new message".to_owned(),
+            count => format!("{} new messages", count),
+        };
+        let body = match &self.account {
+            Some(account) => format!("in {} of {}", folder, account),
+            None => format!("in {}", folder),
+        };
+        Some((summary, body))
+    }
+}
+
+impl SyncObserver for DesktopNotifier {
+    fn folder_finished(&self, folder: &str, report: &SyncReport) {
+        let (summary, body) = match self.message(folder, report) {
+            Some(message) => message,
+            None => return,
+        };
+        let mut notification = Notification::new();
+        notification
+            .appname("everest")
+            .summary(&summary)
+            .body(&body);
+        if let Some(icon) = &self.icon {
+            notification.icon(icon);
+        }
+        if let Err(err) = notification.show() {
+            log::warn!("cannot show new mail notification: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_test() {
+        let mut report = SyncReport::default();
+        let notifier = DesktopNotifier::new();
+        assert_eq!(None, notifier.message("INBOX", &report));
+
+        // messages pushed to the server are not new mail
+        report.left.added = 2;
+        assert_eq!(None, notifier.message("INBOX", &report));
+
+        report.right.added = 1;
+        let message = notifier.message("INBOX", &report);
+        assert_eq!(Some(("1 new message".into(), "in INBOX".into())), message);
+
+        let notifier = notifier.with_side(Side::Left).with_account("work");
+        let message = notifier.message("INBOX", &report);
+        let expected = ("2 new messages".into(), "in INBOX of work".into());
+        assert_eq!(Some(expected), message);
+    }
+}
@@ -0,0 +1,350 @@
+//! Notifications of the report of a sync run, with the `json`
+//! feature, to integrate with chats, push services like ntfy or
+//! dashboards: a command gets the report on its standard input, and
+//! with the `webhook` feature, a webhook gets it posted.
+//!
+//! The report is a JSON object with the `outcome` of the run
+//! (`success`, `partial` or `failure`), the `account` when known, the
+//! changes applied to each side, the statistics of each folder and
+//! the errors:
+//!
+//! ```text
+//! {"account":"work","outcome":"partial","hunks":2,"duration":0.8,
+//!  "left":{"added":0,"removed":0,"flags_changed":1,"bytes":0},
+//!  "right":{"added":1,"removed":0,"flags_changed":0,"bytes":2048},
+//!  "folders":[{"folder":"INBOX","hunks":2,"failed":0,...}],
+//!  "errors":[],"skipped":[{"folder":"Trash","error":"cannot sync folder Trash: ..."}]}
+//! ```
+
+#[cfg(feature = "webhook")]
+use std::time::Duration;
+use std::{
+    io::{self, Write},
+    process::{Command, Stdio},
+};
+
+use serde_json::{json, Value};
+
+use crate::{
+    eventlog::{encode_error, encode_hunk, encode_side},
+    observer::SyncObserver,
+    report::SyncReport,
+    EverestError,
+};
+
+/// Time a webhook gets to answer by default.
+#[cfg(feature = "webhook")]
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Observer sending the report of each sync run to a command or a
+/// webhook, registered with [`crate::sync::SyncBuilder::observer`].
+///
+/// The report is sent from the thread running the sync, once every
+/// folder is synced. Failing to send it does not fail the sync: a
+/// warning is logged. Use [`ReportNotifier::notify`] to handle
+/// failures instead.
+#[derive(Debug, Clone)]
+pub struct ReportNotifier {
+    target: Target,
+    account: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Target {
+    Command {
+        program: String,
+        args: Vec<String>,
+    },
+    #[cfg(feature = "webhook")]
+    Webhook {
+        url: String,
+        timeout: Duration,
+    },
+}
+
+impl ReportNotifier {
+    /// Builds a notifier running the given program with the given
+    /// arguments, the report on its standard input. The outcome of the
+    /// run is also set in the `EVEREST_OUTCOME` environment variable,
+    /// for scripts not parsing the report.
+    pub fn command<P, I, A>(program: P, args: I) -> Self
+    where
+        P: Into<String>,
+        I: IntoIterator<Item = A>,
+        A: Into<String>,
+    {
+        Self::new(Target::Command {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+        })
+    }
+
+    /// Builds a notifier posting the report to the given URL, with
+    /// the `webhook` feature. Responses other than 2xx are failures.
+    #[cfg(feature = "webhook")]
+    pub fn webhook<U: Into<String>>(url: U) -> Self {
+        Self::new(Target::Webhook {
+            url: url.into(),
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    fn new(target: Target) -> Self {
+        Self {
+            target,
+            account: None,
+        }
+    }
+
+    /// Names the account in the report, for notifiers shared by
+    /// several accounts.
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Sets the time a webhook gets to answer, 10 seconds by default.
+    /// Commands are waited for as long as they run.
+    #[cfg(feature = "webhook")]
+    pub fn with_timeout(mut self, duration: Duration) -> Self {
+        if let Target::Webhook { timeout, .. } = &mut self.target {
+            *timeout = duration;
+        }
+        self
+    }
+
+    /// Sends the given report, waiting for the command to exit or the
+    /// webhook to answer.
+    pub fn notify(&self, report: &SyncReport) -> Result<(), EverestError> {
+        let body = self.encode(report).to_string();
+        match &self.target {
+            Target::Command { program, args } => {
+                run(program, args, report.outcome().name(), body.as_bytes())
+            }
+            #[cfg(feature = "webhook")]
+            Target::Webhook { url, timeout } => post(url, *timeout, body.as_bytes()),
+        }
+    }
+
+    fn encode(&self, report: &SyncReport) -> Value {
+        let folders = report.folders.iter().map(|stats| {
+            json!({
+                "folder": stats.folder,
+                "hunks": stats.hunks,
+                "failed": stats.failed,
+                "left": encode_side(&stats.left),
+                "right": encode_side(&stats.right),
+                "duration": stats.duration.as_secs_f64(),
+            })
+        });
+        let errors = report.errors.iter().map(|err| {
+            json!({
+                "folder": err.folder,
+                "hunk": encode_hunk(&err.hunk),
+                "error": encode_error(err),
+            })
+        });
+        let skipped = report
+            .skipped
+            .iter()
+            .map(|err| json!({ "folder": err.folder, "error": encode_error(err) }));
+        let mut encoded = json!({
+            "outcome": report.outcome().name(),
+            "hunks": report.hunks,
+            "duration": report.timings.total().as_secs_f64(),
+            "left": encode_side(&report.left),
+            "right": encode_side(&report.right),
+            "folders": folders.collect::<Vec<_>>(),
+            "errors": errors.collect::<Vec<_>>(),
+            "skipped": skipped.collect::<Vec<_>>(),
+        });
+        if let Some(account) = &self.account {
+            encoded["account"] = account.as_str().into();
+        }
+        encoded
+    }
+}
+
+impl SyncObserver for ReportNotifier {
+    fn sync_finished(&self, report: &SyncReport) {
+        if let Err(err) = self.notify(report) {
+            log::warn!("{}", encode_error(&err));
+        }
+    }
+}
+
+/// Runs the given command with the given report on its standard
+/// input.
+fn run(program: &str, args: &[String], outcome: &str, body: &[u8]) -> Result<(), EverestError> {
+    let err = |err| EverestError::RunNotifyCommandError(err, program.to_owned());
+    let mut child = Command::new(program)
+        .args(args)
+        .env("EVEREST_OUTCOME", outcome)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .map_err(err)?;
+    let mut stdin = child.stdin.take().unwrap();
+    match stdin.write_all(body) {
+        // the command is free not to read the report
+        Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+            let _ = child.kill();
+            let _ = child.wait();
+            return Err(err(e));
+        }
+        _ => drop(stdin),
+    }
+    let status = child.wait().map_err(err)?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(EverestError::NotifyCommandStatusError(
+            program.to_owned(),
+            status,
+        )),
+    }
+}
+
+/// Posts the given report to the given URL.
+#[cfg(feature = "webhook")]
+fn post(url: &str, timeout: Duration, body: &[u8]) -> Result<(), EverestError> {
+    let config = ureq::Agent::config_builder().timeout_global(Some(timeout));
+    let agent: ureq::Agent = config.build().into();
+    agent
+        .post(url)
+        .header("Content-Type", "application/json")
+        .send(body)
+        .map_err(|err| EverestError::PostWebhookError(Box::new(err), url.to_owned()))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        cache::MemoryCache,
+        sync::{MemoryReplica, Sync},
+        ErrorCode,
+    };
+
+    use super::*;
+
+    fn report() -> SyncReport {
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default().with_failing("Trash"))
+            .cache_store(MemoryCache::new())
+            .folders(["INBOX", "Trash"])
+            .build()
+            .unwrap();
+        sync.run().unwrap()
+    }
+
+    #[test]
+    fn encode_test() {
+        let notifier = ReportNotifier::command("true", [""; 0]).with_account("work");
+        let mut encoded = notifier.encode(&report());
+        assert!(encoded["duration"].as_f64().is_some());
+        assert!(encoded["folders"][0]["duration"].as_f64().is_some());
+        encoded.as_object_mut().unwrap().remove("duration");
+        encoded["folders"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("duration");
+        let side = |added: usize, bytes: u64| json!({ "added": added, "removed": 0, "flags_changed": 0, "bytes": bytes });
+        assert_eq!(
+            json!({
+                "account": "work",
+                "outcome": "partial",
+                "hunks": 1,
+                "left": side(0, 0),
+                "right": side(1, 1),
+                "folders": [{
+                    "folder": "INBOX",
+                    "hunks": 1,
+                    "failed": 0,
+                    "left": side(0, 0),
+                    "right": side(1, 1),
+                }],
+                "errors": [],
+                "skipped": [{
+                    "folder": "Trash",
+                    "error": "cannot sync folder Trash: cannot find maildir message Trash",
+                }],
+            }),
+            encoded
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("report.json");
+        let script = r#"cat > "$1" && test "$EVEREST_OUTCOME" = partial"#;
+        let path_arg = path.to_string_lossy();
+        let notifier = ReportNotifier::command("sh", ["-c", script, "sh", &path_arg]);
+        notifier.notify(&report()).unwrap();
+        let sent: Value = serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!("partial", sent["outcome"]);
+
+        // commands not reading the report are fine, failing ones are
+        // not
+        ReportNotifier::command("true", [""; 0])
+            .notify(&report())
+            .unwrap();
+        let err = ReportNotifier::command("false", [""; 0])
+            .notify(&report())
+            .unwrap_err();
+        assert_eq!(ErrorCode::NotifyCommandFailed, err.code());
+        let err = ReportNotifier::command("/nonexistent/notify", [""; 0])
+            .notify(&report())
+            .unwrap_err();
+        assert_eq!(ErrorCode::NotifyCommandFailed, err.code());
+    }
+
+    #[cfg(feature = "webhook")]
+    #[test]
+    fn webhook_test() {
+        use std::{
+            io::{BufRead, BufReader, Read},
+            net::TcpListener,
+            thread,
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let server = thread::spawn(move || {
+            let mut bodies = Vec::new();
+            for status in ["200 OK", "500 Internal Server Error"] {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut len = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    let line = line.trim_end().to_ascii_lowercase();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.strip_prefix("content-length:") {
+                        len = value.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; len];
+                reader.read_exact(&mut body).unwrap();
+                bodies.push(body);
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                reader.get_mut().write_all(response.as_bytes()).unwrap();
+            }
+            bodies
+        });
+
+        let notifier = ReportNotifier::webhook(&url);
+        notifier.notify(&report()).unwrap();
+        let err = notifier.notify(&report()).unwrap_err();
+        assert_eq!(ErrorCode::WebhookPostFailed, err.code());
+
+        let bodies = server.join().unwrap();
+        let sent: Value = serde_json::from_slice(&bodies[0]).unwrap();
+        assert_eq!("partial", sent["outcome"]);
+    }
+}
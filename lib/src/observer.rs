@@ -0,0 +1,191 @@
+//! Hooks called by the sync as it runs, for applications to drive
+//! progress bars and logging.
+
+use std::{error::Error, sync::mpsc};
+
+use crate::{progress::Progress, report::SyncReport, Envelopes, Hunk, Side};
+
+/// Observer of a [`crate::sync::Sync`], registered with
+/// [`crate::sync::SyncBuilder::observer`]. All hooks do nothing by
+/// default, observers only implement the ones they need.
+///
+/// Hooks are called from the thread running the sync: they should
+/// return quickly, leaving any slow work to other threads.
+pub trait SyncObserver {
+    /// Called when the sync of the given folder starts.
+    fn folder_started(&self, _folder: &str) {}
+
+    /// Called once the envelopes of a side are listed.
+    fn envelopes_listed(&self, _folder: &str, _side: Side, _envelopes: &Envelopes) {}
+
+    /// Called for each hunk of the patch, once diffed.
+    fn hunk_generated(&self, _folder: &str, _hunk: &Hunk) {}
+
+    /// Called for each hunk applied successfully.
+    fn hunk_applied(&self, _folder: &str, _hunk: &Hunk) {}
+
+    /// Called when a hunk fails to apply, with a
+    /// [`crate::report::HunkError`], or when the sync of the folder
+    /// fails, with an [`crate::EverestError`].
+    fn error(&self, _folder: &str, _err: &(dyn Error + 'static)) {}
+
+    /// Called after each hunk applied or failed, with the progress of
+    /// the patch.
+    fn progress(&self, _folder: &str, _progress: &Progress) {}
+
+    /// Called once the sync of the given folder is saved.
+    fn folder_finished(&self, _folder: &str, _report: &SyncReport) {}
+
+    /// Called once [`crate::sync::Sync::run`] went through every
+    /// folder, with the report of the whole run.
+    fn sync_finished(&self, _report: &SyncReport) {}
+}
+
+/// Event of a sync, the owned counterpart of the hooks of
+/// [`SyncObserver`], sent by an [`EventSender`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum SyncEvent {
+    FolderStarted {
+        folder: String,
+    },
+    EnvelopesListed {
+        folder: String,
+        side: Side,
+        count: usize,
+    },
+    HunkGenerated {
+        folder: String,
+        hunk: Hunk,
+    },
+    HunkApplied {
+        folder: String,
+        hunk: Hunk,
+    },
+    /// Error of a hunk or of the folder, with its sources.
+    Error {
+        folder: String,
+        message: String,
+    },
+    Progress {
+        folder: String,
+        progress: Progress,
+    },
+    FolderFinished {
+        folder: String,
+        report: SyncReport,
+    },
+    SyncFinished {
+        report: SyncReport,
+    },
+}
+
+/// Observer sending the events of the sync to a channel, built with
+/// [`channel`] or, with the `async` feature, [`stream`]. Events sent
+/// once the receiver is dropped are lost.
+#[derive(Debug, Clone)]
+pub struct EventSender(Sender);
+
+#[derive(Debug, Clone)]
+enum Sender {
+    Std(mpsc::Sender<SyncEvent>),
+    #[cfg(feature = "async")]
+    Futures(futures::channel::mpsc::UnboundedSender<SyncEvent>),
+}
+
+/// Builds an observer sending the events of the sync to the returned
+/// receiver, for threads waiting on them.
+pub fn channel() -> (EventSender, mpsc::Receiver<SyncEvent>) {
+    let (tx, rx) = mpsc::channel();
+    (EventSender(Sender::Std(tx)), rx)
+}
+
+/// Builds an observer sending the events of the sync to the returned
+/// stream, for async frontends to `select!` on them alongside their
+/// own events.
+#[cfg(feature = "async")]
+pub fn stream() -> (
+    EventSender,
+    futures::channel::mpsc::UnboundedReceiver<SyncEvent>,
+) {
+    let (tx, rx) = futures::channel::mpsc::unbounded();
+    (EventSender(Sender::Futures(tx)), rx)
+}
+
+impl EventSender {
+    fn send(&self, event: SyncEvent) {
+        // the receiver may not care about events anymore
+        match &self.0 {
+            Sender::Std(tx) => {
+                let _ = tx.send(event);
+            }
+            #[cfg(feature = "async")]
+            Sender::Futures(tx) => {
+                let _ = tx.unbounded_send(event);
+            }
+        }
+    }
+}
+
+impl SyncObserver for EventSender {
+    fn folder_started(&self, folder: &str) {
+        self.send(SyncEvent::FolderStarted {
+            folder: folder.to_owned(),
+        })
+    }
+
+    fn envelopes_listed(&self, folder: &str, side: Side, envelopes: &Envelopes) {
+        self.send(SyncEvent::EnvelopesListed {
+            folder: folder.to_owned(),
+            side,
+            count: envelopes.len(),
+        })
+    }
+
+    fn hunk_generated(&self, folder: &str, hunk: &Hunk) {
+        self.send(SyncEvent::HunkGenerated {
+            folder: folder.to_owned(),
+            hunk: hunk.clone(),
+        })
+    }
+
+    fn hunk_applied(&self, folder: &str, hunk: &Hunk) {
+        self.send(SyncEvent::HunkApplied {
+            folder: folder.to_owned(),
+            hunk: hunk.clone(),
+        })
+    }
+
+    fn error(&self, folder: &str, err: &(dyn Error + 'static)) {
+        let mut message = err.to_string();
+        let mut source = err.source();
+        while let Some(err) = source {
+            message.push_str(&format!(": {}", err));
+            source = err.source();
+        }
+        self.send(SyncEvent::Error {
+            folder: folder.to_owned(),
+            message,
+        })
+    }
+
+    fn progress(&self, folder: &str, progress: &Progress) {
+        self.send(SyncEvent::Progress {
+            folder: folder.to_owned(),
+            progress: *progress,
+        })
+    }
+
+    fn folder_finished(&self, folder: &str, report: &SyncReport) {
+        self.send(SyncEvent::FolderFinished {
+            folder: folder.to_owned(),
+            report: report.clone(),
+        })
+    }
+
+    fn sync_finished(&self, report: &SyncReport) {
+        self.send(SyncEvent::SyncFinished {
+            report: report.clone(),
+        })
+    }
+}
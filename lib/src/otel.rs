@@ -0,0 +1,79 @@
+//! Export of the spans of the sync to OpenTelemetry, with the
+//! `opentelemetry` feature, so that a distributed trace shows which
+//! phase of which folder, or which IMAP command, was slow.
+//!
+//! The spans are the ones of the `tracing` feature, exported by a
+//! [`tracing_subscriber::Layer`] the application adds to its
+//! subscriber, with the tracer of its OpenTelemetry pipeline:
+//!
+//! ```ignore
+//! use opentelemetry::trace::TracerProvider;
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let tracer = provider.tracer("everest");
+//! let subscriber = tracing_subscriber::registry().with(everest_lib::otel::layer(tracer));
+//! tracing::subscriber::set_global_default(subscriber)?;
+//! ```
+
+use opentelemetry::trace::Tracer;
+use tracing::{level_filters::LevelFilter, Subscriber};
+use tracing_subscriber::{filter::Targets, registry::LookupSpan, Layer};
+
+/// Builds the layer exporting the spans of the crate with the given
+/// tracer, down to the `DEBUG` spans of the phases and the IMAP
+/// commands. Spans of other crates are left to other layers.
+pub fn layer<S, T>(tracer: T) -> impl Layer<S>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+    T: Tracer + 'static,
+    T::Span: Send + Sync,
+{
+    let targets = Targets::new().with_target(env!("CARGO_CRATE_NAME"), LevelFilter::DEBUG);
+    tracing_opentelemetry::layer()
+        .with_tracer(tracer)
+        .with_filter(targets)
+}
+
+#[cfg(all(test, feature = "cache"))]
+mod tests {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::{
+        cache::MemoryCache,
+        sync::{MemoryReplica, Sync},
+    };
+
+    #[test]
+    fn layer_test() {
+        let exporter = InMemorySpanExporter::default();
+        let provider = SdkTracerProvider::builder()
+            .with_simple_exporter(exporter.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry().with(super::layer(provider.tracer("test")));
+
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .build()
+            .unwrap();
+        tracing::subscriber::with_default(subscriber, || {
+            // spans of other crates are not exported
+            tracing::info_span!(target: "other", "other").in_scope(|| sync.run().unwrap());
+        });
+        provider.force_flush().unwrap();
+
+        let spans = exporter.get_finished_spans().unwrap();
+        let mut names = spans
+            .iter()
+            .map(|span| span.name.as_ref())
+            .collect::<Vec<_>>();
+        names.sort_unstable();
+        assert_eq!(
+            vec!["apply", "commit", "diff", "fetch", "fetch", "sync_folder"],
+            names
+        );
+    }
+}
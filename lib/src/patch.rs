@@ -0,0 +1,929 @@
+//! Patches: the hunks bringing both sides of a sync back in sync,
+//! and the diff building them.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt,
+};
+
+use crate::{Envelope, Envelopes, Flag, Id};
+
+/// Side of a sync, the IMAP server on the left and the maildir on
+/// the right for now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Side {
+    Left,
+    Right,
+}
+
+impl Side {
+    /// Returns the other side.
+    pub fn opposite(self) -> Self {
+        match self {
+            Self::Left => Self::Right,
+            Self::Right => Self::Left,
+        }
+    }
+}
+
+impl fmt::Display for Side {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Left => f.write_str("left"),
+            Self::Right => f.write_str("right"),
+        }
+    }
+}
+
+/// Change to apply to one side of the sync.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[non_exhaustive]
+pub struct Hunk {
+    pub target: Side,
+    pub kind: HunkKind,
+}
+
+impl Hunk {
+    pub fn new(target: Side, kind: HunkKind) -> Self {
+        Self { target, kind }
+    }
+}
+
+/// Change to apply to a message, by id.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+#[non_exhaustive]
+pub enum HunkKind {
+    AddMsg(HunkId),
+    RemoveMsg(HunkId),
+    AddFlag(HunkId, Flag),
+    RemoveFlag(HunkId, Flag),
+}
+
+impl HunkKind {
+    /// Returns the ids of the message changed by the hunk.
+    pub fn id(&self) -> &HunkId {
+        match self {
+            Self::AddMsg(id) | Self::RemoveMsg(id) => id,
+            Self::AddFlag(id, _) | Self::RemoveFlag(id, _) => id,
+        }
+    }
+}
+
+/// Ids of the message changed by a hunk, in the replica the change
+/// comes from and in the replica it applies to. The target id is
+/// unknown for messages not added to the target replica yet.
+///
+/// Both replicas share ids for now: the diff always knows the target
+/// id, equal to the source one.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HunkId {
+    pub source: Id,
+    pub target: Option<Id>,
+}
+
+impl HunkId {
+    /// Builds the ids of a message known under the same id by both
+    /// replicas.
+    pub fn shared<I: Into<Id>>(id: I) -> Self {
+        let id = id.into();
+        Self {
+            source: id.clone(),
+            target: Some(id),
+        }
+    }
+
+    /// Builds the ids of a message not added to the target replica
+    /// yet.
+    pub fn unmapped<I: Into<Id>>(source: I) -> Self {
+        Self {
+            source: source.into(),
+            target: None,
+        }
+    }
+
+    /// Returns the id in the target replica, the source one when
+    /// unknown, the target replica then being expected to reuse it.
+    pub fn target_or_source(&self) -> &Id {
+        self.target.as_ref().unwrap_or(&self.source)
+    }
+}
+
+impl From<Id> for HunkId {
+    fn from(id: Id) -> Self {
+        Self::shared(id)
+    }
+}
+
+impl From<&str> for HunkId {
+    fn from(id: &str) -> Self {
+        Self::shared(id)
+    }
+}
+
+/// Renders the id alone when shared, like `42`, otherwise the source
+/// id followed by the target one, like `42>7`, or by nothing when
+/// unknown, like `42>`.
+impl fmt::Display for HunkId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.target {
+            Some(target) if *target == self.source => f.write_str(&self.source),
+            Some(target) => write!(f, "{}>{}", self.source, target),
+            None => write!(f, "{}>", self.source),
+        }
+    }
+}
+
+/// Changes bringing both sides of a sync back in sync, ordered by id.
+pub type Patch = Vec<Hunk>;
+
+/// Renders changes like `+ msg 42` or `- flag Seen on 42`.
+impl fmt::Display for HunkKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::AddMsg(id) => write!(f, "+ msg {}", id),
+            Self::RemoveMsg(id) => write!(f, "- msg {}", id),
+            Self::AddFlag(id, flag) => write!(f, "+ flag {} on {}", flag, id),
+            Self::RemoveFlag(id, flag) => write!(f, "- flag {} on {}", flag, id),
+        }
+    }
+}
+
+/// Renders hunks like `right: + msg 42`.
+impl fmt::Display for Hunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.target, self.kind)
+    }
+}
+
+/// Renders a patch one hunk per line, see [`display_patch`].
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayPatch<'a>(&'a [Hunk]);
+
+impl fmt::Display for DisplayPatch<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, hunk) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", hunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renders the given patch one hunk per line, [`Patch`] being a
+/// plain vector that cannot implement [`fmt::Display`] itself.
+pub fn display_patch(patch: &[Hunk]) -> DisplayPatch<'_> {
+    DisplayPatch(patch)
+}
+
+/// Number of hunks of a patch changing a side, by kind.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SideSummary {
+    /// Messages added to the side.
+    pub added: usize,
+    /// Messages removed from the side.
+    pub removed: usize,
+    /// Flags added to or removed from messages of the side.
+    pub flags_changed: usize,
+}
+
+impl SideSummary {
+    /// Returns `true` if the patch does not change the side.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+/// Renders the changes of a side like `+3 msgs, -1 msg, 12 flag
+/// changes`, leaving out kinds without changes.
+impl fmt::Display for SideSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let changes = [
+            (self.added, "+", "msg"),
+            (self.removed, "-", "msg"),
+            (self.flags_changed, "", "flag change"),
+        ];
+        let changes = changes.into_iter().filter(|(count, _, _)| *count > 0);
+        for (i, (count, sign, name)) in changes.enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}{} {}{}", sign, count, name, plural(count))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compact summary of a patch, built with [`summarize_patch`], for
+/// notifications and commit-style output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatchSummary<'a> {
+    pub left: SideSummary,
+    pub right: SideSummary,
+    names: [&'a str; 2],
+}
+
+impl<'a> PatchSummary<'a> {
+    /// Names the sides in the summary, `left` and `right` by default,
+    /// like `IMAP` and `Maildir`.
+    pub fn with_names(mut self, left: &'a str, right: &'a str) -> Self {
+        self.names = [left, right];
+        self
+    }
+
+    /// Returns the changes of the given side.
+    pub fn side(&self, side: Side) -> &SideSummary {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    /// Returns `true` if the patch changes nothing.
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+}
+
+/// Renders the summary like `left: +3 msgs, -1 msg, 12 flag changes;
+/// right: +5 msgs`, leaving out sides without changes, or as `no
+/// changes`.
+impl fmt::Display for PatchSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("no changes");
+        }
+        let sides = [(self.names[0], &self.left), (self.names[1], &self.right)];
+        let sides = sides.into_iter().filter(|(_, side)| !side.is_empty());
+        for (i, (name, side)) in sides.enumerate() {
+            if i > 0 {
+                f.write_str("; ")?;
+            }
+            write!(f, "{}: {}", name, side)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the hunks of the given patch by side and kind.
+pub fn summarize_patch(patch: &[Hunk]) -> PatchSummary<'static> {
+    let mut summary = PatchSummary {
+        left: SideSummary::default(),
+        right: SideSummary::default(),
+        names: ["left", "right"],
+    };
+    for hunk in patch {
+        let side = match hunk.target {
+            Side::Left => &mut summary.left,
+            Side::Right => &mut summary.right,
+        };
+        match hunk.kind {
+            HunkKind::AddMsg(_) => side.added += 1,
+            HunkKind::RemoveMsg(_) => side.removed += 1,
+            HunkKind::AddFlag(..) | HunkKind::RemoveFlag(..) => side.flags_changed += 1,
+        }
+    }
+    summary
+}
+
+/// Number of ids diffed by each task when building patches in
+/// parallel.
+#[cfg(feature = "parallel")]
+const PARALLEL_CHUNK_SIZE: usize = 4096;
+
+/// Builds the patch turning the previous envelopes of both sides into
+/// the next ones. Envelopes are only borrowed: ids and flags are
+/// cloned for the hunks of the patch only.
+///
+/// Hunks are ordered by id. With the `parallel` feature, ids are
+/// split in chunks diffed in parallel, the patch staying the same.
+pub fn build_patch(
+    prev_imap_envelopes: &Envelopes,
+    next_imap_envelopes: &Envelopes,
+    prev_mdir_envelopes: &Envelopes,
+    next_mdir_envelopes: &Envelopes,
+) -> Patch {
+    #[cfg(feature = "parallel")]
+    {
+        use rayon::prelude::*;
+
+        let envelopes = [
+            prev_imap_envelopes,
+            next_imap_envelopes,
+            prev_mdir_envelopes,
+            next_mdir_envelopes,
+        ];
+        // chunks are collected in order, which keeps hunks ordered
+        sorted_ids(envelopes)
+            .par_chunks(PARALLEL_CHUNK_SIZE)
+            .map(|ids| {
+                build_ids_patch(
+                    ids,
+                    prev_imap_envelopes,
+                    next_imap_envelopes,
+                    prev_mdir_envelopes,
+                    next_mdir_envelopes,
+                )
+            })
+            .collect::<Vec<_>>()
+            .concat()
+    }
+
+    #[cfg(not(feature = "parallel"))]
+    iter_patch(
+        prev_imap_envelopes,
+        next_imap_envelopes,
+        prev_mdir_envelopes,
+        next_mdir_envelopes,
+    )
+    .collect()
+}
+
+/// Builds the patch of [`build_patch`] for the given ids only, for
+/// backends able to tell which ids changed since the previous sync
+/// (like IMAP with `MODSEQ`, or a watched maildir). The other ids are
+/// not even looked at, so the cost depends on the number of changes
+/// rather than on the size of the mailbox. Ids found in none of the
+/// envelopes are ignored.
+pub fn build_changed_patch<'a, I>(
+    changed_ids: I,
+    prev_imap_envelopes: &Envelopes,
+    next_imap_envelopes: &Envelopes,
+    prev_mdir_envelopes: &Envelopes,
+    next_mdir_envelopes: &Envelopes,
+) -> Patch
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let envelopes = [
+        prev_imap_envelopes,
+        next_imap_envelopes,
+        prev_mdir_envelopes,
+        next_mdir_envelopes,
+    ];
+    let mut ids = changed_ids
+        .into_iter()
+        .filter_map(|id| {
+            envelopes
+                .iter()
+                .find_map(|envelopes| envelopes.get_key_value(id))
+                .map(|(id, _)| id)
+        })
+        .collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    build_ids_patch(
+        &ids,
+        prev_imap_envelopes,
+        next_imap_envelopes,
+        prev_mdir_envelopes,
+        next_mdir_envelopes,
+    )
+}
+
+/// Iterates over the hunks of the patch built by [`build_patch`],
+/// diffing ids one by one as hunks are consumed. Hunks can then be
+/// applied while the next ones are being diffed, and the patch is
+/// never held in memory as a whole. Only the ids are collected first,
+/// to keep hunks ordered by id.
+pub fn iter_patch<'a>(
+    prev_imap_envelopes: &'a Envelopes,
+    next_imap_envelopes: &'a Envelopes,
+    prev_mdir_envelopes: &'a Envelopes,
+    next_mdir_envelopes: &'a Envelopes,
+) -> impl Iterator<Item = Hunk> + 'a {
+    let envelopes = [
+        prev_imap_envelopes,
+        next_imap_envelopes,
+        prev_mdir_envelopes,
+        next_mdir_envelopes,
+    ];
+    sorted_ids(envelopes).into_iter().flat_map(move |id| {
+        build_ids_patch(
+            &[id],
+            prev_imap_envelopes,
+            next_imap_envelopes,
+            prev_mdir_envelopes,
+            next_mdir_envelopes,
+        )
+    })
+}
+
+/// Returns the ids of the given envelopes, deduplicated and sorted.
+/// No id is returned when neither side changed since the previous
+/// sync, their fingerprints being far cheaper to compare than ids to
+/// diff.
+fn sorted_ids(envelopes: [&Envelopes; 4]) -> Vec<&Id> {
+    let [prev_imap, next_imap, prev_mdir, next_mdir] = envelopes;
+    if prev_imap.fingerprint() == next_imap.fingerprint()
+        && prev_mdir.fingerprint() == next_mdir.fingerprint()
+    {
+        return vec![];
+    }
+
+    let capacity = envelopes.iter().map(|envelopes| envelopes.len()).max();
+    let mut ids = HashSet::with_capacity(capacity.unwrap_or_default());
+    for envelopes in envelopes {
+        ids.extend(envelopes.keys());
+    }
+    let mut ids = ids.into_iter().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids
+}
+
+fn build_ids_patch(
+    ids: &[&Id],
+    prev_imap_envelopes: &Envelopes,
+    next_imap_envelopes: &Envelopes,
+    prev_mdir_envelopes: &Envelopes,
+    next_mdir_envelopes: &Envelopes,
+) -> Patch {
+    let mut patch = vec![];
+
+    for &id in ids {
+        match (
+            next_imap_envelopes.get(id),
+            prev_imap_envelopes.get(id),
+            next_mdir_envelopes.get(id),
+            prev_mdir_envelopes.get(id),
+        ) {
+            // id present only in imap
+            (Some(_), None, None, None) => {
+                // add maildir msg
+                patch.push(Hunk::new(
+                    Side::Right,
+                    HunkKind::AddMsg(HunkId::shared(id.clone())),
+                ))
+            }
+            // id present only in maildir
+            (None, None, Some(_), None) => {
+                // add imap msg
+                patch.push(Hunk::new(
+                    Side::Left,
+                    HunkKind::AddMsg(HunkId::shared(id.clone())),
+                ))
+            }
+            // id everywhere except in imap
+            (None, Some(_), Some(_), Some(_)) => {
+                // remove maildir msg
+                patch.push(Hunk::new(
+                    Side::Right,
+                    HunkKind::RemoveMsg(HunkId::shared(id.clone())),
+                ))
+            }
+            // id everywhere except in maildir
+            (Some(_), Some(_), None, Some(_)) => {
+                // remove imap msg
+                patch.push(Hunk::new(
+                    Side::Left,
+                    HunkKind::RemoveMsg(HunkId::shared(id.clone())),
+                ))
+            }
+            // id everywhere
+            (
+                Some(imap_envelope),
+                Some(imap_cache_envelope),
+                Some(mdir_envelope),
+                Some(mdir_cache_envelope),
+            ) => build_flags_patch(
+                &mut patch,
+                id,
+                imap_envelope,
+                imap_cache_envelope,
+                mdir_envelope,
+                mdir_cache_envelope,
+            ),
+            _ => (),
+        }
+    }
+
+    patch
+}
+
+fn build_flags_patch(
+    patch: &mut Patch,
+    id: &Id,
+    imap_envelope: &Envelope,
+    imap_cache_envelope: &Envelope,
+    mdir_envelope: &Envelope,
+    mdir_cache_envelope: &Envelope,
+) {
+    // flags only change when one side changed, the most common case
+    // being neither, which the digests of the sets tell most of the
+    // time
+    if imap_envelope.flags == imap_cache_envelope.flags
+        && mdir_envelope.flags == mdir_cache_envelope.flags
+    {
+        return;
+    }
+
+    // standard flags then keywords, from any of the four envelopes,
+    // in the order of flags so that the patch stays the same
+    let mut flags = BTreeSet::new();
+    flags.extend(imap_envelope.flags.iter());
+    flags.extend(imap_cache_envelope.flags.iter());
+    flags.extend(mdir_envelope.flags.iter());
+    flags.extend(mdir_cache_envelope.flags.iter());
+
+    for flag in flags {
+        // flag in imap but not in imap cache
+        if imap_envelope.flags.contains(flag) && !imap_cache_envelope.flags.contains(flag) {
+            // add maildir flag
+            patch.push(Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag(HunkId::shared(id.clone()), flag.to_owned()),
+            ))
+        }
+
+        // flag not in imap but in imap cache
+        if !imap_envelope.flags.contains(flag) && imap_cache_envelope.flags.contains(flag) {
+            // remove maildir flag
+            patch.push(Hunk::new(
+                Side::Right,
+                HunkKind::RemoveFlag(HunkId::shared(id.clone()), flag.to_owned()),
+            ))
+        }
+
+        // flag present only in maildir
+        if !imap_envelope.flags.contains(flag)
+            && !imap_cache_envelope.flags.contains(flag)
+            && mdir_envelope.flags.contains(flag)
+            && !mdir_cache_envelope.flags.contains(flag)
+        {
+            // add imap flag
+            patch.push(Hunk::new(
+                Side::Left,
+                HunkKind::AddFlag(HunkId::shared(id.clone()), flag.to_owned()),
+            ))
+        }
+
+        // flag everywhere except in maildir
+        if imap_envelope.flags.contains(flag)
+            && imap_cache_envelope.flags.contains(flag)
+            && !mdir_envelope.flags.contains(flag)
+            && mdir_cache_envelope.flags.contains(flag)
+        {
+            // remove imap flag
+            patch.push(Hunk::new(
+                Side::Left,
+                HunkKind::RemoveFlag(HunkId::shared(id.clone()), flag.to_owned()),
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, iter::FromIterator};
+
+    #[cfg(feature = "synthetic")]
+    use crate::synthetic;
+
+    use super::*;
+
+    #[test]
+    fn display_test() {
+        let patch = vec![
+            Hunk::new(Side::Right, HunkKind::AddMsg("42".into())),
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("7".into())),
+            Hunk::new(
+                Side::Left,
+                HunkKind::AddFlag("42".into(), Flag::Keyword("$Work".into())),
+            ),
+            Hunk::new(Side::Left, HunkKind::RemoveFlag("42".into(), Flag::Seen)),
+        ];
+        assert_eq!(
+            "right: + msg 42\n\
+             left: - msg 7\n\
+             left: + flag $Work on 42\n\
+             left: - flag Seen on 42",
+            display_patch(&patch).to_string()
+        );
+        assert_eq!("", display_patch(&[]).to_string());
+    }
+
+    #[test]
+    fn summary_test() {
+        let mut patch = vec![
+            Hunk::new(Side::Right, HunkKind::AddMsg("1".into())),
+            Hunk::new(Side::Left, HunkKind::AddMsg("2".into())),
+            Hunk::new(Side::Left, HunkKind::AddMsg("3".into())),
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("4".into())),
+        ];
+        for id in ["5", "6"] {
+            patch.push(Hunk::new(
+                Side::Left,
+                HunkKind::AddFlag(id.into(), Flag::Seen),
+            ));
+        }
+
+        let summary = summarize_patch(&patch);
+        assert_eq!(2, summary.side(Side::Left).added);
+        assert_eq!(
+            "left: +2 msgs, -1 msg, 2 flag changes; right: +1 msg",
+            summary.to_string()
+        );
+        assert_eq!(
+            "IMAP: +2 msgs, -1 msg, 2 flag changes; Maildir: +1 msg",
+            summary.with_names("IMAP", "Maildir").to_string()
+        );
+        assert_eq!("right: +1 msg", summarize_patch(&patch[..1]).to_string());
+        assert_eq!("no changes", summarize_patch(&[]).to_string());
+    }
+
+    #[cfg(feature = "synthetic")]
+    #[test]
+    fn build_changed_patch_test() {
+        let mailbox = synthetic::SyntheticMailbox::generate(10_000, 42);
+        let envelopes = [
+            &mailbox.prev_imap,
+            &mailbox.next_imap,
+            &mailbox.prev_mdir,
+            &mailbox.next_mdir,
+        ];
+        let patch = build_patch(envelopes[0], envelopes[1], envelopes[2], envelopes[3]);
+        let hunk_id = |hunk: &Hunk| hunk.kind.id().source.clone();
+
+        // every id changed by the full patch, in reverse and twice
+        let changed = patch.iter().rev().map(hunk_id).collect::<Vec<_>>();
+        let changed_ids = changed.iter().chain(&changed).map(|id| &**id);
+        let changed_patch = build_changed_patch(
+            changed_ids,
+            envelopes[0],
+            envelopes[1],
+            envelopes[2],
+            envelopes[3],
+        );
+        assert_eq!(patch, changed_patch);
+
+        let first = hunk_id(&patch[0]);
+        let partial = build_changed_patch(
+            [&*first, "unknown"],
+            envelopes[0],
+            envelopes[1],
+            envelopes[2],
+            envelopes[3],
+        );
+        assert!(!partial.is_empty());
+        assert!(partial.iter().all(|hunk| hunk_id(hunk) == first));
+    }
+
+    #[test]
+    fn add_imap_msg_test() {
+        let env1 = Envelope::new("1").with_flags([Flag::Seen]);
+        let env2 = Envelope::new("2").with_flags([Flag::Flagged]);
+
+        let prev_imap_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let next_imap_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let prev_mdir_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let next_mdir_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+
+        let patch = build_patch(
+            &prev_imap_envelopes,
+            &next_imap_envelopes,
+            &prev_mdir_envelopes,
+            &next_mdir_envelopes,
+        );
+
+        assert_eq!(
+            vec![Hunk::new(Side::Left, HunkKind::AddMsg("2".into()))],
+            patch
+        );
+    }
+
+    #[test]
+    fn remove_imap_msg_test() {
+        let env1 = Envelope::new("1").with_flags([Flag::Seen]);
+        let env2 = Envelope::new("2").with_flags([Flag::Flagged]);
+
+        let prev_imap_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let next_imap_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let prev_mdir_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let next_mdir_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+
+        let patch = build_patch(
+            &prev_imap_envelopes,
+            &next_imap_envelopes,
+            &prev_mdir_envelopes,
+            &next_mdir_envelopes,
+        );
+
+        assert_eq!(
+            vec![Hunk::new(Side::Left, HunkKind::RemoveMsg("2".into()))],
+            patch
+        );
+    }
+
+    #[test]
+    fn add_mdir_msg_test() {
+        let env1 = Envelope::new("1").with_flags([Flag::Seen]);
+        let env2 = Envelope::new("2").with_flags([Flag::Flagged]);
+
+        let prev_imap_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let next_imap_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let prev_mdir_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let next_mdir_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+
+        let patch = build_patch(
+            &prev_imap_envelopes,
+            &next_imap_envelopes,
+            &prev_mdir_envelopes,
+            &next_mdir_envelopes,
+        );
+
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::AddMsg("2".into()))],
+            patch
+        );
+    }
+
+    #[test]
+    fn remove_mdir_msg_test() {
+        let env1 = Envelope::new("1").with_flags([Flag::Seen]);
+        let env2 = Envelope::new("2").with_flags([Flag::Flagged]);
+
+        let prev_imap_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let next_imap_envelopes =
+            Envelopes::from(HashMap::from_iter([(env1.id.clone(), env1.clone())]));
+        let prev_mdir_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+        let next_mdir_envelopes = Envelopes::from(HashMap::from_iter([
+            (env1.id.clone(), env1.clone()),
+            (env2.id.clone(), env2.clone()),
+        ]));
+
+        let patch = build_patch(
+            &prev_imap_envelopes,
+            &next_imap_envelopes,
+            &prev_mdir_envelopes,
+            &next_mdir_envelopes,
+        );
+
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::RemoveMsg("2".into()))],
+            patch
+        );
+    }
+
+    #[test]
+    fn single_add_remove_flag_tests() {
+        let e1 = Envelope::new("1").with_flags([Flag::Seen, Flag::Replied]);
+        let e2 = Envelope::new("1").with_flags([Flag::Seen, Flag::Flagged, Flag::Replied]);
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Left,
+                HunkKind::AddFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::RemoveFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Left,
+                HunkKind::RemoveFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::RemoveFlag("1".into(), Flag::Flagged)
+            )],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+    }
+
+    #[test]
+    fn ordering_test() {
+        // spans several chunks when diffing in parallel
+        let envelopes =
+            Envelopes::from_iter((0..10_000).map(|i| Envelope::new(format!("{:05}", i))));
+        let empty = Envelopes::default();
+
+        let patch = build_patch(&empty, &envelopes, &empty, &empty);
+        assert!(iter_patch(&empty, &envelopes, &empty, &empty).eq(patch.iter().cloned()));
+
+        let mut ids = envelopes.keys().cloned().collect::<Vec<_>>();
+        ids.sort_unstable();
+        assert_eq!(
+            ids.into_iter()
+                .map(|id| Hunk::new(Side::Right, HunkKind::AddMsg(id.into())))
+                .collect::<Vec<_>>(),
+            patch
+        );
+    }
+
+    #[test]
+    fn keyword_flag_test() {
+        let work = Flag::Keyword("Work".into());
+        let home = Flag::Keyword("Home".into());
+        let e1 = Envelope::new("1").with_flags([Flag::Seen]);
+        let e2 = Envelope::new("1").with_flags([
+            work.clone(),
+            Flag::Seen,
+            Flag::Flagged,
+            home.clone(),
+            Flag::Draft,
+        ]);
+
+        let imap_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let imap_next = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_prev = Envelopes::from(HashMap::from_iter([(e1.id.clone(), e1.clone())]));
+        let mdir_next = Envelopes::from(HashMap::from_iter([(e2.id.clone(), e2.clone())]));
+        // standard flags first, then keywords by name
+        assert_eq!(
+            vec![
+                Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Draft)),
+                Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Flagged)),
+                Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), home)),
+                Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), work)),
+            ],
+            build_patch(&imap_prev, &imap_next, &mdir_prev, &mdir_next),
+        );
+    }
+}
@@ -0,0 +1,307 @@
+//! Versioned JSON and CBOR serialization of the patch of a folder,
+//! with the `json` and `cbor` features, for external review tools and
+//! for applying a patch once reviewed, see [`crate::sync::Session::retain`].
+//!
+//! Unlike the `Debug` output or the `serde` implementations of
+//! [`Hunk`], this format is stable. A patch file is a map of the
+//! format `version`, the `folder` and its `hunks`, in JSON:
+//!
+//! ```json
+//! {
+//!   "version": 1,
+//!   "folder": "INBOX",
+//!   "hunks": [
+//!     { "side": "right", "kind": "add_msg", "id": "42", "target_id": "42" },
+//!     { "side": "left", "kind": "add_flag", "id": "1663512456.R1.host", "target_id": "7", "flag": "\\Seen" }
+//!   ]
+//! }
+//! ```
+//!
+//! The `side` is the one the hunk applies to, `left` or `right`, and
+//! the `kind` one of `add_msg`, `remove_msg`, `add_flag` and
+//! `remove_flag`, the latter two with the IMAP name of their `flag`.
+//! The `id` is the id of the message on the side it comes from, and
+//! the `target_id` its id on the side the hunk applies to. Messages
+//! keep their id on both sides, so that patches built by the sync
+//! always have both ids equal. A missing `target_id` stands for an id
+//! not known yet, the side the hunk applies to then reusing the `id`.
+//! CBOR files hold the same map.
+//!
+//! Readers reject versions newer than theirs. Fields they do not know
+//! are ignored, so that optional fields can be added without bumping
+//! the version.
+
+#[cfg(feature = "cbor")]
+use ciborium::Value as CborValue;
+#[cfg(feature = "json")]
+use serde_json::{Map, Value as JsonValue};
+
+use crate::{
+    flag::{format_flag, parse_flag},
+    EverestError, Hunk, HunkId, HunkKind, Patch, Side,
+};
+
+/// Version of the patch format, kept under the `version` key.
+pub const VERSION: u64 = 1;
+
+/// Patch of a folder, as written to and read from patch files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PatchFile {
+    pub folder: String,
+    pub patch: Patch,
+}
+
+impl PatchFile {
+    pub fn new<F: Into<String>>(folder: F, patch: Patch) -> Self {
+        Self {
+            folder: folder.into(),
+            patch,
+        }
+    }
+
+    /// Writes the patch file as pretty-printed JSON.
+    #[cfg(feature = "json")]
+    pub fn write_json<W: std::io::Write>(&self, writer: W) -> Result<(), EverestError> {
+        let hunks = self.patch.iter().map(|hunk| {
+            let fields = encode_hunk(hunk).into_iter();
+            let fields = fields.map(|(key, value)| (key.to_owned(), JsonValue::from(value)));
+            JsonValue::Object(fields.collect::<Map<_, _>>())
+        });
+        let value = serde_json::json!({
+            "version": VERSION,
+            "folder": self.folder,
+            "hunks": hunks.collect::<Vec<_>>(),
+        });
+        serde_json::to_writer_pretty(writer, &value)
+            .map_err(|err| EverestError::WritePatchError(err.to_string()))
+    }
+
+    /// Reads a patch file written with [`PatchFile::write_json`].
+    #[cfg(feature = "json")]
+    pub fn read_json<R: std::io::Read>(reader: R) -> Result<Self, EverestError> {
+        let invalid = |err: String| EverestError::ReadPatchError(err);
+        let value: JsonValue =
+            serde_json::from_reader(reader).map_err(|err| invalid(err.to_string()))?;
+        check_version(value.get("version").and_then(JsonValue::as_u64)).map_err(invalid)?;
+        let folder = value.get("folder").and_then(JsonValue::as_str);
+        let folder = folder.ok_or_else(|| invalid("invalid folder".into()))?;
+        let hunks = value.get("hunks").and_then(JsonValue::as_array);
+        let hunks = hunks.ok_or_else(|| invalid("invalid hunks".into()))?;
+        let patch = hunks.iter().map(|hunk| {
+            let hunk = hunk.as_object().ok_or("invalid hunk")?;
+            decode_hunk(|key| hunk.get(key).and_then(JsonValue::as_str))
+        });
+        let patch = patch.collect::<Result<_, String>>().map_err(invalid)?;
+        Ok(Self::new(folder, patch))
+    }
+
+    /// Writes the patch file as CBOR.
+    #[cfg(feature = "cbor")]
+    pub fn write_cbor<W: std::io::Write>(&self, writer: W) -> Result<(), EverestError> {
+        let text = |text: &str| CborValue::Text(text.to_owned());
+        let hunks = self.patch.iter().map(|hunk| {
+            let fields = encode_hunk(hunk).into_iter();
+            CborValue::Map(
+                fields
+                    .map(|(key, value)| (text(key), text(&value)))
+                    .collect(),
+            )
+        });
+        let value = CborValue::Map(vec![
+            (text("version"), CborValue::from(VERSION)),
+            (text("folder"), text(&self.folder)),
+            (text("hunks"), CborValue::Array(hunks.collect())),
+        ]);
+        ciborium::into_writer(&value, writer)
+            .map_err(|err| EverestError::WritePatchError(err.to_string()))
+    }
+
+    /// Reads a patch file written with [`PatchFile::write_cbor`].
+    #[cfg(feature = "cbor")]
+    pub fn read_cbor<R: std::io::Read>(reader: R) -> Result<Self, EverestError> {
+        let invalid = |err: String| EverestError::ReadPatchError(err);
+        let value: CborValue =
+            ciborium::from_reader(reader).map_err(|err| invalid(err.to_string()))?;
+        let map = value
+            .as_map()
+            .ok_or_else(|| invalid("invalid patch".into()))?;
+        let version = cbor_field(map, "version").and_then(CborValue::as_integer);
+        check_version(version.and_then(|v| u64::try_from(v).ok())).map_err(invalid)?;
+        let folder = cbor_field(map, "folder").and_then(CborValue::as_text);
+        let folder = folder.ok_or_else(|| invalid("invalid folder".into()))?;
+        let hunks = cbor_field(map, "hunks").and_then(CborValue::as_array);
+        let hunks = hunks.ok_or_else(|| invalid("invalid hunks".into()))?;
+        let patch = hunks.iter().map(|hunk| {
+            let hunk = hunk.as_map().ok_or("invalid hunk")?;
+            decode_hunk(|key| cbor_field(hunk, key).and_then(CborValue::as_text))
+        });
+        let patch = patch.collect::<Result<_, String>>().map_err(invalid)?;
+        Ok(Self::new(folder, patch))
+    }
+}
+
+/// Returns the value of the given key of a CBOR map.
+#[cfg(feature = "cbor")]
+fn cbor_field<'a>(map: &'a [(CborValue, CborValue)], key: &str) -> Option<&'a CborValue> {
+    let field = map.iter().find(|(k, _)| k.as_text() == Some(key));
+    field.map(|(_, value)| value)
+}
+
+fn check_version(version: Option<u64>) -> Result<(), String> {
+    match version {
+        Some(version) if version > VERSION => Err(format!("unsupported version {}", version)),
+        Some(version) if version > 0 => Ok(()),
+        _ => Err("invalid version".into()),
+    }
+}
+
+/// Returns the fields of the given hunk, in the order they are
+/// written.
+fn encode_hunk(hunk: &Hunk) -> Vec<(&'static str, String)> {
+    let (kind, flag) = match &hunk.kind {
+        HunkKind::AddMsg(_) => ("add_msg", None),
+        HunkKind::RemoveMsg(_) => ("remove_msg", None),
+        HunkKind::AddFlag(_, flag) => ("add_flag", Some(flag)),
+        HunkKind::RemoveFlag(_, flag) => ("remove_flag", Some(flag)),
+    };
+    let id = hunk.kind.id();
+    let mut fields = vec![
+        ("side", hunk.target.to_string()),
+        ("kind", kind.to_owned()),
+        ("id", id.source.to_string()),
+    ];
+    if let Some(target) = &id.target {
+        fields.push(("target_id", target.to_string()));
+    }
+    if let Some(flag) = flag {
+        fields.push(("flag", format_flag(flag).to_owned()));
+    }
+    fields
+}
+
+/// Builds a hunk from its fields, given by the given getter.
+fn decode_hunk<'a, F: Fn(&str) -> Option<&'a str>>(get: F) -> Result<Hunk, String> {
+    let field = |key: &str| get(key).ok_or_else(|| format!("invalid hunk: missing {}", key));
+    let side = match field("side")? {
+        "left" => Side::Left,
+        "right" => Side::Right,
+        side => return Err(format!("invalid hunk: unknown side {}", side)),
+    };
+    let id = HunkId {
+        source: field("id")?.into(),
+        target: get("target_id").map(Into::into),
+    };
+    let flag = || field("flag").map(parse_flag);
+    let kind = match field("kind")? {
+        "add_msg" => HunkKind::AddMsg(id),
+        "remove_msg" => HunkKind::RemoveMsg(id),
+        "add_flag" => HunkKind::AddFlag(id, flag()?),
+        "remove_flag" => HunkKind::RemoveFlag(id, flag()?),
+        kind => return Err(format!("invalid hunk: unknown kind {}", kind)),
+    };
+    Ok(Hunk::new(side, kind))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{ErrorCode, Flag};
+
+    use super::*;
+
+    fn patch_file() -> PatchFile {
+        PatchFile::new(
+            "INBOX",
+            vec![
+                Hunk::new(Side::Right, HunkKind::AddMsg(HunkId::shared("42"))),
+                Hunk::new(
+                    Side::Left,
+                    HunkKind::AddFlag(
+                        HunkId {
+                            source: "1663512456.R1.host".into(),
+                            target: Some("7".into()),
+                        },
+                        Flag::Seen,
+                    ),
+                ),
+                Hunk::new(
+                    Side::Left,
+                    HunkKind::RemoveFlag(HunkId::shared("8"), Flag::Keyword("$Work".into())),
+                ),
+            ],
+        )
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn json_test() {
+        let mut json = Vec::new();
+        patch_file().write_json(&mut json).unwrap();
+        let value: JsonValue = serde_json::from_slice(&json).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "version": 1,
+                "folder": "INBOX",
+                "hunks": [
+                    {
+                        "side": "right",
+                        "kind": "add_msg",
+                        "id": "42",
+                        "target_id": "42",
+                    },
+                    {
+                        "side": "left",
+                        "kind": "add_flag",
+                        "id": "1663512456.R1.host",
+                        "target_id": "7",
+                        "flag": "\\Seen",
+                    },
+                    {
+                        "side": "left",
+                        "kind": "remove_flag",
+                        "id": "8",
+                        "target_id": "8",
+                        "flag": "$Work",
+                    },
+                ],
+            }),
+            value
+        );
+        assert_eq!(patch_file(), PatchFile::read_json(json.as_slice()).unwrap());
+
+        // missing target ids are unknown ones
+        let json = r#"{"version":1,"folder":"INBOX","hunks":[{"side":"right","kind":"add_msg","id":"42"}]}"#;
+        let hunk = Hunk::new(Side::Right, HunkKind::AddMsg(HunkId::unmapped("42")));
+        assert_eq!(
+            vec![hunk],
+            PatchFile::read_json(json.as_bytes()).unwrap().patch
+        );
+
+        // unknown fields are ignored, newer versions are not
+        let json = r#"{"version":1,"folder":"INBOX","hunks":[],"author":"alice"}"#;
+        assert!(PatchFile::read_json(json.as_bytes())
+            .unwrap()
+            .patch
+            .is_empty());
+        let json = r#"{"version":2,"folder":"INBOX","hunks":[]}"#;
+        let err = PatchFile::read_json(json.as_bytes()).unwrap_err();
+        assert_eq!(ErrorCode::PatchInvalid, err.code());
+        assert_eq!("cannot read patch: unsupported version 2", err.to_string());
+        let json =
+            r#"{"version":1,"folder":"INBOX","hunks":[{"side":"up","kind":"add_msg","id":"1"}]}"#;
+        let err = PatchFile::read_json(json.as_bytes()).unwrap_err();
+        assert_eq!(
+            "cannot read patch: invalid hunk: unknown side up",
+            err.to_string()
+        );
+    }
+
+    #[cfg(feature = "cbor")]
+    #[test]
+    fn cbor_test() {
+        let mut cbor = Vec::new();
+        patch_file().write_cbor(&mut cbor).unwrap();
+        assert_eq!(patch_file(), PatchFile::read_cbor(cbor.as_slice()).unwrap());
+        let err = PatchFile::read_cbor(&b"not cbor"[..]).unwrap_err();
+        assert_eq!(ErrorCode::PatchInvalid, err.code());
+    }
+}
@@ -0,0 +1,210 @@
+//! Sync of several folders with their phases overlapping.
+//!
+//! [`crate::sync::Sync`] syncs its folders one after the other, its
+//! replicas holding a single connection each. The [`Scheduler`] is for
+//! embedders able to open several connections, like a pool of IMAP
+//! sessions, running the phases of each folder with their own.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Condvar, Mutex,
+};
+
+use crate::{
+    pool::WorkerPool,
+    trace::{event, span},
+};
+
+/// Number of connections a scheduler may use at once, shared by its
+/// workers.
+#[derive(Debug)]
+struct Budget {
+    available: Mutex<usize>,
+    released: Condvar,
+}
+
+impl Budget {
+    fn new(connections: usize) -> Self {
+        Self {
+            available: Mutex::new(connections),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Runs the given function with a connection, waiting for one to
+    /// be released if none is available.
+    fn with_connection<T, F: FnOnce() -> T>(&self, f: F) -> T {
+        let mut available = self.available.lock().unwrap();
+        while *available == 0 {
+            available = self.released.wait(available).unwrap();
+        }
+        *available -= 1;
+        drop(available);
+
+        let output = f();
+
+        *self.available.lock().unwrap() += 1;
+        self.released.notify_one();
+        output
+    }
+}
+
+/// Schedules the sync of several folders so that their phases
+/// overlap: while the patch of a folder is being applied, the
+/// envelopes of the next folder are fetched.
+///
+/// Up to `parallelism` folders are synced at once, each phase holding
+/// one of the `connections` shared by all folders, so that the server
+/// does not see more connections than allowed.
+#[derive(Debug, Clone)]
+pub struct Scheduler {
+    parallelism: usize,
+    connections: usize,
+    pool: Option<WorkerPool>,
+}
+
+impl Default for Scheduler {
+    /// Syncs 2 folders at once over 2 connections, enough to fetch a
+    /// folder while applying another.
+    fn default() -> Self {
+        Self {
+            parallelism: 2,
+            connections: 2,
+            pool: None,
+        }
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of folders synced at once, at least one.
+    pub fn with_parallelism(mut self, parallelism: usize) -> Self {
+        self.parallelism = parallelism.max(1);
+        self
+    }
+
+    /// Sets the number of connections shared by the folders, at least
+    /// one.
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections.max(1);
+        self
+    }
+
+    /// Syncs the folders on the given pool, whose threads bound the
+    /// parallelism. Each folder synced at once gets its own thread
+    /// otherwise.
+    pub fn with_pool(mut self, pool: WorkerPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Syncs the given folders: `fetch` lists the envelopes of a
+    /// folder and diffs them, then `apply` applies the result. A
+    /// failing folder does not stop the others: results are returned
+    /// in the order of the given folders.
+    pub fn run<S, T, R, E, F, A>(&self, folders: &[S], fetch: F, apply: A) -> Vec<Result<R, E>>
+    where
+        S: AsRef<str> + Sync,
+        R: Send,
+        E: Send,
+        F: Fn(&str) -> Result<T, E> + Sync,
+        A: Fn(&str, T) -> Result<R, E> + Sync,
+    {
+        let budget = Budget::new(self.connections);
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(Vec::with_capacity(folders.len()));
+        let workers = self.parallelism.min(folders.len());
+        let pool = match &self.pool {
+            Some(pool) => pool.clone(),
+            None => WorkerPool::new(workers),
+        };
+
+        pool.run(workers, || loop {
+            let i = next.fetch_add(1, Ordering::Relaxed);
+            let folder = match folders.get(i) {
+                Some(folder) => folder.as_ref(),
+                None => break,
+            };
+            let _span = span!(INFO, "sync_folder", folder).entered();
+            let result = budget
+                .with_connection(|| fetch(folder))
+                .and_then(|fetched| budget.with_connection(|| apply(folder, fetched)));
+            event!(DEBUG, ok = result.is_ok(), "folder synced");
+            results.lock().unwrap().push((i, result));
+        });
+
+        let mut results = results.into_inner().unwrap();
+        results.sort_by_key(|(i, _)| *i);
+        results.into_iter().map(|(_, result)| result).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn run_test() {
+        let folders = ["INBOX", "Sent", "Trash"];
+        let fetched = Mutex::new(Vec::new());
+        let fetched_changed = Condvar::new();
+
+        let results = Scheduler::new().run(
+            &folders,
+            |folder| {
+                fetched.lock().unwrap().push(folder.to_owned());
+                fetched_changed.notify_all();
+                match folder {
+                    "Trash" => Err(format!("cannot fetch {}", folder)),
+                    _ => Ok(folder.len()),
+                }
+            },
+            |folder, len| {
+                if folder == "INBOX" {
+                    // the next folder is fetched while INBOX is applied
+                    let fetched = fetched.lock().unwrap();
+                    let (fetched, timeout) = fetched_changed
+                        .wait_timeout_while(fetched, Duration::from_secs(5), |fetched| {
+                            !fetched.iter().any(|folder| folder == "Sent")
+                        })
+                        .unwrap();
+                    assert!(!timeout.timed_out(), "{:?}", fetched);
+                }
+                Ok(len * 10)
+            },
+        );
+
+        assert_eq!(
+            vec![Ok(50), Ok(40), Err("cannot fetch Trash".to_owned())],
+            results
+        );
+    }
+
+    #[test]
+    fn budget_test() {
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let run = || {
+            let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(n, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            running.fetch_sub(1, Ordering::SeqCst);
+            Ok::<_, ()>(())
+        };
+
+        let folders = (0..8).map(|i| i.to_string()).collect::<Vec<_>>();
+        let results = Scheduler::new()
+            .with_parallelism(4)
+            .with_connections(2)
+            .run(&folders, |_| run(), |_, ()| run());
+
+        assert_eq!(8, results.len());
+        assert!(results.iter().all(Result::is_ok));
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+    }
+}
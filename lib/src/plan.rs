@@ -0,0 +1,308 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use crate::{pool::WorkerPool, Flag, HunkKind, Id, Patch, Side};
+
+/// Side a batch applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum Backend {
+    Imap,
+    Maildir,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Imap => f.write_str("imap"),
+            Self::Maildir => f.write_str("maildir"),
+        }
+    }
+}
+
+/// Operation applied at once to all the messages of a batch.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BatchOp {
+    AddMsgs,
+    RemoveMsgs,
+    AddFlag(Flag),
+    RemoveFlag(Flag),
+}
+
+/// Hunks of a patch sharing the same backend, folder and operation,
+/// for executors to apply with bulk commands (like a single `UID
+/// STORE` over ranges of UIDs) rather than hunk by hunk.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Batch {
+    pub backend: Backend,
+    pub folder: String,
+    pub op: BatchOp,
+    /// Ids of the messages, in patch order: in the source replica for
+    /// messages to add, in the target replica otherwise.
+    pub ids: Vec<Id>,
+}
+
+impl Batch {
+    /// Returns the ids of the batch as sorted ranges of UIDs, `None`
+    /// if any id is not a UID (like maildir ids of messages to add to
+    /// IMAP).
+    pub fn uid_ranges(&self) -> Option<Vec<RangeInclusive<u32>>> {
+        let mut uids = self
+            .ids
+            .iter()
+            .map(|id| id.parse::<u32>().ok())
+            .collect::<Option<Vec<_>>>()?;
+        uids.sort_unstable();
+        uids.dedup();
+        Some(uid_ranges(&uids))
+    }
+
+    /// Returns the ids of the batch as an IMAP sequence set, like
+    /// `1:3,7`, `None` if any id is not a UID.
+    pub fn uid_set(&self) -> Option<String> {
+        Some(format_uid_set(&self.uid_ranges()?))
+    }
+}
+
+/// Groups the given sorted UIDs into ranges of consecutive UIDs.
+pub(crate) fn uid_ranges(uids: &[u32]) -> Vec<RangeInclusive<u32>> {
+    let mut ranges: Vec<RangeInclusive<u32>> = vec![];
+    for &uid in uids {
+        match ranges.last_mut() {
+            Some(range) if range.end().checked_add(1) == Some(uid) => *range = *range.start()..=uid,
+            _ => ranges.push(uid..=uid),
+        }
+    }
+    ranges
+}
+
+/// Formats the given ranges of UIDs as an IMAP sequence set, like
+/// `1:3,7`.
+pub(crate) fn format_uid_set(ranges: &[RangeInclusive<u32>]) -> String {
+    let ranges = ranges.iter().map(|range| {
+        if range.start() == range.end() {
+            range.start().to_string()
+        } else {
+            format!("{}:{}", range.start(), range.end())
+        }
+    });
+    ranges.collect::<Vec<_>>().join(",")
+}
+
+/// Groups the hunks of the given patches, one per folder, into
+/// batches per backend, folder and operation. Batches come in the
+/// order of their first hunk, messages being added before their flags
+/// change and removed last.
+pub fn plan<'a, I>(patches: I) -> Vec<Batch>
+where
+    I: IntoIterator<Item = (&'a str, &'a Patch)>,
+{
+    let mut batches = Vec::<Batch>::new();
+    let mut indexes = HashMap::<(String, Backend, BatchOp), usize>::new();
+
+    for (folder, patch) in patches {
+        for hunk in patch {
+            let backend = match hunk.target {
+                Side::Left => Backend::Imap,
+                Side::Right => Backend::Maildir,
+            };
+            let (op, id) = match &hunk.kind {
+                HunkKind::AddMsg(id) => (BatchOp::AddMsgs, &id.source),
+                HunkKind::RemoveMsg(id) => (BatchOp::RemoveMsgs, id.target_or_source()),
+                HunkKind::AddFlag(id, flag) => {
+                    (BatchOp::AddFlag(flag.clone()), id.target_or_source())
+                }
+                HunkKind::RemoveFlag(id, flag) => {
+                    (BatchOp::RemoveFlag(flag.clone()), id.target_or_source())
+                }
+            };
+            let key = (folder.to_owned(), backend, op);
+            let index = *indexes.entry(key.clone()).or_insert_with(|| {
+                batches.push(Batch {
+                    backend,
+                    folder: key.0,
+                    op: key.2,
+                    ids: vec![],
+                });
+                batches.len() - 1
+            });
+            batches[index].ids.push(id.clone());
+        }
+    }
+
+    // stable, so batches of the same rank keep their order
+    batches.sort_by_key(|batch| match batch.op {
+        BatchOp::AddMsgs => 0,
+        BatchOp::AddFlag(_) | BatchOp::RemoveFlag(_) => 1,
+        BatchOp::RemoveMsgs => 2,
+    });
+    batches
+}
+
+/// Groups the given batches by backend and folder, in the order of
+/// their first batch, returning the indexes of the batches of each
+/// group. Batches of a group depend on each other, unlike batches of
+/// different groups.
+pub fn lanes(batches: &[Batch]) -> Vec<Vec<usize>> {
+    let mut lanes = Vec::<Vec<usize>>::new();
+    let mut indexes = HashMap::<(Backend, &str), usize>::new();
+    for (i, batch) in batches.iter().enumerate() {
+        let lane = *indexes
+            .entry((batch.backend, batch.folder.as_str()))
+            .or_insert_with(|| {
+                lanes.push(vec![]);
+                lanes.len() - 1
+            });
+        lanes[lane].push(i);
+    }
+    lanes
+}
+
+/// Applies the given batches with the given function, running up to
+/// `concurrency` of them at once (at least one). Batches of the same
+/// backend and folder depend on each other, so they are applied one
+/// after the other in their order, while batches of other backends or
+/// folders run concurrently (like maildir writes next to IMAP
+/// commands, or several IMAP connections). A failing batch does not
+/// stop the others: the errors are returned in the order of their
+/// batches.
+pub fn execute<F, E>(batches: &[Batch], concurrency: usize, apply: F) -> Vec<E>
+where
+    F: Fn(&Batch) -> Result<(), E> + Sync,
+    E: Send,
+{
+    execute_on(&WorkerPool::new(concurrency), batches, apply)
+}
+
+/// Same as [`execute`], applying the batches on the workers of the
+/// given pool.
+pub fn execute_on<F, E>(pool: &WorkerPool, batches: &[Batch], apply: F) -> Vec<E>
+where
+    F: Fn(&Batch) -> Result<(), E> + Sync,
+    E: Send,
+{
+    let lanes = lanes(batches);
+    let next_lane = AtomicUsize::new(0);
+    let errors = Mutex::new(Vec::<(usize, E)>::new());
+
+    pool.run(lanes.len(), || {
+        while let Some(lane) = lanes.get(next_lane.fetch_add(1, Ordering::Relaxed)) {
+            for &i in lane {
+                if let Err(err) = apply(&batches[i]) {
+                    errors.lock().unwrap().push((i, err));
+                }
+            }
+        }
+    });
+
+    let mut errors = errors.into_inner().unwrap();
+    errors.sort_by_key(|(i, _)| *i);
+    errors.into_iter().map(|(_, err)| err).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use crate::Hunk;
+
+    use super::*;
+
+    #[test]
+    fn plan_test() {
+        let inbox = vec![
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("4".into())),
+            Hunk::new(Side::Right, HunkKind::AddFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::AddFlag("2".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::AddMsg("a".into())),
+            Hunk::new(Side::Left, HunkKind::AddFlag("3".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::AddFlag("7".into(), Flag::Seen)),
+        ];
+        let sent = vec![Hunk::new(Side::Left, HunkKind::RemoveMsg("4".into()))];
+
+        let batches = plan([("INBOX", &inbox), ("Sent", &sent)]);
+
+        let summary = batches
+            .iter()
+            .map(|batch| {
+                (
+                    batch.backend,
+                    batch.folder.as_str(),
+                    &batch.op,
+                    batch.ids.len(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Backend::Imap, "INBOX", &BatchOp::AddMsgs, 1),
+                (Backend::Maildir, "INBOX", &BatchOp::AddFlag(Flag::Seen), 1),
+                (Backend::Imap, "INBOX", &BatchOp::AddFlag(Flag::Seen), 4),
+                (Backend::Imap, "INBOX", &BatchOp::RemoveMsgs, 1),
+                (Backend::Imap, "Sent", &BatchOp::RemoveMsgs, 1),
+            ],
+            summary
+        );
+        assert_eq!(Some("1:3,7".into()), batches[2].uid_set());
+        assert_eq!(None, batches[0].uid_set());
+        assert_eq!(Some(vec![1..=3, 7..=7]), batches[2].uid_ranges());
+    }
+
+    #[test]
+    fn execute_test() {
+        let patch = vec![
+            Hunk::new(Side::Left, HunkKind::AddMsg("a".into())),
+            Hunk::new(Side::Left, HunkKind::AddFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Left, HunkKind::RemoveMsg("2".into())),
+            Hunk::new(Side::Right, HunkKind::AddMsg("3".into())),
+        ];
+        let folders = ["INBOX", "Sent", "Trash", "Archive"].map(|folder| (folder, &patch));
+        let batches = plan(folders);
+
+        let running = AtomicUsize::new(0);
+        let max_running = AtomicUsize::new(0);
+        let applied = Mutex::new(Vec::new());
+        let errors = execute(&batches, 2, |batch| {
+            let n = running.fetch_add(1, Ordering::SeqCst) + 1;
+            max_running.fetch_max(n, Ordering::SeqCst);
+            thread::sleep(Duration::from_millis(5));
+            applied.lock().unwrap().push(batch.clone());
+            running.fetch_sub(1, Ordering::SeqCst);
+            match batch.op {
+                BatchOp::RemoveMsgs => Err(batch.folder.clone()),
+                _ => Ok(()),
+            }
+        });
+
+        assert_eq!(vec!["INBOX", "Sent", "Trash", "Archive"], errors);
+        assert!(max_running.load(Ordering::SeqCst) <= 2);
+        let applied = applied.into_inner().unwrap();
+        assert_eq!(batches.len(), applied.len());
+        for folder in ["INBOX", "Sent", "Trash", "Archive"] {
+            let ops = applied
+                .iter()
+                .filter(|batch| batch.backend == Backend::Imap && batch.folder == folder)
+                .map(|batch| &batch.op)
+                .collect::<Vec<_>>();
+            assert_eq!(
+                vec![
+                    &BatchOp::AddMsgs,
+                    &BatchOp::AddFlag(Flag::Seen),
+                    &BatchOp::RemoveMsgs
+                ],
+                ops
+            );
+        }
+
+        assert!(execute(&[], 0, |_| Err(())).is_empty());
+    }
+}
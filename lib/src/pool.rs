@@ -0,0 +1,148 @@
+//! Threads running the blocking work of the crate, like maildir IO
+//! and concurrent IMAP commands.
+
+use std::{num::NonZeroUsize, thread};
+
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
+
+/// Pool of worker threads, supplied or sized by embedders so that
+/// the crate cooperates with the resource limits of their
+/// application instead of spawning as many threads as it sees fit.
+///
+/// By default, workers are scoped threads, at most one per CPU. With
+/// the `parallel` feature, an existing rayon pool can be used
+/// instead.
+#[derive(Debug, Clone)]
+pub struct WorkerPool(Workers);
+
+#[derive(Debug, Clone)]
+enum Workers {
+    Threads(usize),
+    #[cfg(feature = "parallel")]
+    Rayon(Arc<rayon::ThreadPool>),
+}
+
+impl Default for WorkerPool {
+    fn default() -> Self {
+        Self::new(thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+}
+
+impl WorkerPool {
+    /// Runs the work on up to the given number of scoped threads, at
+    /// least one.
+    pub fn new(threads: usize) -> Self {
+        Self(Workers::Threads(threads.max(1)))
+    }
+
+    /// Runs the work on the given rayon pool. Workers block on IO,
+    /// so the pool is better dedicated to it rather than shared with
+    /// CPU-bound work.
+    #[cfg(feature = "parallel")]
+    pub fn from_rayon(pool: Arc<rayon::ThreadPool>) -> Self {
+        Self(Workers::Rayon(pool))
+    }
+
+    /// Returns the number of threads of the pool.
+    pub fn threads(&self) -> usize {
+        match &self.0 {
+            Workers::Threads(threads) => *threads,
+            #[cfg(feature = "parallel")]
+            Workers::Rayon(pool) => pool.current_num_threads(),
+        }
+    }
+
+    /// Runs the given function in the pool, so that the parallel
+    /// diffs of [`crate::build_patch`] use the threads of its rayon
+    /// pool rather than the global one. Pools of scoped threads run
+    /// it on the current thread.
+    pub fn install<T, F>(&self, f: F) -> T
+    where
+        T: Send,
+        F: FnOnce() -> T + Send,
+    {
+        match &self.0 {
+            Workers::Threads(_) => f(),
+            #[cfg(feature = "parallel")]
+            Workers::Rayon(pool) => pool.install(f),
+        }
+    }
+
+    /// Runs the given function on the given number of workers at once,
+    /// bounded by the threads of the pool, and waits for all of them.
+    /// Workers share their work through the state of the function,
+    /// like the index of the next item to process.
+    pub(crate) fn run<F: Fn() + Sync>(&self, workers: usize, f: F) {
+        let workers = workers.min(self.threads());
+        if workers == 1 {
+            return f();
+        }
+        match &self.0 {
+            Workers::Threads(_) => thread::scope(|scope| {
+                for _ in 0..workers {
+                    scope.spawn(&f);
+                }
+            }),
+            #[cfg(feature = "parallel")]
+            Workers::Rayon(pool) => pool.scope(|scope| {
+                for _ in 0..workers {
+                    scope.spawn(|_| f());
+                }
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Barrier,
+    };
+
+    use super::*;
+
+    #[test]
+    fn run_test() {
+        let pool = WorkerPool::new(3);
+        assert_eq!(3, pool.threads());
+        assert_eq!(1, WorkerPool::new(0).threads());
+
+        // workers run at once, bounded by the threads of the pool
+        let barrier = Barrier::new(3);
+        let runs = AtomicUsize::new(0);
+        pool.run(10, || {
+            barrier.wait();
+            runs.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(3, runs.into_inner());
+
+        let runs = AtomicUsize::new(0);
+        pool.run(0, || {
+            runs.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(0, runs.into_inner());
+        assert_eq!(42, pool.install(|| 42));
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn rayon_test() {
+        let rayon = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+        let pool = WorkerPool::from_rayon(Arc::new(rayon));
+        assert_eq!(2, pool.threads());
+        assert_eq!(2, pool.install(rayon::current_num_threads));
+
+        let barrier = Barrier::new(2);
+        let runs = AtomicUsize::new(0);
+        pool.run(4, || {
+            barrier.wait();
+            runs.fetch_add(1, Ordering::Relaxed);
+        });
+        assert_eq!(2, runs.into_inner());
+    }
+}
@@ -0,0 +1,23 @@
+//! Types and traits used by most embedders, to import at once:
+//!
+//! ```
+//! use everest_lib::prelude::*;
+//!
+//! fn unread(envelopes: &Envelopes) -> Result<usize> {
+//!     Ok(envelopes.values().filter(|e| !e.flags().contains(&Flag::Seen)).count())
+//! }
+//! ```
+//!
+//! `sync::Sync` is left out not to shadow the `Sync` marker trait,
+//! `SyncBuilder::default` building it instead.
+
+pub use crate::{
+    observer::SyncObserver, plan::Backend, BackendError, Envelope, Envelopes, ErrorKind,
+    EverestError, Flag, Flags, Hunk, HunkKind, Id, Patch, Result, Side,
+};
+
+#[cfg(feature = "cache")]
+pub use crate::{
+    cache::Cache,
+    sync::{ConflictPolicy, Replica, SyncBuilder},
+};
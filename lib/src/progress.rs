@@ -0,0 +1,87 @@
+//! Progress of the patch of a folder being applied, for progress
+//! bars of frontends.
+
+use std::time::Duration;
+
+/// Progress of the patch of a folder, sent to
+/// [`crate::observer::SyncObserver::progress`] after each hunk.
+///
+/// Hunks count as done once applied or failed. The total size of the
+/// messages to copy is only known when the envelopes tell their size,
+/// with the `metadata` feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Hunks done.
+    pub hunks: usize,
+    /// Hunks of the patch.
+    pub total_hunks: usize,
+    /// Size of the messages copied so far, in bytes.
+    pub bytes: u64,
+    /// Size of the messages to copy, in bytes, when known.
+    pub total_bytes: Option<u64>,
+    /// Time spent applying the patch so far.
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Returns the part of the patch done, from 0 to 1, by bytes when
+    /// their total is known, by hunks otherwise.
+    pub fn fraction(&self) -> f64 {
+        match self.total_bytes {
+            Some(total) if total > 0 => self.bytes as f64 / total as f64,
+            _ if self.total_hunks > 0 => self.hunks as f64 / self.total_hunks as f64,
+            _ => 1.0,
+        }
+    }
+
+    /// Estimates the time left to apply the rest of the patch, from
+    /// the throughput measured so far. Nothing can be estimated
+    /// before anything is done.
+    pub fn eta(&self) -> Option<Duration> {
+        let fraction = self.fraction();
+        if fraction >= 1.0 {
+            return Some(Duration::ZERO);
+        }
+        if fraction <= 0.0 {
+            return None;
+        }
+        Some(self.elapsed.mul_f64((1.0 - fraction) / fraction))
+    }
+
+    /// Returns `true` once every hunk is done.
+    pub fn is_done(&self) -> bool {
+        self.hunks >= self.total_hunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn eta_test() {
+        let secs = Duration::from_secs;
+        let mut progress = Progress {
+            total_hunks: 4,
+            ..Default::default()
+        };
+        assert_eq!(None, progress.eta());
+
+        progress.hunks = 1;
+        progress.elapsed = secs(3);
+        assert_eq!(0.25, progress.fraction());
+        assert_eq!(Some(secs(9)), progress.eta());
+
+        // bytes tell the progress better than hunks, when known
+        progress.bytes = 300;
+        progress.total_bytes = Some(400);
+        assert_eq!(Some(secs(1)), progress.eta());
+        assert!(!progress.is_done());
+
+        progress.hunks = 4;
+        progress.bytes = 400;
+        assert_eq!(Some(Duration::ZERO), progress.eta());
+        assert!(progress.is_done());
+        assert_eq!(1.0, Progress::default().fraction());
+    }
+}
@@ -0,0 +1,630 @@
+use std::{
+    error::Error,
+    fmt,
+    ops::AddAssign,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{EverestError, Hunk, HunkKind, Side};
+
+/// Phases of a sync run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Phase {
+    ListImap,
+    ListMaildir,
+    Diff,
+    Apply,
+    SaveCache,
+}
+
+impl Phase {
+    /// All phases, in the order they run.
+    pub const ALL: [Phase; 5] = [
+        Phase::ListImap,
+        Phase::ListMaildir,
+        Phase::Diff,
+        Phase::Apply,
+        Phase::SaveCache,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ListImap => "list imap",
+            Self::ListMaildir => "list maildir",
+            Self::Diff => "diff",
+            Self::Apply => "apply",
+            Self::SaveCache => "save cache",
+        }
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Time spent in each phase of a sync run. Phases run once per
+/// folder, so their durations add up over the folders.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timings([Duration; Phase::ALL.len()]);
+
+impl Timings {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the given duration to the time spent in the given phase.
+    pub fn add(&mut self, phase: Phase, duration: Duration) {
+        self.0[phase as usize] += duration;
+    }
+
+    /// Runs the given function, adding its duration to the time spent
+    /// in the given phase.
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: Phase, f: F) -> T {
+        let start = Instant::now();
+        let output = f();
+        self.add(phase, start.elapsed());
+        output
+    }
+
+    pub fn get(&self, phase: Phase) -> Duration {
+        self.0[phase as usize]
+    }
+
+    /// Returns the time spent in all phases.
+    pub fn total(&self) -> Duration {
+        self.0.iter().sum()
+    }
+
+    /// Iterates over the phases and the time spent in them, in the
+    /// order they run.
+    pub fn iter(&self) -> impl Iterator<Item = (Phase, Duration)> + '_ {
+        Phase::ALL.into_iter().map(|phase| (phase, self.get(phase)))
+    }
+}
+
+impl AddAssign for Timings {
+    fn add_assign(&mut self, other: Self) {
+        for (phase, duration) in other.iter() {
+            self.add(phase, duration);
+        }
+    }
+}
+
+/// Renders the timings as `list imap: 1.2s, list maildir: 300ms, …`.
+impl fmt::Display for Timings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, (phase, duration)) in self.iter().enumerate() {
+            if i > 0 {
+                f.write_str(", ")?;
+            }
+            write!(f, "{}: {:.1?}", phase, duration)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hunk that failed to apply, with where it was applied and why.
+#[derive(Debug, Clone)]
+pub struct HunkError {
+    pub folder: String,
+    pub side: Side,
+    pub hunk: Hunk,
+    pub error: Arc<EverestError>,
+}
+
+impl fmt::Display for HunkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cannot apply hunk `{}` to {} side of folder {}",
+            self.hunk, self.side, self.folder
+        )
+    }
+}
+
+impl Error for HunkError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Folder whose sync failed before its patch could be applied, like
+/// when a side cannot be listed. Its sync is retried by the next run.
+#[derive(Debug, Clone)]
+pub struct FolderError {
+    pub folder: String,
+    pub error: Arc<EverestError>,
+}
+
+impl fmt::Display for FolderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cannot sync folder {}", self.folder)
+    }
+}
+
+impl Error for FolderError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+/// Changes applied to a side of a sync run.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SideReport {
+    /// Messages added to the side.
+    pub added: usize,
+    /// Messages removed from the side.
+    pub removed: usize,
+    /// Flags added to or removed from messages of the side.
+    pub flags_changed: usize,
+    /// Size of the messages copied to the side, in bytes.
+    pub bytes: u64,
+}
+
+impl SideReport {
+    /// Records the given hunk applied to the side, `bytes` being the
+    /// size of the message it copied, if any.
+    pub fn record(&mut self, kind: &HunkKind, bytes: u64) {
+        match kind {
+            HunkKind::AddMsg(_) => self.added += 1,
+            HunkKind::RemoveMsg(_) => self.removed += 1,
+            HunkKind::AddFlag(..) | HunkKind::RemoveFlag(..) => self.flags_changed += 1,
+        }
+        self.bytes += bytes;
+    }
+
+    /// Returns `true` if nothing changed on the side.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+}
+
+impl AddAssign for SideReport {
+    fn add_assign(&mut self, other: Self) {
+        self.added += other.added;
+        self.removed += other.removed;
+        self.flags_changed += other.flags_changed;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Errors collected by a sync run, see [`Outcome`].
+#[derive(Debug, Clone, Copy)]
+pub struct Failures<'a> {
+    /// Hunks that failed to apply.
+    pub hunks: &'a [HunkError],
+    /// Folders that failed to sync.
+    pub folders: &'a [FolderError],
+}
+
+impl Failures<'_> {
+    /// Returns the number of hunks and folders that failed.
+    pub fn len(&self) -> usize {
+        self.hunks.len() + self.folders.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Outcome of a sync run, telling apart a run where everything went
+/// fine from one where only some changes failed, with the errors.
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome<'a> {
+    /// Every folder synced and every hunk applied.
+    Success,
+    /// Some changes applied, others failed and are retried by the next
+    /// sync.
+    Partial(Failures<'a>),
+    /// Nothing synced: every folder or every hunk failed.
+    Failure(Failures<'a>),
+}
+
+impl Outcome<'_> {
+    /// Returns the name of the outcome: `success`, `partial` or
+    /// `failure`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::Partial(_) => "partial",
+            Self::Failure(_) => "failure",
+        }
+    }
+
+    /// Returns the exit code of a command ending with the outcome: 0
+    /// for a success, 2 for a partial success and 1 for a failure.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::Success => 0,
+            Self::Partial(_) => 2,
+            Self::Failure(_) => 1,
+        }
+    }
+
+    /// Returns the errors of the run, none for a success.
+    pub fn failures(&self) -> Option<&Failures<'_>> {
+        match self {
+            Self::Success => None,
+            Self::Partial(failures) | Self::Failure(failures) => Some(failures),
+        }
+    }
+}
+
+impl fmt::Display for Outcome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.failures() {
+            None => f.write_str(self.name()),
+            Some(failures) => write!(
+                f,
+                "{}: {} hunks and {} folders failed",
+                self.name(),
+                failures.hunks.len(),
+                failures.folders.len()
+            ),
+        }
+    }
+}
+
+/// Statistics of the sync of a folder, to see which folders are
+/// responsible for churn.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FolderStats {
+    pub folder: String,
+    /// Changes applied to the left side.
+    pub left: SideReport,
+    /// Changes applied to the right side.
+    pub right: SideReport,
+    /// Number of hunks of the patch, applied or not.
+    pub hunks: usize,
+    /// Number of hunks that failed to apply.
+    pub failed: usize,
+    /// Time spent syncing the folder.
+    pub duration: Duration,
+}
+
+impl FolderStats {
+    /// Builds the statistics of the given folder from its report.
+    pub fn new<F: Into<String>>(folder: F, report: &SyncReport) -> Self {
+        Self {
+            folder: folder.into(),
+            left: report.left,
+            right: report.right,
+            hunks: report.hunks,
+            failed: report.errors.len(),
+            duration: report.timings.total(),
+        }
+    }
+
+    /// Returns the number of changes applied to both sides.
+    pub fn changes(&self) -> usize {
+        let side = |side: &SideReport| side.added + side.removed + side.flags_changed;
+        side(&self.left) + side(&self.right)
+    }
+}
+
+impl AddAssign<&FolderStats> for FolderStats {
+    fn add_assign(&mut self, other: &FolderStats) {
+        self.left += other.left;
+        self.right += other.right;
+        self.hunks += other.hunks;
+        self.failed += other.failed;
+        self.duration += other.duration;
+    }
+}
+
+/// Renders statistics of folders as a table, see
+/// [`SyncReport::table`].
+#[derive(Debug, Clone, Copy)]
+pub struct StatsTable<'a>(&'a SyncReport);
+
+impl fmt::Display for StatsTable<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let side = |side: &SideReport| {
+            format!("+{} -{} ~{}", side.added, side.removed, side.flags_changed)
+        };
+        let row = |stats: &FolderStats| {
+            [
+                stats.folder.clone(),
+                stats.hunks.to_string(),
+                side(&stats.left),
+                side(&stats.right),
+                stats.failed.to_string(),
+                (stats.left.bytes + stats.right.bytes).to_string(),
+                format!("{:.1?}", stats.duration),
+            ]
+        };
+        let mut rows = vec![[
+            "FOLDER", "HUNKS", "LEFT", "RIGHT", "FAILED", "BYTES", "TIME",
+        ]
+        .map(String::from)];
+        rows.extend(self.0.folders.iter().map(row));
+        let mut total = self.0.total();
+        total.folder = "TOTAL".into();
+        rows.push(row(&total));
+
+        let mut widths = [0; 7];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for (i, row) in rows.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            let mut line = String::new();
+            for (j, (cell, width)) in row.iter().zip(widths).enumerate() {
+                match j {
+                    0 => line.push_str(&format!("{:<1$}", cell, width)),
+                    _ => line.push_str(&format!("  {:>1$}", cell, width)),
+                }
+            }
+            f.write_str(&line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Report of a sync run, with what a status UI or a notification
+/// needs: the changes applied to each side, where time went and what
+/// failed.
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    /// Statistics of the folders synced, in the order they were.
+    pub folders: Vec<FolderStats>,
+    /// Changes applied to the left side.
+    pub left: SideReport,
+    /// Changes applied to the right side.
+    pub right: SideReport,
+    /// Time spent in each phase, to see where time goes.
+    pub timings: Timings,
+    /// Number of hunks of the patches, applied or not.
+    pub hunks: usize,
+    /// Hunks that failed to apply, retried by the next sync.
+    pub errors: Vec<HunkError>,
+    /// Folders that failed to sync, retried by the next sync.
+    pub skipped: Vec<FolderError>,
+}
+
+impl SyncReport {
+    /// Returns the changes applied to the given side.
+    pub fn side(&self, side: Side) -> &SideReport {
+        match side {
+            Side::Left => &self.left,
+            Side::Right => &self.right,
+        }
+    }
+
+    pub fn side_mut(&mut self, side: Side) -> &mut SideReport {
+        match side {
+            Side::Left => &mut self.left,
+            Side::Right => &mut self.right,
+        }
+    }
+
+    /// Returns the size of the messages copied to both sides, in
+    /// bytes.
+    pub fn bytes(&self) -> u64 {
+        self.left.bytes + self.right.bytes
+    }
+
+    /// Returns `true` if every folder synced and every hunk applied.
+    pub fn is_success(&self) -> bool {
+        self.errors.is_empty() && self.skipped.is_empty()
+    }
+
+    /// Returns the outcome of the run: a failure when no folder synced
+    /// or every hunk failed, a partial success when only some failed.
+    pub fn outcome(&self) -> Outcome<'_> {
+        let failures = Failures {
+            hunks: &self.errors,
+            folders: &self.skipped,
+        };
+        if failures.is_empty() {
+            Outcome::Success
+        } else if self.folders.is_empty()
+            || (!self.errors.is_empty() && self.errors.len() == self.hunks)
+        {
+            Outcome::Failure(failures)
+        } else {
+            Outcome::Partial(failures)
+        }
+    }
+
+    /// Returns the statistics of all the folders synced together.
+    pub fn total(&self) -> FolderStats {
+        let mut total = FolderStats::default();
+        for stats in &self.folders {
+            total += stats;
+        }
+        total
+    }
+
+    /// Returns the statistics of the folders synced, the ones with the
+    /// most changes first.
+    pub fn churn(&self) -> Vec<&FolderStats> {
+        let mut folders = self.folders.iter().collect::<Vec<_>>();
+        folders.sort_by_key(|stats| std::cmp::Reverse(stats.changes()));
+        folders
+    }
+
+    /// Renders the statistics of the folders as a table, one folder
+    /// per line followed by their total:
+    ///
+    /// ```text
+    /// FOLDER  HUNKS      LEFT     RIGHT  FAILED  BYTES   TIME
+    /// INBOX       3  +1 -0 ~0  +1 -0 ~1       0    512  1.2ms
+    /// TOTAL       3  +1 -0 ~0  +1 -0 ~1       0    512  1.2ms
+    /// ```
+    pub fn table(&self) -> StatsTable<'_> {
+        StatsTable(self)
+    }
+}
+
+impl AddAssign for SyncReport {
+    fn add_assign(&mut self, other: Self) {
+        self.folders.extend(other.folders);
+        self.left += other.left;
+        self.right += other.right;
+        self.timings += other.timings;
+        self.hunks += other.hunks;
+        self.errors.extend(other.errors);
+        self.skipped.extend(other.skipped);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timings_test() {
+        let ms = Duration::from_millis;
+        let mut timings = Timings::new();
+        assert_eq!(42, timings.time(Phase::Diff, || 42));
+        timings.add(Phase::ListImap, ms(1200));
+        timings.add(Phase::ListImap, ms(300));
+        assert_eq!(ms(1500), timings.get(Phase::ListImap));
+
+        let mut report = SyncReport::default();
+        report.timings.add(Phase::Apply, ms(20));
+        report += SyncReport {
+            timings,
+            ..Default::default()
+        };
+        assert_eq!(ms(20), report.timings.get(Phase::Apply));
+        assert_eq!(
+            ms(1520) + report.timings.get(Phase::Diff),
+            report.timings.total()
+        );
+
+        let mut timings = Timings::new();
+        timings.add(Phase::ListImap, ms(1500));
+        timings.add(Phase::SaveCache, Duration::from_micros(300));
+        assert_eq!(
+            "list imap: 1.5s, list maildir: 0.0ns, diff: 0.0ns, apply: 0.0ns, save cache: 300.0µs",
+            timings.to_string()
+        );
+    }
+
+    #[test]
+    fn sync_report_test() {
+        let mut report = SyncReport::default();
+        report
+            .side_mut(Side::Right)
+            .record(&HunkKind::AddMsg("1".into()), 42);
+        report
+            .side_mut(Side::Right)
+            .record(&HunkKind::RemoveFlag("2".into(), crate::Flag::Seen), 0);
+        report.left.record(&HunkKind::RemoveMsg("3".into()), 0);
+        assert!(report.is_success());
+
+        report += SyncReport {
+            right: SideReport {
+                added: 1,
+                bytes: 8,
+                ..Default::default()
+            },
+            skipped: vec![FolderError {
+                folder: "Trash".into(),
+                error: Arc::new(EverestError::FindStateDirError),
+            }],
+            ..Default::default()
+        };
+        let expected = SideReport {
+            added: 2,
+            removed: 0,
+            flags_changed: 1,
+            bytes: 50,
+        };
+        assert_eq!(&expected, report.side(Side::Right));
+        assert_eq!(1, report.left.removed);
+        assert_eq!(50, report.bytes());
+        assert!(!report.is_success());
+        // no folder synced
+        assert_eq!("failure", report.outcome().name());
+        assert_eq!("cannot sync folder Trash", report.skipped[0].to_string());
+    }
+
+    #[test]
+    fn folder_stats_test() {
+        let stats = |folder: &str, added: usize, flags_changed: usize| FolderStats {
+            folder: folder.into(),
+            right: SideReport {
+                added,
+                flags_changed,
+                bytes: 100 * added as u64,
+                ..Default::default()
+            },
+            hunks: added + flags_changed,
+            duration: Duration::from_millis(2),
+            ..Default::default()
+        };
+        let report = SyncReport {
+            folders: vec![stats("INBOX", 1, 0), stats("Archives", 2, 12)],
+            ..Default::default()
+        };
+
+        let total = report.total();
+        assert_eq!(15, total.hunks);
+        assert_eq!(3, total.right.added);
+        assert_eq!(Duration::from_millis(4), total.duration);
+        let churn = report.churn();
+        assert_eq!(
+            vec!["Archives", "INBOX"],
+            churn.iter().map(|s| &s.folder).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "FOLDER    HUNKS      LEFT      RIGHT  FAILED  BYTES   TIME\n\
+             INBOX         1  +0 -0 ~0   +1 -0 ~0       0    100  2.0ms\n\
+             Archives     14  +0 -0 ~0  +2 -0 ~12       0    200  2.0ms\n\
+             TOTAL        15  +0 -0 ~0  +3 -0 ~12       0    300  4.0ms",
+            report.table().to_string()
+        );
+    }
+
+    #[test]
+    fn outcome_test() {
+        let hunk_error = |id: &str| HunkError {
+            folder: "INBOX".into(),
+            side: Side::Right,
+            hunk: Hunk::new(Side::Right, HunkKind::AddMsg(id.into())),
+            error: Arc::new(EverestError::FindMaildirMsgError(id.into())),
+        };
+        let mut report = SyncReport {
+            folders: vec![FolderStats::default()],
+            hunks: 3,
+            ..Default::default()
+        };
+        assert!(matches!(report.outcome(), Outcome::Success));
+        assert_eq!(0, report.outcome().exit_code());
+
+        report.errors = vec![hunk_error("1"), hunk_error("2")];
+        let outcome = report.outcome();
+        let Outcome::Partial(failures) = outcome else {
+            panic!("outcome should be partial: {}", outcome);
+        };
+        assert_eq!(2, failures.hunks.len());
+        assert_eq!(2, outcome.exit_code());
+        assert_eq!("partial: 2 hunks and 0 folders failed", outcome.to_string());
+
+        report.errors.push(hunk_error("3"));
+        assert!(matches!(report.outcome(), Outcome::Failure(f) if f.len() == 3));
+        assert_eq!(1, report.outcome().exit_code());
+
+        // folders skipped while others synced without changes
+        let report = SyncReport {
+            folders: vec![FolderStats::default()],
+            skipped: vec![FolderError {
+                folder: "Trash".into(),
+                error: Arc::new(EverestError::FindStateDirError),
+            }],
+            ..Default::default()
+        };
+        assert_eq!("partial", report.outcome().name());
+    }
+}
@@ -0,0 +1,113 @@
+//! Serialization of the core types with the `serde` feature, so that
+//! external tools can store and inspect envelopes and patches.
+//!
+//! Flags are written as their IMAP names, like the JSON export of the
+//! cache, and envelopes as a list sorted by id.
+
+use serde::{de, ser::SerializeSeq, Deserialize, Deserializer, Serialize, Serializer};
+
+use crate::{
+    flag::{format_flag, parse_flag},
+    Envelope, Envelopes, Flag, Flags,
+};
+
+impl Serialize for Flag {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(format_flag(self))
+    }
+}
+
+impl<'de> Deserialize<'de> for Flag {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let flag = String::deserialize(deserializer)?;
+        match flag.is_empty() || flag.contains(' ') {
+            true => Err(de::Error::invalid_value(
+                de::Unexpected::Str(&flag),
+                &"an IMAP flag",
+            )),
+            false => Ok(parse_flag(&flag)),
+        }
+    }
+}
+
+impl Serialize for Flags {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.to_imap_flags())
+    }
+}
+
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Flag>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+impl Serialize for Envelopes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut envelopes = self.values().collect::<Vec<_>>();
+        envelopes.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        let mut seq = serializer.serialize_seq(Some(envelopes.len()))?;
+        for envelope in envelopes {
+            seq.serialize_element(envelope)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Envelopes {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Envelope>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::{Hunk, HunkId, HunkKind, Patch, Side};
+
+    use super::*;
+
+    #[test]
+    fn serde_test() {
+        let flags = [Flag::Seen, Flag::Keyword("$Important".into())];
+        let envelopes = Envelopes::from_iter([
+            Envelope::new("2"),
+            Envelope::new("1").with_flags(flags.iter().cloned()),
+        ]);
+        let value = serde_json::to_value(&envelopes).unwrap();
+        assert_eq!(
+            json!([
+                { "id": "1", "flags": ["$Important", "\\Seen"] },
+                { "id": "2", "flags": [] },
+            ]),
+            value
+        );
+        assert_eq!(envelopes, serde_json::from_value(value).unwrap());
+
+        let patch: Patch = vec![
+            Hunk::new(Side::Left, HunkKind::RemoveFlag("1".into(), Flag::Seen)),
+            Hunk::new(Side::Right, HunkKind::AddMsg(HunkId::unmapped("3"))),
+        ];
+        let value = serde_json::to_value(&patch).unwrap();
+        assert_eq!(
+            json!([
+                {
+                    "target": "left",
+                    "kind": { "remove_flag": [{ "source": "1", "target": "1" }, "\\Seen"] },
+                },
+                {
+                    "target": "right",
+                    "kind": { "add_msg": { "source": "3", "target": null } },
+                },
+            ]),
+            value
+        );
+        assert_eq!(patch, serde_json::from_value::<Patch>(value).unwrap());
+        assert!(serde_json::from_value::<Flag>(json!("\\Seen \\Draft")).is_err());
+    }
+}
@@ -0,0 +1,1579 @@
+//! Runnable syncs, assembled from their backends, cache and policies
+//! with a [`SyncBuilder`].
+
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    sync::Arc,
+    thread,
+    time::{Instant, SystemTime},
+};
+
+use crate::{
+    backend::maildir::FlagChange,
+    build_changed_patch, build_patch,
+    cache::{content_hash, Cache, FileCache, Snapshot},
+    explain::{explain_patch, Explanation},
+    health::{Check, Diagnosis, Probe},
+    observer::SyncObserver,
+    progress::Progress,
+    report::{FolderError, FolderStats, HunkError, Phase, SyncReport},
+    throttle::Backoff,
+    trace::{event, span},
+    verify::{self, Divergence, FolderDivergences, VerifyMode, VerifyReport},
+    Envelopes, EverestError, Flag, Flags, Hunk, HunkKind, Id, Patch, Side,
+};
+
+/// Side of a sync, holding the messages of several folders. The left
+/// side is usually an IMAP account, see
+/// [`crate::backend::imap_replica::ImapReplica`], and the right side
+/// maildirs, see [`crate::backend::maildir::MdirReplica`].
+///
+/// Both sides identify messages by the same ids: messages added to a
+/// side keep the id they have on the other side, replicas mapping it
+/// to their own ids if needed.
+pub trait Replica {
+    /// Lists the envelopes of the given folder.
+    fn envelopes(&mut self, folder: &str) -> Result<Envelopes, EverestError>;
+
+    /// Reads the raw content of the given message, to copy it to the
+    /// other side.
+    fn read_msg(&mut self, folder: &str, id: &str) -> Result<Vec<u8>, EverestError>;
+
+    /// Adds the given message with the given id and flags.
+    fn add_msg(
+        &mut self,
+        folder: &str,
+        id: &str,
+        raw: &[u8],
+        flags: &Flags,
+    ) -> Result<(), EverestError>;
+
+    fn remove_msg(&mut self, folder: &str, id: &str) -> Result<(), EverestError>;
+
+    fn add_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError>;
+
+    fn remove_flag(&mut self, folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError>;
+
+    /// Applies the given flag changes to messages of the given folder
+    /// at once, like the flag hunks of a patch, and returns the errors
+    /// of the changes that failed by their index. Defaults to applying
+    /// the changes one by one: replicas able to change many messages
+    /// at once, like maildirs, should do it in one go.
+    fn update_flags(
+        &mut self,
+        folder: &str,
+        changes: &[(&str, FlagChange)],
+    ) -> Vec<(usize, Arc<EverestError>)> {
+        let mut errors = vec![];
+        for (index, (id, change)) in changes.iter().enumerate() {
+            let result = match change {
+                FlagChange::Add(flag) => self.add_flag(folder, id, flag),
+                FlagChange::Remove(flag) => self.remove_flag(folder, id, flag),
+            };
+            if let Err(err) = result {
+                errors.push((index, Arc::new(err)));
+            }
+        }
+        errors
+    }
+
+    /// Saves the state the replica keeps in memory about the given
+    /// folder, like the ids of the messages it added, once the
+    /// session of the folder is committed. Does nothing by default.
+    fn flush(&mut self, _folder: &str) -> Result<(), EverestError> {
+        Ok(())
+    }
+
+    /// Checks that the given folders can be synced, without modifying
+    /// anything. Defaults to listing their envelopes: replicas able to
+    /// tell connection and authentication failures apart, like IMAP
+    /// ones, should check them first.
+    fn check(&mut self, folders: &[String]) -> Vec<Check> {
+        let check = |folder: &String| {
+            let result = self.envelopes(folder);
+            Check::new(Probe::Folder(folder.clone()), result)
+        };
+        folders.iter().map(check).collect()
+    }
+}
+
+/// Side winning when both sides changed the same message in ways that
+/// cannot be merged, like a flag removed on one side and kept on the
+/// other.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "snake_case"))]
+pub enum ConflictPolicy {
+    #[default]
+    PreferLeft,
+    PreferRight,
+}
+
+/// Sync between two replicas, assembled with [`Sync::builder`].
+pub struct Sync {
+    left: Box<dyn Replica>,
+    right: Box<dyn Replica>,
+    cache: Box<dyn Cache>,
+    conflict: ConflictPolicy,
+    folders: Vec<String>,
+    observers: Vec<Box<dyn SyncObserver>>,
+    backoff: Backoff,
+    attempts: u32,
+}
+
+/// Builder of a [`Sync`]. Both replicas and the cache are required,
+/// the rest defaults to syncing `INBOX` with the left side winning
+/// conflicts, without retrying failed replica calls.
+#[derive(Default)]
+pub struct SyncBuilder {
+    left: Option<Box<dyn Replica>>,
+    right: Option<Box<dyn Replica>>,
+    cache: Option<Box<dyn Cache>>,
+    conflict: ConflictPolicy,
+    folders: Vec<String>,
+    observers: Vec<Box<dyn SyncObserver>>,
+    backoff: Backoff,
+    attempts: u32,
+}
+
+impl SyncBuilder {
+    pub fn left<R: Replica + 'static>(mut self, replica: R) -> Self {
+        self.left = Some(Box::new(replica));
+        self
+    }
+
+    pub fn right<R: Replica + 'static>(mut self, replica: R) -> Self {
+        self.right = Some(Box::new(replica));
+        self
+    }
+
+    /// Keeps the sync state in a [`FileCache`] in the given directory.
+    pub fn cache<P: Into<PathBuf>>(self, dir: P) -> Self {
+        self.cache_store(FileCache::new(dir))
+    }
+
+    /// Keeps the sync state in the given cache.
+    pub fn cache_store<C: Cache + 'static>(mut self, cache: C) -> Self {
+        self.cache = Some(Box::new(cache));
+        self
+    }
+
+    /// Adds the given observer, called as the sync runs.
+    pub fn observer<O: SyncObserver + 'static>(mut self, observer: O) -> Self {
+        self.observers.push(Box::new(observer));
+        self
+    }
+
+    pub fn conflict(mut self, policy: ConflictPolicy) -> Self {
+        self.conflict = policy;
+        self
+    }
+
+    /// Retries replica calls failing with a transient error up to the
+    /// given number of attempts, waiting between them as the given
+    /// backoff says, see [`Backoff::retry`]. Adding messages is never
+    /// retried, the next sync adding the messages that failed.
+    pub fn retry(mut self, backoff: Backoff, attempts: u32) -> Self {
+        self.backoff = backoff;
+        self.attempts = attempts;
+        self
+    }
+
+    /// Adds the given folder to the folders to sync.
+    pub fn folder<F: Into<String>>(mut self, folder: F) -> Self {
+        self.folders.push(folder.into());
+        self
+    }
+
+    /// Adds the given folders to the folders to sync.
+    pub fn folders<I, F>(mut self, folders: I) -> Self
+    where
+        I: IntoIterator<Item = F>,
+        F: Into<String>,
+    {
+        self.folders.extend(folders.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn build(self) -> Result<Sync, EverestError> {
+        let missing = EverestError::BuildSyncError;
+        Ok(Sync {
+            left: self.left.ok_or(missing("left replica"))?,
+            right: self.right.ok_or(missing("right replica"))?,
+            cache: self.cache.ok_or(missing("cache"))?,
+            conflict: self.conflict,
+            observers: self.observers,
+            backoff: self.backoff,
+            attempts: self.attempts.max(1),
+            folders: match self.folders.is_empty() {
+                true => vec!["INBOX".to_owned()],
+                false => self.folders,
+            },
+        })
+    }
+}
+
+impl Sync {
+    pub fn builder() -> SyncBuilder {
+        SyncBuilder::default()
+    }
+
+    pub fn folders(&self) -> &[String] {
+        &self.folders
+    }
+
+    /// Returns the cache keeping the sync state.
+    pub fn cache(&self) -> &dyn Cache {
+        self.cache.as_ref()
+    }
+
+    /// Syncs the folders one after the other. Hunks failing to apply
+    /// and folders failing to sync do not stop the sync: they are
+    /// reported, and retried by the next sync.
+    ///
+    /// Phases of different folders never overlap, each replica holding
+    /// a single connection: see [`crate::pipeline::Scheduler`] to sync
+    /// folders over several connections.
+    pub fn run(&mut self) -> Result<SyncReport, EverestError> {
+        let mut report = SyncReport::default();
+        for folder in self.folders.clone() {
+            match self.sync_folder(&folder) {
+                Ok(folder_report) => report += folder_report,
+                Err(err) => report.skipped.push(FolderError {
+                    folder,
+                    error: Arc::new(err),
+                }),
+            }
+        }
+        self.notify(|observer| observer.sync_finished(&report));
+        Ok(report)
+    }
+
+    /// Checks that both sides and the cache are ready to sync the
+    /// folders, without modifying anything.
+    pub fn check(&mut self) -> Diagnosis {
+        let folders = self.folders.clone();
+        self.check_folders(&folders)
+    }
+
+    fn check_folders(&mut self, folders: &[String]) -> Diagnosis {
+        let snapshot = |folder: &String| {
+            let result = self.cache.load(folder);
+            Check::new(Probe::Snapshot(folder.clone()), result)
+        };
+        Diagnosis {
+            cache: folders.iter().map(snapshot).collect(),
+            left: self.left.check(folders),
+            right: self.right.check(folders),
+        }
+    }
+
+    /// Compares both sides of the folders without syncing them, and
+    /// reports how they diverge. Folders failing to be compared are
+    /// reported as skipped.
+    pub fn verify(&mut self, mode: VerifyMode) -> VerifyReport {
+        let mut report = VerifyReport::default();
+        for folder in self.folders.clone() {
+            match self.verify_folder(&folder, mode) {
+                Ok(divergences) => report.folders.push(divergences),
+                Err(err) => report.skipped.push(FolderError {
+                    folder,
+                    error: Arc::new(err),
+                }),
+            }
+        }
+        report
+    }
+
+    fn verify_folder(
+        &mut self,
+        folder: &str,
+        mode: VerifyMode,
+    ) -> Result<FolderDivergences, EverestError> {
+        let left = self.left.envelopes(folder)?;
+        let right = self.right.envelopes(folder)?;
+        let mut divergences = verify::compare(&left, &right);
+        let shared = left.keys().filter(|id| right.contains_key(*id));
+        let shared = shared.cloned().collect::<Vec<_>>();
+        if mode == VerifyMode::Contents {
+            for id in &shared {
+                let hash = content_hash(&self.left.read_msg(folder, id)?);
+                if hash != content_hash(&self.right.read_msg(folder, id)?) {
+                    divergences.push(Divergence::Content(id.clone()));
+                }
+            }
+            divergences.sort_by(|a, b| a.id().cmp(b.id()));
+        }
+        Ok(FolderDivergences {
+            folder: folder.to_owned(),
+            compared: shared.len(),
+            divergences,
+        })
+    }
+
+    /// Starts the sync of the given folder, to run it phase by phase.
+    pub fn session<F: Into<String>>(&mut self, folder: F) -> Session<'_> {
+        let folder = folder.into();
+        self.notify(|observer| observer.folder_started(&folder));
+        Session {
+            #[cfg(feature = "tracing")]
+            span: tracing::info_span!("sync_folder", folder = %folder),
+            sync: self,
+            folder,
+            left: None,
+            right: None,
+            patch: None,
+            next: None,
+            applied: false,
+            applied_hunks: Patch::new(),
+            report: SyncReport::default(),
+        }
+    }
+
+    fn sync_folder(&mut self, folder: &str) -> Result<SyncReport, EverestError> {
+        let result = self.session(folder).commit();
+        if let Err(err) = &result {
+            self.notify(|observer| observer.error(folder, err));
+        }
+        result
+    }
+
+    fn notify<F: Fn(&dyn SyncObserver)>(&self, f: F) {
+        for observer in &self.observers {
+            f(observer.as_ref())
+        }
+    }
+
+    /// Applies the given hunk to its side, messages being copied from
+    /// the other side as listed in the given snapshot. Returns the
+    /// size of the copied message, 0 for other hunks.
+    fn apply(&mut self, folder: &str, hunk: &Hunk, next: &Snapshot) -> Result<u64, EverestError> {
+        let (backoff, attempts) = (self.backoff, self.attempts);
+        let (target, source, source_envelopes) = match hunk.target {
+            Side::Left => (&mut self.left, &mut self.right, &next.mdir),
+            Side::Right => (&mut self.right, &mut self.left, &next.imap),
+        };
+        // each call is retried on its own, except adding messages: a
+        // replica may fail once the message is added, like when the
+        // connection drops after an IMAP APPEND, and adding it again
+        // would leave a duplicate the replica does not know about
+        match &hunk.kind {
+            HunkKind::AddMsg(id) => {
+                let raw = backoff.retry(attempts, || source.read_msg(folder, &id.source))?;
+                let flags = source_envelopes
+                    .get(&id.source)
+                    .map(|envelope| envelope.flags.clone())
+                    .unwrap_or_default();
+                target.add_msg(folder, id.target_or_source(), &raw, &flags)?;
+                return Ok(raw.len() as u64);
+            }
+            HunkKind::RemoveMsg(id) => {
+                let id = id.target_or_source();
+                backoff.retry(attempts, || target.remove_msg(folder, id))?
+            }
+            HunkKind::AddFlag(id, flag) => {
+                let id = id.target_or_source();
+                backoff.retry(attempts, || target.add_flag(folder, id, flag))?
+            }
+            HunkKind::RemoveFlag(id, flag) => {
+                let id = id.target_or_source();
+                backoff.retry(attempts, || target.remove_flag(folder, id, flag))?
+            }
+        }
+        Ok(0)
+    }
+
+    /// Applies the given flag changes of the given side at once, see
+    /// [`Replica::update_flags`]. Flag changes being idempotent, the
+    /// ones failing with transient errors are retried as configured
+    /// with [`SyncBuilder::retry`]. Returns the errors of the changes
+    /// that failed by their index.
+    fn update_flags(
+        &mut self,
+        folder: &str,
+        side: Side,
+        changes: &[(&str, FlagChange)],
+    ) -> HashMap<usize, Arc<EverestError>> {
+        let target = match side {
+            Side::Left => &mut self.left,
+            Side::Right => &mut self.right,
+        };
+        let mut errors = HashMap::new();
+        let mut pending = (0..changes.len()).collect::<Vec<_>>();
+        let mut attempt = 1;
+        while !pending.is_empty() {
+            let batch = pending.iter().map(|&index| changes[index]);
+            let batch = batch.collect::<Vec<_>>();
+            let mut retried = vec![];
+            let mut delay = None;
+            for (index, err) in target.update_flags(folder, &batch) {
+                let index = pending[index];
+                match err.retry_after() {
+                    Some(retry_after) if attempt < self.attempts => {
+                        delay = delay.max(Some(retry_after));
+                        retried.push(index);
+                    }
+                    _ => {
+                        errors.insert(index, err);
+                    }
+                }
+            }
+            if let Some(delay) = delay {
+                thread::sleep(delay.max(self.backoff.delay(attempt)));
+            }
+            pending = retried;
+            attempt += 1;
+        }
+        errors
+    }
+}
+
+/// Sync of a folder run phase by phase, for embedders to interleave
+/// their own logic, like showing the patch before applying it.
+///
+/// Phases run in order: [`Session::fetch_left`],
+/// [`Session::fetch_right`], [`Session::diff`], [`Session::apply`]
+/// then [`Session::commit`]. Each phase runs the previous ones not
+/// run yet, and fetching a side again before applying starts the diff
+/// over. Nothing is saved to the cache until the session is committed.
+pub struct Session<'a> {
+    /// Span of the folder, parent of the spans of the phases.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+    sync: &'a mut Sync,
+    folder: String,
+    left: Option<Envelopes>,
+    right: Option<Envelopes>,
+    patch: Option<Patch>,
+    /// Snapshot of the folder once the patch is applied, with the
+    /// previous one it was built from.
+    next: Option<(Snapshot, Snapshot)>,
+    applied: bool,
+    /// Hunks of the patch that applied, the ones recorded in the
+    /// journal.
+    applied_hunks: Patch,
+    report: SyncReport,
+}
+
+impl Session<'_> {
+    pub fn folder(&self) -> &str {
+        &self.folder
+    }
+
+    /// Checks that both sides and the cache are ready to sync the
+    /// folder, see [`Sync::check`].
+    pub fn check(&mut self) -> Diagnosis {
+        self.sync.check_folders(std::slice::from_ref(&self.folder))
+    }
+
+    /// Lists the envelopes of the left side, retried as configured
+    /// with [`SyncBuilder::retry`].
+    pub fn fetch_left(&mut self) -> Result<&Envelopes, EverestError> {
+        self.reset()?;
+        let _span = span!(DEBUG, parent: &self.span, "fetch", side = %Side::Left).entered();
+        let (sync, folder) = (&mut *self.sync, &self.folder);
+        let (backoff, attempts) = (sync.backoff, sync.attempts);
+        let timings = &mut self.report.timings;
+        let left = timings.time(Phase::ListImap, || {
+            backoff.retry(attempts, || sync.left.envelopes(folder))
+        })?;
+        let left = self.left.insert(left);
+        event!(DEBUG, envelopes = left.len(), "envelopes fetched");
+        let folder = &self.folder;
+        self.sync
+            .notify(|observer| observer.envelopes_listed(folder, Side::Left, left));
+        Ok(left)
+    }
+
+    /// Lists the envelopes of the right side, retried as configured
+    /// with [`SyncBuilder::retry`].
+    pub fn fetch_right(&mut self) -> Result<&Envelopes, EverestError> {
+        self.reset()?;
+        let _span = span!(DEBUG, parent: &self.span, "fetch", side = %Side::Right).entered();
+        let (sync, folder) = (&mut *self.sync, &self.folder);
+        let (backoff, attempts) = (sync.backoff, sync.attempts);
+        let timings = &mut self.report.timings;
+        let right = timings.time(Phase::ListMaildir, || {
+            backoff.retry(attempts, || sync.right.envelopes(folder))
+        })?;
+        let right = self.right.insert(right);
+        event!(DEBUG, envelopes = right.len(), "envelopes fetched");
+        let folder = &self.folder;
+        self.sync
+            .notify(|observer| observer.envelopes_listed(folder, Side::Right, right));
+        Ok(right)
+    }
+
+    /// Builds the patch between the cached snapshot of the folder and
+    /// the envelopes of both sides.
+    pub fn diff(&mut self) -> Result<&Patch, EverestError> {
+        self.diff_ids(None)
+    }
+
+    /// Builds the patch like [`Session::diff`], looking only at the
+    /// given ids, see [`build_changed_patch`]. For callers knowing
+    /// which messages changed since the last sync, like from the
+    /// `MODSEQ`s of an IMAP folder and a watched maildir, once mapped
+    /// to the ids of the sync. Changes of other messages are not
+    /// recorded in the snapshot, and are left for the next full diff.
+    /// Diffing again replaces the previous patch.
+    pub fn diff_changed<'i, I>(&mut self, changed: I) -> Result<&Patch, EverestError>
+    where
+        I: IntoIterator<Item = &'i str>,
+    {
+        self.reset()?;
+        let changed = changed.into_iter().map(Id::from).collect::<Vec<_>>();
+        self.diff_ids(Some(&changed))
+    }
+
+    fn diff_ids(&mut self, changed: Option<&[Id]>) -> Result<&Patch, EverestError> {
+        if self.patch.is_none() {
+            if self.left.is_none() {
+                self.fetch_left()?;
+            }
+            if self.right.is_none() {
+                self.fetch_right()?;
+            }
+            let _span = span!(DEBUG, parent: &self.span, "diff").entered();
+            let prev = self.sync.cache.load(&self.folder)?;
+            let (left, right) = (self.left.as_ref(), self.right.as_ref());
+            let (left, right) = (left.unwrap(), right.unwrap());
+            let conflict = self.sync.conflict;
+            let timings = &mut self.report.timings;
+            let patch = timings.time(Phase::Diff, || diff(&prev, left, right, changed, conflict));
+            event!(DEBUG, hunks = patch.len(), "patch built");
+            let next = match changed {
+                None => Snapshot {
+                    imap: left.clone(),
+                    mdir: right.clone(),
+                    ..prev.clone()
+                },
+                Some(ids) => Snapshot {
+                    imap: follow_ids(&prev.imap, left, ids),
+                    mdir: follow_ids(&prev.mdir, right, ids),
+                    ..prev.clone()
+                },
+            };
+            for hunk in &patch {
+                let folder = &self.folder;
+                self.sync
+                    .notify(|observer| observer.hunk_generated(folder, hunk));
+            }
+            self.patch = Some(patch);
+            self.next = Some((prev, next));
+        }
+        Ok(self.patch.as_ref().unwrap())
+    }
+
+    /// Returns the patch of the folder, once diffed.
+    pub fn patch(&self) -> Option<&Patch> {
+        self.patch.as_ref()
+    }
+
+    /// Keeps only the hunks of the patch of the folder found in the
+    /// given one, like a patch exported and reviewed before the
+    /// session, to apply what was reviewed only. Hunks left out are
+    /// not applied and come back with the next sync, and hunks of the
+    /// given patch no longer in the patch of the folder, like changes
+    /// undone since, are ignored.
+    pub fn retain(&mut self, reviewed: &[Hunk]) -> Result<&Patch, EverestError> {
+        self.diff()?;
+        if self.applied {
+            return Err(EverestError::SessionAppliedError(self.folder.clone()));
+        }
+        let patch = self.patch.as_mut().unwrap();
+        let (prev, next) = self.next.as_mut().unwrap();
+        patch.retain(|hunk| {
+            let keep = reviewed.contains(hunk);
+            if !keep {
+                forget(next, prev, hunk);
+            }
+            keep
+        });
+        Ok(patch)
+    }
+
+    /// Explains the hunks of the patch of the folder, diffing it if
+    /// needed, to see why they were built.
+    pub fn explain(&mut self) -> Result<Vec<Explanation>, EverestError> {
+        self.diff()?;
+        let (prev, _) = self.next.as_ref().unwrap();
+        let (left, right) = (self.left.as_ref().unwrap(), self.right.as_ref().unwrap());
+        let patch = self.patch.as_ref().unwrap();
+        Ok(explain_patch(patch, &prev.imap, left, &prev.mdir, right))
+    }
+
+    /// Applies the patch to both sides, and returns the hunks that
+    /// failed to apply. Failed hunks do not stop the others, and are
+    /// retried by the next sync. Applying again does nothing.
+    ///
+    /// The flag hunks of each side are applied at once, when the first
+    /// of them comes, see [`Replica::update_flags`].
+    pub fn apply(&mut self) -> Result<&[HunkError], EverestError> {
+        self.diff()?;
+        if !self.applied {
+            let _span = span!(DEBUG, parent: &self.span, "apply").entered();
+            let patch = self.patch.as_ref().unwrap();
+            let (prev, next) = self.next.as_mut().unwrap();
+            let start = Instant::now();
+            let mut progress = Progress {
+                total_hunks: patch.len(),
+                total_bytes: total_bytes(patch, next),
+                ..Progress::default()
+            };
+            let mut flag_errors = (None, None);
+            for (index, hunk) in patch.iter().enumerate() {
+                let result = match &hunk.kind {
+                    HunkKind::AddFlag(..) | HunkKind::RemoveFlag(..) => {
+                        let errors = match hunk.target {
+                            Side::Left => &mut flag_errors.0,
+                            Side::Right => &mut flag_errors.1,
+                        };
+                        let errors = errors.get_or_insert_with(|| {
+                            let (indexes, changes) = flag_changes(patch, hunk.target);
+                            let errors =
+                                self.sync.update_flags(&self.folder, hunk.target, &changes);
+                            let errors = errors.into_iter();
+                            errors
+                                .map(|(index, err)| (indexes[index], err))
+                                .collect::<HashMap<_, _>>()
+                        });
+                        match errors.remove(&index) {
+                            Some(err) => Err(err),
+                            None => Ok(0),
+                        }
+                    }
+                    _ => self.sync.apply(&self.folder, hunk, next).map_err(Arc::new),
+                };
+                match result {
+                    Ok(bytes) => {
+                        self.report.side_mut(hunk.target).record(&hunk.kind, bytes);
+                        progress.bytes += bytes;
+                        follow(next, hunk);
+                        self.applied_hunks.push(hunk.clone());
+                        let folder = &self.folder;
+                        self.sync
+                            .notify(|observer| observer.hunk_applied(folder, hunk));
+                    }
+                    Err(err) => {
+                        event!(WARN, hunk = %hunk, error = %err, "hunk failed");
+                        let err = HunkError {
+                            folder: self.folder.clone(),
+                            side: hunk.target,
+                            hunk: hunk.clone(),
+                            error: err,
+                        };
+                        self.sync
+                            .notify(|observer| observer.error(&err.folder, &err));
+                        self.report.errors.push(err);
+                        forget(next, prev, hunk);
+                    }
+                }
+                progress.hunks += 1;
+                progress.elapsed = start.elapsed();
+                let folder = &self.folder;
+                self.sync
+                    .notify(|observer| observer.progress(folder, &progress));
+            }
+            self.report.timings.add(Phase::Apply, start.elapsed());
+            self.applied = true;
+            event!(
+                INFO,
+                hunks = patch.len(),
+                failed = self.report.errors.len(),
+                "patch applied"
+            );
+        }
+        Ok(&self.report.errors)
+    }
+
+    /// Records the hunks that applied in the journal of the folder and
+    /// saves its new snapshot, ending the session. Failed hunks never
+    /// changed anything, so that undoing the sync leaves them out.
+    pub fn commit(mut self) -> Result<SyncReport, EverestError> {
+        self.apply()?;
+        let _span = span!(DEBUG, parent: &self.span, "commit").entered();
+        let hunks = self.patch.take().unwrap().len();
+        let (_, mut next) = self.next.take().unwrap();
+        let mut report = self.report;
+        report.hunks = hunks;
+
+        let errors = report.errors.iter();
+        let errors = errors.map(|err| format!("{}: {}", err, err.error));
+        next.record(self.applied_hunks, errors.collect(), SystemTime::now());
+        // the state of the replicas is saved before the snapshot, the
+        // snapshot referring to it
+        let (sync, folder) = (&mut *self.sync, &self.folder);
+        sync.left.flush(folder)?;
+        sync.right.flush(folder)?;
+        let cache = &sync.cache;
+        let saved = report
+            .timings
+            .time(Phase::SaveCache, || cache.save(folder, &next));
+        saved?;
+        report
+            .folders
+            .push(FolderStats::new(folder.as_str(), &report));
+        self.sync
+            .notify(|observer| observer.folder_finished(folder, &report));
+        Ok(report)
+    }
+
+    /// Drops the patch before fetching a side again. Once applied,
+    /// the patch needs to be committed first.
+    fn reset(&mut self) -> Result<(), EverestError> {
+        if self.applied {
+            return Err(EverestError::SessionAppliedError(self.folder.clone()));
+        }
+        self.patch = None;
+        self.next = None;
+        Ok(())
+    }
+}
+
+/// Returns the flag changes of the hunks of the given patch targeting
+/// the given side, with the indexes of their hunks in the patch.
+fn flag_changes(patch: &Patch, side: Side) -> (Vec<usize>, Vec<(&str, FlagChange<'_>)>) {
+    let hunks = patch.iter().enumerate();
+    let hunks = hunks.filter(|(_, hunk)| hunk.target == side);
+    hunks
+        .filter_map(|(index, hunk)| match &hunk.kind {
+            HunkKind::AddFlag(id, flag) => {
+                Some((index, (&**id.target_or_source(), FlagChange::Add(flag))))
+            }
+            HunkKind::RemoveFlag(id, flag) => {
+                Some((index, (&**id.target_or_source(), FlagChange::Remove(flag))))
+            }
+            _ => None,
+        })
+        .unzip()
+}
+
+/// Returns the size of the messages the given patch copies, when the
+/// envelopes of the given snapshot tell the size of all of them.
+fn total_bytes(patch: &Patch, next: &Snapshot) -> Option<u64> {
+    let mut total = 0;
+    for hunk in patch {
+        if let HunkKind::AddMsg(id) = &hunk.kind {
+            let source = match hunk.target {
+                Side::Left => &next.mdir,
+                Side::Right => &next.imap,
+            };
+            total += source.get(&id.source)?.size()?;
+        }
+    }
+    Some(total)
+}
+
+/// Builds the patch of a folder, of the given ids only if any. The
+/// diff lets the IMAP side win conflicts, so the right side wins by
+/// diffing the sides swapped.
+fn diff(
+    prev: &Snapshot,
+    left: &Envelopes,
+    right: &Envelopes,
+    changed: Option<&[Id]>,
+    conflict: ConflictPolicy,
+) -> Patch {
+    let build = |prev_a, a, prev_b, b| match changed {
+        None => build_patch(prev_a, a, prev_b, b),
+        Some(ids) => build_changed_patch(ids.iter().map(|id| &**id), prev_a, a, prev_b, b),
+    };
+    match conflict {
+        ConflictPolicy::PreferLeft => build(&prev.imap, left, &prev.mdir, right),
+        ConflictPolicy::PreferRight => build(&prev.mdir, right, &prev.imap, left)
+            .into_iter()
+            .map(|hunk| Hunk::new(hunk.target.opposite(), hunk.kind))
+            .collect(),
+    }
+}
+
+/// Returns the given previous envelopes of a side with the envelopes
+/// of the given ids taken from its next ones, the other ids keeping
+/// their previous state.
+fn follow_ids(prev: &Envelopes, next: &Envelopes, ids: &[Id]) -> Envelopes {
+    let mut envelopes = prev.clone();
+    for id in ids {
+        match next.get(&**id) {
+            Some(envelope) => envelopes.insert(id.clone(), envelope.clone()),
+            None => envelopes.remove(&**id),
+        };
+    }
+    envelopes
+}
+
+/// Makes the given snapshot follow an applied hunk. Added messages
+/// get the flags they have on the other side.
+fn follow(next: &mut Snapshot, hunk: &Hunk) {
+    let HunkKind::AddMsg(id) = &hunk.kind else {
+        return next.apply_hunk(hunk);
+    };
+    let (envelopes, source_envelopes) = match hunk.target {
+        Side::Left => (&mut next.imap, &next.mdir),
+        Side::Right => (&mut next.mdir, &next.imap),
+    };
+    if let Some(mut envelope) = source_envelopes.get(&id.source).cloned() {
+        envelope.id = id.target_or_source().clone();
+        envelopes.insert(envelope.id.clone(), envelope);
+    }
+}
+
+/// Makes the given snapshot forget the change of the other side that
+/// led to a failed hunk, so that the next sync retries it. The change
+/// is the one of the source of the hunk, known by its source id.
+fn forget(next: &mut Snapshot, prev: &Snapshot, hunk: &Hunk) {
+    let (envelopes, prev_envelopes) = match hunk.target {
+        Side::Left => (&mut next.mdir, &prev.mdir),
+        Side::Right => (&mut next.imap, &prev.imap),
+    };
+    let id = &hunk.kind.id().source;
+    match prev_envelopes.get(id) {
+        Some(envelope) => envelopes.insert(id.clone(), envelope.clone()),
+        None => envelopes.remove(id),
+    };
+}
+
+/// Replica keeping its messages in memory, failing on the given
+/// ids and folders, and losing its connection on every other call if
+/// flaky, or right after adding messages, counted in `adds`.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct MemoryReplica {
+    msgs: std::collections::HashMap<String, (Vec<u8>, Flags)>,
+    failing: Vec<String>,
+    flaky: bool,
+    lost: bool,
+    adds: Option<Arc<std::sync::atomic::AtomicUsize>>,
+}
+
+#[cfg(test)]
+impl MemoryReplica {
+    pub(crate) fn new(msgs: &[(&str, &[Flag])]) -> Self {
+        let msgs = msgs.iter().map(|(id, flags)| {
+            let msg = (id.as_bytes().to_vec(), flags.iter().cloned().collect());
+            (id.to_string(), msg)
+        });
+        Self {
+            msgs: msgs.collect(),
+            failing: vec![],
+            flaky: false,
+            lost: false,
+            adds: None,
+        }
+    }
+
+    pub(crate) fn with_failing(mut self, id: &str) -> Self {
+        self.failing.push(id.to_owned());
+        self
+    }
+
+    pub(crate) fn with_flaky(mut self) -> Self {
+        self.flaky = true;
+        self
+    }
+
+    pub(crate) fn with_lost_adds(mut self, adds: Arc<std::sync::atomic::AtomicUsize>) -> Self {
+        self.adds = Some(adds);
+        self
+    }
+
+    fn lost(id: &str) -> EverestError {
+        let source = std::io::Error::from(std::io::ErrorKind::ConnectionReset);
+        let backend = crate::plan::Backend::Imap;
+        let err = crate::BackendError::new(crate::ErrorKind::Io, backend, "connect");
+        err.with_id(id).with_source(source).into()
+    }
+
+    fn fail_on(&mut self, id: &str) -> Result<(), EverestError> {
+        self.lost = self.flaky && !self.lost;
+        if self.lost {
+            return Err(Self::lost(id));
+        }
+        match self.failing.iter().any(|failing| failing == id) {
+            true => Err(EverestError::FindMaildirMsgError(id.to_owned())),
+            false => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+impl Replica for MemoryReplica {
+    fn envelopes(&mut self, folder: &str) -> Result<Envelopes, EverestError> {
+        self.fail_on(folder)?;
+        let envelopes = self.msgs.iter().map(|(id, (_, flags))| {
+            crate::Envelope::new(id.as_str()).with_flags(flags.iter().cloned())
+        });
+        Ok(envelopes.collect())
+    }
+
+    fn read_msg(&mut self, _folder: &str, id: &str) -> Result<Vec<u8>, EverestError> {
+        self.fail_on(id)?;
+        Ok(self.msgs[id].0.clone())
+    }
+
+    fn add_msg(
+        &mut self,
+        _folder: &str,
+        id: &str,
+        raw: &[u8],
+        flags: &Flags,
+    ) -> Result<(), EverestError> {
+        self.fail_on(id)?;
+        self.msgs
+            .insert(id.to_owned(), (raw.to_vec(), flags.clone()));
+        match &self.adds {
+            Some(adds) => {
+                adds.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                Err(Self::lost(id))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn remove_msg(&mut self, _folder: &str, id: &str) -> Result<(), EverestError> {
+        self.fail_on(id)?;
+        self.msgs.remove(id);
+        Ok(())
+    }
+
+    fn add_flag(&mut self, _folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.fail_on(id)?;
+        self.msgs.get_mut(id).unwrap().1.insert(flag.clone());
+        Ok(())
+    }
+
+    fn remove_flag(&mut self, _folder: &str, id: &str, flag: &Flag) -> Result<(), EverestError> {
+        self.fail_on(id)?;
+        self.msgs.get_mut(id).unwrap().1.remove(flag);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use crate::{
+        cache::{envelopes, MemoryCache},
+        observer::{self, SyncEvent},
+        HunkId,
+    };
+
+    use super::*;
+
+    #[test]
+    fn build_test() {
+        let err = Sync::builder().left(MemoryReplica::default()).build();
+        assert!(matches!(
+            err,
+            Err(EverestError::BuildSyncError("right replica"))
+        ));
+
+        let sync = Sync::builder()
+            .left(MemoryReplica::default())
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .build()
+            .unwrap();
+        assert_eq!(["INBOX"], sync.folders());
+    }
+
+    #[test]
+    fn run_test() {
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[Flag::Seen]), ("2", &[])]))
+            .right(
+                MemoryReplica::new(&[("3", &[])])
+                    .with_failing("2")
+                    .with_failing("Trash"),
+            )
+            .cache_store(MemoryCache::new())
+            .folder("INBOX")
+            .folder("Trash")
+            .build()
+            .unwrap();
+
+        let report = sync.run().unwrap();
+        assert_eq!(3, report.hunks);
+        assert_eq!(1, report.folders.len());
+        assert_eq!("INBOX", report.folders[0].folder);
+        assert_eq!(1, report.folders[0].failed);
+        assert_eq!(1, report.left.added);
+        assert_eq!(1, report.right.added);
+        assert_eq!(2, report.bytes());
+        assert_eq!(1, report.skipped.len());
+        assert_eq!("cannot sync folder Trash", report.skipped[0].to_string());
+        assert_eq!(1, report.errors.len());
+        let err = &report.errors[0];
+        assert_eq!(Side::Right, err.side);
+        assert_eq!(
+            "cannot apply hunk `right: + msg 2` to right side of folder INBOX",
+            err.to_string()
+        );
+
+        let snapshot = sync.cache().load("INBOX").unwrap();
+        // the message failing to be added is forgotten, so that it is
+        // seen as new again by the next sync
+        let expected = envelopes(&[("1", &[Flag::Seen]), ("3", &[])]);
+        assert_eq!(expected, snapshot.imap);
+        assert_eq!(expected, snapshot.mdir);
+        assert_eq!(1, snapshot.journal.len());
+        assert_eq!(
+            vec![format!("{}: cannot find maildir message 2", err)],
+            snapshot.journal[0].errors
+        );
+
+        // the failed message is retried
+        let report = sync.run().unwrap();
+        assert_eq!(1, report.hunks);
+        assert_eq!(1, report.errors.len());
+    }
+
+    #[test]
+    fn retry_test() {
+        let backoff = Backoff {
+            initial: Duration::from_millis(1),
+            max: Duration::from_millis(1),
+        };
+        let build = |attempts| {
+            Sync::builder()
+                .left(MemoryReplica::new(&[("1", &[])]).with_flaky())
+                .right(MemoryReplica::default())
+                .cache_store(MemoryCache::new())
+                .retry(backoff, attempts)
+                .build()
+                .unwrap()
+        };
+
+        // the first call fails, without retries the folder is skipped
+        let report = build(1).run().unwrap();
+        assert_eq!(1, report.skipped.len());
+        assert!(report.skipped[0].error.is_transient());
+
+        // lost connections are retried, fetching and applying alike
+        let report = build(2).run().unwrap();
+        assert!(report.skipped.is_empty() && report.errors.is_empty());
+        assert_eq!(1, report.right.added);
+
+        // flag changes are applied at once, and retried alike
+        let cache = MemoryCache::new();
+        let prev = envelopes(&[("1", &[]), ("2", &[])]);
+        cache
+            .save("INBOX", &Snapshot::new(prev.clone(), prev))
+            .unwrap();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[Flag::Seen]), ("2", &[])]))
+            .right(MemoryReplica::new(&[("1", &[]), ("2", &[Flag::Flagged])]).with_flaky())
+            .cache_store(cache)
+            .retry(backoff, 2)
+            .build()
+            .unwrap();
+        let report = sync.run().unwrap();
+        assert!(report.skipped.is_empty() && report.errors.is_empty());
+        assert_eq!(1, report.right.flags_changed);
+        assert_eq!(1, report.left.flags_changed);
+
+        // messages added before the connection drops are not added
+        // again
+        let adds = Arc::new(AtomicUsize::new(0));
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default().with_lost_adds(adds.clone()))
+            .cache_store(MemoryCache::new())
+            .retry(backoff, 3)
+            .build()
+            .unwrap();
+        let report = sync.run().unwrap();
+        assert_eq!(1, report.errors.len());
+        assert!(report.errors[0].error.is_transient());
+        assert_eq!(1, adds.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn forget_test() {
+        let prev = Snapshot::new(envelopes(&[("1", &[])]), envelopes(&[("a", &[])]));
+        let mut next = Snapshot::new(
+            envelopes(&[("1", &[])]),
+            envelopes(&[("a", &[Flag::Seen]), ("b", &[])]),
+        );
+        let ids = |source: &str, target: Option<&str>| HunkId {
+            source: source.into(),
+            target: target.map(Into::into),
+        };
+
+        // the seen flag of maildir message `a`, known as `1` on the
+        // left side, failed to be added
+        let kind = HunkKind::AddFlag(ids("a", Some("1")), Flag::Seen);
+        forget(&mut next, &prev, &Hunk::new(Side::Left, kind));
+        assert_eq!(envelopes(&[("a", &[]), ("b", &[])]), next.mdir);
+        assert_eq!(prev.imap, next.imap);
+
+        // so did the new maildir message `b`
+        let kind = HunkKind::AddMsg(ids("b", None));
+        forget(&mut next, &prev, &Hunk::new(Side::Left, kind));
+        assert_eq!(prev.mdir, next.mdir);
+    }
+
+    #[test]
+    fn undo_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[]), ("2", &[])]))
+            .right(MemoryReplica::default().with_failing("2"))
+            .cache(dir.path())
+            .build()
+            .unwrap();
+        assert_eq!(1, sync.run().unwrap().errors.len());
+        drop(sync);
+
+        // only the hunk that applied is undone, the failed one never
+        // changed anything
+        let cache = FileCache::new(dir.path());
+        let mut undone = vec![];
+        let entry = cache.undo_last_sync("INBOX", |hunk| {
+            undone.push(hunk.clone());
+            Ok(())
+        });
+        let expected = vec![Hunk::new(Side::Right, HunkKind::AddMsg("1".into()))];
+        assert_eq!(expected, entry.unwrap().unwrap().patch);
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::RemoveMsg("1".into()))],
+            undone
+        );
+        assert!(cache.load("INBOX").unwrap().journal.is_empty());
+    }
+
+    #[test]
+    fn session_test() {
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .build()
+            .unwrap();
+
+        let mut session = sync.session("INBOX");
+        assert_eq!(1, session.fetch_left().unwrap().len());
+        assert!(session.patch().is_none());
+        let patch = session.diff().unwrap().clone();
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::AddMsg("1".into()))],
+            patch
+        );
+        // nothing is applied until asked
+        assert!(session.fetch_right().unwrap().is_empty());
+        assert!(session.patch().is_none());
+
+        assert!(session.apply().unwrap().is_empty());
+        // explanations still tell the envelopes the patch was built
+        // from once applied
+        let explanations = session.explain().unwrap();
+        assert_eq!(1, explanations.len());
+        assert_eq!((false, true), explanations[0].msg.side(Side::Left));
+        assert_eq!((false, false), explanations[0].msg.side(Side::Right));
+        assert!(matches!(
+            session.fetch_left(),
+            Err(EverestError::SessionAppliedError(_))
+        ));
+        // the snapshot is only saved once committed
+        drop(session);
+        assert!(sync.cache().load("INBOX").unwrap().imap.is_empty());
+        let report = sync.session("INBOX").commit().unwrap();
+        assert_eq!(0, report.hunks);
+        assert_eq!(1, sync.cache().load("INBOX").unwrap().imap.len());
+    }
+
+    #[test]
+    fn retain_test() {
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[]), ("2", &[])]))
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .build()
+            .unwrap();
+
+        // the patch is reviewed before the session, keeping one hunk
+        // and one no longer relevant
+        let reviewed = vec![
+            Hunk::new(Side::Right, HunkKind::AddMsg("1".into())),
+            Hunk::new(Side::Right, HunkKind::AddMsg("3".into())),
+        ];
+        let mut session = sync.session("INBOX");
+        assert_eq!(&reviewed[..1], &session.retain(&reviewed).unwrap()[..]);
+        let report = session.commit().unwrap();
+        assert_eq!(1, report.right.added);
+
+        // hunks left out come back
+        let mut session = sync.session("INBOX");
+        let patch = session.diff().unwrap();
+        assert_eq!(
+            vec![Hunk::new(Side::Right, HunkKind::AddMsg("2".into()))],
+            *patch
+        );
+    }
+
+    #[test]
+    fn diff_changed_test() {
+        let cache = MemoryCache::new();
+        let prev = envelopes(&[("1", &[]), ("2", &[])]);
+        cache
+            .save("INBOX", &Snapshot::new(prev.clone(), prev))
+            .unwrap();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[
+                ("1", &[Flag::Seen]),
+                ("2", &[Flag::Flagged]),
+            ]))
+            .right(MemoryReplica::new(&[("1", &[]), ("2", &[])]))
+            .cache_store(cache)
+            .build()
+            .unwrap();
+
+        // only the given ids are diffed
+        let mut session = sync.session("INBOX");
+        let patch = session.diff_changed(["1", "3"]).unwrap();
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag("1".into(), Flag::Seen)
+            )],
+            *patch
+        );
+        assert_eq!(1, session.commit().unwrap().right.flags_changed);
+
+        // changes of other ids are left for the next full diff
+        let mut session = sync.session("INBOX");
+        let patch = session.diff().unwrap();
+        assert_eq!(
+            vec![Hunk::new(
+                Side::Right,
+                HunkKind::AddFlag("2".into(), Flag::Flagged)
+            )],
+            *patch
+        );
+    }
+
+    #[test]
+    fn verify_test() {
+        let mut right = MemoryReplica::new(&[("1", &[Flag::Seen]), ("3", &[])]);
+        right
+            .add_msg("INBOX", "2", b"other", &Flags::default())
+            .unwrap();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[]), ("2", &[])]))
+            .right(right.with_failing("Trash"))
+            .cache_store(MemoryCache::new())
+            .folders(["INBOX", "Trash"])
+            .build()
+            .unwrap();
+
+        let report = sync.verify(VerifyMode::Envelopes);
+        assert!(!report.is_consistent());
+        assert_eq!(
+            "INBOX: 2 msgs compared, 2 divergences\n  \
+             flags of msg 1 differ: left has none, right has \\Seen\n  \
+             msg 3 missing from left\n\
+             Trash: skipped: cannot find maildir message Trash",
+            report.to_string()
+        );
+
+        let report = sync.verify(VerifyMode::Contents);
+        let divergences = report.divergences().map(|(_, d)| d.clone());
+        assert_eq!(
+            vec![
+                Divergence::Flags {
+                    id: "1".into(),
+                    left: Flags::default(),
+                    right: Flags::from_iter([Flag::Seen]),
+                },
+                Divergence::Content("2".into()),
+                Divergence::Missing("3".into(), Side::Left),
+            ],
+            divergences.collect::<Vec<_>>()
+        );
+        // nothing is synced
+        assert!(sync.cache().load("INBOX").unwrap().imap.is_empty());
+    }
+
+    #[test]
+    fn check_test() {
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default().with_failing("Trash"))
+            .cache_store(MemoryCache::new())
+            .folders(["INBOX", "Trash"])
+            .build()
+            .unwrap();
+
+        let diagnosis = sync.check();
+        assert!(!diagnosis.is_healthy());
+        let failures = diagnosis
+            .failures()
+            .map(|(section, check)| (section, &check.probe));
+        assert_eq!(
+            vec![("right", &Probe::Folder("Trash".into()))],
+            failures.collect::<Vec<_>>()
+        );
+        assert_eq!(
+            "left:\n  \
+             ok       folder INBOX\n  \
+             ok       folder Trash\n\
+             right:\n  \
+             ok       folder INBOX\n  \
+             failed   folder Trash: cannot find maildir message Trash\n\
+             cache:\n  \
+             ok       snapshot of INBOX\n  \
+             ok       snapshot of Trash",
+            diagnosis.to_string()
+        );
+
+        // checking modifies nothing
+        assert!(sync.cache().load("INBOX").unwrap().imap.is_empty());
+        let diagnosis = sync.session("INBOX").check();
+        assert!(diagnosis.is_healthy());
+        assert_eq!(1, diagnosis.side(Side::Left).len());
+    }
+
+    /// Observer recording the hooks called.
+    #[derive(Default, Clone)]
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl SyncObserver for Recorder {
+        fn folder_started(&self, folder: &str) {
+            self.0.borrow_mut().push(format!("started {}", folder));
+        }
+
+        fn envelopes_listed(&self, _folder: &str, side: Side, envelopes: &Envelopes) {
+            let listed = format!("listed {} {}", envelopes.len(), side);
+            self.0.borrow_mut().push(listed);
+        }
+
+        fn hunk_generated(&self, _folder: &str, hunk: &Hunk) {
+            self.0.borrow_mut().push(format!("generated {}", hunk));
+        }
+
+        fn hunk_applied(&self, _folder: &str, hunk: &Hunk) {
+            self.0.borrow_mut().push(format!("applied {}", hunk));
+        }
+
+        fn error(&self, _folder: &str, err: &(dyn std::error::Error + 'static)) {
+            self.0.borrow_mut().push(format!("error {}", err));
+        }
+
+        fn progress(&self, _folder: &str, progress: &Progress) {
+            let done = format!("progress {}/{}", progress.hunks, progress.total_hunks);
+            self.0.borrow_mut().push(done);
+        }
+
+        fn folder_finished(&self, folder: &str, report: &SyncReport) {
+            let finished = format!("finished {} {}", folder, report.hunks);
+            self.0.borrow_mut().push(finished);
+        }
+    }
+
+    #[test]
+    fn observer_test() {
+        let recorder = Recorder::default();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[]), ("2", &[])]))
+            .right(MemoryReplica::default().with_failing("2"))
+            .cache_store(MemoryCache::new())
+            .observer(recorder.clone())
+            .build()
+            .unwrap();
+        sync.run().unwrap();
+
+        assert_eq!(
+            vec![
+                "started INBOX",
+                "listed 2 left",
+                "listed 0 right",
+                "generated right: + msg 1",
+                "generated right: + msg 2",
+                "applied right: + msg 1",
+                "progress 1/2",
+                "error cannot apply hunk `right: + msg 2` to right side of folder INBOX",
+                "progress 2/2",
+                "finished INBOX 2",
+            ],
+            *recorder.0.borrow()
+        );
+    }
+
+    #[test]
+    fn events_test() {
+        let (events, rx) = observer::channel();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[])]))
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .observer(events)
+            .build()
+            .unwrap();
+        sync.run().unwrap();
+        drop(sync);
+
+        let events = rx.iter().collect::<Vec<_>>();
+        assert_eq!(8, events.len());
+        assert!(matches!(
+            &events[1],
+            SyncEvent::EnvelopesListed {
+                side: Side::Left,
+                count: 1,
+                ..
+            }
+        ));
+        assert!(matches!(
+            &events[5],
+            SyncEvent::Progress { progress, .. } if progress.is_done() && progress.bytes == 1
+        ));
+        assert!(
+            matches!(&events[6], SyncEvent::FolderFinished { report, .. } if report.hunks == 1)
+        );
+        assert!(
+            matches!(&events[7], SyncEvent::SyncFinished { report } if report.folders.len() == 1)
+        );
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn events_stream_test() {
+        use futures::StreamExt;
+
+        let (events, rx) = observer::stream();
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::default())
+            .right(MemoryReplica::default())
+            .cache_store(MemoryCache::new())
+            .observer(events)
+            .build()
+            .unwrap();
+        sync.run().unwrap();
+        drop(sync);
+
+        let events = futures::executor::block_on(rx.collect::<Vec<_>>());
+        assert!(matches!(&events[0], SyncEvent::FolderStarted { folder } if folder == "INBOX"));
+    }
+
+    #[test]
+    fn conflict_test() {
+        // sides disagreeing on the flag changed since the last sync
+        for (policy, flags) in [
+            (ConflictPolicy::PreferLeft, &[Flag::Seen][..]),
+            (ConflictPolicy::PreferRight, &[]),
+        ] {
+            let cache = MemoryCache::new();
+            let prev = Snapshot::new(envelopes(&[("1", &[])]), envelopes(&[("1", &[Flag::Seen])]));
+            cache.save("INBOX", &prev).unwrap();
+
+            let mut sync = Sync::builder()
+                .left(MemoryReplica::new(&[("1", &[Flag::Seen])]))
+                .right(MemoryReplica::new(&[("1", &[])]))
+                .cache_store(cache)
+                .conflict(policy)
+                .build()
+                .unwrap();
+            sync.run().unwrap();
+
+            let snapshot = sync.cache().load("INBOX").unwrap();
+            assert_eq!(envelopes(&[("1", flags)]), snapshot.imap, "{:?}", policy);
+            assert_eq!(snapshot.imap, snapshot.mdir, "{:?}", policy);
+        }
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_test() {
+        use std::{
+            fmt,
+            sync::{
+                atomic::{AtomicU64, Ordering},
+                Mutex,
+            },
+        };
+
+        use tracing::{
+            field::{Field, Visit},
+            span, Event, Metadata, Subscriber,
+        };
+
+        /// Subscriber writing spans and events as lines, like
+        /// `span fetch side=left`.
+        struct Lines {
+            next_id: AtomicU64,
+            lines: Arc<Mutex<Vec<String>>>,
+        }
+
+        struct Fields(String);
+
+        impl Visit for Fields {
+            fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+                self.0 += &format!(" {}={:?}", field.name(), value);
+            }
+        }
+
+        impl Subscriber for Lines {
+            fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+                true
+            }
+
+            fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+                let mut fields = Fields(format!("span {}", span.metadata().name()));
+                span.record(&mut fields);
+                self.lines.lock().unwrap().push(fields.0);
+                span::Id::from_u64(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+            }
+
+            fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+
+            fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = Fields(format!("{}", event.metadata().level()));
+                event.record(&mut fields);
+                self.lines.lock().unwrap().push(fields.0);
+            }
+
+            fn enter(&self, _span: &span::Id) {}
+
+            fn exit(&self, _span: &span::Id) {}
+        }
+
+        let mut sync = Sync::builder()
+            .left(MemoryReplica::new(&[("1", &[Flag::Seen]), ("2", &[])]))
+            .right(MemoryReplica::new(&[("3", &[])]).with_failing("2"))
+            .cache_store(MemoryCache::new())
+            .folder("INBOX")
+            .build()
+            .unwrap();
+        let lines = Arc::new(Mutex::new(vec![]));
+        let subscriber = Lines {
+            next_id: AtomicU64::new(0),
+            lines: lines.clone(),
+        };
+        tracing::subscriber::with_default(subscriber, || sync.run().unwrap());
+
+        assert_eq!(
+            vec![
+                "span sync_folder folder=INBOX",
+                "span fetch side=left",
+                "DEBUG message=envelopes fetched envelopes=2",
+                "span fetch side=right",
+                "DEBUG message=envelopes fetched envelopes=1",
+                "span diff",
+                "DEBUG message=patch built hunks=3",
+                "span apply",
+                "WARN message=hunk failed hunk=right: + msg 2 error=cannot find maildir message 2",
+                "INFO message=patch applied hunks=3 failed=1",
+                "span commit",
+            ],
+            *lines.lock().unwrap()
+        );
+    }
+}
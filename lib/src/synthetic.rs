@@ -0,0 +1,150 @@
+//! Synthetic mailboxes, to benchmark and test the sync engine on
+//! mailboxes of any size without a server.
+
+use crate::{Envelope, Envelopes, Flag, Flags, Id};
+
+/// Mailbox of synthetic messages as seen by both sides before and
+/// after a sync, ready to be diffed with [`crate::build_patch`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntheticMailbox {
+    pub prev_imap: Envelopes,
+    pub next_imap: Envelopes,
+    pub prev_mdir: Envelopes,
+    pub next_mdir: Envelopes,
+}
+
+impl SyntheticMailbox {
+    /// Generates a mailbox of the given number of messages, in sync
+    /// at the previous sync. Since then, about 1% of the messages
+    /// changed on each side: added, removed or with flags changed.
+    /// The same seed always generates the same mailbox.
+    pub fn generate(size: usize, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let prev = envelopes(size, seed);
+        let mut next_imap = prev.clone();
+        let mut next_mdir = prev.clone();
+
+        for (side, next) in [&mut next_imap, &mut next_mdir].into_iter().enumerate() {
+            for i in 0..size / 100 {
+                let id = format_id(rng.below(size));
+                match rng.below(3) {
+                    0 => {
+                        // new ids are past the generated ones, and
+                        // differ per side
+                        let envelope = envelope(format_id(size + 2 * i + side), &mut rng);
+                        next.insert(envelope.id.clone(), envelope);
+                    }
+                    1 => {
+                        next.remove(id.as_ref());
+                    }
+                    _ => {
+                        if let Some(envelope) = next.get_mut(id.as_ref()) {
+                            envelope.flags = flags(&mut rng);
+                        }
+                    }
+                }
+            }
+        }
+
+        Self {
+            prev_imap: prev.clone(),
+            next_imap,
+            prev_mdir: prev,
+            next_mdir,
+        }
+    }
+}
+
+/// Generates the given number of envelopes with random flags, their
+/// ids being zero-padded numbers from 0. The same seed always
+/// generates the same envelopes.
+pub fn envelopes(size: usize, seed: u64) -> Envelopes {
+    let mut rng = Rng::new(seed);
+    let mut envelopes = Envelopes::default();
+    envelopes.reserve(size);
+    for i in 0..size {
+        let envelope = envelope(format_id(i), &mut rng);
+        envelopes.insert(envelope.id.clone(), envelope);
+    }
+    envelopes
+}
+
+fn format_id(i: usize) -> Id {
+    format!("{:08}", i).into()
+}
+
+fn envelope(id: Id, rng: &mut Rng) -> Envelope {
+    Envelope {
+        id,
+        flags: flags(rng),
+        metadata: None,
+    }
+}
+
+/// Generates flags the way they are usually spread: most messages
+/// seen, some replied or flagged, a few with a keyword.
+fn flags(rng: &mut Rng) -> Flags {
+    let mut flags = Flags::default();
+    for (flag, percent) in [
+        (Flag::Seen, 80),
+        (Flag::Replied, 10),
+        (Flag::Flagged, 5),
+        (Flag::Draft, 1),
+        (Flag::Trashed, 1),
+    ] {
+        if rng.below(100) < percent {
+            flags.insert(flag);
+        }
+    }
+    if rng.below(100) < 5 {
+        flags.insert(Flag::Keyword(format!("Label{}", rng.below(10))));
+    }
+    flags
+}
+
+/// Xorshift generator, good enough for synthetic data and the same
+/// on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::build_patch;
+
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let mailbox = SyntheticMailbox::generate(10_000, 42);
+        assert_eq!(mailbox, SyntheticMailbox::generate(10_000, 42));
+        assert_ne!(mailbox, SyntheticMailbox::generate(10_000, 43));
+        assert_eq!(10_000, mailbox.prev_imap.len());
+        assert_eq!(mailbox.prev_imap, mailbox.prev_mdir);
+
+        let patch = build_patch(
+            &mailbox.prev_imap,
+            &mailbox.next_imap,
+            &mailbox.prev_mdir,
+            &mailbox.next_mdir,
+        );
+        // about 1% of changes per side, each giving a hunk or more
+        assert!((100..1000).contains(&patch.len()), "{}", patch.len());
+    }
+}
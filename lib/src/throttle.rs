@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{plan::Backend, EverestError};
+
+/// Number of requests a backend accepts per period of time. Requests
+/// can come in bursts, as long as their average rate stays below the
+/// limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per: Duration,
+}
+
+impl RateLimit {
+    pub fn per_second(requests: u32) -> Self {
+        Self {
+            requests,
+            per: Duration::from_secs(1),
+        }
+    }
+
+    /// Returns the time between two requests at the limit rate.
+    fn interval(&self) -> Duration {
+        self.per / self.requests.max(1)
+    }
+}
+
+/// Mail providers known to throttle aggressive clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Provider {
+    Gmail,
+    Outlook,
+    Other,
+}
+
+impl Provider {
+    /// Guesses the provider from the host of its IMAP server.
+    pub fn from_host(host: &str) -> Self {
+        let host = host.trim_end_matches('.').to_lowercase();
+        let is = |domain: &str| host == domain || host.ends_with(&format!(".{}", domain));
+        if is("gmail.com") || is("googlemail.com") {
+            Self::Gmail
+        } else if is("outlook.com") || is("office365.com") || is("hotmail.com") {
+            Self::Outlook
+        } else {
+            Self::Other
+        }
+    }
+
+    /// Returns the IMAP rate limit of the provider, staying below the
+    /// rate at which it starts throttling. `None` for providers not
+    /// known to throttle.
+    pub fn imap_rate_limit(&self) -> Option<RateLimit> {
+        match self {
+            Self::Gmail => Some(RateLimit::per_second(10)),
+            Self::Outlook => Some(RateLimit::per_second(5)),
+            Self::Other => None,
+        }
+    }
+}
+
+/// Delays between retries once a backend throttles: doubling from
+/// `initial` up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    /// Starts at 1 second, up to 5 minutes.
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+impl Backoff {
+    /// Returns the delay after the given number of throttling
+    /// responses in a row, from 1.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+
+    /// Runs the given operation up to the given number of attempts,
+    /// retrying it while it fails with a transient error. Retries
+    /// wait for the delay of the attempt, or longer if the error asks
+    /// for it with [`EverestError::retry_after`].
+    pub fn retry<T, F>(&self, attempts: u32, mut f: F) -> Result<T, EverestError>
+    where
+        F: FnMut() -> Result<T, EverestError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Err(err) if attempt < attempts => {
+                    let retry_after = match err.retry_after() {
+                        Some(retry_after) => retry_after,
+                        None => return Err(err),
+                    };
+                    thread::sleep(retry_after.max(self.delay(attempt)));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Limiter {
+    limit: Option<RateLimit>,
+    /// Time at which the next request would be sent, were requests
+    /// sent at the limit rate.
+    next: Option<Instant>,
+    /// Throttling responses in a row.
+    throttled: u32,
+    blocked_until: Option<Instant>,
+}
+
+/// Request rate limits and backoff per backend, so that long syncs do
+/// not get accounts temporarily locked by their provider.
+///
+/// Executors reserve each request with [`Throttle::reserve`] (or wait
+/// for it with [`Throttle::wait`]), and report throttling responses
+/// with [`Throttle::throttled`] to back off.
+#[derive(Debug, Clone, Default)]
+pub struct Throttle {
+    limiters: HashMap<Backend, Limiter>,
+    backoff: Backoff,
+}
+
+impl Throttle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits IMAP requests the way the given provider expects.
+    pub fn for_provider(provider: Provider) -> Self {
+        match provider.imap_rate_limit() {
+            Some(limit) => Self::new().with_limit(Backend::Imap, limit),
+            None => Self::new(),
+        }
+    }
+
+    pub fn with_limit(mut self, backend: Backend, limit: RateLimit) -> Self {
+        self.limiters.entry(backend).or_default().limit = Some(limit);
+        self
+    }
+
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Reserves a request to the given backend made at the given time,
+    /// and returns how long to wait before sending it.
+    pub fn reserve(&mut self, backend: Backend, now: Instant) -> Duration {
+        let limiter = self.limiters.entry(backend).or_default();
+        let mut delay = limiter
+            .blocked_until
+            .map(|until| until.saturating_duration_since(now))
+            .unwrap_or_default();
+
+        if let Some(limit) = limiter.limit {
+            // requests within the limit can be sent in a burst
+            let interval = limit.interval();
+            let burst = limit.per.saturating_sub(interval);
+            let next = limiter.next.map_or(now, |next| next.max(now));
+            let at = next.checked_sub(burst).unwrap_or(now);
+            delay = delay.max(at.saturating_duration_since(now));
+            limiter.next = Some(next.max(now + delay) + interval);
+        }
+
+        delay
+    }
+
+    /// Blocks until a request can be sent to the given backend.
+    pub fn wait(&mut self, backend: Backend) {
+        let delay = self.reserve(backend, Instant::now());
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Records a throttling response of the given backend received at
+    /// the given time: requests are held back for a delay doubling at
+    /// each throttling response in a row, which is returned.
+    pub fn throttled(&mut self, backend: Backend, now: Instant) -> Duration {
+        let limiter = self.limiters.entry(backend).or_default();
+        limiter.throttled += 1;
+        let delay = self.backoff.delay(limiter.throttled);
+        limiter.blocked_until = Some(now + delay);
+        delay
+    }
+
+    /// Records a successful response of the given backend, which
+    /// resets its backoff.
+    pub fn succeeded(&mut self, backend: Backend) {
+        if let Some(limiter) = self.limiters.get_mut(&backend) {
+            limiter.throttled = 0;
+            limiter.blocked_until = None;
+        }
+    }
+}
+
+/// Tells whether the given IMAP response text means that the server
+/// throttles the client, like the `[THROTTLED]` response code or the
+/// messages of Gmail and Outlook. The `[LIMIT]` code of RFC 5530 is
+/// not one: it reports limits of the server, like a message too large,
+/// that retrying does not lift.
+pub fn is_throttling_response(text: &str) -> bool {
+    let text = text.to_lowercase();
+    [
+        "[throttled]",
+        "too many simultaneous connections",
+        "bandwidth limits exceeded",
+        "request is throttled",
+    ]
+    .iter()
+    .any(|pattern| text.contains(pattern))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::*;
+
+    #[test]
+    fn provider_test() {
+        assert_eq!(Provider::Gmail, Provider::from_host("imap.gmail.com"));
+        assert_eq!(
+            Provider::Outlook,
+            Provider::from_host("Outlook.Office365.com.")
+        );
+        assert_eq!(Provider::Other, Provider::from_host("notgmail.com"));
+        assert!(is_throttling_response(
+            "NO [THROTTLED] Request is throttled"
+        ));
+        assert!(is_throttling_response(
+            "NO [ALERT] Too many simultaneous connections. (Failure)"
+        ));
+        assert!(!is_throttling_response("OK Success"));
+        assert!(!is_throttling_response("NO [LIMIT] Message too large"));
+        assert!(!is_throttling_response("NO [LIMIT] Too many keywords"));
+    }
+
+    #[test]
+    fn reserve_test() {
+        let now = Instant::now();
+        let ms = Duration::from_millis;
+        let mut throttle = Throttle::new().with_limit(Backend::Imap, RateLimit::per_second(4));
+
+        // a burst of 4, then one request every 250ms
+        for _ in 0..4 {
+            assert_eq!(Duration::ZERO, throttle.reserve(Backend::Imap, now));
+        }
+        assert_eq!(ms(250), throttle.reserve(Backend::Imap, now));
+        assert_eq!(ms(500), throttle.reserve(Backend::Imap, now));
+        assert_eq!(ms(500), throttle.reserve(Backend::Imap, now + ms(250)));
+        // maildir is not limited
+        assert_eq!(Duration::ZERO, throttle.reserve(Backend::Maildir, now));
+
+        // the limit is forgotten after a while
+        let later = now + Duration::from_secs(10);
+        assert_eq!(Duration::ZERO, throttle.reserve(Backend::Imap, later));
+    }
+
+    #[test]
+    fn backoff_test() {
+        let now = Instant::now();
+        let secs = Duration::from_secs;
+        let mut throttle = Throttle::for_provider(Provider::Other).with_backoff(Backoff {
+            initial: secs(1),
+            max: secs(3),
+        });
+
+        assert_eq!(secs(1), throttle.throttled(Backend::Imap, now));
+        assert_eq!(secs(2), throttle.throttled(Backend::Imap, now));
+        assert_eq!(secs(2), throttle.reserve(Backend::Imap, now));
+        assert_eq!(secs(3), throttle.throttled(Backend::Imap, now));
+        assert_eq!(Duration::ZERO, throttle.reserve(Backend::Maildir, now));
+
+        throttle.succeeded(Backend::Imap);
+        assert_eq!(Duration::ZERO, throttle.reserve(Backend::Imap, now));
+        assert_eq!(secs(1), throttle.throttled(Backend::Imap, now));
+    }
+
+    #[test]
+    fn retry_test() {
+        let backoff = Backoff {
+            initial: Duration::ZERO,
+            max: Duration::ZERO,
+        };
+        let err = |kind| EverestError::ReadCacheError(io::Error::from(kind), "cache".into());
+
+        // transient errors are retried
+        let mut attempts = 0;
+        let result = backoff.retry(3, || {
+            attempts += 1;
+            match attempts {
+                3 => Ok(attempts),
+                _ => Err(err(io::ErrorKind::TimedOut)),
+            }
+        });
+        assert_eq!(3, result.unwrap());
+
+        // others are not, nor are attempts beyond the given number
+        for (kind, expected) in [
+            (io::ErrorKind::PermissionDenied, 1),
+            (io::ErrorKind::TimedOut, 2),
+        ] {
+            let mut attempts = 0;
+            let result = backoff.retry(2, || {
+                attempts += 1;
+                Err::<(), _>(err(kind))
+            });
+            assert!(result.is_err());
+            assert_eq!(expected, attempts);
+        }
+    }
+}
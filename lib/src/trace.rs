@@ -0,0 +1,53 @@
+//! Instrumentation with `tracing` spans and events, with the
+//! `tracing` feature, so that users can follow syncs with their own
+//! subscribers. Without the feature, spans and events compile to
+//! nothing.
+//!
+//! Syncs open an `INFO` span per folder, holding a `DEBUG` span per
+//! phase (`fetch`, `diff`, `apply` and `commit`), and emit events
+//! with the number of envelopes and hunks of each phase. Fetches of
+//! IMAP chunks emit `TRACE` events with their size and duration, and
+//! IMAP commands run in `DEBUG` `imap_command` spans.
+
+/// Builds a span of the given level from the arguments of
+/// `tracing::span!`, like `span!(DEBUG, "diff")` or, for a child of
+/// the given span, `span!(DEBUG, parent: &span, "diff")`.
+macro_rules! span {
+    ($level:ident, parent: $parent:expr, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::span!(parent: $parent, tracing::Level::$level, $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::trace::Span;
+        span
+    }};
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        let span = tracing::span!(tracing::Level::$level, $($arg)+);
+        #[cfg(not(feature = "tracing"))]
+        let span = $crate::trace::Span;
+        span
+    }};
+}
+
+/// Emits an event of the given level from the arguments of
+/// `tracing::event!`, like `event!(DEBUG, hunks = 3, "patch built")`.
+macro_rules! event {
+    ($level:ident, $($arg:tt)+) => {
+        #[cfg(feature = "tracing")]
+        tracing::event!(tracing::Level::$level, $($arg)+);
+    };
+}
+
+pub(crate) use {event, span};
+
+/// Span standing for nothing, without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    pub(crate) fn entered(self) -> Self {
+        self
+    }
+}
@@ -0,0 +1,190 @@
+//! Divergences between both sides of a sync, for users to audit
+//! whether their mail stores actually match, without syncing them.
+//!
+//! A [`VerifyReport`] renders the divergences of each folder, one per
+//! line:
+//!
+//! ```text
+//! INBOX: 3 msgs compared, 2 divergences
+//!   msg 42 missing from right
+//!   flags of msg 7 differ: left has \Seen, right has none
+//! Trash: skipped: cannot find maildir folder Trash
+//! ```
+
+use std::fmt;
+
+use crate::{flag::format_flag, report::FolderError, Envelopes, Flags, Id, Side};
+
+/// How deep [`crate::sync::Sync::verify`] compares both sides.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum VerifyMode {
+    /// Compares the ids and flags of the envelopes.
+    #[default]
+    Envelopes,
+    /// Also compares the content hashes of the messages found on both
+    /// sides, reading every message.
+    Contents,
+}
+
+/// Difference between both sides for a message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The message is missing from the given side.
+    Missing(Id, Side),
+    /// The message has different flags, given by side: the flags only
+    /// the left side has, and the ones only the right side has.
+    Flags { id: Id, left: Flags, right: Flags },
+    /// The message has different contents.
+    Content(Id),
+}
+
+impl Divergence {
+    pub fn id(&self) -> &Id {
+        match self {
+            Self::Missing(id, _) | Self::Flags { id, .. } | Self::Content(id) => id,
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flags = |flags: &Flags| match flags.is_empty() {
+            true => "none".to_owned(),
+            false => flags.iter().map(format_flag).collect::<Vec<_>>().join(" "),
+        };
+        match self {
+            Self::Missing(id, side) => write!(f, "msg {} missing from {}", id, side),
+            Self::Flags { id, left, right } => write!(
+                f,
+                "flags of msg {} differ: left has {}, right has {}",
+                id,
+                flags(left),
+                flags(right)
+            ),
+            Self::Content(id) => write!(f, "content of msg {} differs", id),
+        }
+    }
+}
+
+/// Divergences of a folder.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FolderDivergences {
+    pub folder: String,
+    /// Number of messages found on both sides.
+    pub compared: usize,
+    /// Divergences, ordered by id.
+    pub divergences: Vec<Divergence>,
+}
+
+/// Report of [`crate::sync::Sync::verify`].
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    /// Folders compared, in the order they were.
+    pub folders: Vec<FolderDivergences>,
+    /// Folders that could not be compared, like when a side cannot be
+    /// listed.
+    pub skipped: Vec<FolderError>,
+}
+
+impl VerifyReport {
+    /// Returns `true` if every folder was compared and both sides
+    /// match.
+    pub fn is_consistent(&self) -> bool {
+        self.skipped.is_empty() && self.divergences().next().is_none()
+    }
+
+    /// Returns the divergences of all the folders, with their folder.
+    pub fn divergences(&self) -> impl Iterator<Item = (&str, &Divergence)> {
+        self.folders.iter().flat_map(|folder| {
+            let name = folder.folder.as_str();
+            folder.divergences.iter().map(move |d| (name, d))
+        })
+    }
+}
+
+/// Renders the divergences by folder, see the [module](self)
+/// documentation.
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let plural = |count: usize| if count == 1 { "" } else { "s" };
+        let mut lines = Vec::new();
+        for folder in &self.folders {
+            let (compared, divergences) = (folder.compared, folder.divergences.len());
+            lines.push(format!(
+                "{}: {} msg{} compared, {} divergence{}",
+                folder.folder,
+                compared,
+                plural(compared),
+                divergences,
+                plural(divergences)
+            ));
+            for divergence in &folder.divergences {
+                lines.push(format!("  {}", divergence));
+            }
+        }
+        for err in &self.skipped {
+            lines.push(format!("{}: skipped: {}", err.folder, err.error));
+        }
+        f.write_str(&lines.join("\n"))
+    }
+}
+
+/// Compares the envelopes of both sides of a folder, and returns
+/// their divergences ordered by id. Contents are not compared.
+pub fn compare(left: &Envelopes, right: &Envelopes) -> Vec<Divergence> {
+    let mut divergences = Vec::new();
+    for (id, envelope) in left.iter() {
+        match right.get(id) {
+            None => divergences.push(Divergence::Missing(id.clone(), Side::Right)),
+            Some(other) if other.flags != envelope.flags => divergences.push(Divergence::Flags {
+                id: id.clone(),
+                left: envelope.flags.difference(&other.flags),
+                right: other.flags.difference(&envelope.flags),
+            }),
+            Some(_) => (),
+        }
+    }
+    let missing = right.keys().filter(|id| !left.contains_key(*id));
+    divergences.extend(missing.map(|id| Divergence::Missing(id.clone(), Side::Left)));
+    divergences.sort_by(|a, b| a.id().cmp(b.id()));
+    divergences
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Envelope, Flag};
+
+    use super::*;
+
+    #[test]
+    fn compare_test() {
+        let left = Envelopes::from_iter([
+            Envelope::new("1").with_flag(Flag::Seen),
+            Envelope::new("2"),
+            Envelope::new("3"),
+        ]);
+        let right = Envelopes::from_iter([
+            Envelope::new("1").with_flag(Flag::Keyword("$Work".into())),
+            Envelope::new("3"),
+            Envelope::new("4"),
+        ]);
+        let divergences = compare(&left, &right);
+        let report = VerifyReport {
+            folders: vec![FolderDivergences {
+                folder: "INBOX".into(),
+                compared: 2,
+                divergences,
+            }],
+            skipped: vec![],
+        };
+        assert!(!report.is_consistent());
+        assert_eq!(
+            "INBOX: 2 msgs compared, 3 divergences\n  \
+             flags of msg 1 differ: left has \\Seen, right has $Work\n  \
+             msg 2 missing from right\n  \
+             msg 4 missing from left",
+            report.to_string()
+        );
+        assert!(compare(&left, &left).is_empty());
+    }
+}